@@ -0,0 +1,24 @@
+#![no_main]
+
+use std::sync::OnceLock;
+
+use libfuzzer_sys::fuzz_target;
+use redis_starter_rust::{RedisHandle, Server};
+use tokio::runtime::Runtime;
+
+fn redis() -> &'static (Runtime, RedisHandle) {
+    static REDIS: OnceLock<(Runtime, RedisHandle)> = OnceLock::new();
+    REDIS.get_or_init(|| {
+        let runtime = Runtime::new().unwrap();
+        let handle = runtime.block_on(async { Server::builder().embedded() });
+        (runtime, handle)
+    })
+}
+
+// Splits the input on NUL bytes into a command line and executes it, so the command parser and
+// handlers see every shape of argument list.
+fuzz_target!(|data: &[u8]| {
+    let (runtime, handle) = redis();
+    let command_line = data.split(|&b| b == 0).collect::<Vec<_>>();
+    runtime.block_on(handle.execute(command_line));
+});
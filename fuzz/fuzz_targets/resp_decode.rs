@@ -0,0 +1,14 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use redis_starter_rust::resp::Resp;
+
+// Feeds arbitrary bytes through the same framing and decoding the server applies to every
+// connection. Neither step may panic, whatever the client sends.
+fuzz_target!(|data: &[u8]| {
+    if let Ok(Some(len)) = Resp::frame_len(data, 512 * 1024 * 1024) {
+        let _ = Resp::decode(&String::from_utf8_lossy(&data[..len]));
+    }
+
+    let _ = Resp::decode(&String::from_utf8_lossy(data));
+});
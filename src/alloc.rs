@@ -0,0 +1,115 @@
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    fs,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+/// The system allocator, counting the bytes it hands out so MEMORY STATS can report real memory use
+/// instead of only estimating the dataset.
+pub struct TrackingAllocator;
+
+#[global_allocator]
+static ALLOCATOR: TrackingAllocator = TrackingAllocator;
+
+impl TrackingAllocator {
+    fn allocated(size: usize) {
+        let allocated = ALLOCATED.fetch_add(size, Ordering::Relaxed) + size;
+        PEAK.fetch_max(allocated, Ordering::Relaxed);
+    }
+
+    fn freed(size: usize) {
+        ALLOCATED.fetch_sub(size, Ordering::Relaxed);
+    }
+}
+
+unsafe impl GlobalAlloc for TrackingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            Self::allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            Self::allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        Self::freed(layout.size());
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            Self::freed(layout.size());
+            Self::allocated(new_size);
+        }
+        new_ptr
+    }
+}
+
+/// A snapshot of the allocator counters.
+#[derive(Debug, Clone, Copy)]
+pub struct AllocatorStats {
+    /// Bytes currently handed out to the program.
+    pub allocated: usize,
+    /// The most bytes that were ever handed out at once.
+    pub peak: usize,
+    /// The resident set size of the process, when the platform tells us.
+    pub resident: Option<usize>,
+}
+
+impl AllocatorStats {
+    pub fn current() -> AllocatorStats {
+        AllocatorStats {
+            allocated: ALLOCATED.load(Ordering::Relaxed),
+            peak: PEAK.load(Ordering::Relaxed),
+            resident: resident_set_size(),
+        }
+    }
+
+    /// How much more memory the process holds than it asked for, like `mem_fragmentation_ratio`.
+    pub fn fragmentation(&self) -> Option<f64> {
+        let resident = self.resident?;
+        (self.allocated > 0).then(|| resident as f64 / self.allocated as f64)
+    }
+}
+
+// NOTE: Only Linux is supported, elsewhere the resident size and fragmentation aren't reported.
+fn resident_set_size() -> Option<usize> {
+    let status = fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|line| line.starts_with("VmRSS:"))?;
+    let kilobytes = line
+        .trim_start_matches("VmRSS:")
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse::<usize>()
+        .ok()?;
+    Some(kilobytes * 1024)
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::AllocatorStats;
+
+    #[test]
+    fn allocations_are_counted() {
+        let buffer = vec![1u8; 1 << 20];
+        let stats = AllocatorStats::current();
+        drop(buffer);
+
+        // Other tests allocate concurrently, so exact numbers can't be checked.
+        assert!(stats.allocated >= 1 << 20);
+        assert!(stats.peak >= stats.allocated);
+    }
+}
@@ -0,0 +1,1113 @@
+use std::{
+    fmt::Display,
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bytes::Bytes;
+
+use crate::{
+    keyspace::Snapshot,
+    persistence::{Persistence, PersistenceError, Record},
+    redis::RedisValue,
+    resp::{Protocol, ReplyBuffer, Resp},
+};
+
+const DEFAULT_APPENDDIRNAME: &str = "appendonlydir";
+// Matches Redis, which writes at most 64 elements of a collection per command when rewriting.
+const REWRITE_ITEMS_PER_COMMAND: usize = 64;
+
+/// Append-only file: every write command is logged as a RESP array and replayed on startup.
+///
+/// The log uses the multi-part layout of Redis 7. A base file written by the last rewrite is
+/// followed by incremental files holding the writes since, and a manifest lists them all. They
+/// live in the `appenddirname` directory, so a rewrite only replaces the base instead of copying
+/// the whole history.
+///
+/// Lines starting with `#` are annotations rather than commands. With timestamps enabled, a
+/// `#TS:<unix seconds>` annotation is written whenever a write happens in a new second, which
+/// allows truncating the log to a point in time.
+pub struct Aof {
+    /// Where a single file log of older versions would be, it is upgraded to the multi-part
+    /// layout when loaded.
+    path: PathBuf,
+    dir: PathBuf,
+    filename: String,
+    state: Mutex<AofState>,
+    timestamps: bool,
+    last_timestamp: AtomicU64,
+    load_truncated: bool,
+}
+
+#[derive(Default)]
+struct AofState {
+    /// Loaded on first use.
+    manifest: Option<Manifest>,
+    /// Appends to the last incremental file.
+    writer: Option<BufWriter<File>>,
+    /// The first incremental file started after the snapshot being written in the background was
+    /// taken, which the rewrite has to keep.
+    rewrite_incr: Option<u64>,
+}
+
+/// The files making up the log, in the order they are replayed.
+#[derive(Debug, Default, PartialEq)]
+struct Manifest {
+    base: Option<ManifestFile>,
+    incrs: Vec<ManifestFile>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct ManifestFile {
+    name: String,
+    seq: u64,
+}
+
+/// Why an entry of the log couldn't be read.
+enum EntryError {
+    /// The file ends in the middle of the entry.
+    Truncated(&'static str),
+    Corrupt(String),
+}
+
+impl Display for EntryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EntryError::Truncated(what) => write!(f, "truncated {}", what),
+            EntryError::Corrupt(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+/// An item of the log.
+enum Entry {
+    Command(Resp),
+    /// An annotation, with its time if it is a `#TS` one.
+    Annotation {
+        timestamp: Option<u64>,
+    },
+}
+
+impl Manifest {
+    // NOTE: Redis quotes file names containing spaces, as ours are derived from `appendfilename`
+    //       we don't support those.
+    fn parse(contents: &str) -> Result<Manifest, PersistenceError> {
+        let mut manifest = Manifest::default();
+
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let fields = line.split_whitespace().collect::<Vec<_>>();
+            let field = |name: &str| {
+                fields
+                    .chunks(2)
+                    .find(|pair| pair.len() == 2 && pair[0] == name)
+                    .map(|pair| pair[1])
+            };
+            let corrupt = || PersistenceError::Corrupt(format!("invalid manifest line: {}", line));
+
+            let file = ManifestFile {
+                name: field("file").ok_or_else(corrupt)?.to_string(),
+                seq: field("seq")
+                    .and_then(|seq| seq.parse().ok())
+                    .ok_or_else(corrupt)?,
+            };
+            match field("type") {
+                Some("b") => manifest.base = Some(file),
+                Some("i") => manifest.incrs.push(file),
+                // History files are left over from rewrites and no longer part of the log.
+                Some("h") => {}
+                _ => return Err(corrupt()),
+            }
+        }
+
+        Ok(manifest)
+    }
+
+    fn encode(&self) -> String {
+        let mut out = String::new();
+        if let Some(base) = &self.base {
+            out.push_str(&format!("file {} seq {} type b\n", base.name, base.seq));
+        }
+        for incr in &self.incrs {
+            out.push_str(&format!("file {} seq {} type i\n", incr.name, incr.seq));
+        }
+        out
+    }
+
+    fn files(&self) -> impl Iterator<Item = &ManifestFile> {
+        self.base.iter().chain(&self.incrs)
+    }
+}
+
+impl Aof {
+    /// Creates a log named after the file name of `path`, kept in an `appendonlydir` directory
+    /// next to it.
+    pub fn new(path: PathBuf) -> Aof {
+        let filename = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        Aof {
+            dir: path.with_file_name(DEFAULT_APPENDDIRNAME),
+            filename,
+            path,
+            state: Mutex::default(),
+            timestamps: false,
+            last_timestamp: AtomicU64::new(0),
+            load_truncated: true,
+        }
+    }
+
+    /// Keeps the files of the log in the `dirname` directory instead (`appenddirname`).
+    pub fn with_dirname(mut self, dirname: &str) -> Aof {
+        self.dir = self.path.with_file_name(dirname);
+        self
+    }
+
+    /// Enables writing `#TS` annotations (`aof-timestamp-enabled`).
+    pub fn with_timestamps(mut self, enabled: bool) -> Aof {
+        self.timestamps = enabled;
+        self
+    }
+
+    /// Whether a log ending in the middle of a command, like after a crash mid-write, is cut back
+    /// to its last complete command when loaded (`aof-load-truncated`, the default) or refuses to
+    /// load at all.
+    pub fn with_load_truncated(mut self, enabled: bool) -> Aof {
+        self.load_truncated = enabled;
+        self
+    }
+
+    fn manifest_path(&self) -> PathBuf {
+        self.dir.join(format!("{}.manifest", self.filename))
+    }
+
+    fn file_name(&self, seq: u64, kind: &str) -> String {
+        format!("{}.{}.{}.aof", self.filename, seq, kind)
+    }
+
+    fn manifest<'a>(&self, state: &'a mut AofState) -> Result<&'a mut Manifest, PersistenceError> {
+        if state.manifest.is_none() {
+            let manifest = match fs::read_to_string(self.manifest_path()) {
+                Ok(contents) => Manifest::parse(&contents)?,
+                Err(error) if error.kind() == io::ErrorKind::NotFound => self.upgrade()?,
+                Err(error) => return Err(error.into()),
+            };
+            state.manifest = Some(manifest);
+        }
+
+        Ok(state.manifest.as_mut().unwrap())
+    }
+
+    // NOTE: Like Redis 7, a single file log found without a manifest becomes the base file of the
+    //       multi-part layout.
+    fn upgrade(&self) -> Result<Manifest, PersistenceError> {
+        let mut manifest = Manifest::default();
+
+        if self.path.is_file() {
+            fs::create_dir_all(&self.dir)?;
+            let base = ManifestFile {
+                name: self.file_name(1, "base"),
+                seq: 1,
+            };
+            fs::rename(&self.path, self.dir.join(&base.name))?;
+            manifest.base = Some(base);
+            self.write_manifest(&manifest)?;
+        }
+
+        Ok(manifest)
+    }
+
+    fn write_manifest(&self, manifest: &Manifest) -> io::Result<()> {
+        let temp_path = self.dir.join(format!("temp-{}.manifest", self.filename));
+        let mut temp = File::create(&temp_path)?;
+        temp.write_all(manifest.encode().as_bytes())?;
+        temp.sync_all()?;
+        fs::rename(&temp_path, self.manifest_path())
+    }
+
+    // NOTE: Only called with the state lock held, so checking and updating the last timestamp
+    //       doesn't race with other writers.
+    fn write_timestamp(&self, writer: &mut impl Write, force: bool) -> io::Result<()> {
+        if !self.timestamps {
+            return Ok(());
+        }
+
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        if force || self.last_timestamp.load(Ordering::Relaxed) != now {
+            write!(writer, "#TS:{}\r\n", now)?;
+            self.last_timestamp.store(now, Ordering::Relaxed);
+        }
+
+        Ok(())
+    }
+
+    /// The commands that recreate a key. Collections are written in batches, like Redis does, so no
+    /// single command grows too large, and their expiry is set afterwards.
+    fn rewrite_commands(key: &Bytes, value: &RedisValue, expiry: Option<&u64>) -> Vec<Vec<Bytes>> {
+        let (name, items): (&str, Vec<Bytes>) = match value {
+            RedisValue::String(value) => {
+                let mut command = vec![Bytes::from("SET"), key.clone(), value.clone()];
+                if let Some(expiry) = expiry {
+                    command.push(Bytes::from("PXAT"));
+                    command.push(Bytes::from(expiry.to_string()));
+                }
+                return vec![command];
+            }
+            RedisValue::List(list) => ("RPUSH", list.iter().cloned().collect()),
+            RedisValue::Hash(hash) => (
+                "HSET",
+                hash.iter()
+                    .flat_map(|(field, value)| [field.clone(), value.clone()])
+                    .collect(),
+            ),
+            RedisValue::Set(set) => ("SADD", set.iter().cloned().collect()),
+            RedisValue::SortedSet(zset) => (
+                "ZADD",
+                zset.iter()
+                    .flat_map(|(member, score)| [Bytes::from(score.to_string()), member.clone()])
+                    .collect(),
+            ),
+        };
+
+        let batch = match value {
+            RedisValue::Hash(_) | RedisValue::SortedSet(_) => REWRITE_ITEMS_PER_COMMAND * 2,
+            _ => REWRITE_ITEMS_PER_COMMAND,
+        };
+        let mut commands = items
+            .chunks(batch)
+            .map(|chunk| {
+                let mut command = vec![Bytes::from(name), key.clone()];
+                command.extend_from_slice(chunk);
+                command
+            })
+            .collect::<Vec<_>>();
+
+        if let Some(expiry) = expiry {
+            commands.push(vec![
+                Bytes::from("PEXPIREAT"),
+                key.clone(),
+                Bytes::from(expiry.to_string()),
+            ]);
+        }
+        commands
+    }
+
+    pub(crate) fn encode_command<A: AsRef<[u8]>>(args: &[A]) -> Vec<u8> {
+        let command = Resp::Array(
+            args.iter()
+                .map(|arg| Resp::BulkString(Bytes::copy_from_slice(arg.as_ref())))
+                .collect(),
+        );
+
+        let mut out = ReplyBuffer::new();
+        command.encode_into(Protocol::Resp2, &mut out).unwrap();
+        out.to_vec()
+    }
+
+    // Reads the entry starting at `offset`, returning it along with its encoded length.
+    fn read_entry(contents: &[u8], offset: usize) -> Result<(Entry, usize), EntryError> {
+        if contents[offset] == b'#' {
+            let line = &contents[offset..];
+            let Some(end) = line.windows(2).position(|window| window == b"\r\n") else {
+                return Err(EntryError::Truncated("annotation"));
+            };
+            let timestamp = std::str::from_utf8(&line[1..end])
+                .ok()
+                .and_then(|annotation| annotation.strip_prefix("TS:"))
+                .and_then(|timestamp| timestamp.parse().ok());
+
+            return Ok((Entry::Annotation { timestamp }, end + 2));
+        }
+
+        let len = match Resp::frame_len(&contents[offset..], usize::MAX) {
+            Ok(Some(len)) => len,
+            Ok(None) => return Err(EntryError::Truncated("command")),
+            Err(error) => return Err(EntryError::Corrupt(error.to_string())),
+        };
+
+        let command = Resp::decode(&contents[offset..offset + len])
+            .map_err(|error| EntryError::Corrupt(error.to_string()))?;
+        match &command {
+            Resp::Array(args)
+                if !args.is_empty()
+                    && args.iter().all(|arg| matches!(arg, Resp::BulkString(_))) =>
+            {
+                Ok((Entry::Command(command), len))
+            }
+            _ => Err(EntryError::Corrupt(
+                "expected a command as an array of bulk strings".to_string(),
+            )),
+        }
+    }
+
+    /// Validates every command in an append-only file, stopping at the first corrupt one.
+    pub fn check(contents: &[u8]) -> AofReport {
+        Self::scan(contents, None)
+    }
+
+    // Like `check`, but also stops at the first timestamp annotation after `timestamp_cutoff`.
+    fn scan(contents: &[u8], timestamp_cutoff: Option<u64>) -> AofReport {
+        let mut report = AofReport {
+            size: contents.len(),
+            timestamp_cutoff,
+            ..AofReport::default()
+        };
+
+        while report.ok_up_to < contents.len() {
+            match Self::read_entry(contents, report.ok_up_to) {
+                Ok((Entry::Annotation { timestamp }, len)) => {
+                    if let (Some(timestamp), Some(cutoff)) = (timestamp, timestamp_cutoff) {
+                        if timestamp > cutoff {
+                            break;
+                        }
+                    }
+                    report.ok_up_to += len;
+                }
+                Ok((Entry::Command(_), len)) => {
+                    report.commands += 1;
+                    report.ok_up_to += len;
+                }
+                Err(error) => {
+                    report.error = Some(error.to_string());
+                    break;
+                }
+            }
+        }
+
+        report
+    }
+
+    /// Checks the append-only file at `path` like `redis-check-aof` does. With `fix`, a corrupt
+    /// file is truncated after its last valid command.
+    pub fn check_file(path: &Path, fix: bool) -> io::Result<AofReport> {
+        let mut report = Self::check(&fs::read(path)?);
+
+        if fix && !report.is_ok() {
+            OpenOptions::new()
+                .write(true)
+                .open(path)?
+                .set_len(report.ok_up_to as u64)?;
+            report.fixed = true;
+        }
+
+        Ok(report)
+    }
+
+    /// Truncates the append-only file at `path` before the first `#TS` annotation later than
+    /// `timestamp`, restoring the dataset to that point in time on the next load. Corrupt files
+    /// are left alone.
+    pub fn truncate_to_timestamp(path: &Path, timestamp: u64) -> io::Result<AofReport> {
+        let mut report = Self::scan(&fs::read(path)?, Some(timestamp));
+
+        if report.is_ok() && report.ok_up_to < report.size {
+            OpenOptions::new()
+                .write(true)
+                .open(path)?
+                .set_len(report.ok_up_to as u64)?;
+            report.fixed = true;
+        }
+
+        Ok(report)
+    }
+}
+
+/// What `--check-aof` found in a file.
+#[derive(Debug, Default)]
+pub struct AofReport {
+    pub size: usize,
+    pub commands: usize,
+    /// The offset just past the last valid command.
+    pub ok_up_to: usize,
+    pub error: Option<String>,
+    /// The time the file was checked up to, when truncating it to a timestamp.
+    pub timestamp_cutoff: Option<u64>,
+    /// Whether the file was truncated to `ok_up_to`.
+    pub fixed: bool,
+}
+
+impl AofReport {
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+impl Display for AofReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "AOF analyzed: size={}, ok_up_to={}, commands={}, diff={}",
+            self.size,
+            self.ok_up_to,
+            self.commands,
+            self.size - self.ok_up_to
+        )?;
+
+        match (&self.error, self.fixed) {
+            (None, true) => writeln!(
+                f,
+                "Successfully truncated AOF to timestamp {}",
+                self.timestamp_cutoff.unwrap_or_default()
+            ),
+            (None, false) => writeln!(f, "AOF is valid"),
+            (Some(error), false) => {
+                writeln!(f, "[error] {} at offset {}", error, self.ok_up_to)?;
+                writeln!(
+                    f,
+                    "AOF is not valid. Use the --fix option to try fixing it."
+                )
+            }
+            (Some(error), true) => {
+                writeln!(f, "[error] {} at offset {}", error, self.ok_up_to)?;
+                writeln!(f, "Successfully truncated AOF to {} bytes", self.ok_up_to)
+            }
+        }
+    }
+}
+
+impl Persistence for Aof {
+    fn replay(&self, apply: &mut dyn FnMut(Record)) -> Result<(), PersistenceError> {
+        let mut state = self.state.lock().unwrap();
+        let manifest = self.manifest(&mut state)?;
+
+        let last_file = manifest.files().last().map(|file| file.name.clone());
+        for file in manifest.files() {
+            let path = self.dir.join(&file.name);
+            let contents = fs::read(&path)?;
+            let mut offset = 0;
+
+            while offset < contents.len() {
+                let (entry, len) = match Self::read_entry(&contents, offset) {
+                    Ok(entry) => entry,
+                    // NOTE: Like Redis, only the end of the last file can be cut short by a crash,
+                    //       anything else means the log was damaged some other way.
+                    Err(EntryError::Truncated(_))
+                        if self.load_truncated && last_file.as_ref() == Some(&file.name) =>
+                    {
+                        eprintln!(
+                            "!!! Warning: short read while loading the AOF file {}, truncating it \
+                             to its last valid command at offset {} !!!",
+                            file.name, offset
+                        );
+                        OpenOptions::new()
+                            .write(true)
+                            .open(&path)?
+                            .set_len(offset as u64)?;
+                        break;
+                    }
+                    Err(error) => {
+                        let hint = match error {
+                            EntryError::Truncated(_) => {
+                                ", set aof-load-truncated yes or run --check-aof --fix to load it"
+                            }
+                            EntryError::Corrupt(_) => "",
+                        };
+                        return Err(PersistenceError::Corrupt(format!(
+                            "{} at offset {} of {}{}",
+                            error, offset, file.name, hint
+                        )));
+                    }
+                };
+                if let Entry::Command(command) = entry {
+                    apply(Record::Command(command));
+                }
+
+                offset += len;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn append(&self, command: &[Bytes]) -> Result<(), PersistenceError> {
+        let mut state = self.state.lock().unwrap();
+
+        if state.writer.is_none() {
+            let manifest = self.manifest(&mut state)?;
+            if manifest.incrs.is_empty() {
+                fs::create_dir_all(&self.dir)?;
+                let incr = ManifestFile {
+                    name: self.file_name(1, "incr"),
+                    seq: 1,
+                };
+                File::create(self.dir.join(&incr.name))?;
+                manifest.incrs.push(incr);
+                self.write_manifest(manifest)?;
+            }
+
+            let path = self.dir.join(&manifest.incrs.last().unwrap().name);
+            let file = OpenOptions::new().append(true).open(path)?;
+            state.writer = Some(BufWriter::new(file));
+        }
+
+        let writer = state.writer.as_mut().unwrap();
+        self.write_timestamp(writer, false)?;
+        writer.write_all(&Self::encode_command(command))?;
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    /// Moves appends over to a new incremental file, which the rewrite of the snapshot prepared for
+    /// keeps instead of replacing.
+    fn prepare_snapshot(&self) -> Result<(), PersistenceError> {
+        let mut state = self.state.lock().unwrap();
+        let manifest = self.manifest(&mut state)?;
+
+        let seq = manifest.incrs.last().map_or(1, |incr| incr.seq + 1);
+        let incr = ManifestFile {
+            name: self.file_name(seq, "incr"),
+            seq,
+        };
+        fs::create_dir_all(&self.dir)?;
+        File::create(self.dir.join(&incr.name))?;
+        manifest.incrs.push(incr);
+        self.write_manifest(manifest)?;
+
+        state.writer = None;
+        state.rewrite_incr = Some(seq);
+
+        Ok(())
+    }
+
+    /// Rewrites the log as a new base file holding the minimal set of commands recreating
+    /// `databases`, followed by an empty incremental file. The files it replaces are removed.
+    fn snapshot(&self, databases: &[Snapshot]) -> Result<(), PersistenceError> {
+        let mut state = self.state.lock().unwrap();
+        let rewrite_incr = state.rewrite_incr.take();
+        let old = self.manifest(&mut state)?;
+
+        let base_seq = old.base.as_ref().map_or(1, |base| base.seq + 1);
+        let base = ManifestFile {
+            name: self.file_name(base_seq, "base"),
+            seq: base_seq,
+        };
+        let incr_seq = old.incrs.last().map_or(1, |incr| incr.seq + 1);
+        let incr = ManifestFile {
+            name: self.file_name(incr_seq, "incr"),
+            seq: incr_seq,
+        };
+
+        // Writes made since a background snapshot was prepared aren't part of it, so their files
+        // carry on after the new base.
+        let kept_incrs = match rewrite_incr {
+            Some(first) => old
+                .incrs
+                .iter()
+                .filter(|incr| incr.seq >= first)
+                .cloned()
+                .collect::<Vec<_>>(),
+            None => Vec::new(),
+        };
+        let old_files = old
+            .files()
+            .filter(|file| !kept_incrs.contains(file))
+            .map(|file| file.name.clone())
+            .collect::<Vec<_>>();
+
+        fs::create_dir_all(&self.dir)?;
+        let temp_path = self
+            .dir
+            .join(format!("temp-rewriteaof-{}.aof", std::process::id()));
+        let mut temp = BufWriter::new(File::create(&temp_path)?);
+        self.write_timestamp(&mut temp, true)?;
+
+        // Loading starts in database 0, so only the ones after it need selecting.
+        for (db, snapshot) in databases.iter().enumerate() {
+            if db > 0 && !snapshot.store.is_empty() {
+                let select = [Bytes::from("SELECT"), Bytes::from(db.to_string())];
+                temp.write_all(&Self::encode_command(&select))?;
+            }
+            for (key, value) in &snapshot.store {
+                let expiry = snapshot.expiry_table.get(key);
+                for command in Self::rewrite_commands(key, value, expiry) {
+                    temp.write_all(&Self::encode_command(&command))?;
+                }
+            }
+        }
+
+        temp.flush()?;
+        temp.get_ref().sync_all()?;
+        fs::rename(&temp_path, self.dir.join(&base.name))?;
+        let incrs = match kept_incrs.is_empty() {
+            true => {
+                File::create(self.dir.join(&incr.name))?;
+                vec![incr]
+            }
+            false => kept_incrs,
+        };
+
+        let manifest = Manifest {
+            base: Some(base),
+            incrs,
+        };
+        self.write_manifest(&manifest)?;
+
+        // The old files are no longer part of the log once the new manifest is in place.
+        for name in old_files {
+            let _ = fs::remove_file(self.dir.join(name));
+        }
+
+        state.manifest = Some(manifest);
+        state.writer = None;
+
+        Ok(())
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use crate::{
+        aof::{Aof, Manifest},
+        keyspace::Snapshot,
+        persistence::{Persistence, Record},
+        redis::RedisValue,
+        resp::Resp,
+        Server,
+    };
+    #[allow(unused_imports)]
+    use bytes::Bytes;
+    #[allow(unused_imports)]
+    use std::{collections::HashMap, path::PathBuf, sync::Arc};
+
+    #[allow(dead_code)]
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("redis-aof-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[allow(dead_code)]
+    fn replayed_commands(aof: &Aof) -> Vec<Resp> {
+        let mut commands = Vec::new();
+        aof.replay(&mut |record| {
+            if let Record::Command(command) = record {
+                commands.push(command);
+            }
+        })
+        .unwrap();
+        commands
+    }
+
+    #[tokio::test]
+    async fn writes_survive_a_restart() {
+        let dir = temp_dir("restart");
+        let open = || {
+            Server::builder()
+                .dir(dir.to_str().unwrap())
+                .config("appendonly", "yes")
+                .embedded()
+        };
+
+        let redis = open();
+        redis.execute(["SET", "foo", "bar"]).await;
+        redis.execute(["SET", "baz", "qux", "PX", "100000"]).await;
+        drop(redis);
+
+        let redis = open();
+        assert_eq!(
+            redis.execute(["GET", "foo"]).await,
+            Resp::BulkString("bar".into())
+        );
+        assert_eq!(
+            redis.execute(["GET", "baz"]).await,
+            Resp::BulkString("qux".into())
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn save_and_bgsave_rewrite_the_log() {
+        let dir = temp_dir("save");
+        let open = || {
+            Server::builder()
+                .dir(dir.to_str().unwrap())
+                .config("appendonly", "yes")
+                .embedded()
+        };
+
+        let redis = open();
+        redis.execute(["SET", "foo", "bar"]).await;
+        assert_eq!(
+            redis.execute(["SAVE"]).await,
+            Resp::SimpleString("OK".to_string())
+        );
+        redis.execute(["SET", "baz", "qux"]).await;
+        assert_eq!(
+            redis.execute(["BGSAVE"]).await,
+            Resp::SimpleString("Background saving started".to_string())
+        );
+        loop {
+            let info = redis.execute(["INFO", "persistence"]).await.to_string();
+            if info.contains("rdb_bgsave_in_progress:0") {
+                assert!(info.contains("rdb_last_bgsave_status:ok"));
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+        }
+        drop(redis);
+
+        let redis = open();
+        assert_eq!(
+            redis.execute(["GET", "foo"]).await,
+            Resp::BulkString("bar".into())
+        );
+        assert_eq!(
+            redis.execute(["GET", "baz"]).await,
+            Resp::BulkString("qux".into())
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn relative_expiries_are_logged_as_absolute() {
+        let dir = temp_dir("absolute");
+        let aof = Aof::new(dir.join("appendonly.aof"));
+        aof.append(&[
+            Bytes::from("SET"),
+            Bytes::from("foo"),
+            Bytes::from("bar"),
+            Bytes::from("PXAT"),
+            Bytes::from("1700000000000"),
+        ])
+        .unwrap();
+
+        let contents =
+            std::fs::read_to_string(dir.join("appendonlydir/appendonly.aof.1.incr.aof")).unwrap();
+        assert_eq!(
+            contents,
+            "*5\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n$4\r\nPXAT\r\n$13\r\n1700000000000\r\n"
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn snapshot_rewrites_the_log() {
+        let dir = temp_dir("rewrite");
+        let aof = Aof::new(dir.join("appendonly.aof"));
+        for value in ["one", "two", "three"] {
+            aof.append(&[Bytes::from("SET"), Bytes::from("foo"), Bytes::from(value)])
+                .unwrap();
+        }
+        assert_eq!(replayed_commands(&aof).len(), 3);
+
+        let mut store = HashMap::new();
+        store.insert(
+            Bytes::from("foo"),
+            Arc::new(RedisValue::String(Bytes::from("three"))),
+        );
+        let other = HashMap::from([(
+            Bytes::from("foo"),
+            Arc::new(RedisValue::String(Bytes::from("other"))),
+        )]);
+        aof.snapshot(&[
+            Snapshot {
+                store,
+                expiry_table: HashMap::new(),
+            },
+            Snapshot {
+                store: other,
+                expiry_table: HashMap::new(),
+            },
+        ])
+        .unwrap();
+        aof.append(&[Bytes::from("SET"), Bytes::from("bar"), Bytes::from("baz")])
+            .unwrap();
+
+        assert_eq!(
+            replayed_commands(&aof),
+            vec![
+                Resp::Array(vec![
+                    Resp::BulkString("SET".into()),
+                    Resp::BulkString("foo".into()),
+                    Resp::BulkString("three".into()),
+                ]),
+                Resp::Array(vec![
+                    Resp::BulkString("SELECT".into()),
+                    Resp::BulkString("1".into()),
+                ]),
+                Resp::Array(vec![
+                    Resp::BulkString("SET".into()),
+                    Resp::BulkString("foo".into()),
+                    Resp::BulkString("other".into()),
+                ]),
+                Resp::Array(vec![
+                    Resp::BulkString("SET".into()),
+                    Resp::BulkString("bar".into()),
+                    Resp::BulkString("baz".into()),
+                ]),
+            ]
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn replay_rejects_a_truncated_log() {
+        let dir = temp_dir("truncated");
+        std::fs::write(
+            dir.join("appendonly.aof"),
+            "*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n*3\r\n$3\r\nSET\r\n$3\r\nba",
+        )
+        .unwrap();
+
+        let aof = Aof::new(dir.join("appendonly.aof")).with_load_truncated(false);
+        assert!(aof.replay(&mut |_| {}).is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn replay_cuts_a_truncated_tail() {
+        let dir = temp_dir("truncated-tail");
+        std::fs::write(
+            dir.join("appendonly.aof"),
+            "*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n*3\r\n$3\r\nSET\r\n$3\r\nba",
+        )
+        .unwrap();
+
+        let aof = Aof::new(dir.join("appendonly.aof"));
+        assert_eq!(replayed_commands(&aof).len(), 1);
+        aof.append(&[Bytes::from("SET"), Bytes::from("baz"), Bytes::from("qux")])
+            .unwrap();
+
+        // The partial command is gone, so later writes keep the log readable.
+        let aof = Aof::new(dir.join("appendonly.aof")).with_load_truncated(false);
+        assert_eq!(replayed_commands(&aof).len(), 2);
+
+        std::fs::write(
+            dir.join("appendonlydir/appendonly.aof.1.base.aof"),
+            "*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n*2\r\n$3\r\nGET",
+        )
+        .unwrap();
+        assert!(Aof::new(dir.join("appendonly.aof"))
+            .replay(&mut |_| {})
+            .is_err());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn check_accepts_a_valid_log() {
+        let report = Aof::check(b"*1\r\n$4\r\nPING\r\n*2\r\n$3\r\nGET\r\n$3\r\nfoo\r\n");
+
+        assert!(report.is_ok());
+        assert_eq!(report.commands, 2);
+        assert_eq!(report.ok_up_to, report.size);
+    }
+
+    #[test]
+    fn check_finds_the_first_corrupt_command() {
+        let report = Aof::check(b"*1\r\n$4\r\nPING\r\n:1\r\n*1\r\n$4\r\nPING\r\n");
+        assert_eq!(report.commands, 1);
+        assert_eq!(report.ok_up_to, 14);
+        assert_eq!(
+            report.error,
+            Some("expected a command as an array of bulk strings".to_string())
+        );
+
+        let report = Aof::check(b"*1\r\n$4\r\nPING\r\n*2\r\n$3\r\nGET");
+        assert_eq!(report.ok_up_to, 14);
+        assert_eq!(report.error, Some("truncated command".to_string()));
+    }
+
+    #[test]
+    fn check_file_fixes_by_truncating() {
+        let dir = temp_dir("check-fix");
+        let path = dir.join("appendonly.aof");
+        std::fs::write(&path, b"*1\r\n$4\r\nPING\r\n*2\r\n$3\r\nGET").unwrap();
+
+        let report = Aof::check_file(&path, false).unwrap();
+        assert!(!report.is_ok() && !report.fixed);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 25);
+
+        let report = Aof::check_file(&path, true).unwrap();
+        assert!(report.fixed);
+        assert!(Aof::check_file(&path, false).unwrap().is_ok());
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 14);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn timestamp_annotations_are_written_and_skipped_on_replay() {
+        let dir = temp_dir("timestamps");
+        let path = dir.join("appendonly.aof");
+        let aof = Aof::new(path).with_timestamps(true);
+        for value in ["one", "two"] {
+            aof.append(&[Bytes::from("SET"), Bytes::from("foo"), Bytes::from(value)])
+                .unwrap();
+        }
+
+        let contents =
+            std::fs::read_to_string(dir.join("appendonlydir/appendonly.aof.1.incr.aof")).unwrap();
+        assert!(contents.starts_with("#TS:"));
+        assert!(contents.matches("#TS:").count() <= 2);
+        assert_eq!(replayed_commands(&aof).len(), 2);
+        assert!(Aof::check(contents.as_bytes()).is_ok());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn truncate_to_timestamp_drops_later_writes() {
+        let dir = temp_dir("point-in-time");
+        let path = dir.join("appendonly.aof");
+        let log = "#TS:100\r\n*1\r\n$4\r\nPING\r\n#TS:200\r\n*1\r\n$4\r\nPING\r\n";
+        std::fs::write(&path, log).unwrap();
+
+        let report = Aof::truncate_to_timestamp(&path, 300).unwrap();
+        assert!(!report.fixed);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), log);
+
+        let report = Aof::truncate_to_timestamp(&path, 199).unwrap();
+        assert!(report.fixed);
+        assert_eq!(report.commands, 1);
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "#TS:100\r\n*1\r\n$4\r\nPING\r\n"
+        );
+
+        let report = Aof::truncate_to_timestamp(&path, 50).unwrap();
+        assert!(report.fixed);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rewrites_replace_the_base_and_start_a_new_incr_file() {
+        let dir = temp_dir("multi-part");
+        let aof = Aof::new(dir.join("appendonly.aof")).with_dirname("aofs");
+        aof.append(&[Bytes::from("SET"), Bytes::from("foo"), Bytes::from("bar")])
+            .unwrap();
+
+        let manifest =
+            || std::fs::read_to_string(dir.join("aofs/appendonly.aof.manifest")).unwrap();
+        assert_eq!(manifest(), "file appendonly.aof.1.incr.aof seq 1 type i\n");
+
+        for _ in 0..2 {
+            aof.snapshot(&[Snapshot {
+                store: HashMap::new(),
+                expiry_table: HashMap::new(),
+            }])
+            .unwrap();
+        }
+        aof.append(&[Bytes::from("SET"), Bytes::from("baz"), Bytes::from("qux")])
+            .unwrap();
+
+        assert_eq!(
+            manifest(),
+            "file appendonly.aof.2.base.aof seq 2 type b\nfile appendonly.aof.3.incr.aof seq 3 type i\n"
+        );
+        let mut files = std::fs::read_dir(dir.join("aofs"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(
+            files,
+            vec![
+                "appendonly.aof.2.base.aof",
+                "appendonly.aof.3.incr.aof",
+                "appendonly.aof.manifest"
+            ]
+        );
+        assert_eq!(
+            replayed_commands(&Aof::new(dir.join("appendonly.aof")).with_dirname("aofs")).len(),
+            1
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn writes_during_a_background_rewrite_are_kept() {
+        let dir = temp_dir("background");
+        let aof = Aof::new(dir.join("appendonly.aof"));
+        aof.append(&[Bytes::from("SET"), Bytes::from("foo"), Bytes::from("bar")])
+            .unwrap();
+
+        aof.prepare_snapshot().unwrap();
+        aof.append(&[Bytes::from("SET"), Bytes::from("baz"), Bytes::from("qux")])
+            .unwrap();
+        aof.snapshot(&[Snapshot {
+            store: HashMap::from([(
+                Bytes::from("foo"),
+                Arc::new(RedisValue::String(Bytes::from("bar"))),
+            )]),
+            expiry_table: HashMap::new(),
+        }])
+        .unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(dir.join("appendonlydir/appendonly.aof.manifest")).unwrap(),
+            "file appendonly.aof.1.base.aof seq 1 type b\nfile appendonly.aof.2.incr.aof seq 2 type i\n"
+        );
+        assert_eq!(replayed_commands(&aof).len(), 2);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn single_file_logs_are_upgraded_to_a_base_file() {
+        let dir = temp_dir("upgrade");
+        std::fs::write(
+            dir.join("appendonly.aof"),
+            "*3\r\n$3\r\nSET\r\n$3\r\nfoo\r\n$3\r\nbar\r\n",
+        )
+        .unwrap();
+
+        let aof = Aof::new(dir.join("appendonly.aof"));
+        assert_eq!(replayed_commands(&aof).len(), 1);
+        assert!(!dir.join("appendonly.aof").exists());
+        assert_eq!(
+            std::fs::read_to_string(dir.join("appendonlydir/appendonly.aof.manifest")).unwrap(),
+            "file appendonly.aof.1.base.aof seq 1 type b\n"
+        );
+
+        aof.append(&[Bytes::from("SET"), Bytes::from("baz"), Bytes::from("qux")])
+            .unwrap();
+        assert_eq!(
+            replayed_commands(&Aof::new(dir.join("appendonly.aof"))).len(),
+            2
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn manifest_skips_history_files_and_rejects_garbage() {
+        let manifest = Manifest::parse(
+            "file a.1.base.aof seq 1 type b\nfile a.1.incr.aof seq 1 type h\nfile a.2.incr.aof seq 2 type i\n",
+        )
+        .unwrap();
+        assert_eq!(
+            manifest
+                .files()
+                .map(|file| file.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["a.1.base.aof", "a.2.incr.aof"]
+        );
+
+        assert!(Manifest::parse("file a.1.base.aof type b\n").is_err());
+        assert!(Manifest::parse("file a.1.base.aof seq 1 type x\n").is_err());
+    }
+}
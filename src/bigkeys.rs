@@ -0,0 +1,189 @@
+use std::collections::BTreeMap;
+
+use bytes::Bytes;
+
+use crate::{keyspace::KeyspaceGuard, redis::RedisValue, resp::Resp};
+
+// NOTE: A rough per key cost on top of the key and value bytes: the map entry, the Arc around the
+//       value and the headers of both buffers. Only meant to rank keys, not to match MEMORY USAGE.
+const ENTRY_OVERHEAD: usize = 64;
+/// Roughly what each element of a collection costs on top of its bytes.
+const ELEMENT_OVERHEAD: usize = 16;
+
+/// The estimated memory a key takes up, counted towards the dataset in MEMORY STATS.
+pub fn memory_usage(key: &[u8], value: &RedisValue) -> usize {
+    let value = match value {
+        RedisValue::String(value) => value.len(),
+        RedisValue::List(list) => list.iter().map(|e| ELEMENT_OVERHEAD + e.len()).sum(),
+        RedisValue::Hash(hash) => hash
+            .iter()
+            .map(|(field, value)| ELEMENT_OVERHEAD + field.len() + value.len())
+            .sum(),
+        RedisValue::Set(set) => set.iter().map(|m| ELEMENT_OVERHEAD + m.len()).sum(),
+        // Every member is stored twice, once in score order and once in the map to its score.
+        RedisValue::SortedSet(zset) => zset
+            .iter()
+            .map(|(m, _)| 2 * (ELEMENT_OVERHEAD + m.len() + 8))
+            .sum(),
+    };
+
+    ENTRY_OVERHEAD + key.len() + value
+}
+
+/// The largest keys of each type, gathered by MEMORY BIGKEYS one locked shard at a time.
+pub struct BigKeys {
+    count: usize,
+    scanned: u64,
+    types: BTreeMap<&'static str, TypeStats>,
+}
+
+#[derive(Default)]
+struct TypeStats {
+    keys: u64,
+    total_length: u64,
+    /// At most `count` keys, biggest first.
+    biggest: Vec<BigKey>,
+}
+
+struct BigKey {
+    key: Bytes,
+    length: u64,
+    memory: usize,
+}
+
+impl BigKeys {
+    /// Keeps the `count` biggest keys of each type.
+    pub fn new(count: usize) -> BigKeys {
+        BigKeys {
+            count,
+            scanned: 0,
+            types: BTreeMap::new(),
+        }
+    }
+
+    /// Adds the keys of the locked shards, skipping keys that have expired by `now`.
+    pub fn scan(&mut self, keyspace: &KeyspaceGuard, now: u64) {
+        for key in keyspace.keys() {
+            if keyspace.expiry(key).is_some_and(|expiry| expiry < now) {
+                continue;
+            }
+            let Some(value) = keyspace.get(key) else {
+                continue;
+            };
+
+            self.add(key.clone(), value);
+        }
+    }
+
+    fn add(&mut self, key: Bytes, value: &RedisValue) {
+        let length = value.length() as u64;
+        let memory = memory_usage(&key, value);
+
+        self.scanned += 1;
+        let stats = self.types.entry(value.type_name()).or_default();
+        stats.keys += 1;
+        stats.total_length += length;
+
+        // Ties are broken by key, so the reply doesn't depend on the order shards are walked in.
+        let position = stats
+            .biggest
+            .partition_point(|big| (big.length, &key) > (length, &big.key));
+        if position < self.count {
+            stats.biggest.insert(
+                position,
+                BigKey {
+                    key,
+                    length,
+                    memory,
+                },
+            );
+            stats.biggest.truncate(self.count);
+        }
+    }
+}
+
+impl From<BigKeys> for Resp {
+    fn from(big_keys: BigKeys) -> Self {
+        let field =
+            |name: &str, value: Resp| (Resp::BulkString(Bytes::from(name.to_string())), value);
+
+        let types = big_keys.types.into_iter().map(|(name, stats)| {
+            let biggest = stats.biggest.into_iter().map(|big| {
+                Resp::Map(vec![
+                    field("key", Resp::BulkString(big.key)),
+                    field("length", Resp::Integer(big.length as i64)),
+                    field("memory_usage", Resp::Integer(big.memory as i64)),
+                ])
+            });
+
+            field(
+                name,
+                Resp::Map(vec![
+                    field("keys", Resp::Integer(stats.keys as i64)),
+                    field("total_length", Resp::Integer(stats.total_length as i64)),
+                    field("biggest", Resp::Array(biggest.collect())),
+                ]),
+            )
+        });
+
+        Resp::Map(vec![
+            field("keys_scanned", Resp::Integer(big_keys.scanned as i64)),
+            field("types", Resp::Map(types.collect())),
+        ])
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use crate::{resp::Resp, Server};
+
+    #[tokio::test]
+    async fn bigkeys_reports_the_biggest_keys_per_type() {
+        let redis = Server::builder().embedded();
+        redis.execute(["SET", "small", "a"]).await;
+        redis.execute(["SET", "big", "abcdef"]).await;
+        redis.execute(["SET", "medium", "abc"]).await;
+        redis
+            .execute(["SET", "gone", "abcdefghij", "PX", "1"])
+            .await;
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+
+        let biggest = |key: &str, length: i64| {
+            Resp::Map(vec![
+                (
+                    Resp::BulkString("key".into()),
+                    Resp::BulkString(key.to_string().into()),
+                ),
+                (Resp::BulkString("length".into()), Resp::Integer(length)),
+                (
+                    Resp::BulkString("memory_usage".into()),
+                    Resp::Integer(64 + key.len() as i64 + length),
+                ),
+            ])
+        };
+        assert_eq!(
+            redis.execute(["MEMORY", "BIGKEYS", "COUNT", "2"]).await,
+            Resp::Map(vec![
+                (Resp::BulkString("keys_scanned".into()), Resp::Integer(3)),
+                (
+                    Resp::BulkString("types".into()),
+                    Resp::Map(vec![(
+                        Resp::BulkString("string".into()),
+                        Resp::Map(vec![
+                            (Resp::BulkString("keys".into()), Resp::Integer(3)),
+                            (Resp::BulkString("total_length".into()), Resp::Integer(10)),
+                            (
+                                Resp::BulkString("biggest".into()),
+                                Resp::Array(vec![biggest("big", 6), biggest("medium", 3)])
+                            ),
+                        ])
+                    )])
+                ),
+            ])
+        );
+        assert_eq!(
+            redis.execute(["MEMORY", "BIGKEYS", "LIMIT", "2"]).await,
+            Resp::SimpleError("ERR syntax error".to_string())
+        );
+    }
+}
@@ -0,0 +1,109 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use bytes::Bytes;
+use tokio::sync::oneshot;
+
+/// Clients blocked on keys, like BLPOP waiting for a list to be pushed to, queued longest waiting
+/// first for every key.
+// NOTE: Waking a client only tells it to try again, it takes the shard locks and runs its command
+//       like any other. A client that finds nothing to pop goes back to the front of the queue.
+#[derive(Default)]
+pub struct BlockedClients {
+    next_id: AtomicU64,
+    waiting: Mutex<HashMap<Bytes, VecDeque<Waiter>>>,
+}
+
+/// A client blocked on one or more keys. Every key gets an entry sharing the same wake up call,
+/// which only the first of them to be written to gets to use.
+struct Waiter {
+    id: u64,
+    wake: Arc<Mutex<Option<oneshot::Sender<()>>>>,
+}
+
+/// The blocked client's side: resolves once one of its keys was written to.
+pub struct Blocked {
+    pub id: u64,
+    pub woken: oneshot::Receiver<()>,
+}
+
+impl BlockedClients {
+    /// Blocks a client on `keys`, behind the clients already waiting for them unless it was just
+    /// woken up and keeps its turn. Must be called holding the shard locks of the keys, so no write
+    /// slips in between the client finding them empty and blocking.
+    pub fn block(&self, keys: &[Bytes], keep_turn: bool) -> Blocked {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, woken) = oneshot::channel();
+        let wake = Arc::new(Mutex::new(Some(sender)));
+
+        let mut waiting = self.waiting.lock().unwrap();
+        for key in keys {
+            let queue = waiting.entry(key.clone()).or_default();
+            let waiter = Waiter {
+                id,
+                wake: wake.clone(),
+            };
+            match keep_turn {
+                true => queue.push_front(waiter),
+                false => queue.push_back(waiter),
+            }
+        }
+
+        Blocked { id, woken }
+    }
+
+    /// Forgets a client that stopped waiting, whether it was woken up, timed out or went away.
+    pub fn unblock(&self, keys: &[Bytes], id: u64) {
+        let mut waiting = self.waiting.lock().unwrap();
+        for key in keys {
+            if let Some(queue) = waiting.get_mut(key) {
+                queue.retain(|waiter| waiter.id != id);
+                if queue.is_empty() {
+                    waiting.remove(key);
+                }
+            }
+        }
+    }
+
+    /// Wakes the longest waiting client blocked on `key`, skipping the ones that already stopped
+    /// waiting.
+    pub fn wake(&self, key: &[u8]) {
+        let mut waiting = self.waiting.lock().unwrap();
+        let Some(queue) = waiting.get_mut(key) else {
+            return;
+        };
+
+        while let Some(waiter) = queue.pop_front() {
+            let sender = waiter.wake.lock().unwrap().take();
+            if sender.is_some_and(|sender| sender.send(()).is_ok()) {
+                break;
+            }
+        }
+        if queue.is_empty() {
+            waiting.remove(key);
+        }
+    }
+
+    /// Wakes the longest waiting client on every key, for when a whole database changed at once.
+    pub fn wake_all(&self) {
+        let keys = self
+            .waiting
+            .lock()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        for key in keys {
+            self.wake(&key);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiting.lock().unwrap().is_empty()
+    }
+}
@@ -0,0 +1,946 @@
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    fmt::Write as _,
+    fs::{self, File},
+    hash::{BuildHasher, Hasher},
+    io::{self, Write},
+    net::IpAddr,
+    path::PathBuf,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+
+use crate::{keyspace::SLOT_COUNT, redis::CommandError};
+
+const NODE_ID_LEN: usize = 40;
+// Redis listens for the cluster bus on the client port plus this offset.
+pub(crate) const CLUSTER_PORT_INCR: u16 = 10000;
+pub(crate) const DEFAULT_NODE_TIMEOUT: u64 = 15000;
+// Like Redis, a forgotten node isn't learnt about again from gossip for a minute.
+const FORGET_TTL: u64 = 60_000;
+
+/// This node's view of the cluster, only kept with `cluster-enabled yes`. The node table is
+/// saved to `cluster-config-file` whenever it changes, so a restarted node comes back with the
+/// same identity and slots.
+pub struct Cluster {
+    config_file: PathBuf,
+    /// Milliseconds without a pong before a node is flagged as failing.
+    node_timeout: u64,
+    state: Mutex<ClusterState>,
+}
+
+struct ClusterState {
+    /// Every known node, this one included.
+    nodes: Vec<ClusterNode>,
+    current_epoch: u64,
+    last_vote_epoch: u64,
+    /// Nodes removed with CLUSTER FORGET, and until when gossip about them is ignored.
+    forgotten: HashMap<String, u64>,
+}
+
+/// The messages nodes exchange over the cluster bus. A MEET is a PING that asks the receiver to
+/// add the sender, which it otherwise ignores until it has been introduced.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageKind {
+    Meet,
+    Ping,
+    Pong,
+}
+
+impl MessageKind {
+    fn parse(name: &str) -> Option<MessageKind> {
+        match name {
+            "MEET" => Some(MessageKind::Meet),
+            "PING" => Some(MessageKind::Ping),
+            "PONG" => Some(MessageKind::Pong),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            MessageKind::Meet => "MEET",
+            MessageKind::Ping => "PING",
+            MessageKind::Pong => "PONG",
+        }
+    }
+}
+
+/// A node the heartbeat is due to ping.
+pub struct PingTarget {
+    pub id: String,
+    pub ip: IpAddr,
+    pub cport: u16,
+    pub kind: MessageKind,
+}
+
+/// A node as listed by CLUSTER NODES.
+#[derive(Clone)]
+struct ClusterNode {
+    id: String,
+    ip: String,
+    port: u16,
+    cport: u16,
+    flags: Vec<String>,
+    /// The master of a replica.
+    master: Option<String>,
+    ping_sent: u64,
+    pong_received: u64,
+    config_epoch: u64,
+    connected: bool,
+    slots: Slots,
+}
+
+impl ClusterNode {
+    fn is_myself(&self) -> bool {
+        self.flags.iter().any(|flag| flag == "myself")
+    }
+
+    fn has_flag(&self, flag: &str) -> bool {
+        self.flags.iter().any(|f| f == flag)
+    }
+
+    fn set_flag(&mut self, flag: &str, set: bool) {
+        self.flags.retain(|f| f != flag);
+        if set {
+            self.flags.push(flag.to_string());
+        }
+    }
+
+    fn parse(line: &str) -> Option<ClusterNode> {
+        let fields = line.split(' ').collect::<Vec<_>>();
+        if fields.len() < 8 || fields[0].len() != NODE_ID_LEN {
+            return None;
+        }
+
+        // The address is `ip:port@cport`, optionally followed by `,hostname`.
+        let address = fields[1].split(',').next()?;
+        let (address, cport) = address.split_once('@')?;
+        let (ip, port) = address.rsplit_once(':')?;
+
+        let mut slots = Slots::default();
+        for slot in &fields[8..] {
+            // Importing and migrating slots, e.g. `[42->-<id>]`, aren't owned by the node.
+            if slot.starts_with('[') {
+                continue;
+            }
+            let (start, end) = slot.split_once('-').unwrap_or((slot, slot));
+            let (start, end) = (start.parse::<u16>().ok()?, end.parse::<u16>().ok()?);
+            if start > end || end >= SLOT_COUNT {
+                return None;
+            }
+            for slot in start..=end {
+                slots.insert(slot);
+            }
+        }
+
+        Some(ClusterNode {
+            id: fields[0].to_string(),
+            ip: ip.to_string(),
+            port: port.parse().ok()?,
+            cport: cport.parse().ok()?,
+            flags: fields[2].split(',').map(str::to_string).collect(),
+            master: Some(fields[3])
+                .filter(|master| *master != "-")
+                .map(str::to_string),
+            ping_sent: fields[4].parse().ok()?,
+            pong_received: fields[5].parse().ok()?,
+            config_epoch: fields[6].parse().ok()?,
+            connected: fields[7] == "connected",
+            slots,
+        })
+    }
+
+    fn write_line(&self, out: &mut String) {
+        write!(
+            out,
+            "{} {}:{}@{} {} {} {} {} {} {}",
+            self.id,
+            self.ip,
+            self.port,
+            self.cport,
+            self.flags.join(","),
+            self.master.as_deref().unwrap_or("-"),
+            self.ping_sent,
+            self.pong_received,
+            self.config_epoch,
+            if self.connected {
+                "connected"
+            } else {
+                "disconnected"
+            },
+        )
+        .unwrap();
+
+        for (start, end) in self.slots.ranges() {
+            match start == end {
+                true => write!(out, " {}", start).unwrap(),
+                false => write!(out, " {}-{}", start, end).unwrap(),
+            }
+        }
+        out.push('\n');
+    }
+}
+
+/// A set of hash slots.
+#[derive(Clone, PartialEq)]
+pub struct Slots {
+    bits: Vec<u64>,
+}
+
+impl Default for Slots {
+    fn default() -> Self {
+        Slots {
+            bits: vec![0; SLOT_COUNT as usize / 64],
+        }
+    }
+}
+
+impl Slots {
+    pub fn contains(&self, slot: u16) -> bool {
+        self.bits[slot as usize / 64] & (1 << (slot % 64)) != 0
+    }
+
+    pub fn insert(&mut self, slot: u16) {
+        self.bits[slot as usize / 64] |= 1 << (slot % 64);
+    }
+
+    pub fn remove(&mut self, slot: u16) {
+        self.bits[slot as usize / 64] &= !(1 << (slot % 64));
+    }
+
+    /// The contiguous runs of slots in the set, as inclusive `(start, end)` pairs.
+    pub fn ranges(&self) -> Vec<(u16, u16)> {
+        let mut ranges: Vec<(u16, u16)> = Vec::new();
+        for slot in (0..SLOT_COUNT).filter(|slot| self.contains(*slot)) {
+            match ranges.last_mut() {
+                Some((_, end)) if *end + 1 == slot => *end = slot,
+                _ => ranges.push((slot, slot)),
+            }
+        }
+        ranges
+    }
+}
+
+impl Cluster {
+    /// Loads the node table from `config_file`, or starts a new cluster of one with a fresh
+    /// identity if there is no such file yet.
+    pub fn open(config_file: PathBuf, port: u16) -> io::Result<Cluster> {
+        let state = match fs::read_to_string(&config_file) {
+            Ok(contents) => ClusterState::parse(&contents).ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "unrecoverable error: corrupted cluster config file {:?}",
+                        config_file
+                    ),
+                )
+            })?,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => ClusterState {
+                nodes: vec![ClusterNode {
+                    id: random_node_id(),
+                    ip: String::new(),
+                    port,
+                    cport: port.wrapping_add(CLUSTER_PORT_INCR),
+                    flags: vec!["myself".to_string(), "master".to_string()],
+                    master: None,
+                    ping_sent: 0,
+                    pong_received: 0,
+                    config_epoch: 0,
+                    connected: true,
+                    slots: Slots::default(),
+                }],
+                current_epoch: 0,
+                last_vote_epoch: 0,
+                forgotten: HashMap::new(),
+            },
+            Err(error) => return Err(error),
+        };
+
+        let cluster = Cluster {
+            config_file,
+            node_timeout: DEFAULT_NODE_TIMEOUT,
+            state: Mutex::new(state),
+        };
+        cluster.save(&cluster.state.lock().unwrap())?;
+        Ok(cluster)
+    }
+
+    /// Sets `cluster-node-timeout`, in milliseconds.
+    pub fn with_node_timeout(mut self, node_timeout: u64) -> Cluster {
+        self.node_timeout = node_timeout;
+        self
+    }
+
+    /// How often the heartbeat runs. Each node is pinged every half node timeout.
+    pub fn heartbeat_interval(&self) -> Duration {
+        Duration::from_millis((self.node_timeout / 10).clamp(1, 100))
+    }
+
+    /// Records the address clients reach this node on and its cluster bus port, once the server
+    /// is listening.
+    pub fn set_address(&self, ip: String, port: u16, cport: u16) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let myself = state.myself_mut();
+        if myself.ip == ip && myself.port == port && myself.cport == cport {
+            return Ok(());
+        }
+
+        myself.ip = ip;
+        myself.port = port;
+        myself.cport = cport;
+        self.save(&state)
+    }
+
+    /// Introduces this node to the one listening for the cluster bus on `ip:cport`, adding it to
+    /// the node table once it answers.
+    pub async fn meet(&self, ip: IpAddr, cport: u16) -> Result<(), CommandError> {
+        let message = self.message(MessageKind::Meet);
+        let reply = exchange(ip, cport, &message, self.bus_timeout())
+            .await
+            .map_err(|error| {
+                CommandError::NodeUnreachable(format!("{}:{}", ip, cport), error.to_string())
+            })?;
+
+        self.receive(&reply, ip);
+        Ok(())
+    }
+
+    /// How long to wait for another node to answer over the cluster bus.
+    pub fn bus_timeout(&self) -> Duration {
+        Duration::from_millis(self.node_timeout)
+    }
+
+    /// Removes a node from the table, and ignores gossip about it for a minute so the other
+    /// nodes can be told to forget it as well.
+    pub fn forget(&self, id: &str) -> Result<(), CommandError> {
+        let mut state = self.state.lock().unwrap();
+        if state.myself().id == id {
+            return Err(CommandError::CantForgetMyself);
+        }
+        let Some(index) = state.nodes.iter().position(|node| node.id == id) else {
+            return Err(CommandError::UnknownNode(id.to_string()));
+        };
+
+        state.nodes.remove(index);
+        state
+            .forgotten
+            .insert(id.to_string(), now_ms() + FORGET_TTL);
+        self.save_or_log(&state);
+        Ok(())
+    }
+
+    /// A message announcing this node, followed by gossip about the other nodes it can reach.
+    pub fn message(&self, kind: MessageKind) -> String {
+        let state = self.state.lock().unwrap();
+
+        let mut message = format!("{} {}\n", kind.name(), state.current_epoch);
+        let mut myself = state.myself().clone();
+        myself.set_flag("myself", false);
+        myself.write_line(&mut message);
+        for node in state.nodes.iter().filter(|node| !node.is_myself()) {
+            if node.connected && !node.has_flag("fail?") {
+                node.write_line(&mut message);
+            }
+        }
+        message.push('\n');
+        message
+    }
+
+    /// Applies a message received over the cluster bus from `peer`, returning the reply to send
+    /// back, if any.
+    pub fn receive(&self, message: &str, peer: IpAddr) -> Option<String> {
+        let mut lines = message.lines().filter(|line| !line.is_empty());
+        let mut header = lines.next()?.split(' ');
+        let kind = MessageKind::parse(header.next()?)?;
+        let epoch = header.next()?.parse::<u64>().ok()?;
+        let mut sender = ClusterNode::parse(lines.next()?)?;
+        let gossip = lines.filter_map(ClusterNode::parse).collect::<Vec<_>>();
+
+        let now = now_ms();
+        {
+            let mut state = self.state.lock().unwrap();
+            state.forgotten.retain(|_, until| *until > now);
+            if sender.id == state.myself().id {
+                return None;
+            }
+
+            let known = state.nodes.iter().position(|node| node.id == sender.id);
+            // NOTE: Unknown nodes have to introduce themselves with a MEET first. A PONG can only
+            //       be the answer to one this node sent.
+            let index = match (known, kind) {
+                (Some(index), _) => index,
+                (None, MessageKind::Ping) => return None,
+                (None, _) => {
+                    state.forgotten.remove(&sender.id);
+                    state.nodes.push(sender.clone());
+                    state.nodes.len() - 1
+                }
+            };
+
+            // A node announces where it can be reached, but not always which IP.
+            if sender.ip.is_empty() {
+                sender.ip = peer.to_string();
+            }
+            // NOTE: Without failover there are no config epochs to settle conflicts with, so a
+            //       node may claim any slot that isn't served by this one.
+            let myself_slots = state.myself().slots.clone();
+            for slot in 0..SLOT_COUNT {
+                if !sender.slots.contains(slot) {
+                    continue;
+                }
+                if myself_slots.contains(slot) {
+                    sender.slots.remove(slot);
+                    continue;
+                }
+                for node in state.nodes.iter_mut().filter(|node| node.id != sender.id) {
+                    node.slots.remove(slot);
+                }
+            }
+
+            let node = &mut state.nodes[index];
+            node.ip = sender.ip;
+            node.port = sender.port;
+            node.cport = sender.cport;
+            node.flags = sender.flags;
+            node.set_flag("myself", false);
+            node.set_flag("fail?", false);
+            node.master = sender.master;
+            node.config_epoch = sender.config_epoch;
+            node.slots = sender.slots;
+            node.ping_sent = 0;
+            node.pong_received = now;
+            node.connected = true;
+            state.current_epoch = state.current_epoch.max(epoch);
+
+            // Nodes only heard about through gossip get a MEET from the heartbeat.
+            for mut node in gossip {
+                let known = node.ip.is_empty()
+                    || state.nodes.iter().any(|known| known.id == node.id)
+                    || state.forgotten.contains_key(&node.id);
+                if known {
+                    continue;
+                }
+
+                node.set_flag("myself", false);
+                node.set_flag("fail?", false);
+                for slot in node
+                    .slots
+                    .ranges()
+                    .into_iter()
+                    .flat_map(|(start, end)| start..=end)
+                {
+                    if state.owner(slot).is_some() {
+                        node.slots.remove(slot);
+                    }
+                }
+                node.ping_sent = 0;
+                node.pong_received = 0;
+                node.connected = false;
+                state.nodes.push(node);
+            }
+
+            self.save_or_log(&state);
+        }
+
+        match kind {
+            MessageKind::Pong => None,
+            MessageKind::Meet | MessageKind::Ping => Some(self.message(MessageKind::Pong)),
+        }
+    }
+
+    /// Picks the nodes due a ping, every half node timeout, and flags those that haven't
+    /// answered one for a whole node timeout as failing.
+    pub fn heartbeat(&self) -> Vec<PingTarget> {
+        let now = now_ms();
+        let mut state = self.state.lock().unwrap();
+
+        let mut targets = Vec::new();
+        for node in state.nodes.iter_mut().filter(|node| !node.is_myself()) {
+            if node.ping_sent != 0 && now - node.ping_sent > self.node_timeout {
+                node.set_flag("fail?", true);
+            }
+
+            let last_contact = node.ping_sent.max(node.pong_received);
+            if now - last_contact < self.node_timeout / 2 {
+                continue;
+            }
+            let Ok(ip) = node.ip.parse() else {
+                continue;
+            };
+
+            if node.ping_sent == 0 {
+                node.ping_sent = now;
+            }
+            targets.push(PingTarget {
+                id: node.id.clone(),
+                ip,
+                cport: node.cport,
+                kind: match node.pong_received {
+                    0 => MessageKind::Meet,
+                    _ => MessageKind::Ping,
+                },
+            });
+        }
+        targets
+    }
+
+    /// Marks a node the heartbeat couldn't reach as disconnected.
+    pub fn ping_failed(&self, id: &str) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(node) = state.nodes.iter_mut().find(|node| node.id == id) {
+            node.connected = false;
+        }
+    }
+
+    /// Assigns `slots` to this node. Fails without assigning any if one of them is already owned
+    /// by some node.
+    pub fn add_slots(&self, slots: &[u16]) -> Result<(), CommandError> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(slot) = slots.iter().find(|slot| state.owner(**slot).is_some()) {
+            return Err(CommandError::SlotBusy(*slot));
+        }
+
+        let myself = state.myself_mut();
+        for slot in slots {
+            myself.slots.insert(*slot);
+        }
+        self.save_or_log(&state);
+        Ok(())
+    }
+
+    /// Unassigns `slots`, whichever node owns them. Fails without unassigning any if one of them
+    /// isn't assigned.
+    pub fn del_slots(&self, slots: &[u16]) -> Result<(), CommandError> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(slot) = slots.iter().find(|slot| state.owner(**slot).is_none()) {
+            return Err(CommandError::SlotUnassigned(*slot));
+        }
+
+        for node in &mut state.nodes {
+            for slot in slots {
+                node.slots.remove(*slot);
+            }
+        }
+        self.save_or_log(&state);
+        Ok(())
+    }
+
+    /// The error redirecting a command on `slot` to the node serving it, unless that is this
+    /// node. With `read_from_replica`, a replica serves its master's slots itself.
+    pub fn redirect(&self, slot: u16, read_from_replica: bool) -> Option<CommandError> {
+        let state = self.state.lock().unwrap();
+        let myself = state.myself();
+        if myself.slots.contains(slot) {
+            return None;
+        }
+
+        match state.owner(slot) {
+            None => Some(CommandError::ClusterDown),
+            Some(owner) if read_from_replica && myself.master.as_ref() == Some(&owner.id) => None,
+            Some(owner) => Some(CommandError::Moved(
+                slot,
+                format!("{}:{}", owner.ip, owner.port),
+            )),
+        }
+    }
+
+    pub fn myid(&self) -> String {
+        self.state.lock().unwrap().myself().id.clone()
+    }
+
+    /// The node table in the CLUSTER NODES format, one line per node.
+    pub fn nodes(&self) -> String {
+        let state = self.state.lock().unwrap();
+
+        let mut out = String::new();
+        for node in &state.nodes {
+            node.write_line(&mut out);
+        }
+        out
+    }
+
+    // NOTE: Like Redis, the change has been made either way, failing to save it is only reported.
+    fn save_or_log(&self, state: &ClusterState) {
+        if let Err(error) = self.save(state) {
+            eprintln!("failed to save the cluster config file: {}", error);
+        }
+    }
+
+    // NOTE: Written to a temporary file first, so a crash never leaves a half written table behind.
+    fn save(&self, state: &ClusterState) -> io::Result<()> {
+        let mut contents = String::new();
+        for node in &state.nodes {
+            node.write_line(&mut contents);
+        }
+        writeln!(
+            contents,
+            "vars currentEpoch {} lastVoteEpoch {}",
+            state.current_epoch, state.last_vote_epoch
+        )
+        .unwrap();
+
+        let temp_path = self.config_file.with_extension("tmp");
+        let mut temp = File::create(&temp_path)?;
+        temp.write_all(contents.as_bytes())?;
+        temp.sync_all()?;
+        fs::rename(&temp_path, &self.config_file)
+    }
+}
+
+impl ClusterState {
+    fn parse(contents: &str) -> Option<ClusterState> {
+        let mut state = ClusterState {
+            nodes: Vec::new(),
+            current_epoch: 0,
+            last_vote_epoch: 0,
+            forgotten: HashMap::new(),
+        };
+
+        for line in contents.lines().filter(|line| !line.is_empty()) {
+            if let Some(vars) = line.strip_prefix("vars ") {
+                let fields = vars.split(' ').collect::<Vec<_>>();
+                for pair in fields.chunks(2) {
+                    match pair {
+                        ["currentEpoch", epoch] => state.current_epoch = epoch.parse().ok()?,
+                        ["lastVoteEpoch", epoch] => state.last_vote_epoch = epoch.parse().ok()?,
+                        _ => {}
+                    }
+                }
+                continue;
+            }
+
+            state.nodes.push(ClusterNode::parse(line)?);
+        }
+
+        // Exactly one of the nodes has to be this one.
+        match state.nodes.iter().filter(|node| node.is_myself()).count() {
+            1 => Some(state),
+            _ => None,
+        }
+    }
+
+    fn owner(&self, slot: u16) -> Option<&ClusterNode> {
+        self.nodes.iter().find(|node| node.slots.contains(slot))
+    }
+
+    fn myself(&self) -> &ClusterNode {
+        self.nodes.iter().find(|node| node.is_myself()).unwrap()
+    }
+
+    fn myself_mut(&mut self) -> &mut ClusterNode {
+        self.nodes.iter_mut().find(|node| node.is_myself()).unwrap()
+    }
+}
+
+/// Reads a cluster bus message, which ends with an empty line.
+pub async fn read_message(stream: &mut TcpStream) -> io::Result<String> {
+    let mut reader = BufReader::new(stream);
+    let mut message = String::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        if line == "\n" {
+            return Ok(message);
+        }
+        message.push_str(&line);
+    }
+}
+
+/// Sends `message` to the cluster bus on `ip:cport` and waits for the answer.
+pub async fn exchange(
+    ip: IpAddr,
+    cport: u16,
+    message: &str,
+    timeout: Duration,
+) -> io::Result<String> {
+    let round_trip = async {
+        let mut stream = TcpStream::connect((ip, cport)).await?;
+        stream.write_all(message.as_bytes()).await?;
+        read_message(&mut stream).await
+    };
+
+    tokio::time::timeout(timeout, round_trip)
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))?
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+// NOTE: Node IDs only need to be unique, so hashing the time with std's randomly keyed hasher is
+//       enough without pulling in a random number generator.
+pub(crate) fn random_node_id() -> String {
+    let mut id = String::with_capacity(NODE_ID_LEN);
+    while id.len() < NODE_ID_LEN {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_nanos(),
+        );
+        write!(id, "{:016x}", hasher.finish()).unwrap();
+    }
+    id.truncate(NODE_ID_LEN);
+    id
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::{Cluster, ClusterState, Slots};
+    #[allow(unused_imports)]
+    use crate::{resp::Resp, Server};
+
+    #[allow(dead_code)]
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("redis-cluster-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn slots_are_listed_as_ranges() {
+        let mut slots = Slots::default();
+        for slot in [0, 1, 2, 5, 16383] {
+            slots.insert(slot);
+        }
+        slots.remove(1);
+
+        assert_eq!(slots.ranges(), vec![(0, 0), (2, 2), (5, 5), (16383, 16383)]);
+        slots.insert(1);
+        assert_eq!(slots.ranges(), vec![(0, 2), (5, 5), (16383, 16383)]);
+    }
+
+    #[test]
+    fn nodes_conf_round_trips() {
+        let contents = "\
+e7d1eecce10fd6bb5eb35b9f99a514335d9ba9ca 127.0.0.1:30001@40001,host1 myself,master - 0 0 1 connected 0-5460 [5461->-67ed2db8d677e59ec4a4cefb06858cf2a1a89fa1]
+67ed2db8d677e59ec4a4cefb06858cf2a1a89fa1 127.0.0.1:30002@40002 slave e7d1eecce10fd6bb5eb35b9f99a514335d9ba9ca 0 1426238316232 1 connected
+vars currentEpoch 6 lastVoteEpoch 0
+";
+        let state = ClusterState::parse(contents).unwrap();
+        assert_eq!(state.current_epoch, 6);
+        assert_eq!(state.myself().slots.ranges(), vec![(0, 5460)]);
+
+        let mut nodes = String::new();
+        for node in &state.nodes {
+            node.write_line(&mut nodes);
+        }
+        assert_eq!(
+            nodes,
+            "\
+e7d1eecce10fd6bb5eb35b9f99a514335d9ba9ca 127.0.0.1:30001@40001 myself,master - 0 0 1 connected 0-5460
+67ed2db8d677e59ec4a4cefb06858cf2a1a89fa1 127.0.0.1:30002@40002 slave e7d1eecce10fd6bb5eb35b9f99a514335d9ba9ca 0 1426238316232 1 connected
+"
+        );
+
+        assert!(ClusterState::parse("vars currentEpoch 0 lastVoteEpoch 0\n").is_none());
+        assert!(ClusterState::parse("not a node line\n").is_none());
+    }
+
+    #[test]
+    fn identity_survives_a_restart() {
+        let dir = temp_dir("restart");
+        let config_file = dir.join("nodes.conf");
+
+        let cluster = Cluster::open(config_file.clone(), 7000).unwrap();
+        let id = cluster.myid();
+        assert_eq!(id.len(), 40);
+        assert_eq!(
+            cluster.nodes(),
+            format!("{} :7000@17000 myself,master - 0 0 0 connected\n", id)
+        );
+        cluster
+            .set_address("127.0.0.1".to_string(), 7001, 17001)
+            .unwrap();
+        drop(cluster);
+
+        let cluster = Cluster::open(config_file, 7000).unwrap();
+        assert_eq!(cluster.myid(), id);
+        assert!(cluster.nodes().contains(" 127.0.0.1:7001@17001 "));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn cluster_nodes_needs_cluster_mode() {
+        let redis = Server::builder().embedded();
+
+        assert_eq!(
+            redis.execute(["CLUSTER", "NODES"]).await,
+            Resp::SimpleError("ERR This instance has cluster support disabled".to_string())
+        );
+        assert_eq!(
+            redis.execute(["READONLY"]).await,
+            Resp::SimpleError("ERR This instance has cluster support disabled".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn cluster_nodes_lists_this_node() {
+        let dir = temp_dir("commands");
+        let redis = Server::builder()
+            .dir(dir.to_str().unwrap())
+            .config("cluster-enabled", "yes")
+            .embedded();
+
+        let id = match redis.execute(["CLUSTER", "MYID"]).await {
+            Resp::BulkString(id) => String::from_utf8(id.to_vec()).unwrap(),
+            reply => panic!("expected the node id, got {:?}", reply),
+        };
+        assert_eq!(
+            redis.execute(["CLUSTER", "NODES"]).await,
+            Resp::BulkString(
+                format!("{} :6379@16379 myself,master - 0 0 0 connected\n", id).into()
+            )
+        );
+        assert!(dir.join("nodes.conf").exists());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn slots_can_be_assigned_and_unassigned() {
+        let dir = temp_dir("slots");
+        let open = || {
+            Server::builder()
+                .dir(dir.to_str().unwrap())
+                .config("cluster-enabled", "yes")
+                .embedded()
+        };
+        let ok = Resp::SimpleString("OK".to_string());
+        let error = |message: &str| Resp::SimpleError(message.to_string());
+
+        let redis = open();
+        assert_eq!(
+            redis.execute(["CLUSTER", "ADDSLOTS", "0", "1", "7"]).await,
+            ok
+        );
+        assert_eq!(
+            redis
+                .execute(["CLUSTER", "ADDSLOTSRANGE", "100", "199", "16000", "16383"])
+                .await,
+            ok
+        );
+        assert_eq!(
+            redis.execute(["CLUSTER", "ADDSLOTS", "5", "7"]).await,
+            error("ERR Slot 7 is already busy")
+        );
+        assert_eq!(
+            redis.execute(["CLUSTER", "ADDSLOTS", "5", "5"]).await,
+            error("ERR Slot 5 specified multiple times")
+        );
+        assert_eq!(
+            redis.execute(["CLUSTER", "ADDSLOTS", "16384"]).await,
+            error("ERR Invalid or out of range slot")
+        );
+        assert_eq!(
+            redis.execute(["CLUSTER", "ADDSLOTSRANGE", "9", "8"]).await,
+            error("ERR start slot number 9 is greater than end slot number 8")
+        );
+        assert_eq!(
+            redis.execute(["CLUSTER", "ADDSLOTSRANGE", "1"]).await,
+            error("ERR wrong number of arguments for 'cluster|addslotsrange' command")
+        );
+        assert_eq!(redis.execute(["CLUSTER", "DELSLOTS", "1"]).await, ok);
+        assert_eq!(
+            redis
+                .execute(["CLUSTER", "DELSLOTSRANGE", "150", "250"])
+                .await,
+            error("ERR Slot 200 is already unassigned")
+        );
+        assert_eq!(
+            redis
+                .execute(["CLUSTER", "DELSLOTSRANGE", "150", "199"])
+                .await,
+            ok
+        );
+        drop(redis);
+
+        let redis = open();
+        match redis.execute(["CLUSTER", "NODES"]).await {
+            Resp::BulkString(nodes) => {
+                assert!(nodes.ends_with(b" connected 0 7 100-149 16000-16383\n"))
+            }
+            reply => panic!("expected the node table, got {:?}", reply),
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn keys_in_other_slots_are_redirected() {
+        let dir = temp_dir("redirect");
+        let redis = Server::builder()
+            .dir(dir.to_str().unwrap())
+            .config("cluster-enabled", "yes")
+            .embedded();
+        redis
+            .execute(["CLUSTER", "ADDSLOTSRANGE", "0", "8191"])
+            .await;
+
+        // "bar" hashes to slot 5061 and "foo" to slot 12182.
+        assert_eq!(
+            redis.execute(["SET", "bar", "1"]).await,
+            Resp::SimpleString("OK".to_string())
+        );
+        assert_eq!(
+            redis.execute(["GET", "foo"]).await,
+            Resp::SimpleError("CLUSTERDOWN Hash slot not served".to_string())
+        );
+        assert_eq!(
+            redis.execute(["RENAME", "bar", "foo"]).await,
+            Resp::SimpleError("CROSSSLOT Keys in request don't hash to the same slot".to_string())
+        );
+        assert_eq!(
+            redis.execute(["RENAME", "{bar}a", "{bar}b"]).await,
+            Resp::SimpleError("ERR no such key".to_string())
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn readonly_replicas_serve_reads_for_their_master() {
+        let dir = temp_dir("readonly");
+        std::fs::write(
+            dir.join("nodes.conf"),
+            "\
+e7d1eecce10fd6bb5eb35b9f99a514335d9ba9ca 127.0.0.1:30001@40001 master - 0 0 1 connected 0-16383
+67ed2db8d677e59ec4a4cefb06858cf2a1a89fa1 127.0.0.1:30002@40002 myself,slave e7d1eecce10fd6bb5eb35b9f99a514335d9ba9ca 0 0 1 connected
+vars currentEpoch 1 lastVoteEpoch 0
+",
+        )
+        .unwrap();
+        let redis = Server::builder()
+            .dir(dir.to_str().unwrap())
+            .config("cluster-enabled", "yes")
+            .embedded();
+        let moved = Resp::SimpleError("MOVED 12182 127.0.0.1:30001".to_string());
+        let ok = Resp::SimpleString("OK".to_string());
+
+        assert_eq!(redis.execute(["GET", "foo"]).await, moved);
+        assert_eq!(redis.execute(["READONLY"]).await, ok);
+        assert_eq!(redis.execute(["GET", "foo"]).await, Resp::Null);
+        assert_eq!(redis.execute(["SET", "foo", "bar"]).await, moved);
+        assert_eq!(redis.clone().execute(["GET", "foo"]).await, moved);
+        assert_eq!(redis.execute(["READWRITE"]).await, ok);
+        assert_eq!(redis.execute(["GET", "foo"]).await, moved);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,583 @@
+use bytes::Bytes;
+
+use crate::resp::Resp;
+
+/// What the server knows about a command without running it, as reported by COMMAND INFO and
+/// COMMAND DOCS.
+#[derive(Debug)]
+pub struct CommandInfo {
+    /// Lowercase, `container|subcommand` for subcommands.
+    pub name: &'static str,
+    /// The number of arguments including the command name itself, or minus the minimum when the
+    /// command takes a variable number of them. Subcommands count both of their names.
+    pub arity: i64,
+    pub flags: &'static [&'static str],
+    /// Where the keys are among the arguments: the first and last (negative counts from the end)
+    /// and the step between them. All zero for commands without keys, or with keys that can't be
+    /// found this way.
+    pub first_key: i64,
+    pub last_key: i64,
+    pub step: i64,
+    pub group: &'static str,
+    pub summary: &'static str,
+    pub subcommands: &'static [CommandInfo],
+}
+
+const fn command(
+    name: &'static str,
+    arity: i64,
+    flags: &'static [&'static str],
+    (first_key, last_key, step): (i64, i64, i64),
+    group: &'static str,
+) -> CommandInfo {
+    CommandInfo {
+        name,
+        arity,
+        flags,
+        first_key,
+        last_key,
+        step,
+        group,
+        summary: "",
+        subcommands: &[],
+    }
+}
+
+/// A command that only groups its subcommands, like CONFIG.
+const fn container(name: &'static str, group: &'static str) -> CommandInfo {
+    command(name, -2, &[], NO_KEYS, group)
+}
+
+const NO_KEYS: (i64, i64, i64) = (0, 0, 0);
+const ONE_KEY: (i64, i64, i64) = (1, 1, 1);
+const ALL_KEYS: (i64, i64, i64) = (1, -1, 1);
+const TWO_KEYS: (i64, i64, i64) = (1, 2, 1);
+
+const READ: &[&str] = &["readonly"];
+const READ_FAST: &[&str] = &["readonly", "fast"];
+const WRITE: &[&str] = &["write"];
+const WRITE_FAST: &[&str] = &["write", "fast"];
+const GROW: &[&str] = &["write", "denyoom"];
+const GROW_FAST: &[&str] = &["write", "denyoom", "fast"];
+const BLOCKING: &[&str] = &["write", "noscript", "blocking"];
+const FAST: &[&str] = &["fast"];
+const ANYTIME: &[&str] = &["loading", "stale"];
+const ANYTIME_FAST: &[&str] = &["loading", "stale", "fast"];
+const CONNECTION: &[&str] = &["noscript", "loading", "stale"];
+const CONNECTION_FAST: &[&str] = &["noscript", "loading", "stale", "fast"];
+const ADMIN: &[&str] = &["admin", "noscript", "loading", "stale"];
+const ADMIN_NOSCRIPT: &[&str] = &["admin", "noscript"];
+const CLUSTER_ADMIN: &[&str] = &["admin", "stale"];
+const PUBSUB: &[&str] = &["pubsub", "noscript", "loading", "stale"];
+const PUBSUB_ANYTIME: &[&str] = &["pubsub", "loading", "stale"];
+
+/// Every command the server understands, in no particular order.
+pub static COMMANDS: &[CommandInfo] = &[
+    // Connection
+    command("ping", -1, FAST, NO_KEYS, "connection")
+        .summary("Returns the server's liveliness response."),
+    command("echo", 2, FAST, NO_KEYS, "connection").summary("Returns the given string."),
+    command("select", 2, ANYTIME_FAST, NO_KEYS, "connection")
+        .summary("Changes the selected database."),
+    command(
+        "hello",
+        -1,
+        &["noscript", "loading", "stale", "fast", "no_auth"],
+        NO_KEYS,
+        "connection",
+    )
+    .summary("Handshakes with the Redis server."),
+    command("readonly", 1, ANYTIME_FAST, NO_KEYS, "cluster")
+        .summary("Enables read-only queries for a connection to a Redis Cluster replica node."),
+    command("readwrite", 1, ANYTIME_FAST, NO_KEYS, "cluster")
+        .summary("Enables read-write queries for a connection to a Redis Cluster replica node."),
+    container("client", "connection")
+        .summary("A container for client connection commands.")
+        .subcommands(&[
+            command("client|id", 2, CONNECTION, NO_KEYS, "connection")
+                .summary("Returns the unique client ID of the connection."),
+            command("client|tracking", -3, CONNECTION, NO_KEYS, "connection")
+                .summary("Controls server-assisted client-side caching for the connection."),
+            command("client|caching", 3, CONNECTION, NO_KEYS, "connection")
+                .summary("Instructs the server whether to track the keys in the next request."),
+            command("client|getredir", 2, CONNECTION, NO_KEYS, "connection")
+                .summary("Returns the client ID tracking notifications are redirected to."),
+        ]),
+    // Server
+    command("save", 1, ADMIN_NOSCRIPT, NO_KEYS, "server")
+        .summary("Synchronously saves the database(s) to disk."),
+    command("bgsave", -1, ADMIN_NOSCRIPT, NO_KEYS, "server")
+        .summary("Asynchronously saves the database(s) to disk."),
+    command("lastsave", 1, ANYTIME_FAST, NO_KEYS, "server")
+        .summary("Returns the Unix timestamp of the last successful save to disk."),
+    command("info", -1, ANYTIME, NO_KEYS, "server")
+        .summary("Returns information and statistics about the server."),
+    command("dbsize", 1, READ_FAST, NO_KEYS, "server")
+        .summary("Returns the number of keys in the database."),
+    command("swapdb", 3, WRITE_FAST, NO_KEYS, "server").summary("Swaps two Redis databases."),
+    command("flushdb", -1, WRITE, NO_KEYS, "server")
+        .summary("Remove all keys from the current database."),
+    command("flushall", -1, WRITE, NO_KEYS, "server")
+        .summary("Removes all keys from all databases."),
+    command("debug", -2, ADMIN, NO_KEYS, "server").summary("A container for debugging commands."),
+    command("replconf", -1, ADMIN, NO_KEYS, "server")
+        .summary("An internal command for configuring the replication stream."),
+    command("psync", -3, ADMIN_NOSCRIPT, NO_KEYS, "server")
+        .summary("An internal command used in replication."),
+    container("config", "server")
+        .summary("A container for server configuration commands.")
+        .subcommands(&[
+            command("config|get", -3, ADMIN, NO_KEYS, "server")
+                .summary("Returns the effective values of configuration parameters."),
+            command("config|set", -4, ADMIN, NO_KEYS, "server")
+                .summary("Sets configuration parameters in-flight."),
+            command("config|rewrite", 2, ADMIN, NO_KEYS, "server")
+                .summary("Persists the effective configuration to file."),
+        ]),
+    // COMMAND on its own works too, as COMMAND INFO.
+    command("command", -1, ANYTIME, NO_KEYS, "server")
+        .summary("Returns detailed information about all commands.")
+        .subcommands(&[
+            command("command|count", 2, ANYTIME, NO_KEYS, "server")
+                .summary("Returns a count of commands."),
+            command("command|info", -2, ANYTIME, NO_KEYS, "server")
+                .summary("Returns information about one, multiple or all commands."),
+            command("command|docs", -2, ANYTIME, NO_KEYS, "server")
+                .summary("Returns documentary information about one, multiple or all commands."),
+            command("command|getkeys", -3, ANYTIME, NO_KEYS, "server")
+                .summary("Extracts the key names from an arbitrary command."),
+            command("command|getkeysandflags", -3, ANYTIME, NO_KEYS, "server")
+                .summary("Extracts the key names and access flags for an arbitrary command."),
+        ]),
+    container("latency", "server")
+        .summary("A container for latency diagnostics commands.")
+        .subcommands(&[
+            command("latency|histogram", -2, ADMIN, NO_KEYS, "server").summary(
+                "Returns the cumulative distribution of latencies of a subset or all commands.",
+            ),
+        ]),
+    container("memory", "server")
+        .summary("A container for memory diagnostics commands.")
+        .subcommands(&[
+            command("memory|stats", 2, READ, NO_KEYS, "server")
+                .summary("Returns details about memory usage."),
+            command("memory|bigkeys", -2, READ, NO_KEYS, "server")
+                .summary("Returns the keys using the most memory."),
+        ]),
+    // Transactions
+    command("multi", 1, CONNECTION_FAST, NO_KEYS, "transactions").summary("Starts a transaction."),
+    command("exec", 1, CONNECTION, NO_KEYS, "transactions")
+        .summary("Executes all commands in a transaction."),
+    command("discard", 1, CONNECTION_FAST, NO_KEYS, "transactions")
+        .summary("Discards a transaction."),
+    // Pub/Sub
+    command("subscribe", -2, PUBSUB, NO_KEYS, "pubsub")
+        .summary("Listens for messages published to channels."),
+    command("psubscribe", -2, PUBSUB, NO_KEYS, "pubsub")
+        .summary("Listens for messages published to channels that match one or more patterns."),
+    command("unsubscribe", -1, PUBSUB, NO_KEYS, "pubsub")
+        .summary("Stops listening to messages posted to channels."),
+    command("punsubscribe", -1, PUBSUB, NO_KEYS, "pubsub")
+        .summary("Stops listening to messages published to channels matching patterns."),
+    command(
+        "publish",
+        3,
+        &["pubsub", "loading", "stale", "fast"],
+        NO_KEYS,
+        "pubsub",
+    )
+    .summary("Posts a message to a channel."),
+    container("pubsub", "pubsub")
+        .summary("A container for Pub/Sub commands.")
+        .subcommands(&[
+            command("pubsub|channels", -2, PUBSUB_ANYTIME, NO_KEYS, "pubsub")
+                .summary("Returns the active channels."),
+            command("pubsub|numsub", -2, PUBSUB_ANYTIME, NO_KEYS, "pubsub")
+                .summary("Returns a count of subscribers to channels."),
+            command("pubsub|numpat", 2, PUBSUB_ANYTIME, NO_KEYS, "pubsub")
+                .summary("Returns a count of unique pattern subscriptions."),
+        ]),
+    // Cluster
+    container("cluster", "cluster")
+        .summary("A container for Redis Cluster commands.")
+        .subcommands(&[
+            command("cluster|nodes", 2, &["stale"], NO_KEYS, "cluster")
+                .summary("Returns the cluster configuration for a node."),
+            command("cluster|myid", 2, &["stale"], NO_KEYS, "cluster")
+                .summary("Returns the ID of a node."),
+            command("cluster|addslots", -3, CLUSTER_ADMIN, NO_KEYS, "cluster")
+                .summary("Assigns new hash slots to a node."),
+            command("cluster|delslots", -3, CLUSTER_ADMIN, NO_KEYS, "cluster")
+                .summary("Sets hash slots as unbound for a node."),
+            command(
+                "cluster|addslotsrange",
+                -4,
+                CLUSTER_ADMIN,
+                NO_KEYS,
+                "cluster",
+            )
+            .summary("Assigns new hash slot ranges to a node."),
+            command(
+                "cluster|delslotsrange",
+                -4,
+                CLUSTER_ADMIN,
+                NO_KEYS,
+                "cluster",
+            )
+            .summary("Sets hash slot ranges as unbound for a node."),
+            command("cluster|meet", -4, CLUSTER_ADMIN, NO_KEYS, "cluster")
+                .summary("Forces a node to handshake with another node."),
+            command("cluster|forget", 3, CLUSTER_ADMIN, NO_KEYS, "cluster")
+                .summary("Removes a node from the nodes table."),
+        ]),
+    // Generic
+    command("del", -2, WRITE, ALL_KEYS, "generic").summary("Deletes one or more keys."),
+    command("unlink", -2, WRITE_FAST, ALL_KEYS, "generic")
+        .summary("Asynchronously deletes one or more keys."),
+    command("exists", -2, READ_FAST, ALL_KEYS, "generic")
+        .summary("Determines whether one or more keys exist."),
+    command("type", 2, READ_FAST, ONE_KEY, "generic")
+        .summary("Determines the type of value stored at a key."),
+    command("keys", 2, READ, NO_KEYS, "generic")
+        .summary("Returns all key names that match a pattern."),
+    command("scan", -2, READ, NO_KEYS, "generic")
+        .summary("Iterates over the key names in the database."),
+    command("randomkey", 1, READ, NO_KEYS, "generic")
+        .summary("Returns a random key name from the database."),
+    command("rename", 3, WRITE, TWO_KEYS, "generic")
+        .summary("Renames a key and overwrites the destination."),
+    command("copy", -3, GROW, TWO_KEYS, "generic")
+        .summary("Copies the value of a key to a new key."),
+    command("move", 3, WRITE_FAST, ONE_KEY, "generic").summary("Moves a key to another database."),
+    command("ttl", 2, READ_FAST, ONE_KEY, "generic")
+        .summary("Returns the expiration time in seconds of a key."),
+    command("pttl", 2, READ_FAST, ONE_KEY, "generic")
+        .summary("Returns the expiration time in milliseconds of a key."),
+    command("expire", -3, WRITE_FAST, ONE_KEY, "generic")
+        .summary("Sets the expiration time of a key in seconds."),
+    command("pexpire", -3, WRITE_FAST, ONE_KEY, "generic")
+        .summary("Sets the expiration time of a key in milliseconds."),
+    command("expireat", -3, WRITE_FAST, ONE_KEY, "generic")
+        .summary("Sets the expiration time of a key to a Unix timestamp."),
+    command("pexpireat", -3, WRITE_FAST, ONE_KEY, "generic")
+        .summary("Sets the expiration time of a key to a Unix milliseconds timestamp."),
+    command("dump", 2, READ, ONE_KEY, "generic")
+        .summary("Returns a serialized representation of the value stored at a key."),
+    command("restore", -4, GROW, ONE_KEY, "generic")
+        .summary("Creates a key from the serialized representation of a value."),
+    container("object", "generic")
+        .summary("A container for object introspection commands.")
+        .subcommands(&[
+            command("object|encoding", 3, READ, (2, 2, 1), "generic")
+                .summary("Returns the internal encoding of a Redis object."),
+            command("object|refcount", 3, READ, (2, 2, 1), "generic")
+                .summary("Returns the reference count of a value of a key."),
+            command("object|idletime", 3, READ, (2, 2, 1), "generic")
+                .summary("Returns the time since the last access to a Redis object."),
+            command("object|freq", 3, READ, (2, 2, 1), "generic")
+                .summary("Returns the logarithmic access frequency counter of a Redis object."),
+        ]),
+    // Strings
+    command("set", -3, GROW, ONE_KEY, "string")
+        .summary("Sets the string value of a key, ignoring its type."),
+    command("get", 2, READ_FAST, ONE_KEY, "string").summary("Returns the string value of a key."),
+    command("mget", -2, READ_FAST, ALL_KEYS, "string")
+        .summary("Atomically returns the string values of one or more keys."),
+    command("mset", -3, GROW, (1, -1, 2), "string")
+        .summary("Atomically creates or modifies the string values of one or more keys."),
+    command("msetnx", -3, GROW, (1, -1, 2), "string")
+        .summary("Atomically sets the string values of keys only when none of them exist."),
+    command("append", 3, GROW_FAST, ONE_KEY, "string")
+        .summary("Appends a string to the value of a key."),
+    command("strlen", 2, READ_FAST, ONE_KEY, "string")
+        .summary("Returns the length of a string value."),
+    command("getrange", 4, READ, ONE_KEY, "string")
+        .summary("Returns a substring of the string stored at a key."),
+    command("setrange", 4, GROW, ONE_KEY, "string")
+        .summary("Overwrites a part of a string value with another by an offset."),
+    command("incr", 2, GROW_FAST, ONE_KEY, "string")
+        .summary("Increments the integer value of a key by one."),
+    command("decr", 2, GROW_FAST, ONE_KEY, "string")
+        .summary("Decrements the integer value of a key by one."),
+    command("incrby", 3, GROW_FAST, ONE_KEY, "string")
+        .summary("Increments the integer value of a key by a number."),
+    command("decrby", 3, GROW_FAST, ONE_KEY, "string")
+        .summary("Decrements a number from the integer value of a key."),
+    command("incrbyfloat", 3, GROW_FAST, ONE_KEY, "string")
+        .summary("Increment the floating point value of a key by a number."),
+    // Bitmaps
+    command("setbit", 4, GROW, ONE_KEY, "bitmap")
+        .summary("Sets or clears the bit at offset of the string value."),
+    command("getbit", 3, READ_FAST, ONE_KEY, "bitmap").summary("Returns a bit value by offset."),
+    command("bitcount", -2, READ, ONE_KEY, "bitmap")
+        .summary("Counts the number of set bits (population counting) in a string."),
+    command("bitpos", -3, READ, ONE_KEY, "bitmap")
+        .summary("Finds the first set (1) or clear (0) bit in a string."),
+    command("bitop", -4, GROW, (2, -1, 1), "bitmap")
+        .summary("Performs bitwise operations on multiple strings, and stores the result."),
+    // Lists
+    command("lpush", -3, GROW_FAST, ONE_KEY, "list")
+        .summary("Prepends one or more elements to a list."),
+    command("rpush", -3, GROW_FAST, ONE_KEY, "list")
+        .summary("Appends one or more elements to a list."),
+    command("lpop", -2, WRITE_FAST, ONE_KEY, "list")
+        .summary("Returns the first elements in a list after removing it."),
+    command("rpop", -2, WRITE_FAST, ONE_KEY, "list")
+        .summary("Returns and removes the last elements of a list."),
+    command("blpop", -3, BLOCKING, (1, -2, 1), "list")
+        .summary("Removes and returns the first element in a list, or blocks until there is one."),
+    command("brpop", -3, BLOCKING, (1, -2, 1), "list")
+        .summary("Removes and returns the last element in a list, or blocks until there is one."),
+    command("lmove", 5, GROW, TWO_KEYS, "list")
+        .summary("Returns an element after popping it from one list and pushing it to another."),
+    command(
+        "blmove",
+        6,
+        &["write", "denyoom", "noscript", "blocking"],
+        TWO_KEYS,
+        "list",
+    )
+    .summary("Moves an element from one list to another, or blocks until there is one."),
+    command("llen", 2, READ_FAST, ONE_KEY, "list").summary("Returns the length of a list."),
+    command("lrange", 4, READ, ONE_KEY, "list").summary("Returns a range of elements from a list."),
+    command("linsert", 5, GROW, ONE_KEY, "list")
+        .summary("Inserts an element before or after another element in a list."),
+    command("lset", 4, GROW, ONE_KEY, "list")
+        .summary("Sets the value of an element in a list by its index."),
+    command("lrem", 4, WRITE, ONE_KEY, "list").summary("Removes elements from a list."),
+    command("ltrim", 4, WRITE, ONE_KEY, "list").summary("Removes elements from both ends a list."),
+    // Hashes
+    command("hset", -4, GROW_FAST, ONE_KEY, "hash")
+        .summary("Creates or modifies the value of a field in a hash."),
+    command("hget", 3, READ_FAST, ONE_KEY, "hash")
+        .summary("Returns the value of a field in a hash."),
+    command("hexists", 3, READ_FAST, ONE_KEY, "hash")
+        .summary("Determines whether a field exists in a hash."),
+    command("hdel", -3, WRITE_FAST, ONE_KEY, "hash")
+        .summary("Deletes one or more fields and their values from a hash."),
+    command("hgetall", 2, READ, ONE_KEY, "hash")
+        .summary("Returns all fields and values in a hash."),
+    command("hlen", 2, READ_FAST, ONE_KEY, "hash")
+        .summary("Returns the number of fields in a hash."),
+    command("hkeys", 2, READ, ONE_KEY, "hash").summary("Returns all fields in a hash."),
+    command("hvals", 2, READ, ONE_KEY, "hash").summary("Returns all values in a hash."),
+    command("hmget", -3, READ_FAST, ONE_KEY, "hash")
+        .summary("Returns the values of all fields in a hash."),
+    command("hincrby", 4, GROW_FAST, ONE_KEY, "hash")
+        .summary("Increments the integer value of a field in a hash by a number."),
+    command("hincrbyfloat", 4, GROW_FAST, ONE_KEY, "hash")
+        .summary("Increments the floating point value of a field by a number."),
+    command("hrandfield", -2, READ, ONE_KEY, "hash")
+        .summary("Returns one or more random fields from a hash."),
+    command("hscan", -3, READ, ONE_KEY, "hash")
+        .summary("Iterates over fields and values of a hash."),
+    // Sets
+    command("sadd", -3, GROW_FAST, ONE_KEY, "set").summary("Adds one or more members to a set."),
+    command("srem", -3, WRITE_FAST, ONE_KEY, "set")
+        .summary("Removes one or more members from a set."),
+    command("smembers", 2, READ, ONE_KEY, "set").summary("Returns all members of a set."),
+    command("scard", 2, READ_FAST, ONE_KEY, "set")
+        .summary("Returns the number of members in a set."),
+    command("sismember", 3, READ_FAST, ONE_KEY, "set")
+        .summary("Determines whether a member belongs to a set."),
+    command("sinter", -2, READ, ALL_KEYS, "set").summary("Returns the intersect of multiple sets."),
+    command("sunion", -2, READ, ALL_KEYS, "set").summary("Returns the union of multiple sets."),
+    command("sdiff", -2, READ, ALL_KEYS, "set").summary("Returns the difference of multiple sets."),
+    command("sinterstore", -3, GROW, ALL_KEYS, "set")
+        .summary("Stores the intersect of multiple sets in a key."),
+    command("sunionstore", -3, GROW, ALL_KEYS, "set")
+        .summary("Stores the union of multiple sets in a key."),
+    command("sdiffstore", -3, GROW, ALL_KEYS, "set")
+        .summary("Stores the difference of multiple sets in a key."),
+    command("sintercard", -3, READ, NO_KEYS, "set")
+        .summary("Returns the number of members of the intersect of multiple sets."),
+    command("spop", -2, WRITE_FAST, ONE_KEY, "set")
+        .summary("Returns one or more random members from a set after removing them."),
+    command("srandmember", -2, READ, ONE_KEY, "set")
+        .summary("Get one or multiple random members from a set"),
+    command("smove", 4, WRITE_FAST, TWO_KEYS, "set")
+        .summary("Moves a member from one set to another."),
+    command("sscan", -3, READ, ONE_KEY, "set").summary("Iterates over members of a set."),
+    // Sorted sets
+    command("zadd", -4, GROW_FAST, ONE_KEY, "sorted-set")
+        .summary("Adds one or more members to a sorted set, or updates their scores."),
+    command("zscore", 3, READ_FAST, ONE_KEY, "sorted-set")
+        .summary("Returns the score of a member in a sorted set."),
+    command("zincrby", 4, GROW_FAST, ONE_KEY, "sorted-set")
+        .summary("Increments the score of a member in a sorted set."),
+    command("zcard", 2, READ_FAST, ONE_KEY, "sorted-set")
+        .summary("Returns the number of members in a sorted set."),
+    command("zcount", 4, READ_FAST, ONE_KEY, "sorted-set")
+        .summary("Returns the count of members in a sorted set that have scores within a range."),
+    command("zrank", -3, READ_FAST, ONE_KEY, "sorted-set")
+        .summary("Returns the index of a member in a sorted set ordered by ascending scores."),
+    command("zrevrank", -3, READ_FAST, ONE_KEY, "sorted-set")
+        .summary("Returns the index of a member in a sorted set ordered by descending scores."),
+    command("zrange", -4, READ, ONE_KEY, "sorted-set")
+        .summary("Returns members in a sorted set within a range of indexes."),
+    command("zrevrange", -4, READ, ONE_KEY, "sorted-set")
+        .summary("Returns members in a sorted set within a range of indexes in reverse order."),
+    command("zrangebyscore", -4, READ, ONE_KEY, "sorted-set")
+        .summary("Returns members in a sorted set within a range of scores."),
+    command("zrevrangebyscore", -4, READ, ONE_KEY, "sorted-set")
+        .summary("Returns members in a sorted set within a range of scores in reverse order."),
+    command("zrangebylex", -4, READ, ONE_KEY, "sorted-set")
+        .summary("Returns members in a sorted set within a lexicographical range."),
+    command("zrevrangebylex", -4, READ, ONE_KEY, "sorted-set").summary(
+        "Returns members in a sorted set within a lexicographical range in reverse order.",
+    ),
+    command("zrem", -3, WRITE_FAST, ONE_KEY, "sorted-set")
+        .summary("Removes one or more members from a sorted set."),
+    command("zpopmin", -2, WRITE_FAST, ONE_KEY, "sorted-set")
+        .summary("Returns the lowest-scoring members from a sorted set after removing them."),
+    command("zpopmax", -2, WRITE_FAST, ONE_KEY, "sorted-set")
+        .summary("Returns the highest-scoring members from a sorted set after removing them."),
+    command(
+        "bzpopmin",
+        -3,
+        &["write", "noscript", "blocking", "fast"],
+        (1, -2, 1),
+        "sorted-set",
+    )
+    .summary("Removes and returns the lowest-scoring member, or blocks until there is one."),
+    command(
+        "bzpopmax",
+        -3,
+        &["write", "noscript", "blocking", "fast"],
+        (1, -2, 1),
+        "sorted-set",
+    )
+    .summary("Removes and returns the highest-scoring member, or blocks until there is one."),
+    command("zscan", -3, READ, ONE_KEY, "sorted-set")
+        .summary("Iterates over members and scores of a sorted set."),
+];
+
+/// Looks up a command, or a subcommand given as `container|subcommand`, by its lowercase name.
+pub fn lookup(name: &str) -> Option<&'static CommandInfo> {
+    let (container, subcommand) = match name.split_once('|') {
+        Some((container, _)) => (container, Some(name)),
+        None => (name, None),
+    };
+    let info = COMMANDS.iter().find(|info| info.name == container)?;
+    match subcommand {
+        Some(name) => info.subcommands.iter().find(|info| info.name == name),
+        None => Some(info),
+    }
+}
+
+impl CommandInfo {
+    const fn summary(self, summary: &'static str) -> CommandInfo {
+        CommandInfo { summary, ..self }
+    }
+
+    const fn subcommands(self, subcommands: &'static [CommandInfo]) -> CommandInfo {
+        CommandInfo {
+            subcommands,
+            ..self
+        }
+    }
+
+    /// Whether `argc` arguments, counting the command name, are the right number for the command.
+    pub fn accepts(&self, argc: usize) -> bool {
+        match self.arity {
+            arity if arity >= 0 => argc as i64 == arity,
+            arity => argc as i64 >= -arity,
+        }
+    }
+
+    /// The ACL categories of the command, which follow from its flags and group like they mostly
+    /// do in Redis.
+    pub fn acl_categories(&self) -> Vec<&'static str> {
+        let mut categories = Vec::new();
+        let flagged = |flag: &str| self.flags.contains(&flag);
+        if flagged("write") {
+            categories.push("@write");
+        }
+        if flagged("readonly") {
+            categories.push("@read");
+        }
+        if flagged("admin") {
+            categories.extend(["@admin", "@dangerous"]);
+        }
+        if flagged("pubsub") {
+            categories.push("@pubsub");
+        }
+        if flagged("blocking") {
+            categories.push("@blocking");
+        }
+        categories.push(if flagged("fast") { "@fast" } else { "@slow" });
+        categories.extend(match self.group {
+            "generic" => Some("@keyspace"),
+            "string" | "bitmap" => Some("@string"),
+            "list" => Some("@list"),
+            "hash" => Some("@hash"),
+            "set" => Some("@set"),
+            "sorted-set" => Some("@sortedset"),
+            "transactions" => Some("@transaction"),
+            "connection" => Some("@connection"),
+            _ => None,
+        });
+        categories
+    }
+
+    /// The reply of COMMAND INFO: name, arity, flags, key positions, ACL categories, tips, key
+    /// specifications and subcommands.
+    // NOTE: Neither command tips nor key specifications are kept, their lists are always empty.
+    pub fn to_resp(&self) -> Resp {
+        let text = |text: &str| Resp::SimpleString(text.to_string());
+        Resp::Array(vec![
+            Resp::BulkString(Bytes::from_static(self.name.as_bytes())),
+            Resp::Integer(self.arity),
+            Resp::Set(self.flags.iter().map(|flag| text(flag)).collect()),
+            Resp::Integer(self.first_key),
+            Resp::Integer(self.last_key),
+            Resp::Integer(self.step),
+            Resp::Set(self.acl_categories().into_iter().map(text).collect()),
+            Resp::Set(vec![]),
+            Resp::Set(vec![]),
+            Resp::Array(self.subcommands.iter().map(CommandInfo::to_resp).collect()),
+        ])
+    }
+
+    /// The reply of COMMAND DOCS for this command, which goes under its name.
+    pub fn docs(&self) -> Resp {
+        let field = |name: &'static str, value: Resp| {
+            (Resp::BulkString(Bytes::from_static(name.as_bytes())), value)
+        };
+        let text = |text: &'static str| Resp::BulkString(Bytes::from_static(text.as_bytes()));
+
+        let mut docs = vec![
+            field("summary", text(self.summary)),
+            field("group", text(self.group)),
+        ];
+        if !self.subcommands.is_empty() {
+            let subcommands = self
+                .subcommands
+                .iter()
+                .map(|subcommand| (text(subcommand.name), subcommand.docs()))
+                .collect();
+            docs.push(field("subcommands", Resp::Map(subcommands)));
+        }
+        Resp::Map(docs)
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::{lookup, COMMANDS};
+
+    #[test]
+    fn commands_and_subcommands_can_be_looked_up() {
+        assert_eq!(lookup("get").unwrap().arity, 2);
+        assert_eq!(lookup("config|get").unwrap().arity, -3);
+        assert!(lookup("config|nope").is_none());
+        assert!(lookup("nope").is_none());
+
+        for info in COMMANDS {
+            assert_eq!(lookup(info.name).unwrap().name, info.name);
+            for subcommand in info.subcommands {
+                assert!(subcommand.name.starts_with(&format!("{}|", info.name)));
+            }
+        }
+    }
+}
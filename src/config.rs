@@ -0,0 +1,728 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use thiserror::Error;
+
+use crate::{
+    cluster::DEFAULT_NODE_TIMEOUT,
+    glob::glob_match,
+    replication::MasterLink,
+    resp::Resp,
+    server::{DEFAULT_BIND, DEFAULT_PORT},
+};
+
+pub(crate) const DEFAULT_MAX_INFLIGHT_COMMANDS: usize = 32;
+pub(crate) const DEFAULT_PROTO_MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+pub(crate) const DEFAULT_APPENDFILENAME: &str = "appendonly.aof";
+pub(crate) const DEFAULT_HZ: u64 = 10;
+pub(crate) const DEFAULT_DATABASES: usize = 16;
+pub(crate) const DEFAULT_CLUSTER_CONFIG_FILE: &str = "nodes.conf";
+// Like Redis: after an hour if anything changed, 5 minutes after 100 changes, a minute after 10000.
+const DEFAULT_SAVE: &[(u64, u64)] = &[(3600, 1), (300, 100), (60, 10000)];
+
+/// Every parameter by its redis.conf name, and whether CONFIG SET can change it while the server
+/// is running.
+const PARAMETERS: &[(&str, bool)] = &[
+    ("port", false),
+    ("bind", false),
+    ("dir", false),
+    ("dbfilename", true),
+    ("databases", false),
+    ("io-threads", false),
+    ("max-inflight-commands", false),
+    ("proto-max-bulk-len", true),
+    ("hz", true),
+    ("protected-mode", true),
+    ("requirepass", true),
+    ("maxmemory", true),
+    ("notify-keyspace-events", true),
+    ("save", true),
+    ("appendonly", true),
+    ("appendfilename", false),
+    ("appenddirname", false),
+    ("aof-timestamp-enabled", false),
+    ("aof-load-truncated", true),
+    ("cluster-enabled", false),
+    ("cluster-port", false),
+    ("cluster-node-timeout", false),
+    ("cluster-config-file", false),
+    ("replicaof", false),
+    ("replica-read-only", true),
+];
+
+// The keyspace notification classes of `notify-keyspace-events`, in the order Redis lists them.
+const KEYSPACE_EVENTS: &[(char, u16)] = &[
+    ('g', 1 << 2),
+    ('$', 1 << 3),
+    ('l', 1 << 4),
+    ('s', 1 << 5),
+    ('h', 1 << 6),
+    ('z', 1 << 7),
+    ('x', 1 << 8),
+    ('e', 1 << 9),
+    ('t', 1 << 10),
+    ('d', 1 << 12),
+    ('K', 1 << 0),
+    ('E', 1 << 1),
+    ('m', 1 << 11),
+    ('n', 1 << 13),
+];
+// `A` stands for every class up to `d`.
+const ALL_KEYSPACE_EVENTS: u16 = 0b1_0111_1111_1100;
+
+// CONFIG REWRITE appends the parameters a config file didn't mention yet after this line, once.
+const REWRITE_SIGNATURE: &str = "# Generated by CONFIG REWRITE";
+
+/// The server's configuration, from redis.conf style parameters.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub port: u16,
+    /// Left unset to listen on the loopback interface, which protected mode relies on.
+    pub bind: Option<String>,
+    pub dir: String,
+    /// The RDB file in `dir`.
+    pub dbfilename: String,
+    pub databases: usize,
+    /// How many accept loops share the port, more than one binds it with SO_REUSEPORT.
+    pub io_threads: usize,
+    pub max_inflight_commands: usize,
+    pub proto_max_bulk_len: usize,
+    /// How many times a second the cron runs, between 1 and 500.
+    pub hz: u64,
+    pub protected_mode: bool,
+    pub requirepass: String,
+    // NOTE: Nothing is evicted yet, the limit is only reported back.
+    pub maxmemory: usize,
+    // NOTE: No keyspace notifications are published yet, the classes are only reported back.
+    pub notify_keyspace_events: u16,
+    /// Snapshot after this many seconds if at least this many keys changed. Without any, there
+    /// are no snapshots and no RDB file at all.
+    pub save: Vec<(u64, u64)>,
+    pub appendonly: bool,
+    pub appendfilename: String,
+    pub appenddirname: Option<String>,
+    pub aof_timestamp_enabled: bool,
+    pub aof_load_truncated: bool,
+    pub cluster_enabled: bool,
+    /// The cluster bus port, 0 for the client port plus 10000.
+    pub cluster_port: u16,
+    pub cluster_node_timeout: u64,
+    pub cluster_config_file: String,
+    /// The master to replicate, `<host> <port>`.
+    pub replicaof: Option<String>,
+    pub replica_read_only: bool,
+    /// The names `rename-command` gave to commands, empty for the ones it disabled.
+    pub renamed_commands: BTreeMap<String, String>,
+    /// The file the configuration was read from, which CONFIG REWRITE writes back to.
+    pub file: Option<PathBuf>,
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum ConfigError {
+    #[error("unknown parameter")]
+    Unknown,
+    #[error("can't set immutable config")]
+    Immutable,
+    #[error("duplicate parameter")]
+    Duplicate,
+    #[error("argument must be 'yes' or 'no'")]
+    NotABool,
+    #[error("argument couldn't be parsed into an integer")]
+    NotAnInteger,
+    #[error("argument must be a memory value")]
+    NotAMemoryValue,
+    #[error("Invalid save parameters")]
+    InvalidSave,
+    #[error("Invalid event class character. Use 'Ag$lshzxeKEtmdn'.")]
+    InvalidKeyspaceEvents,
+    #[error("replicaof expects <host> <port>")]
+    InvalidReplicaOf,
+    #[error("Unable to turn on AOF. Check server logs.")]
+    AofFailed,
+    #[error("wrong number of arguments")]
+    WrongArgumentCount,
+    #[error("expected a --parameter")]
+    UnexpectedArgument,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            port: DEFAULT_PORT,
+            bind: None,
+            dir: ".".to_string(),
+            dbfilename: "dump.rdb".to_string(),
+            databases: DEFAULT_DATABASES,
+            io_threads: 1,
+            max_inflight_commands: DEFAULT_MAX_INFLIGHT_COMMANDS,
+            proto_max_bulk_len: DEFAULT_PROTO_MAX_BULK_LEN,
+            hz: DEFAULT_HZ,
+            protected_mode: true,
+            requirepass: String::new(),
+            maxmemory: 0,
+            notify_keyspace_events: 0,
+            save: DEFAULT_SAVE.to_vec(),
+            appendonly: false,
+            appendfilename: DEFAULT_APPENDFILENAME.to_string(),
+            appenddirname: None,
+            aof_timestamp_enabled: false,
+            aof_load_truncated: true,
+            cluster_enabled: false,
+            cluster_port: 0,
+            cluster_node_timeout: DEFAULT_NODE_TIMEOUT,
+            cluster_config_file: DEFAULT_CLUSTER_CONFIG_FILE.to_string(),
+            replicaof: None,
+            replica_read_only: true,
+            renamed_commands: BTreeMap::new(),
+            file: None,
+        }
+    }
+}
+
+impl Config {
+    /// Builds the configuration from parameters by their redis.conf name, `rename-command
+    /// <command>` for renames. Returns the first parameter that couldn't be set, and why.
+    pub fn from_map(parameters: &HashMap<String, String>) -> Result<Config, (String, ConfigError)> {
+        let mut config = Config::default();
+        for (name, value) in parameters {
+            config
+                .set(name, value)
+                .map_err(|error| (name.clone(), error))?;
+        }
+        Ok(config)
+    }
+
+    /// Reads the parameters of a redis.conf file, a `name value...` directive a line.
+    pub fn read_file(path: &Path) -> io::Result<HashMap<String, String>> {
+        let contents = fs::read_to_string(path)?;
+        let mut parameters = HashMap::<String, String>::new();
+        for (number, line) in contents.lines().enumerate() {
+            let invalid = |message: &str| {
+                let message = format!("{}:{}: {}", path.display(), number + 1, message);
+                io::Error::new(io::ErrorKind::InvalidData, message)
+            };
+            let args = split_line(line).ok_or_else(|| invalid("unbalanced quotes"))?;
+            if args.is_empty() {
+                continue;
+            }
+            let (name, value) =
+                parse_directive(&args).ok_or_else(|| invalid("wrong number of arguments"))?;
+
+            // Like in Redis, every save line adds save points rather than replacing them.
+            let value = match parameters.get(&name) {
+                Some(points) if name == "save" && !points.is_empty() && !value.is_empty() => {
+                    format!("{} {}", points, value)
+                }
+                _ => value,
+            };
+            parameters.insert(name, value);
+        }
+        Ok(parameters)
+    }
+
+    /// Writes the configuration back to `path` for CONFIG REWRITE. Like in Redis, the line of
+    /// every parameter the file already sets is updated in place, keeping its comments and order,
+    /// and parameters changed from their defaults that it doesn't mention yet are appended.
+    pub fn rewrite(&self, path: &Path) -> io::Result<()> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(error) => return Err(error),
+        };
+
+        let mut written = HashSet::new();
+        let mut lines = Vec::new();
+        for line in contents.lines() {
+            let name = split_line(line)
+                .and_then(|args| args.first().map(|name| name.to_lowercase()))
+                .filter(|name| Config::is_mutable(name).is_some());
+            match name {
+                // The first line of a parameter gets its value, any others are dropped.
+                Some(name) => {
+                    if written.insert(name.clone()) {
+                        lines.push(self.directive(&name));
+                    }
+                }
+                // Comments, renames and anything else are kept as they are.
+                None => lines.push(line.to_string()),
+            }
+        }
+
+        let defaults = Config::default();
+        let changed = PARAMETERS
+            .iter()
+            .map(|(name, _)| *name)
+            .filter(|name| !written.contains(*name) && self.get(name) != defaults.get(name))
+            .collect::<Vec<_>>();
+        if !changed.is_empty() && !lines.iter().any(|line| line == REWRITE_SIGNATURE) {
+            lines.push(REWRITE_SIGNATURE.to_string());
+        }
+        lines.extend(changed.into_iter().map(|name| self.directive(name)));
+
+        // Written next to the file and moved over it, so a crash never leaves half a config.
+        let temp_path =
+            path.with_file_name(format!("temp-rewriteconf-{}.conf", std::process::id()));
+        fs::write(&temp_path, lines.join("\n") + "\n")?;
+        fs::rename(temp_path, path)
+    }
+
+    /// The redis.conf line setting a parameter to its current value.
+    fn directive(&self, name: &str) -> String {
+        let value = self.get(name).unwrap_or_default();
+        match name {
+            // These take several arguments, which have to stay apart.
+            "save" | "replicaof" | "bind" if !value.is_empty() => format!("{} {}", name, value),
+            _ => format!("{} {}", name, quote(&value)),
+        }
+    }
+
+    /// Whether CONFIG SET can change `name`, `None` if there is no such parameter.
+    pub fn is_mutable(name: &str) -> Option<bool> {
+        PARAMETERS
+            .iter()
+            .find(|(parameter, _)| *parameter == name)
+            .map(|(_, mutable)| *mutable)
+    }
+
+    /// The parameters whose name matches `pattern`, with their values.
+    pub fn matching(&self, pattern: &str) -> Vec<(&'static str, String)> {
+        PARAMETERS
+            .iter()
+            .filter(|(name, _)| glob_match(pattern.as_bytes(), name.as_bytes()))
+            .filter_map(|(name, _)| Some((*name, self.get(name)?)))
+            .collect()
+    }
+
+    /// The value of a parameter, the way CONFIG GET reports it.
+    pub fn get(&self, name: &str) -> Option<String> {
+        let yes_no = |value: bool| if value { "yes" } else { "no" }.to_string();
+        let value = match name {
+            "port" => self.port.to_string(),
+            "bind" => self.bind.as_deref().unwrap_or(DEFAULT_BIND).to_string(),
+            "dir" => self.dir.clone(),
+            "dbfilename" => self.dbfilename.clone(),
+            "databases" => self.databases.to_string(),
+            "io-threads" => self.io_threads.to_string(),
+            "max-inflight-commands" => self.max_inflight_commands.to_string(),
+            "proto-max-bulk-len" => self.proto_max_bulk_len.to_string(),
+            "hz" => self.hz.to_string(),
+            "protected-mode" => yes_no(self.protected_mode),
+            "requirepass" => self.requirepass.clone(),
+            "maxmemory" => self.maxmemory.to_string(),
+            "notify-keyspace-events" => keyspace_events_to_string(self.notify_keyspace_events),
+            "save" => self
+                .save
+                .iter()
+                .map(|(seconds, changes)| format!("{} {}", seconds, changes))
+                .collect::<Vec<_>>()
+                .join(" "),
+            "appendonly" => yes_no(self.appendonly),
+            "appendfilename" => self.appendfilename.clone(),
+            "appenddirname" => self.appenddirname.clone().unwrap_or_default(),
+            "aof-timestamp-enabled" => yes_no(self.aof_timestamp_enabled),
+            "aof-load-truncated" => yes_no(self.aof_load_truncated),
+            "cluster-enabled" => yes_no(self.cluster_enabled),
+            "cluster-port" => self.cluster_port.to_string(),
+            "cluster-node-timeout" => self.cluster_node_timeout.to_string(),
+            "cluster-config-file" => self.cluster_config_file.clone(),
+            "replicaof" => self.replicaof.clone().unwrap_or_default(),
+            "replica-read-only" => yes_no(self.replica_read_only),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /// Parses and sets a parameter, whether or not it can be changed while the server is running.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), ConfigError> {
+        if let Some(command) = name.strip_prefix("rename-command ") {
+            self.renamed_commands
+                .insert(command.to_lowercase(), value.to_string());
+            return Ok(());
+        }
+
+        match name {
+            "port" => self.port = parse_integer(value)?,
+            "bind" => self.bind = Some(value.to_string()),
+            "dir" => self.dir = value.to_string(),
+            "dbfilename" => self.dbfilename = value.to_string(),
+            "databases" => self.databases = parse_integer::<usize>(value)?.max(1),
+            "io-threads" => self.io_threads = parse_integer::<usize>(value)?.max(1),
+            "max-inflight-commands" => self.max_inflight_commands = parse_integer(value)?,
+            "proto-max-bulk-len" => {
+                self.proto_max_bulk_len = parse_memory(value).ok_or(ConfigError::NotAMemoryValue)?
+            }
+            // Redis clamps hz to the same range.
+            "hz" => self.hz = parse_integer::<u64>(value)?.clamp(1, 500),
+            "protected-mode" => self.protected_mode = parse_bool(value)?,
+            "requirepass" => self.requirepass = value.to_string(),
+            "maxmemory" => {
+                self.maxmemory = parse_memory(value).ok_or(ConfigError::NotAMemoryValue)?
+            }
+            "notify-keyspace-events" => {
+                self.notify_keyspace_events =
+                    parse_keyspace_events(value).ok_or(ConfigError::InvalidKeyspaceEvents)?
+            }
+            "save" => self.save = parse_save(value).ok_or(ConfigError::InvalidSave)?,
+            "appendonly" => self.appendonly = parse_bool(value)?,
+            "appendfilename" => self.appendfilename = value.to_string(),
+            "appenddirname" => self.appenddirname = Some(value.to_string()),
+            "aof-timestamp-enabled" => self.aof_timestamp_enabled = parse_bool(value)?,
+            "aof-load-truncated" => self.aof_load_truncated = parse_bool(value)?,
+            "cluster-enabled" => self.cluster_enabled = parse_bool(value)?,
+            "cluster-port" => self.cluster_port = parse_integer(value)?,
+            "cluster-node-timeout" => self.cluster_node_timeout = parse_integer(value)?,
+            "cluster-config-file" => self.cluster_config_file = value.to_string(),
+            "replicaof" => {
+                MasterLink::parse(value).ok_or(ConfigError::InvalidReplicaOf)?;
+                self.replicaof = Some(value.to_string());
+            }
+            "replica-read-only" => self.replica_read_only = parse_bool(value)?,
+            _ => return Err(ConfigError::Unknown),
+        }
+        Ok(())
+    }
+
+    /// Whether connections are only accepted from the loopback interface. Like Redis, protected
+    /// mode only kicks in when the operator hasn't made any choice about exposing the server,
+    /// neither binding an address nor setting a password.
+    pub fn is_protected(&self) -> bool {
+        self.protected_mode && self.requirepass.is_empty() && self.bind.is_none()
+    }
+}
+
+/// The config file and the parameters given on the command line.
+pub type Arguments = (Option<PathBuf>, HashMap<String, String>);
+
+/// Parses redis-server's command line, without the program name: an optional config file, then
+/// parameters as `--name value...`. Errors come with the argument that caused them.
+pub fn parse_arguments(args: &[String]) -> Result<Arguments, (String, ConfigError)> {
+    let (file, mut rest) = match args.split_first() {
+        Some((file, rest)) if !file.starts_with("--") => (Some(PathBuf::from(file)), rest),
+        _ => (None, args),
+    };
+
+    let mut parameters = HashMap::new();
+    while let Some((name, tail)) = rest.split_first() {
+        let Some(name) = name.strip_prefix("--") else {
+            return Err((name.clone(), ConfigError::UnexpectedArgument));
+        };
+        let count = tail
+            .iter()
+            .position(|arg| arg.starts_with("--"))
+            .unwrap_or(tail.len());
+        let mut args = vec![name.to_string()];
+        args.extend_from_slice(&tail[..count]);
+        let (name, value) = parse_directive(&args)
+            .ok_or_else(|| (format!("--{}", name), ConfigError::WrongArgumentCount))?;
+        parameters.insert(name, value);
+        rest = &tail[count..];
+    }
+
+    Ok((file, parameters))
+}
+
+/// Splits a line of redis.conf into its arguments, none for comments and empty lines.
+fn split_line(line: &str) -> Option<Vec<String>> {
+    if line.trim_start().starts_with('#') {
+        return Some(Vec::new());
+    }
+    let args = Resp::split_inline(line.as_bytes()).ok()?;
+    Some(
+        args.iter()
+            .map(|arg| String::from_utf8_lossy(arg).into_owned())
+            .collect(),
+    )
+}
+
+/// Turns the arguments of a directive into a parameter and its value, all the arguments after the
+/// name together. `rename-command GET fetch` stands for `rename-command get` set to `fetch`.
+fn parse_directive(args: &[String]) -> Option<(String, String)> {
+    let (name, values) = args.split_first()?;
+    let name = name.to_lowercase();
+    match (name.as_str(), values) {
+        (_, []) => None,
+        ("rename-command", [command, new_name]) => Some((
+            format!("rename-command {}", command.to_lowercase()),
+            new_name.clone(),
+        )),
+        ("rename-command", _) => None,
+        _ => Some((name, values.join(" "))),
+    }
+}
+
+/// Quotes a value for redis.conf when it is empty or would otherwise be split or unescaped.
+fn quote(value: &str) -> String {
+    let plain = |c: char| !c.is_whitespace() && !matches!(c, '"' | '\'' | '\\');
+    if !value.is_empty() && value.chars().all(plain) {
+        return value.to_string();
+    }
+
+    let mut quoted = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' | '\\' => quoted.extend(['\\', c]),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn parse_bool(value: &str) -> Result<bool, ConfigError> {
+    match value.to_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(ConfigError::NotABool),
+    }
+}
+
+fn parse_integer<T: std::str::FromStr>(value: &str) -> Result<T, ConfigError> {
+    value.parse().map_err(|_| ConfigError::NotAnInteger)
+}
+
+/// Parses a memory amount as written in redis.conf, e.g. `1024`, `64k`, `512mb` or `1gb`.
+pub fn parse_memory(value: &str) -> Option<usize> {
+    let value = value.to_lowercase();
+    let split = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number = number.parse::<usize>().ok()?;
+
+    let multiplier = match unit {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+
+    number.checked_mul(multiplier)
+}
+
+/// Parses `<seconds> <changes> [<seconds> <changes> ...]`, where an empty value (or `""` from the
+/// command line) turns snapshots off.
+fn parse_save(value: &str) -> Option<Vec<(u64, u64)>> {
+    if value == "\"\"" {
+        return Some(Vec::new());
+    }
+
+    let numbers = value
+        .split_whitespace()
+        .map(|number| number.parse::<u64>().ok())
+        .collect::<Option<Vec<_>>>()?;
+    let pairs = numbers.chunks_exact(2);
+    if !pairs.remainder().is_empty() {
+        return None;
+    }
+    Some(pairs.map(|pair| (pair[0], pair[1])).collect())
+}
+
+fn parse_keyspace_events(value: &str) -> Option<u16> {
+    value.chars().try_fold(0, |flags, class| match class {
+        'A' => Some(flags | ALL_KEYSPACE_EVENTS),
+        class => KEYSPACE_EVENTS
+            .iter()
+            .find(|(name, _)| *name == class)
+            .map(|(_, flag)| flags | flag),
+    })
+}
+
+fn keyspace_events_to_string(flags: u16) -> String {
+    let mut classes = String::new();
+    let mut remaining = flags;
+    if flags & ALL_KEYSPACE_EVENTS == ALL_KEYSPACE_EVENTS {
+        classes.push('A');
+        remaining &= !ALL_KEYSPACE_EVENTS;
+    }
+    for (name, flag) in KEYSPACE_EVENTS {
+        if remaining & flag != 0 {
+            classes.push(*name);
+        }
+    }
+    classes
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::{parse_arguments, parse_memory, Config, ConfigError};
+    #[allow(unused_imports)]
+    use std::{collections::HashMap, fs, path::PathBuf};
+
+    #[test]
+    fn parameters_are_validated_and_reported_back() {
+        let mut config = Config::default();
+        config.set("maxmemory", "100mb").unwrap();
+        config.set("save", "900 1 300 10").unwrap();
+        config.set("hz", "1000").unwrap();
+        config.set("appendonly", "YES").unwrap();
+
+        assert_eq!(config.get("maxmemory").unwrap(), "104857600");
+        assert_eq!(config.get("save").unwrap(), "900 1 300 10");
+        assert_eq!(config.get("hz").unwrap(), "500");
+        assert_eq!(config.get("appendonly").unwrap(), "yes");
+
+        assert_eq!(
+            config.set("maxmemory", "lots"),
+            Err(ConfigError::NotAMemoryValue)
+        );
+        assert_eq!(config.set("save", "900"), Err(ConfigError::InvalidSave));
+        assert_eq!(config.set("hz", "often"), Err(ConfigError::NotAnInteger));
+        assert_eq!(
+            config.set("appendonly", "maybe"),
+            Err(ConfigError::NotABool)
+        );
+        assert_eq!(config.set("nope", "1"), Err(ConfigError::Unknown));
+
+        config.set("save", "").unwrap();
+        assert!(config.save.is_empty());
+    }
+
+    #[test]
+    fn snapshots_default_to_dump_rdb_in_the_working_directory() {
+        let config = Config::default();
+        assert_eq!(config.get("dir").unwrap(), ".");
+        assert_eq!(config.get("dbfilename").unwrap(), "dump.rdb");
+        assert!(!config.save.is_empty());
+    }
+
+    #[test]
+    fn keyspace_events_are_normalized() {
+        let mut config = Config::default();
+        config.set("notify-keyspace-events", "Ex").unwrap();
+        assert_eq!(config.get("notify-keyspace-events").unwrap(), "xE");
+
+        config.set("notify-keyspace-events", "KA").unwrap();
+        assert_eq!(config.get("notify-keyspace-events").unwrap(), "AK");
+
+        assert_eq!(
+            config.set("notify-keyspace-events", "Kq"),
+            Err(ConfigError::InvalidKeyspaceEvents)
+        );
+        assert_eq!(config.get("notify-keyspace-events").unwrap(), "AK");
+    }
+
+    #[test]
+    fn from_map_reports_the_parameter_that_failed() {
+        let parameters = HashMap::from([
+            ("port".to_string(), "7000".to_string()),
+            ("rename-command GET".to_string(), "fetch".to_string()),
+        ]);
+        let config = Config::from_map(&parameters).unwrap();
+        assert_eq!(config.port, 7000);
+        assert_eq!(config.renamed_commands["get"], "fetch");
+
+        let parameters = HashMap::from([("databases".to_string(), "many".to_string())]);
+        assert_eq!(
+            Config::from_map(&parameters),
+            Err(("databases".to_string(), ConfigError::NotAnInteger))
+        );
+    }
+
+    #[test]
+    fn memory_values_use_redis_units() {
+        assert_eq!(parse_memory("512"), Some(512));
+        assert_eq!(parse_memory("1k"), Some(1000));
+        assert_eq!(parse_memory("1KB"), Some(1024));
+        assert_eq!(parse_memory("2gb"), Some(2 * 1024 * 1024 * 1024));
+        assert_eq!(parse_memory("1tb"), None);
+    }
+
+    #[test]
+    fn command_lines_take_a_config_file_then_parameters() {
+        let args = [
+            "redis.conf",
+            "--port",
+            "7000",
+            "--save",
+            "",
+            "--replicaof",
+            "host",
+            "6380",
+        ];
+        let (file, parameters) = parse_arguments(&args.map(String::from)).unwrap();
+        assert_eq!(file, Some(PathBuf::from("redis.conf")));
+        assert_eq!(parameters["port"], "7000");
+        assert_eq!(parameters["save"], "");
+        assert_eq!(parameters["replicaof"], "host 6380");
+
+        let args = ["--rename-command", "GET", "\"\"", "--appendonly", "yes"];
+        let (file, parameters) = parse_arguments(&args.map(String::from)).unwrap();
+        assert_eq!(file, None);
+        assert_eq!(parameters["rename-command get"], "\"\"");
+        assert_eq!(parameters["appendonly"], "yes");
+
+        assert_eq!(
+            parse_arguments(&["--dir", "/tmp", "--port"].map(String::from)),
+            Err(("--port".to_string(), ConfigError::WrongArgumentCount))
+        );
+        assert_eq!(
+            parse_arguments(&["redis.conf", "7000"].map(String::from)),
+            Err(("7000".to_string(), ConfigError::UnexpectedArgument))
+        );
+    }
+
+    #[test]
+    fn rewrite_updates_the_config_file_in_place() {
+        let path = std::env::temp_dir().join(format!("redis-rewrite-{}.conf", std::process::id()));
+        let original = [
+            "# Memory",
+            "maxmemory 1mb",
+            "save 900 1",
+            "",
+            "save 300 10",
+            "rename-command DEBUG \"\"",
+            "requirepass \"with spaces\"",
+            "maxmemory 2mb",
+        ];
+        fs::write(&path, original.join("\n")).unwrap();
+
+        let mut config = Config::from_map(&Config::read_file(&path).unwrap()).unwrap();
+        assert_eq!(config.maxmemory, 2 * 1024 * 1024);
+        assert_eq!(config.save, [(900, 1), (300, 10)]);
+        assert_eq!(config.requirepass, "with spaces");
+        assert_eq!(config.renamed_commands["debug"], "");
+
+        config.set("maxmemory", "100").unwrap();
+        config.set("save", "").unwrap();
+        config.set("hz", "20").unwrap();
+        config.set("dir", "/tmp/some dir").unwrap();
+        config.rewrite(&path).unwrap();
+        let rewritten = fs::read_to_string(&path).unwrap();
+        let expected = [
+            "# Memory",
+            "maxmemory 100",
+            "save \"\"",
+            "",
+            "rename-command DEBUG \"\"",
+            "requirepass \"with spaces\"",
+            "# Generated by CONFIG REWRITE",
+            "dir \"/tmp/some dir\"",
+            "hz 20",
+        ];
+        assert_eq!(rewritten, expected.join("\n") + "\n");
+
+        // A second rewrite keeps the lines of the first where they are.
+        config.set("hz", "30").unwrap();
+        config.rewrite(&path).unwrap();
+        let rewritten = fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            rewritten,
+            expected.join("\n").replace("hz 20", "hz 30") + "\n"
+        );
+        assert_eq!(
+            Config::from_map(&Config::read_file(&path).unwrap()).unwrap(),
+            config
+        );
+
+        fs::remove_file(path).unwrap();
+    }
+}
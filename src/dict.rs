@@ -0,0 +1,355 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    mem,
+};
+
+use bytes::Bytes;
+
+const INITIAL_SIZE: usize = 4;
+// NOTE: Like Redis, every write to a table that is being resized moves one bucket over, and a
+//       step gives up after this many empty buckets per bucket it was asked to move, so a sparse
+//       table can't make a single write slow.
+const EMPTY_VISITS_PER_BUCKET: usize = 10;
+// Tables shrink once less than 1 in this many buckets would be used.
+const MIN_FILL_RATIO: usize = 10;
+
+type Bucket<V> = Vec<(Bytes, V)>;
+
+/// A hash table keyed by bytes that resizes incrementally, like the dict of Redis. Growing or
+/// shrinking allocates a second table, and entries move over to it a bucket at a time on every
+/// write and from the server cron, instead of all at once. Rehashing a table of millions of keys
+/// in one go would stall every client waiting on its shard.
+pub struct Dict<V> {
+    /// The second table is only used while resizing, new entries then always go into it.
+    tables: [Vec<Bucket<V>>; 2],
+    len: usize,
+    /// The next bucket of the first table to move over, while resizing.
+    rehash_index: Option<usize>,
+    hasher: RandomState,
+}
+
+impl<V> Default for Dict<V> {
+    fn default() -> Self {
+        Dict {
+            tables: [Vec::new(), Vec::new()],
+            len: 0,
+            rehash_index: None,
+            hasher: RandomState::new(),
+        }
+    }
+}
+
+impl<V> Dict<V> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_rehashing(&self) -> bool {
+        self.rehash_index.is_some()
+    }
+
+    fn hash(&self, key: &[u8]) -> usize {
+        let mut hasher = self.hasher.build_hasher();
+        hasher.write(key);
+        hasher.finish() as usize
+    }
+
+    /// The tables that can hold entries right now, the first one first.
+    fn live_tables(&self) -> usize {
+        match self.rehash_index {
+            Some(_) => 2,
+            None => 1,
+        }
+    }
+
+    /// Where `key` is, as table, bucket and position in the bucket.
+    fn find(&self, key: &[u8]) -> Option<(usize, usize, usize)> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let hash = self.hash(key);
+        for table in 0..self.live_tables() {
+            let bucket = hash & (self.tables[table].len() - 1);
+            let position = self.tables[table][bucket]
+                .iter()
+                .position(|(k, _)| k.as_ref() == key);
+            if let Some(position) = position {
+                return Some((table, bucket, position));
+            }
+        }
+        None
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&V> {
+        let (table, bucket, position) = self.find(key)?;
+        Some(&self.tables[table][bucket][position].1)
+    }
+
+    pub fn get_mut(&mut self, key: &[u8]) -> Option<&mut V> {
+        self.rehash(1);
+        let (table, bucket, position) = self.find(key)?;
+        Some(&mut self.tables[table][bucket][position].1)
+    }
+
+    /// Inserts `value` under `key`, returning the value it replaced.
+    pub fn insert(&mut self, key: Bytes, value: V) -> Option<V> {
+        self.rehash(1);
+        if let Some((table, bucket, position)) = self.find(&key) {
+            return Some(mem::replace(
+                &mut self.tables[table][bucket][position].1,
+                value,
+            ));
+        }
+
+        self.expand_if_needed();
+        let table = self.live_tables() - 1;
+        let bucket = self.hash(&key) & (self.tables[table].len() - 1);
+        self.tables[table][bucket].push((key, value));
+        self.len += 1;
+        None
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<V> {
+        self.rehash(1);
+        let (table, bucket, position) = self.find(key)?;
+        let (_, value) = self.tables[table][bucket].swap_remove(position);
+        self.len -= 1;
+
+        self.shrink_if_needed();
+        Some(value)
+    }
+
+    /// Iterates over every entry in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&Bytes, &V)> {
+        self.tables[..self.live_tables()]
+            .iter()
+            .flatten()
+            .flatten()
+            .map(|(key, value)| (key, value))
+    }
+
+    /// Visits the entries of the bucket at `cursor`, returning the cursor of the next bucket, or 0
+    /// once every bucket was visited. Like the dictScan of Redis, the cursor is incremented with its
+    /// bits reversed, so an entry present for the whole scan is visited at least once even when the
+    /// table is resized in between, although it may be visited twice.
+    pub fn scan(&self, mut cursor: u64, mut visit: impl FnMut(&Bytes, &V)) -> u64 {
+        if self.len == 0 {
+            return 0;
+        }
+
+        let mask = |table: &Vec<Bucket<V>>| table.len() as u64 - 1;
+        let next = |cursor: u64, mask: u64| {
+            (cursor | !mask)
+                .reverse_bits()
+                .wrapping_add(1)
+                .reverse_bits()
+        };
+        let mut visit_bucket = |bucket: &Bucket<V>| {
+            for (key, value) in bucket {
+                visit(key, value);
+            }
+        };
+
+        if !self.is_rehashing() {
+            let mask = mask(&self.tables[0]);
+            visit_bucket(&self.tables[0][(cursor & mask) as usize]);
+            return next(cursor, mask);
+        }
+
+        // While resizing, a bucket of the smaller table covers a few buckets of the larger one,
+        // which are all visited in one go.
+        let (small, large) = match self.tables[0].len() <= self.tables[1].len() {
+            true => (&self.tables[0], &self.tables[1]),
+            false => (&self.tables[1], &self.tables[0]),
+        };
+        let (small_mask, large_mask) = (mask(small), mask(large));
+        visit_bucket(&small[(cursor & small_mask) as usize]);
+        loop {
+            visit_bucket(&large[(cursor & large_mask) as usize]);
+            cursor = next(cursor, large_mask);
+            if cursor & (small_mask ^ large_mask) == 0 {
+                return cursor;
+            }
+        }
+    }
+
+    /// Moves up to `buckets` buckets over to the new table while resizing, returning whether
+    /// there are more left to move. When not resizing, it starts shrinking a table that got too
+    /// sparse since its last write.
+    pub fn rehash(&mut self, buckets: usize) -> bool {
+        if !self.is_rehashing() {
+            self.shrink_if_needed();
+        }
+        let Some(mut index) = self.rehash_index else {
+            return false;
+        };
+
+        let mut empty_visits = buckets * EMPTY_VISITS_PER_BUCKET;
+        let mut moved = 0;
+        while moved < buckets && index < self.tables[0].len() {
+            if self.tables[0][index].is_empty() {
+                index += 1;
+                empty_visits -= 1;
+                if empty_visits == 0 {
+                    break;
+                }
+                continue;
+            }
+
+            let mask = self.tables[1].len() - 1;
+            for (key, value) in mem::take(&mut self.tables[0][index]) {
+                let bucket = self.hash(&key) & mask;
+                self.tables[1][bucket].push((key, value));
+            }
+            index += 1;
+            moved += 1;
+        }
+
+        if index < self.tables[0].len() {
+            self.rehash_index = Some(index);
+            return true;
+        }
+
+        self.tables[0] = mem::take(&mut self.tables[1]);
+        self.rehash_index = None;
+        // Removals made while resizing may have left the new table too sparse already.
+        self.shrink_if_needed();
+        self.is_rehashing()
+    }
+
+    fn resize(&mut self, size: usize) {
+        let size = size.max(INITIAL_SIZE).next_power_of_two();
+        if self.tables[0].is_empty() {
+            self.tables[0] = (0..size).map(|_| Vec::new()).collect();
+            return;
+        }
+
+        self.tables[1] = (0..size).map(|_| Vec::new()).collect();
+        self.rehash_index = Some(0);
+    }
+
+    fn expand_if_needed(&mut self) {
+        if self.is_rehashing() {
+            return;
+        }
+        if self.tables[0].is_empty() || self.len >= self.tables[0].len() {
+            self.resize(self.len * 2);
+        }
+    }
+
+    fn shrink_if_needed(&mut self) {
+        let size = self.tables[0].len();
+        if !self.is_rehashing() && size > INITIAL_SIZE && self.len * MIN_FILL_RATIO < size {
+            self.resize(self.len);
+        }
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::Dict;
+    #[allow(unused_imports)]
+    use bytes::Bytes;
+
+    #[allow(dead_code)]
+    fn key(i: usize) -> Bytes {
+        Bytes::from(format!("key:{}", i))
+    }
+
+    #[test]
+    fn entries_can_be_found_while_resizing() {
+        let mut dict = Dict::default();
+        for i in 0..1000 {
+            assert_eq!(dict.insert(key(i), i), None);
+        }
+        assert_eq!(dict.len(), 1000);
+        assert_eq!(dict.insert(key(7), 70), Some(7));
+
+        let mut resized_at_some_point = false;
+        for i in 1000..1100 {
+            dict.insert(key(i), i);
+            resized_at_some_point |= dict.is_rehashing();
+            for i in (0..=i).step_by(97) {
+                assert!(dict.get(&key(i)).is_some());
+            }
+        }
+        assert!(resized_at_some_point);
+
+        for i in 0..1000 {
+            let expected = if i == 7 { 70 } else { i };
+            assert_eq!(dict.get(&key(i)), Some(&expected));
+            *dict.get_mut(&key(i)).unwrap() += 1;
+        }
+        assert_eq!(dict.iter().count(), 1100);
+        assert_eq!(dict.get(b"missing"), None);
+    }
+
+    #[test]
+    fn growing_moves_a_bucket_at_a_time() {
+        let mut dict = Dict::default();
+        let mut i = 0;
+        while !dict.is_rehashing() {
+            dict.insert(key(i), i);
+            i += 1;
+        }
+
+        // Every later write only moves a bucket, so a resize is spread over as many writes.
+        let size = dict.tables[0].len();
+        dict.insert(key(i), i);
+        assert!(dict.is_rehashing());
+        assert!(dict.rehash_index.unwrap() < size);
+
+        while dict.rehash(1) {}
+        assert!(!dict.is_rehashing());
+        assert_eq!(dict.tables[0].len(), size * 2);
+        assert_eq!(dict.iter().count(), i + 1);
+    }
+
+    #[test]
+    fn removing_most_entries_shrinks_the_table() {
+        let mut dict = Dict::default();
+        for i in 0..1000 {
+            dict.insert(key(i), i);
+        }
+        while dict.rehash(100) {}
+        let grown = dict.tables[0].len();
+
+        for i in 10..1000 {
+            assert_eq!(dict.remove(&key(i)), Some(i));
+        }
+        while dict.rehash(100) {}
+        assert!(dict.tables[0].len() < grown / 10);
+        assert_eq!(dict.len(), 10);
+        assert_eq!(dict.remove(&key(500)), None);
+        for i in 0..10 {
+            assert_eq!(dict.get(&key(i)), Some(&i));
+        }
+    }
+
+    #[test]
+    fn scans_visit_every_entry_across_resizes() {
+        let mut dict = Dict::default();
+        for i in 0..100 {
+            dict.insert(key(i), i);
+        }
+
+        // Entries added during the scan grow the table, which keeps resizing as the scan goes on.
+        let mut visited = vec![0; 300];
+        let mut added = 100;
+        let mut cursor = dict.scan(0, |_, &i| visited[i] += 1);
+        while cursor != 0 {
+            for _ in 0..4 {
+                if added < 300 {
+                    dict.insert(key(added), added);
+                    added += 1;
+                }
+            }
+            cursor = dict.scan(cursor, |_, &i| visited[i] += 1);
+        }
+
+        assert!(visited[..100].iter().all(|&visits| visits >= 1));
+        assert_eq!(Dict::<usize>::default().scan(0, |_, _| unreachable!()), 0);
+    }
+}
@@ -0,0 +1,104 @@
+/// Matches `string` against a glob-style `pattern`, like the patterns of KEYS and SCAN MATCH.
+/// `*` matches any run of bytes, `?` any single byte, `[abc]`, `[^abc]` and `[a-z]` a byte out of a
+/// class, and `\` escapes the byte after it.
+// NOTE: Every token but `*` consumes exactly one byte, so retrying from the last `*` is enough and
+//       a match never takes more than pattern length times string length steps.
+pub fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // Where to resume after the last `*`, and how much of the string it swallowed so far.
+    let mut star = None;
+
+    while s < string.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            star = Some((p, s));
+            continue;
+        }
+        if let Some(next) = match_token(pattern, p, string[s]) {
+            p = next;
+            s += 1;
+            continue;
+        }
+        match star {
+            Some((after_star, swallowed)) => {
+                p = after_star;
+                s = swallowed + 1;
+                star = Some((after_star, s));
+            }
+            None => return false,
+        }
+    }
+
+    pattern[p..].iter().all(|&byte| byte == b'*')
+}
+
+/// Matches `byte` against the token at `p`, returning where the next token starts if it matched.
+fn match_token(pattern: &[u8], p: usize, byte: u8) -> Option<usize> {
+    match *pattern.get(p)? {
+        b'?' => Some(p + 1),
+        b'[' => match_class(pattern, p + 1, byte),
+        b'\\' if p + 1 < pattern.len() => (pattern[p + 1] == byte).then_some(p + 2),
+        literal => (literal == byte).then_some(p + 1),
+    }
+}
+
+/// Matches `byte` against the class starting after the `[` at `p - 1`. Like in Redis, a class
+/// missing its `]` runs to the end of the pattern and ranges can be given in either order.
+fn match_class(pattern: &[u8], mut p: usize, byte: u8) -> Option<usize> {
+    let negated = pattern.get(p) == Some(&b'^');
+    if negated {
+        p += 1;
+    }
+
+    let mut matched = false;
+    while p < pattern.len() && pattern[p] != b']' {
+        match pattern[p] {
+            b'\\' if p + 1 < pattern.len() => {
+                matched |= pattern[p + 1] == byte;
+                p += 2;
+            }
+            start if pattern.get(p + 1) == Some(&b'-') && p + 2 < pattern.len() => {
+                let end = pattern[p + 2];
+                matched |= (start.min(end)..=start.max(end)).contains(&byte);
+                p += 3;
+            }
+            literal => {
+                matched |= literal == byte;
+                p += 1;
+            }
+        }
+    }
+
+    (matched != negated).then_some((p + 1).min(pattern.len()))
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::glob_match;
+
+    #[test]
+    fn wildcards_match_any_bytes() {
+        assert!(glob_match(b"*", b""));
+        assert!(glob_match(b"user:*", b"user:1000"));
+        assert!(glob_match(b"*:*:name", b"user:1000:name"));
+        assert!(!glob_match(b"*:name", b"user:1000:email"));
+        assert!(glob_match(b"h?llo", b"hello"));
+        assert!(!glob_match(b"h?llo", b"hllo"));
+        assert!(glob_match(b"a*a*a*b", b"aaaaaaaaaaaaaaaaaaaaab"));
+        assert!(!glob_match(b"a*a*a*b", b"aaaaaaaaaaaaaaaaaaaaaa"));
+    }
+
+    #[test]
+    fn classes_and_escapes_match_single_bytes() {
+        assert!(glob_match(b"h[ae]llo", b"hallo"));
+        assert!(!glob_match(b"h[ae]llo", b"hillo"));
+        assert!(glob_match(b"h[^e]llo", b"hallo"));
+        assert!(!glob_match(b"h[^e]llo", b"hello"));
+        assert!(glob_match(b"key[0-9]", b"key7"));
+        assert!(glob_match(b"key[9-0]", b"key7"));
+        assert!(!glob_match(b"key[0-9]", b"keyx"));
+        assert!(glob_match(b"what\\?", b"what?"));
+        assert!(!glob_match(b"what\\?", b"whats"));
+        assert!(glob_match(b"[\\]]", b"]"));
+    }
+}
@@ -0,0 +1,134 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+use tokio::sync::Mutex;
+
+use crate::{redis::Redis, resp::Resp, session::Session};
+
+/// An in-process connection to the database. Commands are executed directly against the store,
+/// without going through TCP or the RESP encoder.
+///
+/// ```
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// use redis_starter_rust::{resp::Resp, Server};
+///
+/// let redis = Server::builder().embedded();
+/// redis.execute(["SET", "foo", "bar"]).await;
+///
+/// assert_eq!(redis.execute(["GET", "foo"]).await, Resp::BulkString("bar".into()));
+/// # }
+/// ```
+///
+/// Each handle acts as its own connection: state such as READONLY isn't shared with its clones.
+pub struct RedisHandle {
+    redis: Arc<Redis>,
+    session: Mutex<Session>,
+}
+
+impl Clone for RedisHandle {
+    fn clone(&self) -> Self {
+        RedisHandle::new(self.redis.clone())
+    }
+}
+
+impl RedisHandle {
+    pub(crate) fn new(redis: Arc<Redis>) -> RedisHandle {
+        RedisHandle {
+            session: Mutex::new(redis.session()),
+            redis,
+        }
+    }
+
+    /// Executes a command line, e.g. `["SET", "foo", "bar"]`, and returns its reply.
+    pub async fn execute<I, A>(&self, command_line: I) -> Resp
+    where
+        I: IntoIterator<Item = A>,
+        A: AsRef<[u8]>,
+    {
+        let request = Resp::Array(
+            command_line
+                .into_iter()
+                .map(|arg| Resp::BulkString(Bytes::copy_from_slice(arg.as_ref())))
+                .collect(),
+        );
+
+        // NOTE: Holding the session for the whole command runs a handle's commands one at a time,
+        //       like a connection does.
+        let mut session = self.session.lock().await;
+        match self.redis.parse_client_request(request) {
+            Ok(command) => self.redis.execute(command, &mut session).await,
+            Err(error) => self.redis.reject(error, &mut session),
+        }
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use crate::{resp::Resp, Server};
+
+    #[tokio::test]
+    async fn handles_share_the_same_store() {
+        let first = Server::builder().embedded();
+        let second = first.clone();
+
+        assert_eq!(
+            first.execute(["SET", "foo", "bar"]).await,
+            Resp::SimpleString("OK".to_string())
+        );
+        assert_eq!(
+            second.execute(["get", "foo"]).await,
+            Resp::BulkString("bar".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn handle_talks_to_a_running_server() {
+        let server = Server::builder().port(0).spawn().await.unwrap();
+        let handle = server.handle();
+
+        assert_eq!(
+            handle.execute(["PING"]).await,
+            Resp::SimpleString("PONG".to_string())
+        );
+        assert_eq!(
+            handle.execute(["CONFIG", "GET", "port"]).await,
+            Resp::Map(vec![(
+                Resp::BulkString("port".into()),
+                Resp::BulkString("0".into()),
+            )])
+        );
+    }
+
+    #[tokio::test]
+    async fn malformed_commands_reply_with_errors() {
+        let redis = Server::builder().embedded();
+
+        assert_eq!(
+            redis.execute(Vec::<&str>::new()).await,
+            Resp::SimpleError(
+                "ERR Protocol error: expected a non-empty array of bulk strings".to_string()
+            )
+        );
+        assert_eq!(
+            redis.execute(["GET"]).await,
+            Resp::SimpleError("ERR wrong number of arguments for 'get' command".to_string())
+        );
+        assert_eq!(
+            redis.execute(["SET", "foo", "bar", "PX"]).await,
+            Resp::SimpleError("ERR syntax error".to_string())
+        );
+        assert_eq!(
+            redis.execute(["SET", "foo", "bar", "PX", "soon"]).await,
+            Resp::SimpleError("ERR value is not an integer or out of range".to_string())
+        );
+        assert_eq!(
+            redis.execute(["CONFIG", "RESETSTAT"]).await,
+            Resp::SimpleError("ERR unknown subcommand 'resetstat'. Try config HELP.".to_string())
+        );
+        assert_eq!(
+            redis.execute(["CONFIG", "a\r\nb"]).await,
+            Resp::SimpleError("ERR unknown subcommand 'a  b'. Try config HELP.".to_string())
+        );
+    }
+}
@@ -0,0 +1,122 @@
+use crate::{redis::Command, resp::Resp};
+
+/// Runs around every command a client executes, registered with
+/// [`ServerBuilder::hook`](crate::ServerBuilder::hook). Hooks run in the order they were
+/// registered and can be used for auditing, custom metrics or rewriting commands.
+///
+/// Commands replayed from persistence at startup don't go through hooks.
+pub trait CommandHook: Send + Sync {
+    /// Called before `command` executes, and may rewrite it. Returning a reply skips executing
+    /// the command, and the remaining hooks, and sends that reply instead.
+    fn before(&self, _command: &mut Command) -> Option<Resp> {
+        None
+    }
+
+    /// Called once `command` has executed, and may rewrite its `reply`.
+    fn after(&self, _command: &Command, _reply: &mut Resp) {}
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    };
+
+    #[allow(unused_imports)]
+    use super::CommandHook;
+    #[allow(unused_imports)]
+    use crate::{redis::Command, resp::Resp, Server};
+
+    #[allow(dead_code)]
+    #[derive(Default)]
+    struct Audit {
+        log: Mutex<Vec<(String, Resp)>>,
+    }
+
+    impl CommandHook for Arc<Audit> {
+        fn after(&self, command: &Command, reply: &mut Resp) {
+            let name = command.name().to_string();
+            self.log.lock().unwrap().push((name, reply.clone()));
+        }
+    }
+
+    #[allow(dead_code)]
+    struct Rewrite;
+
+    impl CommandHook for Rewrite {
+        fn before(&self, command: &mut Command) -> Option<Resp> {
+            match command {
+                Command::Get { key } if key.as_ref() == b"alias" => {
+                    *key = "foo".into();
+                    None
+                }
+                Command::DebugPopulate { .. } => {
+                    Some(Resp::SimpleError("ERR DEBUG is disabled".to_string()))
+                }
+                _ => None,
+            }
+        }
+
+        fn after(&self, _command: &Command, reply: &mut Resp) {
+            if *reply == Resp::Null {
+                *reply = Resp::BulkString("default".into());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn hooks_observe_commands_and_replies() {
+        let audit = Arc::new(Audit::default());
+        let redis = Server::builder().hook(audit.clone()).embedded();
+
+        redis.execute(["SET", "foo", "bar"]).await;
+        redis.execute(["GET", "foo"]).await;
+
+        assert_eq!(
+            *audit.log.lock().unwrap(),
+            vec![
+                ("set".to_string(), Resp::SimpleString("OK".to_string())),
+                ("get".to_string(), Resp::BulkString("bar".into())),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn hooks_can_rewrite_and_reject_commands() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let redis = Server::builder()
+            .hook(Rewrite)
+            .hook(Counter(calls.clone()))
+            .embedded();
+        redis.execute(["SET", "foo", "bar"]).await;
+
+        assert_eq!(
+            redis.execute(["GET", "alias"]).await,
+            Resp::BulkString("bar".into())
+        );
+        assert_eq!(
+            redis.execute(["GET", "missing"]).await,
+            Resp::BulkString("default".into())
+        );
+        assert_eq!(
+            redis.execute(["DEBUG", "POPULATE", "10"]).await,
+            Resp::SimpleError("ERR DEBUG is disabled".to_string())
+        );
+        assert_eq!(
+            redis.execute(["GET", "key:0"]).await,
+            Resp::BulkString("default".into())
+        );
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+    }
+
+    #[allow(dead_code)]
+    struct Counter(Arc<AtomicUsize>);
+
+    impl CommandHook for Counter {
+        fn before(&self, _command: &mut Command) -> Option<Resp> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            None
+        }
+    }
+}
@@ -0,0 +1,678 @@
+use std::{collections::HashMap, ops::Range, sync::Arc, time::Instant};
+
+use bytes::Bytes;
+
+use tokio::sync::{Mutex, MutexGuard};
+
+use crate::{
+    dict::Dict,
+    object::{Encoding, Object},
+    redis::RedisValue,
+    storage::{Storage, StorageFactory},
+};
+
+// NOTE: Keys are spread over a fixed number of shards per database, each behind its own async
+//       lock, so that commands touching unrelated keys can run in parallel.
+const SHARD_COUNT: usize = 16;
+// How many steps of its walk an expiry sample can take per key it samples.
+const EXPIRE_SAMPLE_STEPS_PER_KEY: usize = 20;
+pub(crate) const SLOT_COUNT: u16 = 16384;
+
+// NOTE: Values are shared behind an Arc so a snapshot can hold on to them without copying. Writers
+//       go through Arc::make_mut, which only clones a value while a snapshot still references it.
+// The storage only holds values, the shard keeps what Redis keeps in an object's header next to it.
+struct Shard {
+    storage: Box<dyn Storage>,
+    objects: Dict<Object>,
+}
+
+impl Shard {
+    fn clear(&mut self) {
+        let keys = self
+            .storage
+            .scan()
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        for key in keys {
+            self.storage.delete(&key);
+        }
+        self.objects = Dict::default();
+    }
+}
+
+/// A frozen, point-in-time copy of a database that can be serialized (e.g. by BGSAVE or a full
+/// resync) while writes carry on against the live shards.
+pub struct Snapshot {
+    pub store: HashMap<Bytes, Arc<RedisValue>>,
+    pub expiry_table: HashMap<Bytes, u64>,
+}
+
+/// Every database of the server, each with its own shards. The shards of all databases are
+/// numbered together, the ones of database `n` coming right after the ones of database `n - 1`.
+pub struct Keyspace {
+    shards: Vec<Mutex<Shard>>,
+}
+
+impl Keyspace {
+    pub fn new(storage: &StorageFactory, databases: usize) -> Keyspace {
+        Keyspace {
+            shards: (0..databases * SHARD_COUNT)
+                .map(|_| {
+                    Mutex::new(Shard {
+                        storage: storage(),
+                        objects: Dict::default(),
+                    })
+                })
+                .collect(),
+        }
+    }
+
+    /// The shard of `db` holding `key`.
+    fn shard_index(db: usize, key: &[u8]) -> usize {
+        db * SHARD_COUNT + key_slot(key) as usize % SHARD_COUNT
+    }
+
+    /// The shard of `db` a SCAN cursor is walking, which has to be locked to continue the scan.
+    pub fn cursor_shard(db: usize, cursor: u64) -> usize {
+        db * SHARD_COUNT + (cursor % SHARD_COUNT as u64) as usize
+    }
+
+    pub fn databases(&self) -> usize {
+        self.shards.len() / SHARD_COUNT
+    }
+
+    /// The indices of the shards of `db`, for walking a database a shard at a time.
+    pub fn database_shards(db: usize) -> Range<usize> {
+        db * SHARD_COUNT..(db + 1) * SHARD_COUNT
+    }
+
+    /// Locks every shard of `db` owning one of `keys`.
+    pub async fn lock<K: AsRef<[u8]>>(&self, db: usize, keys: &[K]) -> KeyspaceGuard<'_> {
+        self.lock_across(db, &[db], keys).await
+    }
+
+    /// Locks the shards owning one of `keys` in each of `databases`, for commands moving keys from
+    /// `db` to another database.
+    pub async fn lock_across<K: AsRef<[u8]>>(
+        &self,
+        db: usize,
+        databases: &[usize],
+        keys: &[K],
+    ) -> KeyspaceGuard<'_> {
+        let indices = databases
+            .iter()
+            .flat_map(|&db| {
+                keys.iter()
+                    .map(move |key| Self::shard_index(db, key.as_ref()))
+            })
+            .collect();
+
+        self.lock_indices(db, indices).await
+    }
+
+    /// Locks a single shard by index, for background work that walks the keyspace one shard at a
+    /// time instead of stalling every command at once.
+    pub async fn lock_shard(&self, index: usize) -> KeyspaceGuard<'_> {
+        self.lock_indices(index / SHARD_COUNT, vec![index]).await
+    }
+
+    /// The number of shards of every database together.
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Locks every shard of `db`.
+    pub async fn lock_all(&self, db: usize) -> KeyspaceGuard<'_> {
+        self.lock_databases(db, &[db]).await
+    }
+
+    /// Locks every shard of each of `databases`, selecting `db`.
+    pub async fn lock_databases(&self, db: usize, databases: &[usize]) -> KeyspaceGuard<'_> {
+        let indices = databases
+            .iter()
+            .flat_map(|&db| Self::database_shards(db))
+            .collect();
+
+        self.lock_indices(db, indices).await
+    }
+
+    /// Locks every shard of every database, selecting the first one.
+    pub async fn lock_everything(&self) -> KeyspaceGuard<'_> {
+        self.lock_indices(0, (0..self.shards.len()).collect()).await
+    }
+
+    /// Locks every shard of every database without waiting, failing if any of them is already
+    /// held. Only meant for startup, before any client could be holding a lock.
+    pub fn try_lock_everything(&self) -> Option<KeyspaceGuard<'_>> {
+        let mut shards = Vec::with_capacity(self.shards.len());
+        for (index, shard) in self.shards.iter().enumerate() {
+            shards.push((index, shard.try_lock().ok()?));
+        }
+
+        Some(KeyspaceGuard {
+            shards,
+            propagated: Vec::new(),
+            db: 0,
+        })
+    }
+
+    /// Takes a consistent snapshot of every database. The shards are only locked while their maps
+    /// are cloned, which copies keys and Arc pointers but never the values themselves.
+    #[allow(dead_code)]
+    pub async fn snapshot(&self) -> Vec<Snapshot> {
+        self.lock_everything().await.snapshot()
+    }
+
+    /// Locks the shards at `indices`. Shards are always acquired in ascending order, which also
+    /// means databases in ascending order, so two commands can never wait on each other.
+    async fn lock_indices(&self, db: usize, mut indices: Vec<usize>) -> KeyspaceGuard<'_> {
+        indices.sort_unstable();
+        indices.dedup();
+
+        let mut shards = Vec::with_capacity(indices.len());
+        for index in indices {
+            shards.push((index, self.shards[index].lock().await));
+        }
+
+        KeyspaceGuard {
+            shards,
+            propagated: Vec::new(),
+            db,
+        }
+    }
+}
+
+/// A view over the shards a command has locked, reading and writing keys of the selected
+/// database. Accessing a key outside of the locked shards is a bug in the command's key scope and
+/// panics.
+pub struct KeyspaceGuard<'a> {
+    shards: Vec<(usize, MutexGuard<'a, Shard>)>,
+    /// Queued writes, along with the database they were made in.
+    propagated: Vec<(usize, Vec<Bytes>)>,
+    db: usize,
+}
+
+impl<'a> KeyspaceGuard<'a> {
+    /// The database keys are read from and written to.
+    pub fn db(&self) -> usize {
+        self.db
+    }
+
+    /// Switches to another database, whose shards must be locked to access its keys.
+    pub fn select(&mut self, db: usize) {
+        self.db = db;
+    }
+
+    /// Copies the locked shards into a snapshot per database, sharing their values. Databases up
+    /// to the last locked one are included, so with every shard locked there is one per database.
+    pub fn snapshot(&self) -> Vec<Snapshot> {
+        let mut databases = Vec::new();
+        for (index, shard) in &self.shards {
+            while databases.len() <= index / SHARD_COUNT {
+                databases.push(Snapshot {
+                    store: HashMap::new(),
+                    expiry_table: HashMap::new(),
+                });
+            }
+
+            let snapshot = databases.last_mut().unwrap();
+            for (key, value) in shard.storage.scan() {
+                snapshot.store.insert(key.clone(), value.clone());
+                if let Some(expiry) = shard.storage.expiry(key) {
+                    snapshot.expiry_table.insert(key.clone(), expiry);
+                }
+            }
+        }
+
+        databases
+    }
+
+    /// Swaps the contents of two databases, whose shards must all be locked.
+    pub fn swap_databases(&mut self, first: usize, second: usize) {
+        for offset in 0..SHARD_COUNT {
+            let position = |db: usize| {
+                self.shards
+                    .binary_search_by_key(&(db * SHARD_COUNT + offset), |(i, _)| *i)
+                    .unwrap_or_else(|_| panic!("database {} swapped without holding its locks", db))
+            };
+            let (first, second) = (position(first), position(second));
+            let (low, high) = (first.min(second), first.max(second));
+            if low == high {
+                continue;
+            }
+
+            let (head, tail) = self.shards.split_at_mut(high);
+            std::mem::swap(&mut *head[low].1, &mut *tail[0].1);
+        }
+    }
+
+    fn shard(&self, key: &[u8]) -> &Shard {
+        let index = Keyspace::shard_index(self.db, key);
+        match self.shards.binary_search_by_key(&index, |(i, _)| *i) {
+            Ok(position) => &self.shards[position].1,
+            Err(_) => panic!(
+                "key '{}' accessed without holding its shard lock",
+                String::from_utf8_lossy(key)
+            ),
+        }
+    }
+
+    fn shard_mut(&mut self, key: &[u8]) -> &mut Shard {
+        let index = Keyspace::shard_index(self.db, key);
+        match self.shards.binary_search_by_key(&index, |(i, _)| *i) {
+            Ok(position) => &mut self.shards[position].1,
+            Err(_) => panic!(
+                "key '{}' accessed without holding its shard lock",
+                String::from_utf8_lossy(key)
+            ),
+        }
+    }
+
+    /// Visits a few keys of a SCAN of the selected database starting at `cursor`, returning the
+    /// cursor to continue from, or 0 once every shard was walked. The shards are walked in order,
+    /// and the cursor keeps the index of its shard in its lowest bits and the shard's own cursor
+    /// above them.
+    pub fn scan_step(&self, cursor: u64, visit: &mut dyn FnMut(&Bytes, &RedisValue)) -> u64 {
+        let index = Keyspace::cursor_shard(self.db, cursor);
+        let shard_offset = index % SHARD_COUNT;
+        let shard = match self.shards.binary_search_by_key(&index, |(i, _)| *i) {
+            Ok(position) => &self.shards[position].1,
+            Err(_) => panic!("shard {} scanned without holding its lock", index),
+        };
+
+        let shard_cursor = cursor / SHARD_COUNT as u64;
+        let next = shard
+            .storage
+            .scan_step(shard_cursor, &mut |key, value| visit(key, value));
+        match next {
+            0 if shard_offset + 1 == SHARD_COUNT => 0,
+            0 => shard_offset as u64 + 1,
+            next => next * SHARD_COUNT as u64 + shard_offset as u64,
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&RedisValue> {
+        self.shard(key).storage.get(key).map(|value| value.as_ref())
+    }
+
+    /// The value of `key`, to be written to. Its encoding is brought up to date with the previous
+    /// write first, so every state the value goes through counts towards converting it.
+    pub fn get_mut(&mut self, key: &[u8]) -> Option<&mut RedisValue> {
+        let shard = self.shard_mut(key);
+        let value = shard.storage.get_mut(key)?;
+        if let Some(object) = shard.objects.get_mut(key) {
+            object.encoding = Encoding::of(value, Some(object.encoding));
+        }
+        Some(Arc::make_mut(value))
+    }
+
+    /// The value of `key` with its reference count bumped, so it can be stored under another key
+    /// without copying it until one of them is written to.
+    pub fn get_shared(&self, key: &[u8]) -> Option<Arc<RedisValue>> {
+        self.shard(key).storage.get(key).cloned()
+    }
+
+    pub fn insert(&mut self, key: Bytes, value: RedisValue) {
+        self.insert_shared(key, Arc::new(value));
+    }
+
+    /// Stores `value` as a new object, which starts out in the most compact encoding it fits.
+    pub fn insert_shared(&mut self, key: Bytes, value: Arc<RedisValue>) {
+        let shard = self.shard_mut(&key);
+        shard.objects.insert(key.clone(), Object::new(&value));
+        shard.storage.set(key, value);
+    }
+
+    /// Stores the string `value` was changed into in place, like by APPEND, rather than set.
+    pub fn insert_modified_string(&mut self, key: Bytes, value: Bytes) {
+        let shard = self.shard_mut(&key);
+        shard.objects.insert(key.clone(), Object::modified_string());
+        shard.storage.set(key, Arc::new(RedisValue::String(value)));
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<Arc<RedisValue>> {
+        let shard = self.shard_mut(key);
+        shard.objects.remove(key);
+        shard.storage.delete(key)
+    }
+
+    /// The object header of `key`, with the encoding its value has after the last write.
+    pub fn object(&self, key: &[u8]) -> Option<Object> {
+        let shard = self.shard(key);
+        let value = shard.storage.get(key)?;
+        Some(match shard.objects.get(key) {
+            Some(object) => Object {
+                encoding: Encoding::of(value, Some(object.encoding)),
+                ..*object
+            },
+            // Storage backends can be handed values behind the keyspace's back.
+            None => Object::new(value),
+        })
+    }
+
+    /// Records that `key` was just accessed.
+    pub fn touch(&mut self, key: &[u8]) {
+        if let Some(object) = self.shard_mut(key).objects.get_mut(key) {
+            object.accessed = Instant::now();
+        }
+    }
+
+    pub fn expiry(&self, key: &[u8]) -> Option<u64> {
+        self.shard(key).storage.expiry(key)
+    }
+
+    pub fn set_expiry(&mut self, key: Bytes, expiry: u64) {
+        self.shard_mut(&key).storage.set_expiry(key, expiry);
+    }
+
+    pub fn remove_expiry(&mut self, key: &[u8]) {
+        self.shard_mut(key).storage.remove_expiry(key);
+    }
+
+    /// Queues a command line describing a write to the selected database, to be persisted (and
+    /// later replicated) once the command finishes. Recorded while the shard locks are held, so
+    /// writes to the same key are always propagated in the order they were applied.
+    pub fn propagate(&mut self, command: Vec<Bytes>) {
+        self.propagated.push((self.db, command));
+    }
+
+    pub fn take_propagated(&mut self) -> Vec<(usize, Vec<Bytes>)> {
+        std::mem::take(&mut self.propagated)
+    }
+
+    /// Removes `key` if it expired before `now`, returning whether it did. The removal is
+    /// propagated as a DEL, like Redis does, so the log and replicas never rely on their own clock
+    /// to agree with this server.
+    // TODO: Emit the `expired` keyspace event once keyspace notifications exist.
+    pub fn expire_if_needed(&mut self, key: &[u8], now: u64) -> bool {
+        match self.expiry(key) {
+            Some(expiry) if expiry < now => {}
+            _ => return false,
+        }
+
+        self.remove(key);
+        self.propagate(vec![Bytes::from("DEL"), Bytes::copy_from_slice(key)]);
+        true
+    }
+
+    /// Looks at about `count` keys with an expiry, starting from `cursor`, and removes the ones
+    /// that expired before `now`. The locked shards of the selected database are walked in order,
+    /// the cursor keeping the position of its shard among them in its lowest bits like
+    /// `scan_step`, and it wraps around to 0 once every key has been visited. Returns how many
+    /// keys were looked at and how many of them were removed.
+    pub fn expire_sample(&mut self, now: u64, count: usize, cursor: &mut u64) -> (usize, usize) {
+        // Like in Redis, a sparse table doesn't get to make a sample walk every empty bucket.
+        let mut steps = count * EXPIRE_SAMPLE_STEPS_PER_KEY;
+        let mut sampled = Vec::new();
+        let shards = self.selected().collect::<Vec<_>>();
+        let mut position = (*cursor % SHARD_COUNT as u64) as usize;
+        let mut shard_cursor = *cursor / SHARD_COUNT as u64;
+        while sampled.len() < count && steps > 0 && position < shards.len() {
+            shard_cursor = shards[position]
+                .storage
+                .expiring_step(shard_cursor, &mut |key, _| sampled.push(key.clone()));
+            if shard_cursor == 0 {
+                position += 1;
+            }
+            steps -= 1;
+        }
+        *cursor = match position < shards.len() {
+            true => shard_cursor * SHARD_COUNT as u64 + position as u64,
+            false => 0,
+        };
+
+        let mut expired = 0;
+        for key in &sampled {
+            if self.expire_if_needed(key, now) {
+                expired += 1;
+            }
+        }
+
+        (sampled.len(), expired)
+    }
+
+    /// Moves the incremental resizing of the locked shards along, returning whether any of them
+    /// has more to do.
+    pub fn rehash(&mut self, steps: usize) -> bool {
+        let mut more = false;
+        for (_, shard) in &mut self.shards {
+            more |= shard.storage.rehash(steps);
+            more |= shard.objects.rehash(steps);
+        }
+        more
+    }
+
+    /// Removes every key of the selected database from the locked shards.
+    pub fn clear(&mut self) {
+        let db = self.db;
+        for (_, shard) in self
+            .shards
+            .iter_mut()
+            .filter(|(i, _)| i / SHARD_COUNT == db)
+        {
+            shard.clear();
+        }
+    }
+
+    /// Removes every key of the locked shards, whatever their database.
+    pub fn clear_all(&mut self) {
+        for (_, shard) in &mut self.shards {
+            shard.clear();
+        }
+    }
+
+    /// The locked shards of the selected database.
+    fn selected(&self) -> impl Iterator<Item = &Shard> {
+        self.shards
+            .iter()
+            .filter(|(index, _)| index / SHARD_COUNT == self.db)
+            .map(|(_, shard)| &**shard)
+    }
+
+    /// How many keys the locked shards of the selected database hold, including expired ones that
+    /// weren't removed yet.
+    pub fn len(&self) -> usize {
+        self.selected().map(|shard| shard.storage.len()).sum()
+    }
+
+    /// How many keys of the selected database expired before `now` but weren't removed yet.
+    pub fn expired(&self, now: u64) -> usize {
+        self.selected()
+            .flat_map(|shard| shard.storage.expiring())
+            .filter(|(_, expiry)| *expiry < now)
+            .count()
+    }
+
+    /// The keys of the selected database in the locked shards.
+    pub fn keys(&self) -> impl Iterator<Item = &Bytes> {
+        self.selected()
+            .flat_map(|shard| shard.storage.scan().map(|(key, _)| key))
+    }
+}
+
+/// Maps a key to one of the 16384 hash slots, honouring `{hash tags}` like Redis Cluster does so
+/// related keys can be forced onto the same shard.
+pub fn key_slot(key: &[u8]) -> u16 {
+    let bytes = key;
+
+    let hashed = match bytes.iter().position(|&b| b == b'{') {
+        Some(open) => match bytes[open + 1..].iter().position(|&b| b == b'}') {
+            Some(0) | None => bytes,
+            Some(len) => &bytes[open + 1..open + 1 + len],
+        },
+        None => bytes,
+    };
+
+    crc16(hashed) % SLOT_COUNT
+}
+
+// CRC16-CCITT (XMODEM), the variant used by Redis Cluster.
+fn crc16(bytes: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+    for &byte in bytes {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            if crc & 0x8000 != 0 {
+                crc = (crc << 1) ^ 0x1021;
+            } else {
+                crc <<= 1;
+            }
+        }
+    }
+    crc
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use crate::keyspace::{crc16, key_slot, Keyspace};
+    #[allow(unused_imports)]
+    use crate::{
+        redis::RedisValue,
+        storage::{MemoryStorage, Storage},
+    };
+    #[allow(unused_imports)]
+    use bytes::Bytes;
+
+    #[allow(dead_code)]
+    fn memory_storage() -> Box<dyn Storage> {
+        Box::new(MemoryStorage::default())
+    }
+
+    #[test]
+    fn key_slot_matches_redis_cluster() {
+        assert_eq!(key_slot(b"foo"), 12182);
+        assert_eq!(key_slot(b"123456789"), 12739);
+    }
+
+    #[test]
+    fn key_slot_uses_hash_tag() {
+        assert_eq!(key_slot(b"{user1000}.following"), key_slot(b"user1000"));
+        assert_eq!(key_slot(b"{user1000}.followers"), key_slot(b"user1000"));
+    }
+
+    #[test]
+    fn key_slot_ignores_empty_hash_tag() {
+        assert_eq!(key_slot(b"foo{}{bar}"), crc16(b"foo{}{bar}") % 16384);
+        assert_eq!(key_slot(b"foo{{bar}}zap"), key_slot(b"{bar"));
+    }
+
+    #[tokio::test]
+    async fn lock_sees_keys_in_locked_shards() {
+        let keyspace = Keyspace::new(&memory_storage, 2);
+        keyspace
+            .lock(0, &[b"foo"])
+            .await
+            .insert(Bytes::from("foo"), RedisValue::String(Bytes::from("bar")));
+
+        let guard = keyspace.lock(0, &[b"foo", b"foo"]).await;
+        assert!(matches!(guard.get(b"foo"), Some(RedisValue::String(s)) if s == "bar"));
+    }
+
+    #[tokio::test]
+    async fn lock_all_sees_every_key() {
+        let keyspace = Keyspace::new(&memory_storage, 2);
+        for i in 0..100 {
+            let key = format!("key:{}", i);
+            keyspace.lock(0, &[&key]).await.insert(
+                Bytes::from(key),
+                RedisValue::String(Bytes::from(i.to_string())),
+            );
+        }
+
+        let guard = keyspace.lock_all(0).await;
+        assert_eq!(guard.keys().count(), 100);
+    }
+
+    #[tokio::test]
+    async fn snapshot_is_not_affected_by_later_writes() {
+        let keyspace = Keyspace::new(&memory_storage, 2);
+        keyspace
+            .lock(0, &[b"foo"])
+            .await
+            .insert(Bytes::from("foo"), RedisValue::String(Bytes::from("bar")));
+
+        let snapshot = keyspace.snapshot().await.remove(0);
+
+        let mut guard = keyspace.lock(0, &[b"foo".as_slice(), b"baz"]).await;
+        if let Some(value) = guard.get_mut(b"foo") {
+            *value = RedisValue::String(Bytes::from("barbar"));
+        }
+        guard.insert(Bytes::from("baz"), RedisValue::String(Bytes::from("qux")));
+
+        assert!(matches!(guard.get(b"foo"), Some(RedisValue::String(s)) if s == "barbar"));
+        assert!(matches!(
+            snapshot.store.get(b"foo".as_slice()).map(|v| v.as_ref()),
+            Some(RedisValue::String(s)) if s == "bar"
+        ));
+        assert!(!snapshot.store.contains_key(b"baz".as_slice()));
+    }
+
+    #[tokio::test]
+    async fn databases_are_separate_and_can_be_swapped() {
+        let keyspace = Keyspace::new(&memory_storage, 2);
+        keyspace
+            .lock(1, &[b"foo"])
+            .await
+            .insert(Bytes::from("foo"), RedisValue::String(Bytes::from("bar")));
+        assert!(keyspace.lock(0, &[b"foo"]).await.get(b"foo").is_none());
+
+        let mut guard = keyspace.lock_databases(0, &[0, 1]).await;
+        guard.swap_databases(0, 1);
+        assert!(matches!(guard.get(b"foo"), Some(RedisValue::String(s)) if s == "bar"));
+        guard.select(1);
+        assert!(guard.get(b"foo").is_none());
+
+        let snapshot = guard.snapshot();
+        assert_eq!(snapshot.len(), 2);
+        assert!(snapshot[0].store.contains_key(b"foo".as_slice()));
+        assert!(snapshot[1].store.is_empty());
+    }
+
+    #[tokio::test]
+    async fn expire_sample_removes_expired_keys() {
+        let keyspace = Keyspace::new(&memory_storage, 2);
+        let mut guard = keyspace.lock_all(0).await;
+        for i in 0..30 {
+            let key = Bytes::from(format!("key:{}", i));
+            guard.insert(key.clone(), RedisValue::String(Bytes::from("value")));
+            guard.set_expiry(key, if i < 20 { 100 } else { 300 });
+        }
+
+        let mut cursor = 0;
+        let mut expired = 0;
+        for _ in 0..3 {
+            expired += guard.expire_sample(200, 10, &mut cursor).1;
+        }
+
+        assert_eq!(expired, 20);
+        assert_eq!(guard.keys().count(), 10);
+
+        let mut cursor = 0;
+        assert_eq!(guard.expire_sample(200, 20, &mut cursor), (10, 0));
+        assert_eq!(cursor, 0);
+    }
+
+    #[tokio::test]
+    async fn expire_samples_resume_where_the_last_one_stopped() {
+        let keyspace = Keyspace::new(&memory_storage, 1);
+        let mut guard = keyspace.lock_all(0).await;
+        for i in 0..1000 {
+            let key = Bytes::from(format!("key:{}", i));
+            guard.insert(key.clone(), RedisValue::String(Bytes::from("value")));
+            guard.set_expiry(key, 300);
+        }
+        // Keys can be visited twice while a table is resized.
+        while guard.rehash(100) {}
+
+        let (mut cursor, mut sampled, mut samples) = (0, 0, 0);
+        loop {
+            sampled += guard.expire_sample(200, 20, &mut cursor).0;
+            samples += 1;
+            if cursor == 0 {
+                break;
+            }
+        }
+        assert_eq!(sampled, 1000);
+        assert!(samples <= 1000 / 20 + 1, "took {} samples", samples);
+    }
+}
@@ -0,0 +1,166 @@
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+use bytes::Bytes;
+
+use crate::resp::Resp;
+
+// Bucket `i` counts calls that took at most 2^i microseconds (and more than 2^(i-1)).
+const BUCKETS: usize = 64;
+const PERCENTILES: [(&str, f64); 3] = [("p50", 0.5), ("p99", 0.99), ("p99.9", 0.999)];
+
+/// Latency distribution of a single command, in power of two microsecond buckets like the
+/// histograms Redis reports.
+pub struct LatencyHistogram {
+    calls: u64,
+    buckets: [u64; BUCKETS],
+}
+
+impl LatencyHistogram {
+    fn new() -> LatencyHistogram {
+        LatencyHistogram {
+            calls: 0,
+            buckets: [0; BUCKETS],
+        }
+    }
+
+    pub fn record(&mut self, latency: Duration) {
+        let usec = latency.as_micros().min(u64::MAX as u128) as u64;
+        let bucket = match usec {
+            0 | 1 => 0,
+            usec => (64 - (usec - 1).leading_zeros() as usize).min(BUCKETS - 1),
+        };
+
+        self.calls += 1;
+        self.buckets[bucket] += 1;
+    }
+
+    /// The upper bound, in microseconds, of the bucket holding the given percentile (0.0 to 1.0).
+    pub fn percentile(&self, percentile: f64) -> u64 {
+        let target = ((self.calls as f64 * percentile).ceil() as u64).max(1);
+
+        let mut seen = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= target {
+                return 1 << bucket;
+            }
+        }
+
+        0
+    }
+
+    // Like Redis, only non-empty buckets are listed, each with the cumulative count of calls up
+    // to its upper bound.
+    fn to_resp(&self) -> Resp {
+        let mut histogram = Vec::new();
+        let mut cumulative = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            if *count == 0 {
+                continue;
+            }
+            cumulative += count;
+            histogram.push((Resp::Integer(1 << bucket), Resp::Integer(cumulative as i64)));
+        }
+
+        let percentiles = PERCENTILES
+            .iter()
+            .map(|(name, percentile)| {
+                (
+                    Resp::BulkString(Bytes::from(*name)),
+                    Resp::Integer(self.percentile(*percentile) as i64),
+                )
+            })
+            .collect();
+
+        Resp::Map(vec![
+            (
+                Resp::BulkString(Bytes::from("calls")),
+                Resp::Integer(self.calls as i64),
+            ),
+            (
+                Resp::BulkString(Bytes::from("histogram_usec")),
+                Resp::Map(histogram),
+            ),
+            (
+                Resp::BulkString(Bytes::from("percentiles_usec")),
+                Resp::Map(percentiles),
+            ),
+        ])
+    }
+}
+
+/// Latency histograms of every command executed so far, keyed by command name.
+#[derive(Default)]
+pub struct LatencyStats {
+    histograms: Mutex<HashMap<String, LatencyHistogram>>,
+}
+
+impl LatencyStats {
+    pub fn record(&self, command: &str, latency: Duration) {
+        let mut histograms = self.histograms.lock().unwrap();
+        match histograms.get_mut(command) {
+            Some(histogram) => histogram.record(latency),
+            None => {
+                let mut histogram = LatencyHistogram::new();
+                histogram.record(latency);
+                histograms.insert(command.to_string(), histogram);
+            }
+        }
+    }
+
+    /// The LATENCY HISTOGRAM reply for `commands`, or for every command when none are given.
+    /// Commands that haven't been called yet are left out.
+    pub fn histogram(&self, commands: &[String]) -> Resp {
+        let histograms = self.histograms.lock().unwrap();
+
+        let mut names = histograms
+            .keys()
+            .filter(|name| commands.is_empty() || commands.contains(name))
+            .collect::<Vec<_>>();
+        names.sort();
+
+        Resp::Map(
+            names
+                .into_iter()
+                .map(|name| {
+                    (
+                        Resp::BulkString(Bytes::from(name.clone())),
+                        histograms[name].to_resp(),
+                    )
+                })
+                .collect(),
+        )
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use crate::latency::LatencyHistogram;
+    #[allow(unused_imports)]
+    use std::time::Duration;
+
+    #[test]
+    fn records_into_power_of_two_buckets() {
+        let mut histogram = LatencyHistogram::new();
+        for usec in [0, 1, 2, 3, 4, 5, 1000] {
+            histogram.record(Duration::from_micros(usec));
+        }
+
+        assert_eq!(histogram.calls, 7);
+        assert_eq!(&histogram.buckets[..4], &[2, 1, 2, 1]);
+        assert_eq!(histogram.buckets[10], 1);
+    }
+
+    #[test]
+    fn percentiles_report_bucket_upper_bounds() {
+        let mut histogram = LatencyHistogram::new();
+        for _ in 0..99 {
+            histogram.record(Duration::from_micros(3));
+        }
+        histogram.record(Duration::from_millis(1));
+
+        assert_eq!(histogram.percentile(0.5), 4);
+        assert_eq!(histogram.percentile(0.99), 4);
+        assert_eq!(histogram.percentile(0.999), 1024);
+    }
+}
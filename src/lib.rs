@@ -0,0 +1,37 @@
+mod alloc;
+mod aof;
+mod bigkeys;
+mod blocking;
+mod cluster;
+mod commands;
+mod config;
+mod crc64;
+mod dict;
+mod glob;
+mod handle;
+mod hook;
+mod keyspace;
+mod latency;
+mod object;
+mod persistence;
+mod pubsub;
+mod rdb;
+mod redis;
+mod replication;
+pub mod resp;
+mod server;
+mod session;
+mod storage;
+mod tracking;
+mod zset;
+
+pub use alloc::{AllocatorStats, TrackingAllocator};
+pub use aof::{Aof, AofReport};
+pub use handle::RedisHandle;
+pub use hook::CommandHook;
+pub use keyspace::Snapshot;
+pub use persistence::{Persistence, PersistenceError, Record};
+pub use rdb::{Rdb, RdbError, RdbReport};
+pub use redis::{Command, RedisValue};
+pub use server::{Server, ServerBuilder};
+pub use storage::{MemoryStorage, Storage};
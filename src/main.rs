@@ -1,65 +1,62 @@
-use anyhow::Result;
-use redis::CommandMessage;
-use tokio::{
-    io::{AsyncReadExt, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
-    sync::{
-        mpsc::{self, Sender},
-        oneshot,
-    },
-};
-
-mod rdb;
-mod redis;
-mod resp;
-
-async fn handle_connection(stream: &mut TcpStream, tx: Sender<CommandMessage>) {
-    loop {
-        let mut buffer = [0; 1024];
-        let read_amount = stream.read(&mut buffer).await.unwrap();
+use std::path::Path;
 
-        if read_amount == 0 {
-            break;
-        }
-
-        let (resp_tx, resp_rx) = oneshot::channel();
-        let received_string = String::from_utf8_lossy(&buffer[..read_amount]).to_string();
-        tx.send((received_string, resp_tx)).await.unwrap();
-
-        let response = resp_rx.await.unwrap();
-        stream.write_all(response.as_bytes()).await.unwrap();
-    }
-}
+use anyhow::Result;
+use redis_starter_rust::{Aof, Rdb, Server};
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = std::env::args().collect::<Vec<_>>();
-    let (tx, mut rx) = mpsc::channel(32);
-
-    let server_task = tokio::spawn(async move {
-        let listener = TcpListener::bind("127.0.0.1:6379").await.unwrap();
 
-        loop {
-            let mut handles = Vec::new();
-            let (mut stream, _) = listener.accept().await.unwrap();
+    if args.get(1).map(String::as_str) == Some("--check-rdb") {
+        let path = match args.get(2) {
+            Some(path) => path,
+            None => {
+                eprintln!("Usage: {} --check-rdb <file>", args[0]);
+                std::process::exit(1);
+            }
+        };
+
+        println!("[info] Checking RDB file {}", path);
+        let report = Rdb::check_file(Path::new(path))?;
+        print!("{}", report);
+        std::process::exit(if report.is_ok() { 0 } else { 1 });
+    }
 
-            let task_tx = tx.clone();
-            handles.push(tokio::spawn(async move {
-                handle_connection(&mut stream, task_tx).await;
-            }));
-        }
-    });
+    if args.get(1).map(String::as_str) == Some("--check-aof") {
+        let fix = args.get(2).map(String::as_str) == Some("--fix");
+        let path = match args.get(if fix { 3 } else { 2 }) {
+            Some(path) => path,
+            None => {
+                eprintln!("Usage: {} --check-aof [--fix] <file>", args[0]);
+                std::process::exit(1);
+            }
+        };
+
+        let report = Aof::check_file(Path::new(path), fix)?;
+        print!("{}", report);
+        std::process::exit(if report.is_ok() || report.fixed { 0 } else { 1 });
+    }
 
-    let redis_task = tokio::spawn(async move {
-        let mut redis = redis::Redis::new(args);
+    if args.get(1).map(String::as_str) == Some("--aof-truncate-to-timestamp") {
+        let (timestamp, path) = match (args.get(2).map(|arg| arg.parse::<u64>()), args.get(3)) {
+            (Some(Ok(timestamp)), Some(path)) => (timestamp, path),
+            _ => {
+                eprintln!(
+                    "Usage: {} --aof-truncate-to-timestamp <unix seconds> <file>",
+                    args[0]
+                );
+                std::process::exit(1);
+            }
+        };
+
+        let report = Aof::truncate_to_timestamp(Path::new(path), timestamp)?;
+        print!("{}", report);
+        std::process::exit(if report.is_ok() { 0 } else { 1 });
+    }
 
-        while let Some((message, resp)) = rx.recv().await {
-            redis.handle_message(message, resp).await;
-        }
-    });
+    let server = Server::builder().args(args).spawn().await?;
 
-    server_task.await.unwrap();
-    redis_task.await.unwrap();
+    server.wait().await?;
 
     Ok(())
 }
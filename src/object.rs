@@ -0,0 +1,217 @@
+use std::time::Instant;
+
+use crate::redis::{parse_strict_integer, RedisValue};
+
+// NOTE: The limits are Redis' defaults for `hash-max-listpack-*`, `set-max-*`,
+//       `zset-max-listpack-*` and `list-max-listpack-size -2`, which are not configurable here.
+const MAX_LISTPACK_ENTRIES: usize = 128;
+const MAX_LISTPACK_VALUE: usize = 64;
+const MAX_INTSET_ENTRIES: usize = 512;
+const MAX_LIST_LISTPACK_BYTES: usize = 8 * 1024;
+// Strings up to this long are allocated together with their object header.
+const MAX_EMBSTR_LEN: usize = 44;
+// The longest string an i64 is written as, longer strings are never encoded as integers.
+const MAX_INTEGER_LEN: usize = 20;
+
+/// How Redis would lay out a value in memory, as reported by OBJECT ENCODING. Values here are
+/// always plain Rust collections, the encoding only follows the rules Redis converts by.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Encoding {
+    Int,
+    Embstr,
+    Raw,
+    Listpack,
+    Quicklist,
+    Intset,
+    Hashtable,
+    Skiplist,
+}
+
+impl Encoding {
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Int => "int",
+            Encoding::Embstr => "embstr",
+            Encoding::Raw => "raw",
+            Encoding::Listpack => "listpack",
+            Encoding::Quicklist => "quicklist",
+            Encoding::Intset => "intset",
+            Encoding::Hashtable => "hashtable",
+            Encoding::Skiplist => "skiplist",
+        }
+    }
+
+    /// The encoding of `value`, which was `previous` before it last changed. Like in Redis, sets,
+    /// hashes and sorted sets never go back to a compact encoding once they outgrew it, and lists
+    /// only do once they shrink to half the size of a listpack.
+    ///
+    /// Only ever looks at as many elements as a compact encoding holds, so it costs the same
+    /// however large the value is.
+    pub fn of(value: &RedisValue, previous: Option<Encoding>) -> Encoding {
+        let small = |bytes: &[u8]| bytes.len() <= MAX_LISTPACK_VALUE;
+        match value {
+            // A string changed in place stays raw until it is set again.
+            RedisValue::String(_) if previous == Some(Encoding::Raw) => Encoding::Raw,
+            RedisValue::String(value)
+                if value.len() <= MAX_INTEGER_LEN && parse_strict_integer(value).is_some() =>
+            {
+                Encoding::Int
+            }
+            RedisValue::String(value) if value.len() <= MAX_EMBSTR_LEN => Encoding::Embstr,
+            RedisValue::String(_) => Encoding::Raw,
+            RedisValue::List(list) => {
+                let limit = match previous {
+                    Some(Encoding::Quicklist) => MAX_LIST_LISTPACK_BYTES / 2,
+                    _ => MAX_LIST_LISTPACK_BYTES,
+                };
+                // Roughly what a listpack takes, a couple of header bytes per element. Adding up
+                // stops once past the limit, which long lists get to within a few elements.
+                let mut bytes = 0;
+                let fits = list.iter().all(|element| {
+                    bytes += element.len() + 2;
+                    bytes <= limit
+                });
+                match fits {
+                    true => Encoding::Listpack,
+                    false => Encoding::Quicklist,
+                }
+            }
+            RedisValue::Hash(hash) => {
+                let compact = hash.len() <= MAX_LISTPACK_ENTRIES
+                    && hash
+                        .iter()
+                        .all(|(field, value)| small(field) && small(value));
+                match compact && previous != Some(Encoding::Hashtable) {
+                    true => Encoding::Listpack,
+                    false => Encoding::Hashtable,
+                }
+            }
+            RedisValue::Set(set) => {
+                let integers = set.len() <= MAX_INTSET_ENTRIES
+                    && set
+                        .iter()
+                        .all(|member| parse_strict_integer(member).is_some());
+                let compact = set.len() <= MAX_LISTPACK_ENTRIES && set.iter().all(|m| small(m));
+                match previous {
+                    Some(Encoding::Hashtable) => Encoding::Hashtable,
+                    None | Some(Encoding::Intset) if integers => Encoding::Intset,
+                    _ if compact => Encoding::Listpack,
+                    _ => Encoding::Hashtable,
+                }
+            }
+            RedisValue::SortedSet(zset) => {
+                let compact = zset.len() <= MAX_LISTPACK_ENTRIES
+                    && zset.iter().all(|(member, _)| small(member));
+                match compact && previous != Some(Encoding::Skiplist) {
+                    true => Encoding::Listpack,
+                    false => Encoding::Skiplist,
+                }
+            }
+        }
+    }
+}
+
+/// What the keyspace keeps about a value besides the value itself, like the header of a Redis
+/// object.
+#[derive(Clone, Copy, Debug)]
+pub struct Object {
+    pub encoding: Encoding,
+    /// When the key was last read or written, for OBJECT IDLETIME.
+    pub accessed: Instant,
+}
+
+impl Object {
+    pub fn new(value: &RedisValue) -> Object {
+        Object {
+            encoding: Encoding::of(value, None),
+            accessed: Instant::now(),
+        }
+    }
+
+    /// The header of a string appended to or overwritten in place, which Redis keeps as a raw
+    /// string whatever it holds.
+    pub fn modified_string() -> Object {
+        Object {
+            encoding: Encoding::Raw,
+            accessed: Instant::now(),
+        }
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::Encoding;
+    #[allow(unused_imports)]
+    use crate::redis::RedisValue;
+    #[allow(unused_imports)]
+    use bytes::Bytes;
+    #[allow(unused_imports)]
+    use std::collections::{HashSet, VecDeque};
+
+    #[allow(dead_code)]
+    fn set(members: impl Iterator<Item = String>) -> RedisValue {
+        RedisValue::Set(members.map(Bytes::from).collect::<HashSet<_>>())
+    }
+
+    #[test]
+    fn strings_are_encoded_by_their_contents() {
+        let string = |value: &'static str| RedisValue::String(Bytes::from(value));
+
+        assert_eq!(Encoding::of(&string("12345"), None), Encoding::Int);
+        assert_eq!(Encoding::of(&string("012345"), None), Encoding::Embstr);
+        assert_eq!(Encoding::of(&string("hello"), None), Encoding::Embstr);
+        assert_eq!(
+            Encoding::of(&RedisValue::String(Bytes::from("x".repeat(45))), None),
+            Encoding::Raw
+        );
+        assert_eq!(
+            Encoding::of(&string("12"), Some(Encoding::Raw)),
+            Encoding::Raw
+        );
+    }
+
+    #[test]
+    fn sets_only_grow_out_of_compact_encodings() {
+        let integers = set((0..10).map(|i| i.to_string()));
+        assert_eq!(Encoding::of(&integers, None), Encoding::Intset);
+        assert_eq!(
+            Encoding::of(&integers, Some(Encoding::Listpack)),
+            Encoding::Listpack
+        );
+
+        let words = set((0..10).map(|i| format!("member:{}", i)));
+        assert_eq!(
+            Encoding::of(&words, Some(Encoding::Intset)),
+            Encoding::Listpack
+        );
+
+        let many = set((0..200).map(|i| format!("member:{}", i)));
+        assert_eq!(Encoding::of(&many, None), Encoding::Hashtable);
+        assert_eq!(
+            Encoding::of(&words, Some(Encoding::Hashtable)),
+            Encoding::Hashtable
+        );
+    }
+
+    #[test]
+    fn lists_shrink_back_to_half_a_listpack() {
+        let list = |len: usize| {
+            RedisValue::List(
+                (0..len)
+                    .map(|_| Bytes::from("x".repeat(14)))
+                    .collect::<VecDeque<_>>(),
+            )
+        };
+
+        assert_eq!(Encoding::of(&list(100), None), Encoding::Listpack);
+        assert_eq!(Encoding::of(&list(1_000_000), None), Encoding::Quicklist);
+        assert_eq!(
+            Encoding::of(&list(400), Some(Encoding::Quicklist)),
+            Encoding::Quicklist
+        );
+        assert_eq!(
+            Encoding::of(&list(200), Some(Encoding::Quicklist)),
+            Encoding::Listpack
+        );
+    }
+}
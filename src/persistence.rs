@@ -0,0 +1,50 @@
+use std::io;
+
+use bytes::Bytes;
+use thiserror::Error;
+
+use crate::{keyspace::Snapshot, redis::RedisValue, resp::Resp};
+
+/// A unit of persisted state handed back while replaying.
+pub enum Record {
+    /// A key of database `db` restored from a point-in-time snapshot.
+    Entry {
+        db: usize,
+        key: Bytes,
+        value: RedisValue,
+        expiry: Option<u64>,
+    },
+    /// A write command to execute again, e.g. from an append-only log.
+    Command(Resp),
+}
+
+#[derive(Debug, Error)]
+pub enum PersistenceError {
+    #[error("io error: {0}")]
+    Io(#[from] io::Error),
+    #[error("corrupt file: {0}")]
+    Corrupt(String),
+    #[error("{0}")]
+    Unsupported(&'static str),
+}
+
+/// A persistence engine. The server only talks to this trait, so file formats stay out of the
+/// command path and engines can be swapped through configuration (`appendonly`) or
+/// [`ServerBuilder::persistence`](crate::ServerBuilder::persistence).
+pub trait Persistence: Send + Sync {
+    /// Loads everything persisted so far, calling `apply` for each record in order.
+    fn replay(&self, apply: &mut dyn FnMut(Record)) -> Result<(), PersistenceError>;
+
+    /// Records a write command right after it has been executed. The arguments are already
+    /// rewritten to be deterministic, e.g. relative expiries become absolute ones.
+    fn append(&self, command: &[Bytes]) -> Result<(), PersistenceError>;
+
+    /// Replaces whatever was persisted with the contents of `databases`, one snapshot per database.
+    fn snapshot(&self, databases: &[Snapshot]) -> Result<(), PersistenceError>;
+
+    /// Called while the keyspace is still locked, right before a snapshot of it is handed to
+    /// another thread, so writes appended while it is being written aren't lost.
+    fn prepare_snapshot(&self) -> Result<(), PersistenceError> {
+        Ok(())
+    }
+}
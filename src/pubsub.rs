@@ -0,0 +1,301 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::{glob::glob_match, resp::Resp};
+
+/// Where to send the messages of every channel or pattern, by the id of the subscribed client.
+type Subscribers = Mutex<HashMap<Bytes, HashMap<u64, UnboundedSender<Resp>>>>;
+
+/// The channels and patterns clients are subscribed to, and where to send the messages published
+/// to them.
+#[derive(Default)]
+pub struct PubSub {
+    channels: Subscribers,
+    patterns: Subscribers,
+}
+
+/// A connection's side of pub/sub: what it is subscribed to, and where the messages published
+/// there are sent. Dropping it unsubscribes from everything.
+pub struct Subscriptions {
+    id: u64,
+    pubsub: Arc<PubSub>,
+    channels: HashSet<Bytes>,
+    patterns: HashSet<Bytes>,
+    sender: UnboundedSender<Resp>,
+}
+
+impl PubSub {
+    /// The subscriptions of the client `id`, which receives its messages through `sender`.
+    pub fn subscriptions(
+        self: &Arc<Self>,
+        id: u64,
+        sender: UnboundedSender<Resp>,
+    ) -> Subscriptions {
+        Subscriptions {
+            id,
+            pubsub: self.clone(),
+            channels: HashSet::new(),
+            patterns: HashSet::new(),
+            sender,
+        }
+    }
+
+    /// Sends `message` to every client subscribed to `channel` or a pattern matching it,
+    /// returning how many subscriptions it was sent to.
+    pub fn publish(&self, channel: &Bytes, message: &Bytes) -> usize {
+        let mut received = 0;
+
+        let channels = self.channels.lock().unwrap();
+        if let Some(subscribers) = channels.get(channel) {
+            let push = Resp::Push(vec![
+                Resp::BulkString(Bytes::from("message")),
+                Resp::BulkString(channel.clone()),
+                Resp::BulkString(message.clone()),
+            ]);
+            for subscriber in subscribers.values() {
+                let _ = subscriber.send(push.clone());
+            }
+            received += subscribers.len();
+        }
+
+        // NOTE: Like in Redis, a client subscribed to the channel and to patterns matching it gets
+        //       the message once for every one of them.
+        let patterns = self.patterns.lock().unwrap();
+        for (pattern, subscribers) in patterns.iter() {
+            if !glob_match(pattern, channel) {
+                continue;
+            }
+            let push = Resp::Push(vec![
+                Resp::BulkString(Bytes::from("pmessage")),
+                Resp::BulkString(pattern.clone()),
+                Resp::BulkString(channel.clone()),
+                Resp::BulkString(message.clone()),
+            ]);
+            for subscriber in subscribers.values() {
+                let _ = subscriber.send(push.clone());
+            }
+            received += subscribers.len();
+        }
+
+        received
+    }
+
+    /// Sends `message` to the client `id` only, if it is subscribed to `channel`. Returns whether
+    /// it was.
+    pub fn send_to(&self, channel: &[u8], id: u64, message: Resp) -> bool {
+        let channels = self.channels.lock().unwrap();
+        match channels
+            .get(channel)
+            .and_then(|subscribers| subscribers.get(&id))
+        {
+            Some(subscriber) => subscriber.send(message).is_ok(),
+            None => false,
+        }
+    }
+
+    pub fn is_subscribed(&self, channel: &[u8], id: u64) -> bool {
+        let channels = self.channels.lock().unwrap();
+        channels
+            .get(channel)
+            .is_some_and(|subscribers| subscribers.contains_key(&id))
+    }
+
+    /// The channels with at least one subscriber, only those matching `pattern` if one is given.
+    /// Clients subscribed to patterns don't make a channel active.
+    pub fn active_channels(&self, pattern: Option<&[u8]>) -> Vec<Bytes> {
+        let channels = self.channels.lock().unwrap();
+        channels
+            .keys()
+            .filter(|channel| match pattern {
+                Some(pattern) => glob_match(pattern, channel),
+                None => true,
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// How many clients are subscribed to `channel`, not counting the ones subscribed to patterns.
+    pub fn subscriber_count(&self, channel: &[u8]) -> usize {
+        let channels = self.channels.lock().unwrap();
+        channels.get(channel).map_or(0, HashMap::len)
+    }
+
+    /// How many different patterns clients are subscribed to.
+    pub fn pattern_count(&self) -> usize {
+        self.patterns.lock().unwrap().len()
+    }
+}
+
+impl Subscriptions {
+    /// How many channels and patterns the connection is subscribed to, which keeps it in
+    /// subscribe mode while there are any.
+    pub fn count(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+
+    pub fn channels(&self) -> impl Iterator<Item = &Bytes> {
+        self.channels.iter()
+    }
+
+    pub fn patterns(&self) -> impl Iterator<Item = &Bytes> {
+        self.patterns.iter()
+    }
+
+    /// Subscribes to `channel`, returning whether it wasn't already.
+    pub fn subscribe(&mut self, channel: Bytes) -> bool {
+        if !self.channels.insert(channel.clone()) {
+            return false;
+        }
+        self.register(&self.pubsub.channels, channel);
+        true
+    }
+
+    /// Unsubscribes from `channel`, returning whether it was subscribed.
+    pub fn unsubscribe(&mut self, channel: &Bytes) -> bool {
+        if !self.channels.remove(channel) {
+            return false;
+        }
+        self.deregister(&self.pubsub.channels, channel);
+        true
+    }
+
+    /// Subscribes to the channels matching `pattern`, returning whether it wasn't already.
+    pub fn psubscribe(&mut self, pattern: Bytes) -> bool {
+        if !self.patterns.insert(pattern.clone()) {
+            return false;
+        }
+        self.register(&self.pubsub.patterns, pattern);
+        true
+    }
+
+    /// Unsubscribes from `pattern`, returning whether it was subscribed.
+    pub fn punsubscribe(&mut self, pattern: &Bytes) -> bool {
+        if !self.patterns.remove(pattern) {
+            return false;
+        }
+        self.deregister(&self.pubsub.patterns, pattern);
+        true
+    }
+
+    fn register(&self, subscribers: &Subscribers, name: Bytes) {
+        let mut subscribers = subscribers.lock().unwrap();
+        subscribers
+            .entry(name)
+            .or_default()
+            .insert(self.id, self.sender.clone());
+    }
+
+    fn deregister(&self, subscribers: &Subscribers, name: &Bytes) {
+        let mut subscribers = subscribers.lock().unwrap();
+        if let Some(clients) = subscribers.get_mut(name) {
+            clients.remove(&self.id);
+            if clients.is_empty() {
+                subscribers.remove(name);
+            }
+        }
+    }
+}
+
+impl Drop for Subscriptions {
+    fn drop(&mut self) {
+        for channel in &self.channels {
+            self.deregister(&self.pubsub.channels, channel);
+        }
+        for pattern in &self.patterns {
+            self.deregister(&self.pubsub.patterns, pattern);
+        }
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::PubSub;
+    #[allow(unused_imports)]
+    use crate::{resp::Resp, session::Pushes};
+    #[allow(unused_imports)]
+    use bytes::Bytes;
+    #[allow(unused_imports)]
+    use std::sync::Arc;
+
+    #[test]
+    fn messages_reach_the_subscribers_of_their_channel() {
+        let pubsub = Arc::new(PubSub::default());
+        let (mut first_pushes, second_pushes) = (Pushes::default(), Pushes::default());
+        let mut first = pubsub.subscriptions(1, first_pushes.sender());
+        let mut second = pubsub.subscriptions(2, second_pushes.sender());
+        assert!(first.subscribe(Bytes::from("news")));
+        assert!(!first.subscribe(Bytes::from("news")));
+        second.subscribe(Bytes::from("news"));
+        second.subscribe(Bytes::from("sports"));
+
+        assert_eq!(pubsub.publish(&"news".into(), &"hi".into()), 2);
+        assert_eq!(pubsub.publish(&"weather".into(), &"rain".into()), 0);
+        assert_eq!(
+            first_pushes.try_next(),
+            Some(Resp::Push(vec![
+                Resp::BulkString("message".into()),
+                Resp::BulkString("news".into()),
+                Resp::BulkString("hi".into()),
+            ]))
+        );
+        assert_eq!(first_pushes.try_next(), None);
+
+        assert!(first.unsubscribe(&"news".into()));
+        assert!(!first.unsubscribe(&"news".into()));
+        assert_eq!(pubsub.publish(&"news".into(), &"bye".into()), 1);
+
+        drop(second);
+        assert_eq!(pubsub.publish(&"sports".into(), &"goal".into()), 0);
+        assert!(pubsub.channels.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn patterns_receive_the_messages_of_matching_channels() {
+        let pubsub = Arc::new(PubSub::default());
+        let (mut first_pushes, second_pushes) = (Pushes::default(), Pushes::default());
+        let mut first = pubsub.subscriptions(1, first_pushes.sender());
+        let mut second = pubsub.subscriptions(2, second_pushes.sender());
+        assert!(first.psubscribe(Bytes::from("news.*")));
+        first.subscribe(Bytes::from("news.tech"));
+        second.psubscribe(Bytes::from("news.*"));
+        second.psubscribe(Bytes::from("*"));
+
+        assert_eq!(pubsub.publish(&"news.tech".into(), &"hi".into()), 4);
+        assert_eq!(pubsub.publish(&"weather".into(), &"rain".into()), 1);
+        assert_eq!(
+            first_pushes.try_next(),
+            Some(Resp::Push(vec![
+                Resp::BulkString("message".into()),
+                Resp::BulkString("news.tech".into()),
+                Resp::BulkString("hi".into()),
+            ]))
+        );
+        assert_eq!(
+            first_pushes.try_next(),
+            Some(Resp::Push(vec![
+                Resp::BulkString("pmessage".into()),
+                Resp::BulkString("news.*".into()),
+                Resp::BulkString("news.tech".into()),
+                Resp::BulkString("hi".into()),
+            ]))
+        );
+
+        assert_eq!(pubsub.pattern_count(), 2);
+        assert_eq!(pubsub.subscriber_count(b"news.tech"), 1);
+        assert_eq!(
+            pubsub.active_channels(Some(b"news.*")),
+            [Bytes::from("news.tech")]
+        );
+        assert!(pubsub.active_channels(Some(b"sports.*")).is_empty());
+
+        assert!(first.punsubscribe(&"news.*".into()));
+        drop(second);
+        assert_eq!(pubsub.pattern_count(), 0);
+        assert_eq!(pubsub.publish(&"news.tech".into(), &"bye".into()), 1);
+    }
+}
@@ -1,254 +1,10276 @@
-use core::panic;
 use std::{
-    collections::HashMap,
+    cmp::Ordering as Comparison,
+    collections::{hash_map::RandomState, HashMap, HashSet, VecDeque},
+    hash::{BuildHasher, Hasher},
+    net::{IpAddr, SocketAddr},
     path::PathBuf,
-    time::{SystemTime, UNIX_EPOCH},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-use crate::{oneshot, rdb::Rdb, resp::Resp};
+use crate::{
+    alloc::AllocatorStats,
+    aof::Aof,
+    bigkeys::{self, BigKeys},
+    blocking::BlockedClients,
+    cluster::{Cluster, Slots, CLUSTER_PORT_INCR},
+    commands,
+    config::{Config, ConfigError},
+    crc64::crc64,
+    glob::glob_match,
+    hook::CommandHook,
+    keyspace::{key_slot, Keyspace, KeyspaceGuard, SLOT_COUNT},
+    latency::LatencyStats,
+    object::Encoding,
+    persistence::{Persistence, PersistenceError, Record},
+    pubsub::PubSub,
+    rdb::{Rdb, RdbError},
+    replication::{FullResync, MasterLink, Replicas},
+    resp::{ParseError, Protocol, Resp},
+    session::{Session, Transaction},
+    storage::StorageFactory,
+    tracking::{Access, Tracking, TrackingOptions},
+    zset::{LexBound, ScoreBound, SortedSet},
+};
 use bytes::Bytes;
-use oneshot::Sender;
-
-pub type CommandMessage = (String, Sender<String>);
+use thiserror::Error;
+use tokio::sync::{Semaphore, SemaphorePermit};
 
+#[derive(Clone)]
 pub enum RedisValue {
-    String(String),
+    String(Bytes),
+    List(VecDeque<Bytes>),
+    Hash(HashMap<Bytes, Bytes>),
+    Set(HashSet<Bytes>),
+    SortedSet(SortedSet),
+}
+
+impl RedisValue {
+    /// The type name TYPE reports.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            RedisValue::String(_) => "string",
+            RedisValue::List(_) => "list",
+            RedisValue::Hash(_) => "hash",
+            RedisValue::Set(_) => "set",
+            RedisValue::SortedSet(_) => "zset",
+        }
+    }
+
+    /// The length of a string in bytes, or the number of elements of a collection.
+    pub fn length(&self) -> usize {
+        match self {
+            RedisValue::String(value) => value.len(),
+            RedisValue::List(list) => list.len(),
+            RedisValue::Hash(hash) => hash.len(),
+            RedisValue::Set(set) => set.len(),
+            RedisValue::SortedSet(zset) => zset.len(),
+        }
+    }
+}
+
+// NOTE: The active expiry cycle mirrors Redis: sample a handful of keys with an expiry per shard,
+//       and keep sampling a shard while more than a quarter of its samples had expired, but never
+//       spend more than a quarter of the time between two cycles doing so.
+const ACTIVE_EXPIRE_KEYS_PER_SAMPLE: usize = 20;
+const ACTIVE_EXPIRE_ACCEPTABLE_STALE_PERCENT: usize = 25;
+const ACTIVE_EXPIRE_CYCLE_TIME_PERCENT: u64 = 25;
+// The cron moves resizing tables along this many buckets at a time, for at most this long a shard.
+const REHASH_STEPS: usize = 100;
+const REHASH_CYCLE_BUDGET: Duration = Duration::from_millis(1);
+
+// Like Redis, a save point doesn't retry a failed background save for this many seconds.
+const BGSAVE_RETRY_DELAY: u64 = 5;
+
+// The Redis version reported to clients, which gate features like RESP3 on it. This server
+// implements the commands of Redis 7.0.
+pub(crate) const REDIS_VERSION: &str = "7.0.0";
+
+// Like Redis, the error for an unknown command only echoes this much of its name and arguments.
+const MAX_ECHOED_ARGS_LEN: usize = 128;
+
+/// The state of BGSAVE, shared with the thread writing the snapshot.
+#[derive(Default)]
+struct BackgroundSave {
+    in_progress: AtomicBool,
+    failed: AtomicBool,
+    /// How many writes were made since the last successful save, which save points count.
+    dirty: AtomicU64,
+    /// When the last successful save finished, in seconds since the epoch, as LASTSAVE reports.
+    last_save: AtomicU64,
+    /// When the last background save started, in seconds since the epoch.
+    last_attempt: AtomicU64,
+}
+
+impl BackgroundSave {
+    /// Records a successful save of the dataset as it was after `dirty` writes since the one
+    /// before, which leaves the writes made while saving counted.
+    fn saved(&self, dirty: u64) {
+        self.dirty.fetch_sub(dirty, Ordering::SeqCst);
+        self.last_save
+            .store(Redis::ms_since_epoch() / 1000, Ordering::SeqCst);
+    }
 }
 
 pub struct Redis {
-    store: HashMap<String, RedisValue>,
-    expiry_table: HashMap<String, u64>,
-    config: HashMap<String, String>,
+    keyspace: Keyspace,
+    /// Swapped when CONFIG SET changes which engine the configuration selects.
+    persistence: RwLock<Option<Arc<dyn Persistence>>>,
+    /// Set when the engine was given rather than picked from the configuration, which then
+    /// leaves it alone.
+    custom_persistence: bool,
+    background_save: Arc<BackgroundSave>,
+    inflight: Semaphore,
+    cluster: Option<Cluster>,
+    /// Set on a replica, whose dataset follows the master it is linked to.
+    master_link: Option<MasterLink>,
+    replicas: Replicas,
+    /// The database the writes handed to replicas and persistence were last made in, unknown
+    /// right after a new stream of writes starts.
+    propagated_db: Mutex<Option<usize>>,
+    /// Clients blocked on keys, per database.
+    blocked: Vec<BlockedClients>,
+    pubsub: Arc<PubSub>,
+    tracking: Tracking,
+    next_client_id: AtomicU64,
+    latency: LatencyStats,
+    hooks: Vec<Box<dyn CommandHook>>,
+    /// Bytes allocated before the dataset was loaded, reported as `startup.allocated`.
+    startup_allocated: usize,
+    /// Client facing command names changed by `rename-command`. A new name maps to the command it
+    /// stands for, and a command that was renamed or disabled maps to nothing.
+    renamed_commands: HashMap<String, Option<String>>,
+    config: RwLock<Config>,
 }
 
 impl Redis {
-    pub fn new(args: Vec<String>) -> Redis {
-        let config = Self::parse_command_line_arguments(args);
-
-        let (store, expiry_table) =
-            if config.contains_key("dir") && config.contains_key("dbfilename") {
-                let mut path = PathBuf::new();
-                path.push(config.get("dir").unwrap());
-                path.push(config.get("dbfilename").unwrap());
-                Self::load_store_from_path(path)
-            } else {
-                (HashMap::new(), HashMap::new())
-            };
+    pub fn new(
+        config: Config,
+        storage: &StorageFactory,
+        persistence: Option<Box<dyn Persistence>>,
+        hooks: Vec<Box<dyn CommandHook>>,
+    ) -> Result<Redis, PersistenceError> {
+        let startup_allocated = AllocatorStats::current().allocated;
+        let custom_persistence = persistence.is_some();
+        let persistence = persistence
+            .or_else(|| Self::persistence_from_config(&config))
+            .map(Arc::from);
+
+        let cluster = Self::cluster_from_config(&config);
+        let master_link = config
+            .replicaof
+            .as_deref()
+            .map(|master| MasterLink::parse(master).expect("replicaof expects <host> <port>"));
+        let renamed_commands = Self::renamed_commands(&config);
+        let databases = config.databases;
+
+        let pubsub = Arc::<PubSub>::default();
+
+        let redis = Redis {
+            keyspace: Keyspace::new(storage, databases),
+            persistence: RwLock::new(persistence),
+            custom_persistence,
+            background_save: Arc::new(BackgroundSave {
+                last_save: AtomicU64::new(Self::ms_since_epoch() / 1000),
+                ..BackgroundSave::default()
+            }),
+            inflight: Semaphore::new(config.max_inflight_commands),
+            cluster,
+            master_link,
+            replicas: Replicas::default(),
+            propagated_db: Mutex::new(None),
+            blocked: (0..databases).map(|_| BlockedClients::default()).collect(),
+            tracking: Tracking::new(pubsub.clone()),
+            pubsub,
+            // Like in Redis, client ids start at 1.
+            next_client_id: AtomicU64::new(1),
+            latency: LatencyStats::default(),
+            hooks,
+            startup_allocated,
+            renamed_commands,
+            config: RwLock::new(config),
+        };
+
+        redis.load()?;
+        Ok(redis)
+    }
+
+    // NOTE: Like in redis.conf, every command can be renamed once. An empty name disables it.
+    fn renamed_commands(config: &Config) -> HashMap<String, Option<String>> {
+        let mut renamed_commands = HashMap::new();
+        for command in config.renamed_commands.keys() {
+            renamed_commands.insert(command.clone(), None);
+        }
+        // Inserted after hiding the old names, so two commands can swap names.
+        for (command, name) in &config.renamed_commands {
+            if !name.is_empty() {
+                renamed_commands.insert(name.to_lowercase(), Some(command.clone()));
+            }
+        }
+
+        renamed_commands
+    }
 
-        Redis {
-            store,
-            expiry_table,
-            config,
+    fn cluster_from_config(config: &Config) -> Option<Cluster> {
+        if !config.cluster_enabled {
+            return None;
         }
+
+        let mut path = PathBuf::new();
+        path.push(&config.dir);
+        path.push(&config.cluster_config_file);
+
+        let cluster =
+            Cluster::open(path, config.port).expect("failed to load the cluster config file");
+        Some(cluster.with_node_timeout(config.cluster_node_timeout))
+    }
+
+    /// Picks the persistence engine: the append-only file when `appendonly` is enabled, otherwise
+    /// the RDB file unless snapshots are turned off.
+    fn persistence_from_config(config: &Config) -> Option<Box<dyn Persistence>> {
+        if config.appendonly {
+            let mut path = PathBuf::new();
+            path.push(&config.dir);
+            path.push(&config.appendfilename);
+            let mut aof = Aof::new(path)
+                .with_timestamps(config.aof_timestamp_enabled)
+                .with_load_truncated(config.aof_load_truncated);
+            if let Some(dirname) = &config.appenddirname {
+                aof = aof.with_dirname(dirname);
+            }
+            return Some(Box::new(aof));
+        }
+
+        // NOTE: `save ""` turns snapshots off, so there is no RDB file to load or write either.
+        if config.save.is_empty() {
+            return None;
+        }
+
+        let mut path = PathBuf::new();
+        path.push(&config.dir);
+        path.push(&config.dbfilename);
+        Some(Box::new(Rdb::new(path)))
     }
 
-    fn load_store_from_path(path: PathBuf) -> (HashMap<String, RedisValue>, HashMap<String, u64>) {
-        Rdb::load_from_path(path)
+    /// The persistence engine writes are currently handed to, if any.
+    fn persistence(&self) -> Option<Arc<dyn Persistence>> {
+        self.persistence.read().unwrap().clone()
     }
 
-    fn parse_command_line_arguments(args: Vec<String>) -> HashMap<String, String> {
-        let mut args = args.iter().skip(1);
-        let mut config = HashMap::new();
+    fn load(&self) -> Result<(), PersistenceError> {
+        let Some(persistence) = self.persistence() else {
+            return Ok(());
+        };
+
+        // NOTE: Nothing else can be holding a shard lock while the server is being constructed.
+        let mut keyspace = self.keyspace.try_lock_everything().unwrap();
 
-        while let Some(arg) = args.next() {
-            match arg.as_str() {
-                "--dir" => {
-                    let value = args.next().unwrap();
-                    config.insert("dir".to_string(), value.to_string());
+        persistence.replay(&mut |record| self.apply_record(&mut keyspace, record))?;
+
+        // Replayed commands are already persisted.
+        keyspace.take_propagated();
+        Ok(())
+    }
+
+    /// Loads a persisted key or runs a persisted command, in whatever database the records before
+    /// it selected.
+    fn apply_record(&self, keyspace: &mut KeyspaceGuard, record: Record) {
+        match record {
+            Record::Entry { db, .. } if db >= self.keyspace.databases() => {
+                eprintln!("skipping key of database {}, which is out of range", db);
+            }
+            Record::Entry {
+                db,
+                key,
+                value,
+                expiry,
+            } => {
+                keyspace.select(db);
+                if let Some(expiry) = expiry {
+                    keyspace.set_expiry(key.clone(), expiry);
+                }
+                keyspace.insert(key, value);
+            }
+            Record::Command(request) => match Redis::parse_request(request) {
+                Ok(command) => {
+                    self.handle_command(keyspace, command);
+                }
+                Err(error) => eprintln!("skipping unparsable persisted command: {}", error),
+            },
+        }
+    }
+
+    /// Replaces the whole dataset with the keys of an RDB payload, which is how a replica loads
+    /// the snapshot its master sends for a full resync. The new dataset is persisted as a whole.
+    pub async fn replace_dataset(&self, rdb: &[u8]) -> Result<(), RdbError> {
+        let mut keyspace = self.keyspace.lock_everything().await;
+        keyspace.clear_all();
+        Rdb::read_records(rdb, &mut |record| self.apply_record(&mut keyspace, record))?;
+
+        if let Some(persistence) = self.persistence() {
+            self.forget_propagated_db();
+            if let Err(error) = persistence.snapshot(&keyspace.snapshot()) {
+                eprintln!(
+                    "failed to persist the dataset received from master: {}",
+                    error
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies a write the master streamed to this replica to database `db`, which a SELECT in
+    /// the stream changes. Nobody reads the reply, and neither hooks nor latency tracking see it as
+    /// it wasn't sent by a client.
+    pub async fn apply_replicated(&self, command: Command, db: &mut usize) {
+        let access = self.access(&command);
+        let mut keyspace = self.lock_scope(command.key_scope(), *db).await;
+
+        self.handle_command(&mut keyspace, command);
+        self.propagate(keyspace.take_propagated());
+        self.track(access, None);
+        *db = keyspace.db();
+    }
+
+    /// Registers a replica about to do a full resync. Every shard is locked while its snapshot is
+    /// taken, so each write is either in the snapshot or streamed to the replica afterwards.
+    pub async fn register_replica(&self, ip: Option<IpAddr>, port: Option<u16>) -> FullResync {
+        let keyspace = self.keyspace.lock_everything().await;
+        // The replica starts loading in database 0, whatever the stream selected last.
+        self.forget_propagated_db();
+        self.replicas.register(keyspace.snapshot(), ip, port)
+    }
+
+    /// Locks the shards a command needs, reading and writing keys of database `db`. Databases out
+    /// of range are left out, the command refuses them without touching their keys.
+    async fn lock_scope(&self, scope: KeyScope<'_>, db: usize) -> KeyspaceGuard<'_> {
+        let databases = self.keyspace.databases();
+        match scope {
+            KeyScope::Keys(keys) => self.keyspace.lock(db, &keys).await,
+            KeyScope::Across { db: other, keys } if other < databases => {
+                self.keyspace.lock_across(db, &[db, other], &keys).await
+            }
+            KeyScope::Across { keys, .. } => self.keyspace.lock(db, &keys).await,
+            KeyScope::All => self.keyspace.lock_all(db).await,
+            KeyScope::Databases(first, second) => {
+                let mut locked = vec![first, second];
+                locked.retain(|db| *db < databases);
+                self.keyspace.lock_databases(db, &locked).await
+            }
+            KeyScope::Everything => {
+                let mut keyspace = self.keyspace.lock_everything().await;
+                keyspace.select(db);
+                keyspace
+            }
+        }
+    }
+
+    pub fn replicas(&self) -> &Replicas {
+        &self.replicas
+    }
+
+    pub fn proto_max_bulk_len(&self) -> usize {
+        self.config.read().unwrap().proto_max_bulk_len
+    }
+
+    pub fn cluster(&self) -> Option<&Cluster> {
+        self.cluster.as_ref()
+    }
+
+    pub fn master_link(&self) -> Option<&MasterLink> {
+        self.master_link.as_ref()
+    }
+
+    fn replica_read_only(&self) -> bool {
+        self.config.read().unwrap().replica_read_only
+    }
+
+    /// The port to listen for the cluster bus on in cluster mode: `cluster-port`, or else the
+    /// client `port` plus 10000. When the client port is picked by the OS, so is this one.
+    pub fn cluster_bus_port(&self, port: u16) -> Option<u16> {
+        self.cluster.as_ref()?;
+
+        let configured = self.config.read().unwrap().cluster_port;
+        match (configured, port) {
+            (0, 0) => Some(0),
+            (0, port) => Some(port.wrapping_add(CLUSTER_PORT_INCR)),
+            (configured, _) => Some(configured),
+        }
+    }
+
+    /// Tells the server which address and cluster bus port it ended up listening on, which
+    /// cluster nodes advertise.
+    pub fn listening_on(&self, address: SocketAddr, cport: u16) {
+        let Some(cluster) = &self.cluster else {
+            return;
+        };
+
+        let ip = match address.ip().is_unspecified() {
+            true => String::new(),
+            false => address.ip().to_string(),
+        };
+        if let Err(error) = cluster.set_address(ip, address.port(), cport) {
+            eprintln!("failed to save the cluster config file: {}", error);
+        }
+    }
+
+    /// Whether a client connecting from `peer` may run commands, which in protected mode is only
+    /// the case for loopback connections.
+    pub fn accepts_connections_from(&self, peer: IpAddr) -> bool {
+        !self.config.read().unwrap().is_protected() || peer.is_loopback()
+    }
+
+    /// How long to wait between two active expiry cycles, `hz` times a second.
+    pub fn active_expire_interval(&self) -> Duration {
+        Duration::from_micros(1_000_000 / self.config.read().unwrap().hz)
+    }
+
+    /// Removes expired keys that nobody is reading, so they don't hold on to memory until they are
+    /// next accessed. `cursors` keeps each shard's position between cycles.
+    pub async fn active_expire_cycle(&self, cursors: &mut [u64]) {
+        let started = Instant::now();
+        let budget = self.active_expire_interval() * ACTIVE_EXPIRE_CYCLE_TIME_PERCENT as u32 / 100;
+
+        for (index, cursor) in cursors.iter_mut().enumerate() {
+            loop {
+                let mut keyspace = self.keyspace.lock_shard(index).await;
+                let (sampled, expired) = keyspace.expire_sample(
+                    Self::ms_since_epoch(),
+                    ACTIVE_EXPIRE_KEYS_PER_SAMPLE,
+                    cursor,
+                );
+                self.propagate(keyspace.take_propagated());
+                drop(keyspace);
+
+                let mostly_fresh =
+                    expired * 100 <= sampled * ACTIVE_EXPIRE_ACCEPTABLE_STALE_PERCENT;
+                if sampled == 0 || mostly_fresh || started.elapsed() > budget {
+                    break;
                 }
-                "--dbfilename" => {
-                    let value = args.next().unwrap();
-                    config.insert("dbfilename".to_string(), value.to_string());
+            }
+        }
+    }
+
+    /// Spends up to a millisecond per shard moving incrementally resizing tables along, like the
+    /// rehashing Redis does in its cron.
+    pub async fn rehash_cycle(&self) {
+        for index in 0..self.keyspace.shard_count() {
+            let started = Instant::now();
+            let mut keyspace = self.keyspace.lock_shard(index).await;
+            while keyspace.rehash(REHASH_STEPS) {
+                if started.elapsed() > REHASH_CYCLE_BUDGET {
+                    break;
                 }
-                _ => todo!("arg: {} not implemented", arg),
+            }
+        }
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.keyspace.shard_count()
+    }
+
+    /// Parses a request sent by a client, which unlike persisted requests can only name commands
+    /// by the names `rename-command` left them with.
+    pub fn parse_client_request(&self, request: Resp) -> Result<Command, CommandError> {
+        let Resp::Array(mut array) = request else {
+            return Err(CommandError::InvalidRequest);
+        };
+
+        if let Some(name) = array.first() {
+            let name = name.to_string();
+            match self.renamed_commands.get(&name.to_lowercase()) {
+                Some(Some(command)) => array[0] = Resp::BulkString(command.clone().into()),
+                Some(None) => return Err(CommandError::unknown_command(&name, &array[1..])),
+                None => {}
             }
         }
 
-        config
+        Redis::parse_request(Resp::Array(array))
     }
 
-    pub async fn handle_message(&mut self, message: String, resp: Sender<String>) {
-        let decoded_message = Resp::decode(&message.to_lowercase()).unwrap();
-        let (command, args) = match decoded_message {
-            Resp::Array(array) => {
+    pub fn parse_request(request: Resp) -> Result<Command, CommandError> {
+        let (command, args) = match request {
+            Resp::Array(array) if !array.is_empty() => {
                 let mut iter = array.into_iter();
                 let command = iter.next().unwrap();
                 let args = iter.collect::<Vec<_>>();
                 (command, args)
             }
-            _ => {
-                panic!("Invalid message");
-            }
+            _ => return Err(CommandError::InvalidRequest),
         };
 
-        let command = Redis::parse_command(command, args);
+        Redis::parse_command(command, args)
+    }
 
-        let response = self.handle_command(command);
-        let encoded_response = response.encoded().unwrap();
-        resp.send(encoded_response).unwrap();
+    /// The session of a new connection, with an id of its own.
+    pub fn session(&self) -> Session {
+        Session {
+            id: self.next_client_id.fetch_add(1, Ordering::Relaxed),
+            ..Session::default()
+        }
     }
 
-    pub fn parse_command(command: Resp, args: Vec<Resp>) -> Command {
-        let command = command.to_string().to_lowercase();
+    /// Runs a command holding only the shard locks for the keys it touches, so commands on
+    /// unrelated keys can execute in parallel.
+    ///
+    /// At most `max-inflight-commands` commands execute at once. Further callers wait their turn
+    /// in FIFO order, and as a connection doesn't read its next request until the current one is
+    /// answered, a saturated server slows down reads per connection rather than buffering.
+    pub async fn execute(&self, mut command: Command, session: &mut Session) -> Resp {
+        let permit = self.inflight.acquire().await.unwrap();
 
-        // TODO: Args might be empty/wrong, handle these cases
-        match command.as_str() {
-            "ping" => Command::Ping,
-            "echo" => {
-                let message = args[0].to_string();
-                Command::Echo { message }
-            }
-            "set" => Self::parse_set_command(args),
-            "get" => {
-                let key = args[0].to_string();
-                Command::Get { key }
+        for hook in &self.hooks {
+            if let Some(reply) = hook.before(&mut command) {
+                return reply;
             }
-            "config" => {
-                let subcommand = args[0].to_string();
-                match subcommand.as_str() {
-                    "get" => {
-                        let key = args[1].to_string();
-                        Command::ConfigGet { key }
-                    }
-                    _ => todo!("subcommand: config {} not implemented", subcommand),
+        }
+        if session.subscribe_mode() && !matches!(command, Command::Ping) {
+            return CommandError::SubscribeContext(command.name().to_string()).into();
+        }
+        let queuing = !matches!(command, Command::Multi | Command::Exec | Command::Discard);
+        if session.transaction.is_some() && queuing {
+            return self.queue(command, session);
+        }
+
+        // Only kept around for the after hooks, as executing consumes the command.
+        let executed = (!self.hooks.is_empty()).then(|| command.clone());
+
+        // NOTE: Latency includes waiting for the shard locks, which is this server's equivalent of
+        //       waiting for the event loop in Redis.
+        let started = Instant::now();
+        let name = command.name();
+
+        let mut response = match command {
+            // Walks the keyspace a shard at a time, so other clients never wait on more than one.
+            Command::BigKeys { count } => self.big_keys(session.db, count).await,
+            Command::MemoryStats => self.memory_stats().await,
+            Command::Scan { cursor, options } => self.scan(session.db, cursor, &options).await,
+            Command::Select { db } => match self.check_select(db) {
+                Ok(()) => {
+                    session.db = db;
+                    Resp::SimpleString("OK".to_string())
+                }
+                Err(error) => error.into(),
+            },
+            // Waits for the other node to answer, without holding any lock.
+            Command::ClusterMeet { ip, cport } => match &self.cluster {
+                Some(cluster) => match cluster.meet(ip, cport).await {
+                    Ok(()) => Resp::SimpleString("OK".to_string()),
+                    Err(error) => error.into(),
+                },
+                None => CommandError::ClusterSupportDisabled.into(),
+            },
+            command if command.changes_session() => self.change_session(command, session),
+            Command::Multi => match session.transaction {
+                Some(_) => CommandError::NestedMulti.into(),
+                None => {
+                    session.transaction = Some(Transaction::default());
+                    Resp::SimpleString("OK".to_string())
                 }
+            },
+            Command::Exec => self.exec(session).await,
+            Command::Discard => match session.transaction.take() {
+                Some(_) => Resp::SimpleString("OK".to_string()),
+                None => CommandError::DiscardWithoutMulti.into(),
+            },
+            // Replies in a shape RESP2 clients can tell apart from the messages they receive.
+            Command::Ping if session.subscribe_mode() => Resp::Array(vec![
+                Resp::BulkString(Bytes::from("pong")),
+                Resp::BulkString(Bytes::new()),
+            ]),
+            // NOTE: Connections subscribe through `subscribe`, as every channel gets a reply of
+            //       its own. Handles have no connection to push messages to.
+            command @ (Command::Subscribe { .. } | Command::Unsubscribe { .. }) => {
+                CommandError::DenyBlocking(command.name()).into()
             }
-            "keys" => {
-                let pattern = args[0].to_string();
-                Command::Keys { pattern }
+            command
+                if command.is_write() && self.master_link.is_some() && self.replica_read_only() =>
+            {
+                CommandError::ReadOnlyReplica.into()
             }
-            cmd => Command::NotImplemented {
-                cmd: cmd.to_string(),
+            command => match self.cluster_redirect(&command, session) {
+                Some(redirect) => redirect.into(),
+                None => match command {
+                    Command::BlockingPop {
+                        keys,
+                        timeout,
+                        left,
+                    } => {
+                        let pop =
+                            |keyspace: &mut KeyspaceGuard| Self::pop_first(keyspace, &keys, left);
+                        self.block_until(session.db, &keys, &keys, timeout, permit, pop)
+                            .await
+                            .unwrap_or(Resp::NullArray)
+                    }
+                    Command::BlockingZPop { keys, timeout, max } => {
+                        let pop =
+                            |keyspace: &mut KeyspaceGuard| Self::zpop_first(keyspace, &keys, max);
+                        self.block_until(session.db, &keys, &keys, timeout, permit, pop)
+                            .await
+                            .unwrap_or(Resp::NullArray)
+                    }
+                    Command::BlockingMove {
+                        source,
+                        destination,
+                        from_left,
+                        to_left,
+                        timeout,
+                    } => {
+                        let keys = [source, destination];
+                        let moved = |keyspace: &mut KeyspaceGuard| match Self::lmove(
+                            keyspace, &keys[0], &keys[1], from_left, to_left,
+                        ) {
+                            Resp::Null => None,
+                            reply => Some(reply),
+                        };
+                        let db = session.db;
+                        self.block_until(db, &keys, &keys[..1], timeout, permit, moved)
+                            .await
+                            .unwrap_or(Resp::Null)
+                    }
+                    command => {
+                        let written = self.written(&command, session.db);
+                        let access = self.access(&command);
+                        let mut keyspace = self.lock_scope(command.key_scope(), session.db).await;
+
+                        let response = self.handle_command(&mut keyspace, command);
+                        self.propagate(keyspace.take_propagated());
+                        self.track(access, Some(session));
+                        self.wake(written);
+                        response
+                    }
+                },
             },
+        };
+
+        self.latency.record(name, started.elapsed());
+        // CLIENT CACHING only applies to the command right after it.
+        if name != "client|caching" {
+            session.caching = None;
+        }
+
+        if let Some(command) = executed {
+            for hook in &self.hooks {
+                hook.after(&command, &mut response);
+            }
         }
+
+        response
     }
 
-    pub fn parse_set_command(args: Vec<Resp>) -> Command {
-        let mut args = args.iter();
-        let key = args.next().unwrap().to_string();
-        let value = args.next().unwrap().to_string();
+    /// What `command` writes when run in database `db`, so the clients blocked on it get to try
+    /// again once it ran: keys along with their database, or every key of a database for `None`.
+    fn written(&self, command: &Command, db: usize) -> Vec<(usize, Option<Bytes>)> {
+        let db = match command {
+            Command::Move { db, .. } | Command::Copy { db: Some(db), .. } => *db,
+            Command::SwapDb { first, second } => return vec![(*first, None), (*second, None)],
+            _ => db,
+        };
+        let blocked = self
+            .blocked
+            .get(db)
+            .is_some_and(|blocked| !blocked.is_empty());
+        if !command.is_write() || !blocked {
+            return Vec::new();
+        }
 
-        let mut options = Vec::new();
+        command
+            .key_specs()
+            .iter()
+            .map(|spec| (db, Some(Bytes::copy_from_slice(spec.key))))
+            .collect()
+    }
 
-        while let Some(arg) = args.next() {
-            match arg.to_string().as_str() {
-                "px" => {
-                    let value = args.next().unwrap().to_string();
-                    options.push(("px".to_string(), Some(value)));
-                }
-                _ => todo!("arg: {} not implemented", arg),
+    fn wake(&self, written: Vec<(usize, Option<Bytes>)>) {
+        for (db, key) in written {
+            let Some(blocked) = self.blocked.get(db) else {
+                continue;
+            };
+            match key {
+                Some(key) => blocked.wake(&key),
+                None => blocked.wake_all(),
             }
         }
+    }
 
-        Command::Set {
-            key,
-            value,
-            options,
+    /// Queues a command sent between MULTI and EXEC. What can be refused without running it is
+    /// refused right away, which also makes EXEC discard the transaction.
+    fn queue(&self, command: Command, session: &mut Session) -> Resp {
+        let refused = match command.is_write() && self.master_link.is_some() {
+            true if self.replica_read_only() => Some(CommandError::ReadOnlyReplica),
+            _ if command.is_subscription() => Some(CommandError::DenyBlocking(command.name())),
+            _ => self.cluster_redirect(&command, session),
+        };
+
+        let transaction = session.transaction.as_mut().unwrap();
+        match refused {
+            Some(error) => {
+                transaction.aborted = true;
+                error.into()
+            }
+            None => {
+                transaction.queued.push(command);
+                Resp::SimpleString("QUEUED".to_string())
+            }
         }
     }
 
-    pub fn handle_command(&mut self, command: Command) -> Resp {
+    /// The reply to a request that couldn't be parsed into a command. Like any other refused
+    /// command, it makes EXEC discard the transaction being queued.
+    pub fn reject(&self, error: CommandError, session: &mut Session) -> Resp {
+        if let Some(transaction) = &mut session.transaction {
+            transaction.aborted = true;
+        }
+        error.into()
+    }
+
+    /// Runs the commands queued since MULTI. Every shard is locked for the whole transaction, so
+    /// no other client sees the keyspace in between two of them, like Redis running them all in
+    /// one go.
+    // NOTE: Blocking commands don't wait inside a transaction, `handle_command` runs them like
+    //       their non blocking versions.
+    async fn exec(&self, session: &mut Session) -> Resp {
+        let Some(transaction) = session.transaction.take() else {
+            return CommandError::ExecWithoutMulti.into();
+        };
+        if transaction.aborted {
+            return CommandError::ExecAbort.into();
+        }
+
+        let mut keyspace = self.keyspace.lock_everything().await;
+        keyspace.select(session.db);
+        let mut replies = Vec::with_capacity(transaction.queued.len());
+        let mut written = Vec::new();
+        for command in transaction.queued {
+            written.extend(self.written(&command, keyspace.db()));
+            let reply = match command {
+                command if command.changes_session() => self.change_session(command, session),
+                command => {
+                    let access = self.access(&command);
+                    let reply = self.handle_command(&mut keyspace, command);
+                    self.track(access, Some(session));
+                    reply
+                }
+            };
+            replies.push(reply);
+        }
+        session.db = keyspace.db();
+
+        // Replicas and the append-only file get the writes of the transaction together as well.
+        let mut propagated = keyspace.take_propagated();
+        if propagated.len() > 1 {
+            let (first, last) = (propagated[0].0, propagated[propagated.len() - 1].0);
+            propagated.insert(0, (first, vec![Bytes::from("MULTI")]));
+            propagated.push((last, vec![Bytes::from("EXEC")]));
+        }
+        self.propagate(propagated);
+        self.wake(written);
+
+        Resp::Array(replies)
+    }
+
+    /// The SUBSCRIBE and UNSUBSCRIBE commands and their pattern versions, returning the
+    /// confirmation of every channel or pattern along with how many subscriptions the connection
+    /// has left after it.
+    pub fn subscribe(&self, command: Command, session: &mut Session) -> Vec<Resp> {
+        let subscriptions = session.subscriptions.get_or_insert_with(|| {
+            self.pubsub
+                .subscriptions(session.id, session.pushes.sender())
+        });
+        let confirm = |kind: &str, channel: Resp, count: usize| {
+            Resp::Push(vec![
+                Resp::BulkString(Bytes::copy_from_slice(kind.as_bytes())),
+                channel,
+                Resp::Integer(count as i64),
+            ])
+        };
+
         match command {
-            Command::Ping => Resp::SimpleString("PONG".to_string()),
-            Command::Echo { message } => Resp::BulkString(Bytes::from(message)),
-            Command::Set {
-                key,
-                value,
-                options,
-            } => self.set(key, value, options),
-            Command::Get { key } => self.get(key),
-            Command::ConfigGet { key } => {
-                if let Some(value) = self.config.get(&key) {
-                    Resp::Array(vec![
-                        Resp::BulkString(Bytes::from(key)),
-                        Resp::BulkString(Bytes::from(value.clone())),
-                    ])
+            Command::Subscribe { channels, pattern } => {
+                let kind = if pattern { "psubscribe" } else { "subscribe" };
+                channels
+                    .into_iter()
+                    .map(|channel| {
+                        match pattern {
+                            true => subscriptions.psubscribe(channel.clone()),
+                            false => subscriptions.subscribe(channel.clone()),
+                        };
+                        confirm(kind, Resp::BulkString(channel), subscriptions.count())
+                    })
+                    .collect()
+            }
+            Command::Unsubscribe {
+                mut channels,
+                pattern,
+            } => {
+                let kind = if pattern {
+                    "punsubscribe"
                 } else {
-                    Resp::Null
+                    "unsubscribe"
+                };
+                if channels.is_empty() {
+                    channels = match pattern {
+                        true => subscriptions.patterns().cloned().collect(),
+                        false => subscriptions.channels().cloned().collect(),
+                    };
                 }
-            }
-            Command::Keys { pattern: _ } => {
-                // TODO: Implement pattern matching
-                let mut keys = Vec::new();
-                for key in self.store.keys() {
-                    keys.push(Resp::BulkString(Bytes::from(key.clone())));
+                if channels.is_empty() {
+                    return vec![confirm(kind, Resp::Null, subscriptions.count())];
                 }
-                Resp::Array(keys)
+                channels
+                    .into_iter()
+                    .map(|channel| {
+                        match pattern {
+                            true => subscriptions.punsubscribe(&channel),
+                            false => subscriptions.unsubscribe(&channel),
+                        };
+                        confirm(kind, Resp::BulkString(channel), subscriptions.count())
+                    })
+                    .collect()
             }
-            Command::NotImplemented { cmd } => {
-                Resp::SimpleError(format!("ERR command '{}' not implemented yet", cmd))
+            _ => unreachable!("only subscriptions change what a connection is subscribed to"),
+        }
+    }
+
+    /// What `command` reads or writes, for the clients tracking keys. Nothing while no client has
+    /// tracking on.
+    fn access(&self, command: &Command) -> Option<Access> {
+        if !self.tracking.is_enabled() {
+            return None;
+        }
+        let keys = || {
+            command
+                .key_specs()
+                .iter()
+                .map(|spec| Bytes::copy_from_slice(spec.key))
+                .collect::<Vec<_>>()
+        };
+        match command {
+            Command::FlushDb | Command::FlushAll | Command::SwapDb { .. } => Some(Access::Flush),
+            command if command.is_write() => Some(Access::Write(keys())),
+            _ => Some(Access::Read(keys())),
+        }
+    }
+
+    /// Remembers the keys a tracking client read, or invalidates the keys that were written.
+    /// `session` is the client that ran the command, if any. Reads have to be remembered before
+    /// their shard locks are released, so no write slips in before the client is tracked.
+    fn track(&self, access: Option<Access>, session: Option<&Session>) {
+        match access {
+            Some(Access::Read(keys)) if !keys.is_empty() => {
+                let Some(session) = session else {
+                    return;
+                };
+                let tracks = session.tracking.as_ref();
+                if tracks.is_some_and(|options| options.tracks_reads(session.caching)) {
+                    self.tracking.remember(session.id, keys);
+                }
             }
+            Some(Access::Write(keys)) => self
+                .tracking
+                .invalidate(&keys, session.map(|session| session.id)),
+            Some(Access::Flush) => self.tracking.invalidate_all(),
+            _ => {}
         }
     }
 
-    fn set(&mut self, key: String, value: String, options: Vec<(String, Option<String>)>) -> Resp {
-        let mut expiry = None;
-        for (option, value) in options {
-            match option.as_str() {
-                "px" => {
-                    expiry = Some(value.unwrap().parse::<u64>().unwrap());
+    /// READONLY, READWRITE, REPLCONF and the CLIENT subcommands that only change the connection's
+    /// session.
+    fn change_session(&self, command: Command, session: &mut Session) -> Resp {
+        match command {
+            Command::ReadOnly | Command::ReadWrite if self.cluster.is_none() => {
+                CommandError::ClusterSupportDisabled.into()
+            }
+            Command::ReadOnly => {
+                session.readonly = true;
+                Resp::SimpleString("OK".to_string())
+            }
+            Command::ReadWrite => {
+                session.readonly = false;
+                Resp::SimpleString("OK".to_string())
+            }
+            Command::ReplConf { options } => Self::replconf(options, session),
+            Command::Hello {
+                protocol,
+                auth,
+                name,
+            } => self.hello(protocol, auth, name, session),
+            Command::ClientId => Resp::Integer(session.id as i64),
+            Command::ClientTracking { options: None } => {
+                session.tracking = None;
+                self.tracking.disable(session.id);
+                Resp::SimpleString("OK".to_string())
+            }
+            Command::ClientTracking {
+                options: Some(options),
+            } => match self.enable_tracking(options, session) {
+                Ok(()) => Resp::SimpleString("OK".to_string()),
+                Err(error) => error.into(),
+            },
+            Command::ClientCaching { yes } => {
+                let Some(options) = &session.tracking else {
+                    return CommandError::CachingWithoutTracking.into();
+                };
+                match yes {
+                    true if !options.optin => CommandError::CachingMode("YES", "OPTIN").into(),
+                    false if !options.optout => CommandError::CachingMode("NO", "OPTOUT").into(),
+                    _ => {
+                        session.caching = Some(yes);
+                        Resp::SimpleString("OK".to_string())
+                    }
                 }
-                _ => todo!("option: {} not implemented", option),
             }
+            Command::ClientGetRedir => match &session.tracking {
+                Some(options) => Resp::Integer(options.redirect.unwrap_or(0) as i64),
+                None => Resp::Integer(-1),
+            },
+            _ => unreachable!("only session commands change the session"),
         }
+    }
 
-        if let Some(expiry) = expiry {
-            let expiry = Self::ms_since_epoch() + expiry;
-            self.expiry_table.insert(key.clone(), expiry);
-        } else {
-            self.expiry_table.remove(&key);
+    /// Runs `attempt` with the shard locks of `keys` held until it has a reply, blocking on the
+    /// `watched` keys in between. Gives up with `None` once `timeout` passes, which commands reply
+    /// to differently.
+    // NOTE: A blocked client gives its in-flight permit back while it waits, so any number of them
+    //       can be blocked without holding up the clients that would push to their lists.
+    async fn block_until(
+        &self,
+        db: usize,
+        keys: &[Bytes],
+        watched: &[Bytes],
+        timeout: Option<Duration>,
+        permit: SemaphorePermit<'_>,
+        attempt: impl Fn(&mut KeyspaceGuard) -> Option<Resp>,
+    ) -> Option<Resp> {
+        let deadline = timeout.map(|timeout| tokio::time::Instant::now() + timeout);
+        let mut permit = Some(permit);
+        let mut keep_turn = false;
+
+        loop {
+            let mut blocked = {
+                let mut keyspace = self.keyspace.lock(db, keys).await;
+                if let Some(reply) = self.serve_blocked(&mut keyspace, keys, &attempt) {
+                    return Some(reply);
+                }
+                self.blocked[db].block(watched, keep_turn)
+            };
+            permit.take();
+
+            let woken = match deadline {
+                Some(deadline) => tokio::time::timeout_at(deadline, &mut blocked.woken)
+                    .await
+                    .is_ok(),
+                None => (&mut blocked.woken).await.is_ok(),
+            };
+            self.blocked[db].unblock(watched, blocked.id);
+            if woken {
+                keep_turn = true;
+                continue;
+            }
+
+            // A wake up that came in just as the timeout fired still deserves a last look.
+            if blocked.woken.try_recv().is_ok() {
+                let mut keyspace = self.keyspace.lock(db, keys).await;
+                return self.serve_blocked(&mut keyspace, keys, &attempt);
+            }
+            return None;
         }
+    }
 
-        self.store.insert(key, RedisValue::String(value));
-        Resp::SimpleString("OK".to_string())
+    /// Serves a blocked client if its keys are ready, then hands the turn to the next client blocked
+    /// on whatever is left.
+    fn serve_blocked(
+        &self,
+        keyspace: &mut KeyspaceGuard,
+        keys: &[Bytes],
+        attempt: &impl Fn(&mut KeyspaceGuard) -> Option<Resp>,
+    ) -> Option<Resp> {
+        let reply = attempt(keyspace)?;
+        for key in keys {
+            if keyspace.get(key).is_some() {
+                self.blocked[keyspace.db()].wake(key);
+            }
+        }
+        self.propagate(keyspace.take_propagated());
+        // NOTE: All of the keys are invalidated, not only the one something was popped from.
+        if self.tracking.is_enabled() {
+            self.tracking.invalidate(keys, None);
+        }
+        Some(reply)
     }
 
-    fn ms_since_epoch() -> u64 {
-        let since_the_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    /// HELLO, switching the connection to another protocol version, then describing the server
+    /// and the connection.
+    fn hello(
+        &self,
+        protocol: Option<Protocol>,
+        auth: Option<(Bytes, Bytes)>,
+        name: Option<Bytes>,
+        session: &mut Session,
+    ) -> Resp {
+        if let Some((username, password)) = auth {
+            // NOTE: There are no ACLs, only the default user with the password from requirepass.
+            let requirepass = &self.config.read().unwrap().requirepass;
+            let matches = requirepass.is_empty() || password == requirepass.as_bytes();
+            if &username[..] != b"default" || !matches {
+                return CommandError::WrongPass.into();
+            }
+        }
+        if let Some(protocol) = protocol {
+            session.protocol = protocol;
+            // The invalidations of a tracking client are shaped for the protocol it speaks.
+            if let Some(options) = &session.tracking {
+                let resp3 = protocol == Protocol::Resp3;
+                self.tracking
+                    .enable(session.id, options.clone(), session.pushes.sender(), resp3);
+            }
+        }
+        if let Some(name) = name {
+            session.name = Some(name);
+        }
 
-        since_the_epoch.as_secs() * 1000 + since_the_epoch.subsec_nanos() as u64 / 1_000_000
+        let field =
+            |name: &str, value: Resp| (Resp::BulkString(Bytes::from(name.to_string())), value);
+        let text = |value: &str| Resp::BulkString(Bytes::from(value.to_string()));
+        let proto = match session.protocol {
+            Protocol::Resp2 => 2,
+            Protocol::Resp3 => 3,
+        };
+        Resp::Map(vec![
+            field("server", text("redis")),
+            field("version", text(REDIS_VERSION)),
+            field("proto", Resp::Integer(proto)),
+            field("id", Resp::Integer(session.id as i64)),
+            field(
+                "mode",
+                text(if self.cluster.is_some() {
+                    "cluster"
+                } else {
+                    "standalone"
+                }),
+            ),
+            field(
+                "role",
+                text(if self.master_link.is_some() {
+                    "replica"
+                } else {
+                    "master"
+                }),
+            ),
+            field("modules", Resp::Array(vec![])),
+        ])
     }
 
-    fn get(&self, key: String) -> Resp {
-        if let Some(expiry) = self.expiry_table.get(&key) {
-            let time_now_in_ms = Self::ms_since_epoch();
+    /// CLIENT TRACKING ON. Calling it again while tracking adds BCAST prefixes, but can't switch
+    /// between modes.
+    fn enable_tracking(
+        &self,
+        mut options: TrackingOptions,
+        session: &mut Session,
+    ) -> Result<(), CommandError> {
+        if !options.bcast && !options.prefixes.is_empty() {
+            return Err(CommandError::PrefixWithoutBcast);
+        }
+        if let Some(current) = &session.tracking {
+            if current.bcast != options.bcast {
+                return Err(CommandError::TrackingModeSwitch("BCAST mode on/off"));
+            }
+        }
+        if options.bcast && (options.optin || options.optout) {
+            return Err(CommandError::OptInOptOutWithBcast);
+        }
+        if options.optin && options.optout {
+            return Err(CommandError::OptInAndOptOut);
+        }
+        if let Some(current) = &session.tracking {
+            if options.optin && current.optout || options.optout && current.optin {
+                return Err(CommandError::TrackingModeSwitch("OPTIN/OPTOUT mode"));
+            }
+        }
+        if let Some(redirect) = options.redirect {
+            // NOTE: Redirected invalidations are published like pub/sub messages, so unlike in
+            //       Redis the client has to be subscribed to them already.
+            if !self.tracking.can_redirect_to(redirect) {
+                return Err(CommandError::NoSuchRedirectClient);
+            }
+        }
+
+        let existing = session
+            .tracking
+            .as_ref()
+            .map(|current| current.prefixes.clone())
+            .unwrap_or_default();
+        for (index, prefix) in options.prefixes.iter().enumerate() {
+            let overlaps = |other: &Bytes| prefix.starts_with(other) || other.starts_with(prefix);
+            if let Some(other) = existing.iter().find(|other| overlaps(other)) {
+                return Err(CommandError::PrefixOverlap(
+                    String::from_utf8_lossy(prefix).to_string(),
+                    "an existing",
+                    String::from_utf8_lossy(other).to_string(),
+                ));
+            }
+            if let Some(other) = options.prefixes[index + 1..].iter().find(|o| overlaps(o)) {
+                return Err(CommandError::PrefixOverlap(
+                    String::from_utf8_lossy(prefix).to_string(),
+                    "another provided",
+                    String::from_utf8_lossy(other).to_string(),
+                ));
+            }
+        }
+        options.prefixes.splice(0..0, existing);
 
-            eprintln!("expiry: {}, time_now_in_ms: {}", expiry, time_now_in_ms);
+        let resp3 = session.protocol == Protocol::Resp3;
+        self.tracking
+            .enable(session.id, options.clone(), session.pushes.sender(), resp3);
+        session.tracking = Some(options);
+        Ok(())
+    }
 
-            if expiry < &time_now_in_ms {
-                return Resp::Null;
+    fn replconf(options: Vec<(String, String)>, session: &mut Session) -> Resp {
+        for (option, value) in options {
+            match option.as_str() {
+                "listening-port" => match value.parse::<u16>() {
+                    Ok(port) => session.replica_listening_port = Some(port),
+                    Err(_) => return CommandError::NotAnInteger.into(),
+                },
+                // Only the master side of the link uses these, see `replication::serve_replica`.
+                "capa" | "ack" | "getack" => {}
+                _ => return CommandError::UnrecognizedReplConfOption(option).into(),
             }
         }
 
-        match self.store.get(&key) {
-            Some(RedisValue::String(value)) => Resp::BulkString(Bytes::from(value.clone())),
-            None => Resp::Null,
+        Resp::SimpleString("OK".to_string())
+    }
+
+    /// In cluster mode, the error sending a command elsewhere when its keys are served by another
+    /// node. Replicas serve reads of their master's slots themselves for READONLY connections.
+    fn cluster_redirect(&self, command: &Command, session: &Session) -> Option<CommandError> {
+        let cluster = self.cluster.as_ref()?;
+
+        let specs = command.key_specs();
+        let slot = key_slot(specs.first()?.key);
+        if specs.iter().any(|spec| key_slot(spec.key) != slot) {
+            return Some(CommandError::CrossSlot);
         }
+
+        let read_only = specs.iter().all(|spec| spec.flags.contains(&"RO"));
+        cluster.redirect(slot, session.readonly && read_only)
     }
-}
 
-#[derive(Debug)]
+    async fn big_keys(&self, db: usize, count: usize) -> Resp {
+        let mut big_keys = BigKeys::new(count);
+        for index in Keyspace::database_shards(db) {
+            let keyspace = self.keyspace.lock_shard(index).await;
+            big_keys.scan(&keyspace, Self::ms_since_epoch());
+        }
+
+        big_keys.into()
+    }
+
+    /// Walks database `db` like SCAN, locking one shard at a time.
+    async fn scan(&self, db: usize, mut cursor: u64, options: &ScanOptions) -> Resp {
+        let mut batch = ScanBatch::new(options.count);
+        loop {
+            let keyspace = self
+                .keyspace
+                .lock_shard(Keyspace::cursor_shard(db, cursor))
+                .await;
+            cursor = Self::scan_shard(&keyspace, cursor, options, &mut batch);
+            if cursor == 0 || batch.is_full(options.count) {
+                break;
+            }
+        }
+
+        Self::scan_reply(cursor, batch.keys)
+    }
+
+    /// Takes SCAN steps through the shard `cursor` is in, adding the keys that pass the filters to
+    /// `batch` until it is full. Returns the cursor to continue from, which is in the next shard
+    /// once this one was walked to its end.
+    // NOTE: Like in Redis, COUNT is how many keys to look at, not how many to return. Keys that
+    //       don't match or have expired still count, so a batch can come back short or empty.
+    fn scan_shard(
+        keyspace: &KeyspaceGuard,
+        mut cursor: u64,
+        options: &ScanOptions,
+        batch: &mut ScanBatch,
+    ) -> u64 {
+        let now = Self::ms_since_epoch();
+        let shard = Keyspace::cursor_shard(keyspace.db(), cursor);
+        let mut steps = 0;
+        loop {
+            cursor = keyspace.scan_step(cursor, &mut |key, value| {
+                batch.visited += 1;
+                let expired = keyspace.expiry(key).is_some_and(|expiry| expiry < now);
+                let other_type = match &options.type_name {
+                    Some(type_name) => value.type_name() != type_name,
+                    None => false,
+                };
+                if !expired && !other_type && options.matches(key) {
+                    batch.keys.push(Resp::BulkString(key.clone()));
+                }
+            });
+            steps += 1;
+            if cursor == 0 || Keyspace::cursor_shard(keyspace.db(), cursor) != shard {
+                break;
+            }
+            if steps >= batch.steps_left || batch.visited >= options.count {
+                break;
+            }
+        }
+
+        batch.steps_left = batch.steps_left.saturating_sub(steps);
+        cursor
+    }
+
+    fn scan_reply(cursor: u64, elements: Vec<Resp>) -> Resp {
+        Resp::Array(vec![
+            Resp::BulkString(Bytes::from(cursor.to_string())),
+            Resp::Array(elements),
+        ])
+    }
+
+    /// HSCAN, SSCAN and ZSCAN. A collection small enough for a compact encoding is returned whole,
+    /// like Redis does. Otherwise the cursor is a CRC-64 of the element to continue from, elements
+    /// being walked in order of their checksum, so one present for the whole scan is returned
+    /// exactly once whatever changes in between.
+    // NOTE: Unlike the buckets of a dict, a std collection can't be walked from the middle, so
+    //       every step sorts the whole collection by checksum.
+    fn element_scan(
+        keyspace: &mut KeyspaceGuard,
+        scan: ElementScan,
+        key: Bytes,
+        cursor: u64,
+        options: ScanOptions,
+    ) -> Resp {
+        if Self::live_value(keyspace, &key).is_none() {
+            return Self::scan_reply(0, Vec::new());
+        }
+        let compact = matches!(
+            keyspace.object(&key).map(|object| object.encoding),
+            Some(Encoding::Listpack | Encoding::Intset)
+        );
+
+        let elements = match (scan, keyspace.get(&key).unwrap()) {
+            (ElementScan::Hash, RedisValue::Hash(hash)) => hash
+                .iter()
+                .map(|(field, value)| {
+                    let value = Resp::BulkString(value.clone());
+                    (field, (!options.no_values).then_some(value))
+                })
+                .collect::<Vec<_>>(),
+            (ElementScan::Set, RedisValue::Set(set)) => {
+                set.iter().map(|member| (member, None)).collect()
+            }
+            (ElementScan::SortedSet, RedisValue::SortedSet(zset)) => zset
+                .iter()
+                .map(|(member, score)| (member, Some(Resp::Double(score))))
+                .collect(),
+            _ => return CommandError::WrongType.into(),
+        };
+
+        let (elements, cursor) = match compact {
+            true => (elements, 0),
+            false => {
+                let mut elements = elements
+                    .into_iter()
+                    .map(|element| (crc64(0, element.0), element))
+                    .filter(|(checksum, _)| *checksum >= cursor)
+                    .collect::<Vec<_>>();
+                elements.sort_unstable_by_key(|(checksum, _)| *checksum);
+
+                // Elements sharing a checksum can't be told apart by a cursor, so they go together.
+                let mut end = options.count.min(elements.len());
+                while end < elements.len() && elements[end].0 == elements[end - 1].0 {
+                    end += 1;
+                }
+                let next = elements.get(end).map_or(0, |(checksum, _)| *checksum);
+                elements.truncate(end);
+                (
+                    elements.into_iter().map(|(_, element)| element).collect(),
+                    next,
+                )
+            }
+        };
+
+        let mut reply = Vec::new();
+        for (element, value) in elements {
+            if options.matches(element) {
+                reply.push(Resp::BulkString(element.clone()));
+                reply.extend(value);
+            }
+        }
+        Self::scan_reply(cursor, reply)
+    }
+
+    async fn memory_stats(&self) -> Resp {
+        let (mut keys, mut dataset) = (0, 0);
+        for index in 0..self.keyspace.shard_count() {
+            let keyspace = self.keyspace.lock_shard(index).await;
+            let (shard_keys, shard_dataset) = Self::dataset_size(&keyspace);
+            keys += shard_keys;
+            dataset += shard_dataset;
+        }
+
+        self.memory_stats_reply(keys, dataset)
+    }
+
+    /// The number of keys in the locked shards and an estimate of the memory they take up.
+    fn dataset_size(keyspace: &KeyspaceGuard) -> (usize, usize) {
+        let mut dataset = (0, 0);
+        for key in keyspace.keys() {
+            if let Some(value) = keyspace.get(key) {
+                dataset.0 += 1;
+                dataset.1 += bigkeys::memory_usage(key, value);
+            }
+        }
+        dataset
+    }
+
+    fn memory_stats_reply(&self, keys: usize, dataset: usize) -> Resp {
+        let stats = AllocatorStats::current();
+        let field =
+            |name: &str, value: Resp| (Resp::BulkString(Bytes::from(name.to_string())), value);
+        let percentage = |part: usize, whole: usize| match whole {
+            0 => 0.0,
+            whole => part as f64 * 100.0 / whole as f64,
+        };
+
+        // NOTE: Like Redis, what was allocated at startup isn't counted as part of the dataset.
+        let net_allocated = stats.allocated.saturating_sub(self.startup_allocated);
+        let mut reply = vec![
+            field("peak.allocated", Resp::Integer(stats.peak as i64)),
+            field("total.allocated", Resp::Integer(stats.allocated as i64)),
+            field(
+                "startup.allocated",
+                Resp::Integer(self.startup_allocated as i64),
+            ),
+            field(
+                "overhead.total",
+                Resp::Integer(stats.allocated.saturating_sub(dataset) as i64),
+            ),
+            field("keys.count", Resp::Integer(keys as i64)),
+            field(
+                "keys.bytes-per-key",
+                Resp::Integer(net_allocated.checked_div(keys).unwrap_or(0) as i64),
+            ),
+            field("dataset.bytes", Resp::Integer(dataset as i64)),
+            field(
+                "dataset.percentage",
+                Resp::Double(percentage(dataset, net_allocated)),
+            ),
+            field(
+                "peak.percentage",
+                Resp::Double(percentage(stats.allocated, stats.peak)),
+            ),
+            field("allocator.allocated", Resp::Integer(stats.allocated as i64)),
+        ];
+
+        if let (Some(resident), Some(fragmentation)) = (stats.resident, stats.fragmentation()) {
+            reply.push(field("allocator.resident", Resp::Integer(resident as i64)));
+            reply.push(field("fragmentation", Resp::Double(fragmentation)));
+            reply.push(field(
+                "fragmentation.bytes",
+                Resp::Integer(resident as i64 - stats.allocated as i64),
+            ));
+        }
+
+        Resp::Map(reply)
+    }
+
+    /// CONFIG SET. Every parameter is validated before any of them changes, then the changes that
+    /// take more than the new value to apply are made.
+    fn config_set(&self, keyspace: &KeyspaceGuard, parameters: Vec<(String, String)>) -> Resp {
+        let mut config = self.config.write().unwrap();
+        let mut updated = config.clone();
+        for (i, (name, value)) in parameters.iter().enumerate() {
+            let result = match Config::is_mutable(name) {
+                None => return CommandError::UnknownConfigParameter(name.clone()).into(),
+                Some(false) => Err(ConfigError::Immutable),
+                Some(true) if parameters[..i].iter().any(|(other, _)| other == name) => {
+                    Err(ConfigError::Duplicate)
+                }
+                Some(true) => updated.set(name, value),
+            };
+            if let Err(error) = result {
+                return CommandError::ConfigSet(name.clone(), error).into();
+            }
+        }
+
+        if let Err(error) = self.reconfigure_persistence(keyspace, &config, &updated) {
+            eprintln!("failed to switch persistence: {}", error);
+            return CommandError::ConfigSet("appendonly".to_string(), ConfigError::AofFailed)
+                .into();
+        }
+        *config = updated;
+        Resp::SimpleString("OK".to_string())
+    }
+
+    /// Switches to the persistence engine `updated` picks, when that isn't the one `current`
+    /// picked. A new append-only file starts out as a rewrite of the dataset, like in Redis.
+    fn reconfigure_persistence(
+        &self,
+        keyspace: &KeyspaceGuard,
+        current: &Config,
+        updated: &Config,
+    ) -> Result<(), PersistenceError> {
+        let rdb_changed = current.save.is_empty() != updated.save.is_empty()
+            || current.dbfilename != updated.dbfilename;
+        let changed =
+            current.appendonly != updated.appendonly || (!updated.appendonly && rdb_changed);
+        if self.custom_persistence || !changed {
+            return Ok(());
+        }
+
+        let persistence = Self::persistence_from_config(updated).map(Arc::<dyn Persistence>::from);
+        if let Some(aof) = persistence.as_ref().filter(|_| updated.appendonly) {
+            aof.snapshot(&keyspace.snapshot())?;
+        }
+        // The new engine didn't see the SELECT of the writes before.
+        self.forget_propagated_db();
+        *self.persistence.write().unwrap() = persistence;
+        Ok(())
+    }
+
+    /// Writes a snapshot of the keyspace on another thread. Values are shared with the snapshot, so
+    /// writes carrying on meanwhile only copy the values they change.
+    fn background_save(&self, keyspace: &KeyspaceGuard) -> Resp {
+        let Some(persistence) = self.persistence() else {
+            return CommandError::PersistenceDisabled.into();
+        };
+
+        let status = self.background_save.clone();
+        if status.in_progress.swap(true, Ordering::SeqCst) {
+            return CommandError::BackgroundSaveInProgress.into();
+        }
+
+        if let Err(error) = persistence.prepare_snapshot() {
+            status.in_progress.store(false, Ordering::SeqCst);
+            return CommandError::SaveFailed(error).into();
+        }
+        // Writes from now on go to a new file, which doesn't know which database was selected.
+        self.forget_propagated_db();
+
+        let snapshot = keyspace.snapshot();
+        let dirty = status.dirty.load(Ordering::SeqCst);
+        status
+            .last_attempt
+            .store(Self::ms_since_epoch() / 1000, Ordering::SeqCst);
+        std::thread::spawn(move || {
+            let result = persistence.snapshot(&snapshot);
+            match &result {
+                Ok(()) => status.saved(dirty),
+                Err(error) => eprintln!("background save failed: {}", error),
+            }
+            status.failed.store(result.is_err(), Ordering::SeqCst);
+            status.in_progress.store(false, Ordering::SeqCst);
+        });
+
+        Resp::SimpleString("Background saving started".to_string())
+    }
+
+    /// Starts a BGSAVE once a save point is reached: `changes` writes or more, and more than
+    /// `seconds` since the last successful save. Only the RDB file the configuration picks is
+    /// saved this way.
+    pub async fn save_point_cycle(&self) {
+        let save = {
+            let config = self.config.read().unwrap();
+            if config.appendonly || self.custom_persistence {
+                return;
+            }
+            config.save.clone()
+        };
+        let status = &self.background_save;
+        let now = Self::ms_since_epoch() / 1000;
+        let dirty = status.dirty.load(Ordering::SeqCst);
+        let since_save = now.saturating_sub(status.last_save.load(Ordering::SeqCst));
+        let reached = save
+            .iter()
+            .any(|&(seconds, changes)| dirty >= changes && since_save > seconds);
+        let since_attempt = now.saturating_sub(status.last_attempt.load(Ordering::SeqCst));
+        let retry = !status.failed.load(Ordering::SeqCst) || since_attempt > BGSAVE_RETRY_DELAY;
+        if !reached || !retry || status.in_progress.load(Ordering::SeqCst) {
+            return;
+        }
+
+        let keyspace = self.keyspace.lock_everything().await;
+        if self.persistence().is_some() {
+            self.background_save(&keyspace);
+        }
+    }
+
+    /// INFO, with the sections this server keeps track of.
+    fn info(&self, sections: &[String]) -> Resp {
+        let everything = sections.is_empty()
+            || sections
+                .iter()
+                .any(|section| matches!(section.as_str(), "all" | "default" | "everything"));
+        let wanted = |section: &str| everything || sections.iter().any(|s| s == section);
+        let flag = |set: bool| if set { 1 } else { 0 };
+
+        let mut info = Vec::new();
+        if wanted("server") {
+            info.push("# Server".to_string());
+            info.push(format!("redis_version:{}", REDIS_VERSION));
+            let mode = match self.cluster {
+                Some(_) => "cluster",
+                None => "standalone",
+            };
+            info.push(format!("redis_mode:{}", mode));
+            info.push(format!("process_id:{}", std::process::id()));
+            info.push(format!("tcp_port:{}", self.config.read().unwrap().port));
+            info.push(String::new());
+        }
+        if wanted("memory") {
+            let stats = AllocatorStats::current();
+            info.push("# Memory".to_string());
+            info.push(format!("used_memory:{}", stats.allocated));
+            info.push(format!("used_memory_peak:{}", stats.peak));
+            info.push(format!("used_memory_startup:{}", self.startup_allocated));
+            if let (Some(resident), Some(fragmentation)) = (stats.resident, stats.fragmentation()) {
+                info.push(format!("used_memory_rss:{}", resident));
+                info.push(format!("mem_fragmentation_ratio:{:.2}", fragmentation));
+            }
+            info.push(String::new());
+        }
+        if wanted("persistence") {
+            let status = &self.background_save;
+            let aof_enabled = self.config.read().unwrap().appendonly;
+            let persistence = self.persistence();
+            info.push("# Persistence".to_string());
+            info.push("loading:0".to_string());
+            info.push(format!(
+                "persistence_enabled:{}",
+                flag(persistence.is_some())
+            ));
+            info.push(format!(
+                "rdb_changes_since_last_save:{}",
+                status.dirty.load(Ordering::SeqCst)
+            ));
+            info.push(format!(
+                "rdb_bgsave_in_progress:{}",
+                flag(status.in_progress.load(Ordering::SeqCst))
+            ));
+            info.push(format!(
+                "rdb_last_bgsave_status:{}",
+                if status.failed.load(Ordering::SeqCst) {
+                    "err"
+                } else {
+                    "ok"
+                }
+            ));
+            info.push(format!(
+                "rdb_last_save_time:{}",
+                status.last_save.load(Ordering::SeqCst)
+            ));
+            info.push(format!(
+                "aof_enabled:{}",
+                flag(aof_enabled && persistence.is_some())
+            ));
+            info.push(String::new());
+        }
+        if wanted("replication") {
+            info.push("# Replication".to_string());
+            match &self.master_link {
+                Some(link) => info.extend(link.info()),
+                None => info.extend(self.replicas.info()),
+            }
+            info.push(String::new());
+        }
+
+        Resp::BulkString(Bytes::from(info.join("\r\n")))
+    }
+
+    /// Hands the writes a command made to the persistence engine and the replicas, selecting the
+    /// database each was made in first whenever it changes, like Redis does.
+    fn propagate(&self, commands: Vec<(usize, Vec<Bytes>)>) {
+        if commands.is_empty() {
+            return;
+        }
+
+        self.background_save
+            .dirty
+            .fetch_add(commands.len() as u64, Ordering::SeqCst);
+
+        // NOTE: Held until the writes are handed over, so a write is never sent after another
+        //       command's SELECT.
+        let mut propagated_db = self.propagated_db.lock().unwrap();
+        let mut stream = Vec::with_capacity(commands.len());
+        for (db, command) in commands {
+            if *propagated_db != Some(db) {
+                stream.push(vec![Bytes::from("SELECT"), Bytes::from(db.to_string())]);
+                *propagated_db = Some(db);
+            }
+            stream.push(command);
+        }
+
+        self.replicas.propagate(&stream);
+
+        let Some(persistence) = self.persistence() else {
+            return;
+        };
+
+        for command in stream {
+            if let Err(error) = persistence.append(&command) {
+                eprintln!("failed to persist write: {}", error);
+            }
+        }
+    }
+
+    /// Makes the next write select its database, for when writes start going somewhere that
+    /// didn't see the SELECT of the ones before.
+    fn forget_propagated_db(&self) {
+        *self.propagated_db.lock().unwrap() = None;
+    }
+
+    /// Checks the database a client wants to SELECT, which in cluster mode can only be 0.
+    fn check_select(&self, db: usize) -> Result<(), CommandError> {
+        if self.cluster.is_some() && db != 0 {
+            return Err(CommandError::NotAllowedInClusterMode("SELECT"));
+        }
+        match db < self.keyspace.databases() {
+            true => Ok(()),
+            false => Err(CommandError::DbIndexOutOfRange),
+        }
+    }
+
+    pub fn parse_command(command: Resp, args: Vec<Resp>) -> Result<Command, CommandError> {
+        let name = command.to_string();
+        Self::check_arity(&name, &args)?;
+        let command = name.to_lowercase();
+
+        let command = match command.as_str() {
+            "ping" => Command::Ping,
+            "save" => {
+                Self::exact_args::<0>(&command, &args)?;
+                Command::Save
+            }
+            "bgsave" => {
+                // NOTE: SCHEDULE is accepted for compatibility, a save never has to wait for a
+                //       rewrite here.
+                match args.as_slice() {
+                    [] => {}
+                    [option] if option.to_string().to_lowercase() == "schedule" => {}
+                    _ => return Err(CommandError::Syntax),
+                }
+                Command::BgSave
+            }
+            "lastsave" => {
+                Self::exact_args::<0>(&command, &args)?;
+                Command::LastSave
+            }
+            "info" => Command::Info {
+                sections: args
+                    .iter()
+                    .map(|section| section.to_string().to_lowercase())
+                    .collect(),
+            },
+            "echo" => {
+                let [message] = Self::exact_args(&command, &args)?;
+                Command::Echo {
+                    message: message.to_bytes(),
+                }
+            }
+            "set" => Self::parse_set_command(args)?,
+            "get" => {
+                let [key] = Self::exact_args(&command, &args)?;
+                Command::Get {
+                    key: key.to_bytes(),
+                }
+            }
+            "config" => {
+                let subcommand = args
+                    .first()
+                    .ok_or_else(|| CommandError::WrongArity(command.clone()))?
+                    .to_string()
+                    .to_lowercase();
+                match subcommand.as_str() {
+                    "get" => {
+                        let [_, key] = Self::exact_args("config|get", &args)?;
+                        Command::ConfigGet {
+                            key: key.to_string(),
+                        }
+                    }
+                    "rewrite" => {
+                        Self::exact_args::<1>("config|rewrite", &args)?;
+                        Command::ConfigRewrite
+                    }
+                    "set" => {
+                        let pairs = args[1..].chunks_exact(2);
+                        if !pairs.remainder().is_empty() {
+                            return Err(CommandError::WrongArity("config|set".to_string()));
+                        }
+                        let parameters = pairs
+                            .map(|pair| (pair[0].to_string().to_lowercase(), pair[1].to_string()))
+                            .collect();
+                        Command::ConfigSet { parameters }
+                    }
+                    _ => return Err(CommandError::UnknownSubcommand(command, subcommand)),
+                }
+            }
+            "dbsize" => {
+                Self::exact_args::<0>(&command, &args)?;
+                Command::DbSize
+            }
+            "multi" => {
+                Self::exact_args::<0>(&command, &args)?;
+                Command::Multi
+            }
+            "exec" => {
+                Self::exact_args::<0>(&command, &args)?;
+                Command::Exec
+            }
+            "discard" => {
+                Self::exact_args::<0>(&command, &args)?;
+                Command::Discard
+            }
+            "subscribe" | "psubscribe" => Command::Subscribe {
+                channels: args.iter().map(Resp::to_bytes).collect(),
+                pattern: command == "psubscribe",
+            },
+            "unsubscribe" | "punsubscribe" => Command::Unsubscribe {
+                channels: args.iter().map(Resp::to_bytes).collect(),
+                pattern: command == "punsubscribe",
+            },
+            "pubsub" => {
+                let subcommand = args
+                    .first()
+                    .ok_or_else(|| CommandError::WrongArity(command.clone()))?
+                    .to_string()
+                    .to_lowercase();
+                match subcommand.as_str() {
+                    "channels" => match &args[1..] {
+                        [] => Command::PubSubChannels { pattern: None },
+                        [pattern] => Command::PubSubChannels {
+                            pattern: Some(pattern.to_bytes()),
+                        },
+                        _ => return Err(CommandError::WrongArity("pubsub|channels".to_string())),
+                    },
+                    "numsub" => Command::PubSubNumSub {
+                        channels: args[1..].iter().map(Resp::to_bytes).collect(),
+                    },
+                    "numpat" => {
+                        Self::exact_args::<1>("pubsub|numpat", &args)?;
+                        Command::PubSubNumPat
+                    }
+                    _ => return Err(CommandError::UnknownSubcommand(command, subcommand)),
+                }
+            }
+            "publish" => {
+                let [channel, message] = Self::exact_args(&command, &args)?;
+                Command::Publish {
+                    channel: channel.to_bytes(),
+                    message: message.to_bytes(),
+                }
+            }
+            "select" => {
+                let [db] = Self::exact_args(&command, &args)?;
+                Command::Select {
+                    db: Self::parse_db_index(db, CommandError::InvalidDbIndex(""))?,
+                }
+            }
+            "swapdb" => {
+                let [first, second] = Self::exact_args(&command, &args)?;
+                Command::SwapDb {
+                    first: Self::parse_db_index(first, CommandError::InvalidDbIndex("first "))?,
+                    second: Self::parse_db_index(second, CommandError::InvalidDbIndex("second "))?,
+                }
+            }
+            "move" => {
+                let [key, db] = Self::exact_args(&command, &args)?;
+                Command::Move {
+                    key: key.to_bytes(),
+                    db: Self::parse_db_index(db, CommandError::NotAnInteger)?,
+                }
+            }
+            // NOTE: ASYNC is accepted for compatibility, the keys are always dropped right away.
+            "flushdb" | "flushall" => {
+                match args.as_slice() {
+                    [] => {}
+                    [mode]
+                        if matches!(mode.to_string().to_lowercase().as_str(), "async" | "sync") => {
+                    }
+                    _ => return Err(CommandError::Syntax),
+                }
+                match command.as_str() {
+                    "flushdb" => Command::FlushDb,
+                    _ => Command::FlushAll,
+                }
+            }
+            "randomkey" => {
+                Self::exact_args::<0>(&command, &args)?;
+                Command::RandomKey
+            }
+            "keys" => {
+                let [pattern] = Self::exact_args(&command, &args)?;
+                Command::Keys {
+                    pattern: pattern.to_string(),
+                }
+            }
+            "scan" => {
+                let cursor = args
+                    .first()
+                    .ok_or_else(|| CommandError::WrongArity(command.clone()))?;
+                Command::Scan {
+                    cursor: Self::parse_cursor(cursor)?,
+                    options: Self::parse_scan_options(&command, &args[1..])?,
+                }
+            }
+            "hscan" | "sscan" | "zscan" => {
+                let [key, cursor, options @ ..] = args.as_slice() else {
+                    return Err(CommandError::WrongArity(command));
+                };
+                Command::ElementScan {
+                    scan: match command.as_str() {
+                        "hscan" => ElementScan::Hash,
+                        "sscan" => ElementScan::Set,
+                        _ => ElementScan::SortedSet,
+                    },
+                    key: key.to_bytes(),
+                    cursor: Self::parse_cursor(cursor)?,
+                    options: Self::parse_scan_options(&command, options)?,
+                }
+            }
+            "rename" => {
+                let [source, destination] = Self::exact_args(&command, &args)?;
+                Command::Rename {
+                    source: source.to_bytes(),
+                    destination: destination.to_bytes(),
+                }
+            }
+            "copy" => Self::parse_copy_command(args)?,
+            "del" | "unlink" => Command::Del {
+                keys: args.iter().map(|key| key.to_bytes()).collect(),
+                unlink: command == "unlink",
+            },
+            "mget" => Command::MGet {
+                keys: args.iter().map(|key| key.to_bytes()).collect(),
+            },
+            "mset" | "msetnx" => {
+                let pairs = args.chunks_exact(2);
+                if !pairs.remainder().is_empty() {
+                    return Err(CommandError::WrongArity(command));
+                }
+                Command::MSet {
+                    pairs: pairs
+                        .map(|pair| (pair[0].to_bytes(), pair[1].to_bytes()))
+                        .collect(),
+                    only_new: command == "msetnx",
+                }
+            }
+            "append" => {
+                let [key, value] = Self::exact_args(&command, &args)?;
+                Command::Append {
+                    key: key.to_bytes(),
+                    value: value.to_bytes(),
+                }
+            }
+            "type" => {
+                let [key] = Self::exact_args(&command, &args)?;
+                Command::Type {
+                    key: key.to_bytes(),
+                }
+            }
+            "strlen" => {
+                let [key] = Self::exact_args(&command, &args)?;
+                Command::Strlen {
+                    key: key.to_bytes(),
+                }
+            }
+            "getrange" => {
+                let [key, start, end] = Self::exact_args(&command, &args)?;
+                Command::GetRange {
+                    key: key.to_bytes(),
+                    start: Self::parse_integer(start)?,
+                    end: Self::parse_integer(end)?,
+                }
+            }
+            "setrange" => {
+                let [key, offset, value] = Self::exact_args(&command, &args)?;
+                let offset = Self::parse_integer(offset)?;
+                if offset < 0 {
+                    return Err(CommandError::OffsetOutOfRange);
+                }
+                Command::SetRange {
+                    key: key.to_bytes(),
+                    offset: offset as usize,
+                    value: value.to_bytes(),
+                }
+            }
+            "setbit" => {
+                let [key, offset, value] = Self::exact_args(&command, &args)?;
+                let value = match value.to_string().as_str() {
+                    "0" => false,
+                    "1" => true,
+                    _ => return Err(CommandError::BitNotAnInteger),
+                };
+                Command::SetBit {
+                    key: key.to_bytes(),
+                    offset: Self::parse_bit_offset(offset)?,
+                    value,
+                }
+            }
+            "getbit" => {
+                let [key, offset] = Self::exact_args(&command, &args)?;
+                Command::GetBit {
+                    key: key.to_bytes(),
+                    offset: Self::parse_bit_offset(offset)?,
+                }
+            }
+            "bitcount" => {
+                let Some((key, range)) = args.split_first() else {
+                    return Err(CommandError::WrongArity(command));
+                };
+                let range = match range {
+                    [] => None,
+                    [start, end] => Some((
+                        Self::parse_integer(start)?,
+                        Self::parse_integer(end)?,
+                        false,
+                    )),
+                    [start, end, unit] => Some((
+                        Self::parse_integer(start)?,
+                        Self::parse_integer(end)?,
+                        Self::parse_bit_unit(unit)?,
+                    )),
+                    _ => return Err(CommandError::Syntax),
+                };
+                Command::BitCount {
+                    key: key.to_bytes(),
+                    range,
+                }
+            }
+            "bitpos" => {
+                let [key, bit, range @ ..] = args.as_slice() else {
+                    return Err(CommandError::WrongArity(command));
+                };
+                let bit = match Self::parse_integer(bit)? {
+                    0 => false,
+                    1 => true,
+                    _ => return Err(CommandError::BitPosNotABit),
+                };
+                let (start, end, bits) = match range {
+                    [] => (None, None, false),
+                    [start] => (Some(Self::parse_integer(start)?), None, false),
+                    [start, end] => (
+                        Some(Self::parse_integer(start)?),
+                        Some(Self::parse_integer(end)?),
+                        false,
+                    ),
+                    [start, end, unit] => (
+                        Some(Self::parse_integer(start)?),
+                        Some(Self::parse_integer(end)?),
+                        Self::parse_bit_unit(unit)?,
+                    ),
+                    _ => return Err(CommandError::Syntax),
+                };
+                Command::BitPos {
+                    key: key.to_bytes(),
+                    bit,
+                    start,
+                    end,
+                    bits,
+                }
+            }
+            "bitop" => {
+                let [operation, destination, keys @ ..] = args.as_slice() else {
+                    return Err(CommandError::WrongArity(command));
+                };
+                if keys.is_empty() {
+                    return Err(CommandError::WrongArity(command));
+                }
+                let operation = match operation.to_string().to_lowercase().as_str() {
+                    "and" => BitOperation::And,
+                    "or" => BitOperation::Or,
+                    "xor" => BitOperation::Xor,
+                    "not" if keys.len() == 1 => BitOperation::Not,
+                    "not" => return Err(CommandError::BitOpNotSingleKey),
+                    _ => return Err(CommandError::Syntax),
+                };
+                Command::BitOp {
+                    operation,
+                    destination: destination.to_bytes(),
+                    keys: keys.iter().map(|key| key.to_bytes()).collect(),
+                }
+            }
+            "incr" | "decr" => {
+                let [key] = Self::exact_args(&command, &args)?;
+                Command::IncrBy {
+                    key: key.to_bytes(),
+                    increment: None,
+                    decrement: command == "decr",
+                }
+            }
+            "incrby" | "decrby" => {
+                let [key, increment] = Self::exact_args(&command, &args)?;
+                Command::IncrBy {
+                    key: key.to_bytes(),
+                    increment: Some(Self::parse_integer(increment)?),
+                    decrement: command == "decrby",
+                }
+            }
+            "incrbyfloat" => {
+                let [key, increment] = Self::exact_args(&command, &args)?;
+                Command::IncrByFloat {
+                    key: key.to_bytes(),
+                    increment: parse_float(&increment.to_bytes()).ok_or(CommandError::NotAFloat)?,
+                }
+            }
+            "lpush" | "rpush" => Command::Push {
+                key: args[0].to_bytes(),
+                elements: args[1..].iter().map(|element| element.to_bytes()).collect(),
+                left: command == "lpush",
+            },
+            "lpop" | "rpop" => {
+                let (key, count) = match args.as_slice() {
+                    [key] => (key, None),
+                    [key, count] => {
+                        let count = Self::parse_integer(count)?;
+                        if count < 0 {
+                            return Err(CommandError::NotPositive);
+                        }
+                        (key, Some(count as usize))
+                    }
+                    _ => return Err(CommandError::WrongArity(command)),
+                };
+                Command::Pop {
+                    key: key.to_bytes(),
+                    count,
+                    left: command == "lpop",
+                }
+            }
+            "blpop" | "brpop" => {
+                let Some((timeout, keys)) = args.split_last().filter(|(_, keys)| !keys.is_empty())
+                else {
+                    return Err(CommandError::WrongArity(command));
+                };
+                Command::BlockingPop {
+                    keys: keys.iter().map(|key| key.to_bytes()).collect(),
+                    timeout: Self::parse_timeout(timeout)?,
+                    left: command == "blpop",
+                }
+            }
+            "lmove" | "blmove" => {
+                let blocking = command == "blmove";
+                let (source, destination, from, to, timeout) = match args.as_slice() {
+                    [source, destination, from, to] if !blocking => {
+                        (source, destination, from, to, None)
+                    }
+                    [source, destination, from, to, timeout] if blocking => {
+                        (source, destination, from, to, Some(timeout))
+                    }
+                    _ => return Err(CommandError::WrongArity(command)),
+                };
+                let side = |side: &Resp| match side.to_string().to_lowercase().as_str() {
+                    "left" => Ok(true),
+                    "right" => Ok(false),
+                    _ => Err(CommandError::Syntax),
+                };
+                let (source, destination) = (source.to_bytes(), destination.to_bytes());
+                let (from_left, to_left) = (side(from)?, side(to)?);
+
+                match timeout {
+                    Some(timeout) => Command::BlockingMove {
+                        source,
+                        destination,
+                        from_left,
+                        to_left,
+                        timeout: Self::parse_timeout(timeout)?,
+                    },
+                    None => Command::LMove {
+                        source,
+                        destination,
+                        from_left,
+                        to_left,
+                    },
+                }
+            }
+            "llen" => {
+                let [key] = Self::exact_args(&command, &args)?;
+                Command::LLen {
+                    key: key.to_bytes(),
+                }
+            }
+            "lrange" => {
+                let [key, start, stop] = Self::exact_args(&command, &args)?;
+                Command::LRange {
+                    key: key.to_bytes(),
+                    start: Self::parse_integer(start)?,
+                    stop: Self::parse_integer(stop)?,
+                }
+            }
+            "linsert" => {
+                let [key, position, pivot, element] = Self::exact_args(&command, &args)?;
+                let before = match position.to_string().to_lowercase().as_str() {
+                    "before" => true,
+                    "after" => false,
+                    _ => return Err(CommandError::Syntax),
+                };
+                Command::LInsert {
+                    key: key.to_bytes(),
+                    before,
+                    pivot: pivot.to_bytes(),
+                    element: element.to_bytes(),
+                }
+            }
+            "lset" => {
+                let [key, index, element] = Self::exact_args(&command, &args)?;
+                Command::LSet {
+                    key: key.to_bytes(),
+                    index: Self::parse_integer(index)?,
+                    element: element.to_bytes(),
+                }
+            }
+            "lrem" => {
+                let [key, count, element] = Self::exact_args(&command, &args)?;
+                Command::LRem {
+                    key: key.to_bytes(),
+                    count: Self::parse_integer(count)?,
+                    element: element.to_bytes(),
+                }
+            }
+            "ltrim" => {
+                let [key, start, stop] = Self::exact_args(&command, &args)?;
+                Command::LTrim {
+                    key: key.to_bytes(),
+                    start: Self::parse_integer(start)?,
+                    stop: Self::parse_integer(stop)?,
+                }
+            }
+            "hset" => {
+                let pairs = args.get(1..).unwrap_or_default().chunks_exact(2);
+                if pairs.len() == 0 || !pairs.remainder().is_empty() {
+                    return Err(CommandError::WrongArity(command));
+                }
+                Command::HSet {
+                    key: args[0].to_bytes(),
+                    pairs: pairs
+                        .map(|pair| (pair[0].to_bytes(), pair[1].to_bytes()))
+                        .collect(),
+                }
+            }
+            "hget" | "hexists" => {
+                let [key, field] = Self::exact_args(&command, &args)?;
+                let (key, field) = (key.to_bytes(), field.to_bytes());
+                match command.as_str() {
+                    "hget" => Command::HGet { key, field },
+                    _ => Command::HExists { key, field },
+                }
+            }
+            "hdel" => Command::HDel {
+                key: args[0].to_bytes(),
+                fields: args[1..].iter().map(|field| field.to_bytes()).collect(),
+            },
+            "hgetall" | "hlen" | "hkeys" | "hvals" => {
+                let [key] = Self::exact_args(&command, &args)?;
+                let key = key.to_bytes();
+                match command.as_str() {
+                    "hgetall" => Command::HGetAll { key },
+                    "hlen" => Command::HLen { key },
+                    "hkeys" => Command::HKeys { key },
+                    _ => Command::HVals { key },
+                }
+            }
+            "hmget" => Command::HMGet {
+                key: args[0].to_bytes(),
+                fields: args[1..].iter().map(|field| field.to_bytes()).collect(),
+            },
+            "hincrby" => {
+                let [key, field, increment] = Self::exact_args(&command, &args)?;
+                Command::HIncrBy {
+                    key: key.to_bytes(),
+                    field: field.to_bytes(),
+                    increment: Self::parse_integer(increment)?,
+                }
+            }
+            "hincrbyfloat" => {
+                let [key, field, increment] = Self::exact_args(&command, &args)?;
+                Command::HIncrByFloat {
+                    key: key.to_bytes(),
+                    field: field.to_bytes(),
+                    increment: parse_float(&increment.to_bytes()).ok_or(CommandError::NotAFloat)?,
+                }
+            }
+            "hrandfield" => {
+                let (key, count, with_values) = match args.as_slice() {
+                    [key] => (key, None, false),
+                    [key, count] => (key, Some(Self::parse_random_count(count)?), false),
+                    [key, count, option] if option.to_string().to_lowercase() == "withvalues" => {
+                        let count = Self::parse_random_count(count)?;
+                        // NOTE: Like in Redis, every pick replies with two elements.
+                        if count < -i64::MAX / 2 {
+                            return Err(CommandError::ValueOutOfRange);
+                        }
+                        (key, Some(count), true)
+                    }
+                    [_, _, _] => return Err(CommandError::Syntax),
+                    _ => return Err(CommandError::WrongArity(command)),
+                };
+                Command::HRandField {
+                    key: key.to_bytes(),
+                    count,
+                    with_values,
+                }
+            }
+            "sadd" | "srem" => {
+                let key = args[0].to_bytes();
+                let members = args[1..].iter().map(|member| member.to_bytes()).collect();
+                match command.as_str() {
+                    "sadd" => Command::SAdd { key, members },
+                    _ => Command::SRem { key, members },
+                }
+            }
+            "smembers" | "scard" => {
+                let [key] = Self::exact_args(&command, &args)?;
+                let key = key.to_bytes();
+                match command.as_str() {
+                    "smembers" => Command::SMembers { key },
+                    _ => Command::SCard { key },
+                }
+            }
+            "sinter" | "sunion" | "sdiff" | "sinterstore" | "sunionstore" | "sdiffstore" => {
+                let operation = match command.trim_end_matches("store") {
+                    "sinter" => SetOperation::Inter,
+                    "sunion" => SetOperation::Union,
+                    _ => SetOperation::Diff,
+                };
+                let (destination, keys) = match command.ends_with("store") {
+                    true if args.len() >= 2 => (Some(args[0].to_bytes()), &args[1..]),
+                    false if !args.is_empty() => (None, &args[..]),
+                    _ => return Err(CommandError::WrongArity(command)),
+                };
+                Command::SetOperation {
+                    operation,
+                    keys: keys.iter().map(|key| key.to_bytes()).collect(),
+                    destination,
+                }
+            }
+            "sintercard" => {
+                let Some((numkeys, args)) = args.split_first() else {
+                    return Err(CommandError::WrongArity(command));
+                };
+                let numkeys = Self::parse_integer(numkeys)?;
+                if numkeys <= 0 {
+                    return Err(CommandError::NumKeysNotPositive);
+                }
+                if numkeys as usize > args.len() {
+                    return Err(CommandError::TooManyKeys);
+                }
+                let (keys, options) = args.split_at(numkeys as usize);
+
+                let limit = match options {
+                    [] => 0,
+                    [option, limit] if option.to_string().to_lowercase() == "limit" => {
+                        usize::try_from(Self::parse_integer(limit)?)
+                            .map_err(|_| CommandError::NegativeLimit)?
+                    }
+                    _ => return Err(CommandError::Syntax),
+                };
+                Command::SInterCard {
+                    keys: keys.iter().map(|key| key.to_bytes()).collect(),
+                    limit,
+                }
+            }
+            "spop" => {
+                let (key, count) = match args.as_slice() {
+                    [key] => (key, None),
+                    [key, count] => {
+                        let count = Self::parse_integer(count)?;
+                        let count =
+                            usize::try_from(count).map_err(|_| CommandError::NotPositive)?;
+                        (key, Some(count))
+                    }
+                    _ => return Err(CommandError::WrongArity(command)),
+                };
+                Command::SPop {
+                    key: key.to_bytes(),
+                    count,
+                }
+            }
+            "srandmember" => {
+                let (key, count) = match args.as_slice() {
+                    [key] => (key, None),
+                    [key, count] => (key, Some(Self::parse_random_count(count)?)),
+                    _ => return Err(CommandError::WrongArity(command)),
+                };
+                Command::SRandMember {
+                    key: key.to_bytes(),
+                    count,
+                }
+            }
+            "smove" => {
+                let [source, destination, member] = Self::exact_args(&command, &args)?;
+                Command::SMove {
+                    source: source.to_bytes(),
+                    destination: destination.to_bytes(),
+                    member: member.to_bytes(),
+                }
+            }
+            "zadd" => Self::parse_zadd_command(args)?,
+            "zscore" => {
+                let [key, member] = Self::exact_args(&command, &args)?;
+                Command::ZScore {
+                    key: key.to_bytes(),
+                    member: member.to_bytes(),
+                }
+            }
+            "zrange" | "zrevrange" | "zrangebyscore" | "zrevrangebyscore" | "zrangebylex"
+            | "zrevrangebylex" => Self::parse_zrange_command(command, args)?,
+            "zrank" | "zrevrank" => {
+                let (key, member, with_score) = match args.as_slice() {
+                    [key, member] => (key, member, false),
+                    [key, member, option] if option.to_string().to_lowercase() == "withscore" => {
+                        (key, member, true)
+                    }
+                    [_, _, _] => return Err(CommandError::Syntax),
+                    _ => return Err(CommandError::WrongArity(command)),
+                };
+                Command::ZRank {
+                    key: key.to_bytes(),
+                    member: member.to_bytes(),
+                    rev: command == "zrevrank",
+                    with_score,
+                }
+            }
+            "zincrby" => {
+                let [key, increment, member] = Self::exact_args(&command, &args)?;
+                Command::ZIncrBy {
+                    key: key.to_bytes(),
+                    increment: parse_float(&increment.to_bytes()).ok_or(CommandError::NotAFloat)?,
+                    member: member.to_bytes(),
+                }
+            }
+            "zcard" => {
+                let [key] = Self::exact_args(&command, &args)?;
+                Command::ZCard {
+                    key: key.to_bytes(),
+                }
+            }
+            "zcount" => {
+                let [key, min, max] = Self::exact_args(&command, &args)?;
+                let (Some(min), Some(max)) = (
+                    ScoreBound::parse(&min.to_bytes()),
+                    ScoreBound::parse(&max.to_bytes()),
+                ) else {
+                    return Err(CommandError::ScoreRangeNotAFloat);
+                };
+                Command::ZCount {
+                    key: key.to_bytes(),
+                    min,
+                    max,
+                }
+            }
+            "zpopmin" | "zpopmax" => {
+                let (key, count) = match args.as_slice() {
+                    [key] => (key, None),
+                    [key, count] => {
+                        let count = Self::parse_integer(count)?;
+                        let count =
+                            usize::try_from(count).map_err(|_| CommandError::NotPositive)?;
+                        (key, Some(count))
+                    }
+                    _ => return Err(CommandError::WrongArity(command)),
+                };
+                Command::ZPop {
+                    key: key.to_bytes(),
+                    count,
+                    max: command == "zpopmax",
+                }
+            }
+            "bzpopmin" | "bzpopmax" => {
+                let Some((timeout, keys)) = args.split_last().filter(|(_, keys)| !keys.is_empty())
+                else {
+                    return Err(CommandError::WrongArity(command));
+                };
+                Command::BlockingZPop {
+                    keys: keys.iter().map(|key| key.to_bytes()).collect(),
+                    timeout: Self::parse_timeout(timeout)?,
+                    max: command == "bzpopmax",
+                }
+            }
+            "zrem" => Command::ZRem {
+                key: args[0].to_bytes(),
+                members: args[1..].iter().map(|member| member.to_bytes()).collect(),
+            },
+            "sismember" => {
+                let [key, member] = Self::exact_args(&command, &args)?;
+                Command::SIsMember {
+                    key: key.to_bytes(),
+                    member: member.to_bytes(),
+                }
+            }
+            "exists" => Command::Exists {
+                keys: args.iter().map(|key| key.to_bytes()).collect(),
+            },
+            "ttl" | "pttl" => {
+                let [key] = Self::exact_args(&command, &args)?;
+                Command::Ttl {
+                    key: key.to_bytes(),
+                    milliseconds: command == "pttl",
+                }
+            }
+            "expire" | "pexpire" | "expireat" | "pexpireat" => {
+                Self::parse_expire_command(&command, args)?
+            }
+            "dump" => {
+                let [key] = Self::exact_args(&command, &args)?;
+                Command::Dump {
+                    key: key.to_bytes(),
+                }
+            }
+            "restore" => Self::parse_restore_command(args)?,
+            "command" => {
+                let Some(subcommand) = args.first() else {
+                    return Ok(Command::CommandInfo { names: Vec::new() });
+                };
+                let subcommand = subcommand.to_string().to_lowercase();
+                let names = || {
+                    args[1..]
+                        .iter()
+                        .map(|name| name.to_string().to_lowercase())
+                        .collect()
+                };
+                match subcommand.as_str() {
+                    "count" => {
+                        Self::exact_args::<1>("command|count", &args)?;
+                        Command::CommandCount
+                    }
+                    "info" => Command::CommandInfo { names: names() },
+                    "docs" => Command::CommandDocs { names: names() },
+                    "getkeys" | "getkeysandflags" => {
+                        let mut command_line = args.into_iter().skip(1);
+                        let Some(name) = command_line.next() else {
+                            return Err(CommandError::WrongArity(format!(
+                                "command|{}",
+                                subcommand
+                            )));
+                        };
+                        let command = match Redis::parse_command(name, command_line.collect()) {
+                            Err(CommandError::UnknownCommand(..)) => {
+                                return Err(CommandError::InvalidCommandSpecified)
+                            }
+                            Ok(command) => command,
+                            Err(CommandError::WrongArity(_)) => {
+                                return Err(CommandError::InvalidCommandArguments)
+                            }
+                            Err(error) => return Err(error),
+                        };
+
+                        Command::GetKeys {
+                            command: Box::new(command),
+                            with_flags: subcommand == "getkeysandflags",
+                        }
+                    }
+                    _ => return Err(CommandError::UnknownSubcommand(command, subcommand)),
+                }
+            }
+            "latency" => {
+                let subcommand = args
+                    .first()
+                    .ok_or_else(|| CommandError::WrongArity(command.clone()))?
+                    .to_string()
+                    .to_lowercase();
+                match subcommand.as_str() {
+                    "histogram" => Command::LatencyHistogram {
+                        commands: args[1..]
+                            .iter()
+                            .map(|name| name.to_string().to_lowercase())
+                            .collect(),
+                    },
+                    _ => return Err(CommandError::UnknownSubcommand(command, subcommand)),
+                }
+            }
+            "object" => {
+                let subcommand = args
+                    .first()
+                    .ok_or_else(|| CommandError::WrongArity(command.clone()))?
+                    .to_string()
+                    .to_lowercase();
+                let object = match subcommand.as_str() {
+                    "encoding" => ObjectSubcommand::Encoding,
+                    "refcount" => ObjectSubcommand::RefCount,
+                    "idletime" => ObjectSubcommand::IdleTime,
+                    "freq" => ObjectSubcommand::Freq,
+                    _ => return Err(CommandError::UnknownSubcommand(command, subcommand)),
+                };
+                let [_, key] = Self::exact_args(&format!("object|{}", subcommand), &args)?;
+                Command::Object {
+                    subcommand: object,
+                    key: key.to_bytes(),
+                }
+            }
+            "debug" => {
+                let subcommand = args
+                    .first()
+                    .ok_or_else(|| CommandError::WrongArity(command.clone()))?
+                    .to_string()
+                    .to_lowercase();
+                match subcommand.as_str() {
+                    "populate" => Self::parse_debug_populate_command(&args[1..])?,
+                    _ => return Err(CommandError::UnknownSubcommand(command, subcommand)),
+                }
+            }
+            "cluster" => {
+                let subcommand = args
+                    .first()
+                    .ok_or_else(|| CommandError::WrongArity(command.clone()))?
+                    .to_string()
+                    .to_lowercase();
+                match subcommand.as_str() {
+                    "nodes" => {
+                        Self::exact_args::<1>("cluster|nodes", &args)?;
+                        Command::ClusterNodes
+                    }
+                    "myid" => {
+                        Self::exact_args::<1>("cluster|myid", &args)?;
+                        Command::ClusterMyId
+                    }
+                    "addslots" | "delslots" | "addslotsrange" | "delslotsrange" => {
+                        Self::parse_cluster_slots_command(&subcommand, &args[1..])?
+                    }
+                    "meet" => Self::parse_cluster_meet_command(&args[1..])?,
+                    "forget" => {
+                        let [_, id] = Self::exact_args::<2>("cluster|forget", &args)?;
+                        Command::ClusterForget { id: id.to_string() }
+                    }
+                    _ => return Err(CommandError::UnknownSubcommand(command, subcommand)),
+                }
+            }
+            "client" => Self::parse_client_command(args)?,
+            "hello" => Self::parse_hello_command(args)?,
+            "readonly" => {
+                Self::exact_args::<0>(&command, &args)?;
+                Command::ReadOnly
+            }
+            "readwrite" => {
+                Self::exact_args::<0>(&command, &args)?;
+                Command::ReadWrite
+            }
+            "replconf" => {
+                if args.is_empty() {
+                    return Err(CommandError::WrongArity(command));
+                }
+                let pairs = args.chunks_exact(2);
+                if !pairs.remainder().is_empty() {
+                    return Err(CommandError::Syntax);
+                }
+                Command::ReplConf {
+                    options: pairs
+                        .map(|pair| (pair[0].to_string().to_lowercase(), pair[1].to_string()))
+                        .collect(),
+                }
+            }
+            "psync" => {
+                let [replid, offset] = Self::exact_args(&command, &args)?;
+                Command::Psync {
+                    replid: replid.to_string(),
+                    offset: Self::parse_integer(offset)?,
+                }
+            }
+            "memory" => {
+                let subcommand = args
+                    .first()
+                    .ok_or_else(|| CommandError::WrongArity(command.clone()))?
+                    .to_string()
+                    .to_lowercase();
+                match subcommand.as_str() {
+                    "bigkeys" => Self::parse_memory_bigkeys_command(&args[1..])?,
+                    "stats" => {
+                        Self::exact_args::<0>("memory|stats", &args[1..])?;
+                        Command::MemoryStats
+                    }
+                    _ => return Err(CommandError::UnknownSubcommand(command, subcommand)),
+                }
+            }
+            _ => return Err(CommandError::unknown_command(&name, &args)),
+        };
+
+        Ok(command)
+    }
+
+    /// Checks the number of arguments of a command, named the way the client sent it, and of its
+    /// subcommand, against the command table. The parsers can count on having at least as many
+    /// arguments as the table asks for.
+    fn check_arity(name: &str, args: &[Resp]) -> Result<(), CommandError> {
+        let Some(info) = commands::lookup(&name.to_lowercase()) else {
+            return Err(CommandError::unknown_command(name, args));
+        };
+        if !info.accepts(args.len() + 1) {
+            return Err(CommandError::WrongArity(info.name.to_string()));
+        }
+
+        let subcommand = match args.first() {
+            Some(subcommand) if !info.subcommands.is_empty() => {
+                format!("{}|{}", info.name, subcommand.to_string().to_lowercase())
+            }
+            _ => return Ok(()),
+        };
+        match commands::lookup(&subcommand) {
+            Some(info) if !info.accepts(args.len() + 1) => {
+                Err(CommandError::WrongArity(info.name.to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn exact_args<'a, const N: usize>(
+        command: &str,
+        args: &'a [Resp],
+    ) -> Result<[&'a Resp; N], CommandError> {
+        if args.len() != N {
+            return Err(CommandError::WrongArity(command.to_string()));
+        }
+
+        Ok(std::array::from_fn(|index| &args[index]))
+    }
+
+    pub fn parse_hello_command(args: Vec<Resp>) -> Result<Command, CommandError> {
+        let mut args = args.into_iter();
+        let protocol = match args.next() {
+            Some(version) => match version.to_string().parse::<i64>() {
+                Ok(2) => Some(Protocol::Resp2),
+                Ok(3) => Some(Protocol::Resp3),
+                Ok(_) => return Err(CommandError::NoProto),
+                Err(_) => return Err(CommandError::InvalidProtocolVersion),
+            },
+            None => None,
+        };
+
+        let mut auth = None;
+        let mut name = None;
+        while let Some(option) = args.next() {
+            match option.to_string().to_lowercase().as_str() {
+                "auth" => match (args.next(), args.next()) {
+                    (Some(username), Some(password)) => {
+                        auth = Some((username.to_bytes(), password.to_bytes()))
+                    }
+                    _ => return Err(CommandError::Syntax),
+                },
+                "setname" => match args.next() {
+                    Some(value) => name = Some(value.to_bytes()),
+                    None => return Err(CommandError::Syntax),
+                },
+                _ => return Err(CommandError::Syntax),
+            }
+        }
+
+        Ok(Command::Hello {
+            protocol,
+            auth,
+            name,
+        })
+    }
+
+    pub fn parse_client_command(args: Vec<Resp>) -> Result<Command, CommandError> {
+        let subcommand = args
+            .first()
+            .ok_or_else(|| CommandError::WrongArity("client".to_string()))?
+            .to_string()
+            .to_lowercase();
+
+        let command = match subcommand.as_str() {
+            "id" => {
+                Self::exact_args::<1>("client|id", &args)?;
+                Command::ClientId
+            }
+            "getredir" => {
+                Self::exact_args::<1>("client|getredir", &args)?;
+                Command::ClientGetRedir
+            }
+            "caching" => {
+                let [_, mode] = Self::exact_args("client|caching", &args)?;
+                match mode.to_string().to_lowercase().as_str() {
+                    "yes" => Command::ClientCaching { yes: true },
+                    "no" => Command::ClientCaching { yes: false },
+                    _ => return Err(CommandError::Syntax),
+                }
+            }
+            "tracking" => {
+                let mut options = TrackingOptions::default();
+                let mut rest = args[2..].iter();
+                while let Some(option) = rest.next() {
+                    match option.to_string().to_lowercase().as_str() {
+                        "redirect" => {
+                            let id = rest.next().ok_or(CommandError::Syntax)?;
+                            if options.redirect.is_some() {
+                                return Err(CommandError::MultipleRedirects);
+                            }
+                            let id = id.to_string().parse::<u64>();
+                            options.redirect = Some(id.map_err(|_| CommandError::NotAnInteger)?);
+                        }
+                        "prefix" => {
+                            let prefix = rest.next().ok_or(CommandError::Syntax)?;
+                            options.prefixes.push(prefix.to_bytes());
+                        }
+                        "bcast" => options.bcast = true,
+                        "optin" => options.optin = true,
+                        "optout" => options.optout = true,
+                        "noloop" => options.noloop = true,
+                        _ => return Err(CommandError::Syntax),
+                    }
+                }
+                match args[1].to_string().to_lowercase().as_str() {
+                    "on" => Command::ClientTracking {
+                        options: Some(options),
+                    },
+                    "off" => Command::ClientTracking { options: None },
+                    _ => return Err(CommandError::Syntax),
+                }
+            }
+            _ => {
+                return Err(CommandError::UnknownSubcommand(
+                    "client".to_string(),
+                    subcommand,
+                ))
+            }
+        };
+
+        Ok(command)
+    }
+
+    pub fn parse_copy_command(args: Vec<Resp>) -> Result<Command, CommandError> {
+        let mut args = args.iter();
+        let source = args.next().unwrap().to_bytes();
+        let destination = args.next().unwrap().to_bytes();
+        let mut replace = false;
+        let mut db = None;
+
+        while let Some(arg) = args.next() {
+            match arg.to_string().to_lowercase().as_str() {
+                "replace" => replace = true,
+                "db" => {
+                    let index = args.next().ok_or(CommandError::Syntax)?;
+                    db = Some(Self::parse_db_index(index, CommandError::NotAnInteger)?);
+                }
+                _ => return Err(CommandError::Syntax),
+            }
+        }
+
+        Ok(Command::Copy {
+            source,
+            destination,
+            replace,
+            db,
+        })
+    }
+
+    /// Parses the index of a database, with `invalid` as the error when it isn't an integer. That
+    /// it is one of the databases is only checked when executing, as their number is configurable.
+    fn parse_db_index(index: &Resp, invalid: CommandError) -> Result<usize, CommandError> {
+        let index = Self::parse_integer(index).map_err(|_| invalid)?;
+        usize::try_from(index).map_err(|_| CommandError::DbIndexOutOfRange)
+    }
+
+    pub fn parse_expire_command(command: &str, args: Vec<Resp>) -> Result<Command, CommandError> {
+        let key = args[0].to_bytes();
+        let time = Self::parse_integer(&args[1])?;
+        let mut conditions = Vec::new();
+
+        for arg in &args[2..] {
+            let condition = match arg.to_string().to_lowercase().as_str() {
+                "nx" => ExpireCondition::Nx,
+                "xx" => ExpireCondition::Xx,
+                "gt" => ExpireCondition::Gt,
+                "lt" => ExpireCondition::Lt,
+                _ => return Err(CommandError::UnsupportedOption(arg.to_string())),
+            };
+            conditions.push(condition);
+        }
+
+        let has = |condition| conditions.contains(&condition);
+        if has(ExpireCondition::Nx)
+            && (has(ExpireCondition::Xx) || has(ExpireCondition::Gt) || has(ExpireCondition::Lt))
+        {
+            return Err(CommandError::ExpireNxIncompatible);
+        }
+        if has(ExpireCondition::Gt) && has(ExpireCondition::Lt) {
+            return Err(CommandError::ExpireGtLtIncompatible);
+        }
+
+        Ok(Command::Expire {
+            key,
+            time,
+            milliseconds: command.starts_with('p'),
+            absolute: command.ends_with("at"),
+            conditions,
+        })
+    }
+
+    pub fn parse_restore_command(args: Vec<Resp>) -> Result<Command, CommandError> {
+        let mut args = args.iter();
+        let key = args.next().unwrap().to_bytes();
+        let ttl = Self::parse_integer(args.next().unwrap())?;
+        let payload = args.next().unwrap().to_bytes();
+        let mut replace = false;
+        let mut absolute_ttl = false;
+
+        if ttl < 0 {
+            return Err(CommandError::InvalidTtl);
+        }
+
+        while let Some(arg) = args.next() {
+            match arg.to_string().to_lowercase().as_str() {
+                "replace" => replace = true,
+                "absttl" => absolute_ttl = true,
+                // NOTE: There is no LRU or LFU eviction, so idle time and frequency hints are
+                //       validated and then ignored.
+                "idletime" | "freq" => {
+                    let hint = Self::parse_integer(args.next().ok_or(CommandError::Syntax)?)?;
+                    if hint < 0 {
+                        return Err(CommandError::Syntax);
+                    }
+                }
+                _ => return Err(CommandError::Syntax),
+            }
+        }
+
+        Ok(Command::Restore {
+            key,
+            ttl: ttl as u64,
+            payload,
+            replace,
+            absolute_ttl,
+        })
+    }
+
+    fn parse_cursor(arg: &Resp) -> Result<u64, CommandError> {
+        arg.to_string()
+            .parse::<u64>()
+            .map_err(|_| CommandError::InvalidCursor)
+    }
+
+    /// Parses the MATCH and COUNT options of the SCAN family, along with TYPE for SCAN and
+    /// NOVALUES for HSCAN.
+    fn parse_scan_options(command: &str, args: &[Resp]) -> Result<ScanOptions, CommandError> {
+        let mut options = ScanOptions {
+            pattern: None,
+            count: 10,
+            type_name: None,
+            no_values: false,
+        };
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.to_string().to_lowercase().as_str() {
+                "match" => {
+                    options.pattern = Some(args.next().ok_or(CommandError::Syntax)?.to_bytes())
+                }
+                "count" => {
+                    let count = Self::parse_integer(args.next().ok_or(CommandError::Syntax)?)?;
+                    if count < 1 {
+                        return Err(CommandError::Syntax);
+                    }
+                    options.count = count as usize;
+                }
+                "type" if command == "scan" => {
+                    let type_name = args.next().ok_or(CommandError::Syntax)?;
+                    options.type_name = Some(type_name.to_string().to_lowercase());
+                }
+                "novalues" if command == "hscan" => options.no_values = true,
+                _ => return Err(CommandError::Syntax),
+            }
+        }
+
+        Ok(options)
+    }
+
+    /// Parses the offset of a bit in a string, which like in Redis can't reach past 512MB.
+    fn parse_bit_offset(arg: &Resp) -> Result<usize, CommandError> {
+        Self::parse_integer(arg)
+            .ok()
+            .filter(|offset| (0..1 << 32).contains(offset))
+            .map(|offset| offset as usize)
+            .ok_or(CommandError::BitOffsetInvalid)
+    }
+
+    /// Parses the BYTE or BIT unit of BITCOUNT and BITPOS ranges, telling whether it is BIT.
+    fn parse_bit_unit(arg: &Resp) -> Result<bool, CommandError> {
+        match arg.to_string().to_lowercase().as_str() {
+            "byte" => Ok(false),
+            "bit" => Ok(true),
+            _ => Err(CommandError::Syntax),
+        }
+    }
+
+    /// Parses the timeout of a blocking command, in seconds. Zero means waiting forever.
+    fn parse_timeout(arg: &Resp) -> Result<Option<Duration>, CommandError> {
+        let timeout = parse_float(&arg.to_bytes())
+            .filter(|timeout| timeout.is_finite())
+            .ok_or(CommandError::TimeoutNotAFloat)?;
+        if timeout < 0.0 {
+            return Err(CommandError::NegativeTimeout);
+        }
+
+        Ok((timeout > 0.0).then(|| Duration::from_secs_f64(timeout)))
+    }
+
+    fn parse_integer(arg: &Resp) -> Result<i64, CommandError> {
+        arg.to_string()
+            .parse::<i64>()
+            .map_err(|_| CommandError::NotAnInteger)
+    }
+
+    /// Parses the count of a random pick, which like in Redis can't be `i64::MIN` as a negative
+    /// count stands for as many picks as its absolute value.
+    fn parse_random_count(arg: &Resp) -> Result<i64, CommandError> {
+        match Self::parse_integer(arg)? {
+            i64::MIN => Err(CommandError::ValueOutOfRange),
+            count => Ok(count),
+        }
+    }
+
+    pub fn parse_debug_populate_command(args: &[Resp]) -> Result<Command, CommandError> {
+        if args.is_empty() || args.len() > 3 {
+            return Err(CommandError::WrongArity("debug|populate".to_string()));
+        }
+
+        let count = args[0]
+            .to_string()
+            .parse::<u64>()
+            .map_err(|_| CommandError::NotAnInteger)?;
+        let prefix = args
+            .get(1)
+            .map(|prefix| prefix.to_string())
+            .unwrap_or_else(|| "key".to_string());
+        let size = match args.get(2) {
+            Some(size) => Some(
+                size.to_string()
+                    .parse::<usize>()
+                    .map_err(|_| CommandError::NotAnInteger)?,
+            ),
+            None => None,
+        };
+
+        Ok(Command::DebugPopulate {
+            count,
+            prefix,
+            size,
+        })
+    }
+
+    pub fn parse_cluster_slots_command(
+        subcommand: &str,
+        args: &[Resp],
+    ) -> Result<Command, CommandError> {
+        let ranges = subcommand.ends_with("range");
+        if args.is_empty() || (ranges && args.len() % 2 == 1) {
+            return Err(CommandError::WrongArity(format!("cluster|{}", subcommand)));
+        }
+
+        let parse_slot = |slot: &Resp| {
+            slot.to_string()
+                .parse::<u16>()
+                .ok()
+                .filter(|slot| *slot < SLOT_COUNT)
+                .ok_or(CommandError::InvalidSlot)
+        };
+
+        let mut slots = Vec::new();
+        if ranges {
+            for range in args.chunks(2) {
+                let (start, end) = (parse_slot(&range[0])?, parse_slot(&range[1])?);
+                if start > end {
+                    return Err(CommandError::SlotRangeReversed(start, end));
+                }
+                slots.extend(start..=end);
+            }
+        } else {
+            for slot in args {
+                slots.push(parse_slot(slot)?);
+            }
+        }
+
+        let mut seen = Slots::default();
+        for slot in &slots {
+            if seen.contains(*slot) {
+                return Err(CommandError::SlotSpecifiedMultipleTimes(*slot));
+            }
+            seen.insert(*slot);
+        }
+
+        Ok(Command::ClusterSlots {
+            slots,
+            assign: subcommand.starts_with("add"),
+            ranges,
+        })
+    }
+
+    pub fn parse_cluster_meet_command(args: &[Resp]) -> Result<Command, CommandError> {
+        let (ip, port, cport) = match args {
+            [ip, port] => (ip.to_string(), port.to_string(), None),
+            [ip, port, cport] => (ip.to_string(), port.to_string(), Some(cport.to_string())),
+            _ => return Err(CommandError::WrongArity("cluster|meet".to_string())),
+        };
+
+        let port = port
+            .parse::<u16>()
+            .map_err(|_| CommandError::InvalidBasePort(port.clone()))?;
+        let cport = match cport {
+            Some(cport) => cport
+                .parse::<u16>()
+                .map_err(|_| CommandError::InvalidBusPort(cport.clone()))?,
+            None => port.wrapping_add(CLUSTER_PORT_INCR),
+        };
+        let ip = ip
+            .parse::<IpAddr>()
+            .map_err(|_| CommandError::InvalidNodeAddress(format!("{}:{}", ip, port)))?;
+
+        Ok(Command::ClusterMeet { ip, cport })
+    }
+
+    pub fn parse_memory_bigkeys_command(args: &[Resp]) -> Result<Command, CommandError> {
+        match args {
+            [] => Ok(Command::BigKeys { count: 1 }),
+            [option, count] if option.to_string().to_lowercase() == "count" => {
+                let count = count
+                    .to_string()
+                    .parse::<usize>()
+                    .map_err(|_| CommandError::NotAnInteger)?;
+                Ok(Command::BigKeys { count })
+            }
+            _ => Err(CommandError::Syntax),
+        }
+    }
+
+    /// Parses `ZADD key [NX | XX] [GT | LT] [CH] [INCR] score member [score member ...]`.
+    fn parse_zadd_command(args: Vec<Resp>) -> Result<Command, CommandError> {
+        let (mut nx, mut xx, mut gt, mut lt, mut changed, mut increment) =
+            (false, false, false, false, false, false);
+        let mut rest = &args[1..];
+        while let Some((option, tail)) = rest.split_first() {
+            match option.to_string().to_lowercase().as_str() {
+                "nx" => nx = true,
+                "xx" => xx = true,
+                "gt" => gt = true,
+                "lt" => lt = true,
+                "ch" => changed = true,
+                "incr" => increment = true,
+                _ => break,
+            }
+            rest = tail;
+        }
+
+        let pairs = rest.chunks_exact(2);
+        if rest.is_empty() || !pairs.remainder().is_empty() {
+            return Err(CommandError::Syntax);
+        }
+        if nx && xx {
+            return Err(CommandError::ZAddNxXxIncompatible);
+        }
+        if (gt || lt) && nx || gt && lt {
+            return Err(CommandError::ZAddGtLtNxIncompatible);
+        }
+        if increment && pairs.len() > 1 {
+            return Err(CommandError::ZAddIncrementPairs);
+        }
+
+        Ok(Command::ZAdd {
+            key: args[0].to_bytes(),
+            pairs: pairs
+                .map(|pair| {
+                    let score = parse_float(&pair[0].to_bytes()).ok_or(CommandError::NotAFloat)?;
+                    Ok((score, pair[1].to_bytes()))
+                })
+                .collect::<Result<_, CommandError>>()?,
+            condition: match (nx, xx) {
+                (true, _) => Some(SetCondition::Nx),
+                (_, true) => Some(SetCondition::Xx),
+                _ => None,
+            },
+            comparison: match (gt, lt) {
+                (true, _) => Some(Comparison::Greater),
+                (_, true) => Some(Comparison::Less),
+                _ => None,
+            },
+            changed,
+            increment,
+        })
+    }
+
+    /// Parses ZRANGE with its BYSCORE, BYLEX, REV, LIMIT and WITHSCORES options, as well as the
+    /// older commands that each stand for some of them, like ZREVRANGEBYSCORE.
+    fn parse_zrange_command(command: String, args: Vec<Resp>) -> Result<Command, CommandError> {
+        let legacy = command != "zrange";
+        let mut rev = command.starts_with("zrev");
+        let (mut by_score, mut by_lex) = (command.ends_with("byscore"), command.ends_with("bylex"));
+        let (mut limit, mut with_scores) = (None, false);
+        let mut options = args[3..].iter();
+        while let Some(option) = options.next() {
+            match option.to_string().to_lowercase().as_str() {
+                "withscores" if !(legacy && by_lex) => with_scores = true,
+                "limit" if !legacy || by_score || by_lex => {
+                    let (Some(offset), Some(count)) = (options.next(), options.next()) else {
+                        return Err(CommandError::Syntax);
+                    };
+                    limit = Some((Self::parse_integer(offset)?, Self::parse_integer(count)?));
+                }
+                "rev" if !legacy => rev = true,
+                "byscore" if !legacy => by_score = true,
+                "bylex" if !legacy => by_lex = true,
+                _ => return Err(CommandError::Syntax),
+            }
+        }
+        if by_score && by_lex {
+            return Err(CommandError::Syntax);
+        }
+        if limit.is_some() && !by_score && !by_lex {
+            return Err(CommandError::ZRangeLimitWithoutBy);
+        }
+        if with_scores && by_lex {
+            return Err(CommandError::ZRangeWithScoresByLex);
+        }
+
+        // Ranges by score or member are given highest first when reversed.
+        let (start, stop) = (args[1].to_bytes(), args[2].to_bytes());
+        let (min, max) = match rev {
+            true => (&stop, &start),
+            false => (&start, &stop),
+        };
+        let by = if by_score {
+            match (ScoreBound::parse(min), ScoreBound::parse(max)) {
+                (Some(min), Some(max)) => ZRangeBy::Score(min, max),
+                _ => return Err(CommandError::ScoreRangeNotAFloat),
+            }
+        } else if by_lex {
+            match (LexBound::parse(min), LexBound::parse(max)) {
+                (Some(min), Some(max)) => ZRangeBy::Lex(min, max),
+                _ => return Err(CommandError::LexRangeInvalid),
+            }
+        } else {
+            ZRangeBy::Rank(
+                Self::parse_integer(&args[1])?,
+                Self::parse_integer(&args[2])?,
+            )
+        };
+
+        Ok(Command::ZRange {
+            key: args[0].to_bytes(),
+            by,
+            rev,
+            limit,
+            with_scores,
+            legacy,
+        })
+    }
+
+    pub fn parse_set_command(args: Vec<Resp>) -> Result<Command, CommandError> {
+        let mut args = args.iter();
+        let key = args.next().unwrap().to_bytes();
+        let value = args.next().unwrap().to_bytes();
+
+        let mut expiry = None;
+        let mut condition = None;
+        let mut get = false;
+
+        // Like Redis, repeating an option is fine but combining conflicting ones isn't.
+        while let Some(arg) = args.next() {
+            let option = arg.to_string().to_lowercase();
+            let new_expiry = match option.as_str() {
+                "nx" | "xx" => {
+                    let new_condition = match option.as_str() {
+                        "nx" => SetCondition::Nx,
+                        _ => SetCondition::Xx,
+                    };
+                    if condition.is_some_and(|condition| condition != new_condition) {
+                        return Err(CommandError::Syntax);
+                    }
+                    condition = Some(new_condition);
+                    continue;
+                }
+                "get" => {
+                    get = true;
+                    continue;
+                }
+                "keepttl" => SetExpiry::KeepTtl,
+                "ex" | "px" | "exat" | "pxat" => {
+                    let time = Self::parse_integer(args.next().ok_or(CommandError::Syntax)?)?;
+                    match option.as_str() {
+                        "ex" => SetExpiry::Ex(time),
+                        "px" => SetExpiry::Px(time),
+                        "exat" => SetExpiry::ExAt(time),
+                        _ => SetExpiry::PxAt(time),
+                    }
+                }
+                _ => return Err(CommandError::Syntax),
+            };
+
+            if expiry.is_some_and(|expiry| {
+                std::mem::discriminant(&expiry) != std::mem::discriminant(&new_expiry)
+            }) {
+                return Err(CommandError::Syntax);
+            }
+            expiry = Some(new_expiry);
+        }
+
+        Ok(Command::Set {
+            key,
+            value,
+            expiry,
+            condition,
+            get,
+        })
+    }
+
+    pub fn handle_command(&self, keyspace: &mut KeyspaceGuard, command: Command) -> Resp {
+        // Like in Redis, looking at a key's metadata doesn't count as accessing it.
+        if !matches!(
+            command,
+            Command::Object { .. } | Command::Type { .. } | Command::Ttl { .. }
+        ) {
+            for spec in command.key_specs() {
+                keyspace.touch(spec.key);
+            }
+        }
+
+        match command {
+            Command::Ping => Resp::SimpleString("PONG".to_string()),
+            Command::Save => match self.persistence() {
+                None => CommandError::PersistenceDisabled.into(),
+                // Both would be writing the same file.
+                Some(_) if self.background_save.in_progress.load(Ordering::SeqCst) => {
+                    CommandError::BackgroundSaveInProgress.into()
+                }
+                Some(persistence) => {
+                    self.forget_propagated_db();
+                    let dirty = self.background_save.dirty.load(Ordering::SeqCst);
+                    match persistence.snapshot(&keyspace.snapshot()) {
+                        Ok(()) => {
+                            self.background_save.saved(dirty);
+                            Resp::SimpleString("OK".to_string())
+                        }
+                        Err(error) => CommandError::SaveFailed(error).into(),
+                    }
+                }
+            },
+            Command::Select { db } => match self.check_select(db) {
+                Ok(()) => {
+                    keyspace.select(db);
+                    Resp::SimpleString("OK".to_string())
+                }
+                Err(error) => error.into(),
+            },
+            Command::SwapDb { first, second } => self.swap_databases(keyspace, first, second),
+            Command::Move { key, db } => self.move_key(keyspace, key, db),
+            Command::FlushDb => {
+                keyspace.clear();
+                keyspace.propagate(vec![Bytes::from("FLUSHDB")]);
+                Resp::SimpleString("OK".to_string())
+            }
+            Command::FlushAll => {
+                keyspace.clear_all();
+                keyspace.propagate(vec![Bytes::from("FLUSHALL")]);
+                Resp::SimpleString("OK".to_string())
+            }
+            Command::BgSave => self.background_save(keyspace),
+            Command::LastSave => {
+                Resp::Integer(self.background_save.last_save.load(Ordering::SeqCst) as i64)
+            }
+            Command::Info { sections } => self.info(&sections),
+            Command::Echo { message } => Resp::BulkString(message),
+            Command::Set {
+                key,
+                value,
+                expiry,
+                condition,
+                get,
+            } => Self::set(keyspace, key, value, expiry, condition, get),
+            Command::Get { key } => Self::get(keyspace, key),
+            Command::MGet { keys } => {
+                let values = keys
+                    .iter()
+                    .map(|key| match Self::live_value(keyspace, key) {
+                        Some(RedisValue::String(value)) => Resp::BulkString(value.clone()),
+                        Some(_) | None => Resp::Null,
+                    });
+                Resp::Array(values.collect())
+            }
+            Command::MSet { pairs, only_new } => Self::mset(keyspace, pairs, only_new),
+            Command::Append { key, value } => Self::append(keyspace, key, value),
+            Command::GetRange { key, start, end } => match Self::live_value(keyspace, &key) {
+                Some(RedisValue::String(value)) => match index_range(value.len(), start, end) {
+                    Some(range) => Resp::BulkString(value.slice(range)),
+                    None => Resp::BulkString(Bytes::new()),
+                },
+                Some(_) => CommandError::WrongType.into(),
+                None => Resp::BulkString(Bytes::new()),
+            },
+            Command::SetRange { key, offset, value } => {
+                Self::setrange(keyspace, key, offset, value, self.proto_max_bulk_len())
+            }
+            Command::SetBit { key, offset, value } => self.setbit(keyspace, key, offset, value),
+            Command::BitOp {
+                operation,
+                destination,
+                keys,
+            } => Self::bitop(keyspace, operation, destination, keys),
+            Command::GetBit { key, offset } => match Self::live_value(keyspace, &key) {
+                Some(RedisValue::String(value)) => Resp::Integer(bit_at(value, offset) as i64),
+                Some(_) => CommandError::WrongType.into(),
+                None => Resp::Integer(0),
+            },
+            Command::BitCount { key, range } => {
+                let value = match Self::live_value(keyspace, &key) {
+                    Some(RedisValue::String(value)) => value,
+                    Some(_) => return CommandError::WrongType.into(),
+                    None => return Resp::Integer(0),
+                };
+                let bits = match range {
+                    Some((start, end, bits)) => bit_range(value.len(), start, end, bits),
+                    None => Some(0..value.len() * 8),
+                };
+                Resp::Integer(bits.map_or(0, |bits| count_bits(value, bits)) as i64)
+            }
+            Command::BitPos {
+                key,
+                bit,
+                start,
+                end,
+                bits,
+            } => {
+                let value = match Self::live_value(keyspace, &key) {
+                    Some(RedisValue::String(value)) => value,
+                    Some(_) => return CommandError::WrongType.into(),
+                    // A missing key is an empty string, whose clear bits go on forever.
+                    None => return Resp::Integer(if bit { -1 } else { 0 }),
+                };
+                let Some(mut range) =
+                    bit_range(value.len(), start.unwrap_or(0), end.unwrap_or(-1), bits)
+                else {
+                    return Resp::Integer(-1);
+                };
+
+                match range.find(|offset| bit_at(value, *offset) == bit) {
+                    Some(offset) => Resp::Integer(offset as i64),
+                    // Without an end the string counts as padded with clear bits, so the first one
+                    // is just past it.
+                    None if !bit && end.is_none() => Resp::Integer(value.len() as i64 * 8),
+                    None => Resp::Integer(-1),
+                }
+            }
+            Command::Strlen { key } => match Self::live_value(keyspace, &key) {
+                Some(RedisValue::String(value)) => Resp::Integer(value.len() as i64),
+                Some(_) => CommandError::WrongType.into(),
+                None => Resp::Integer(0),
+            },
+            Command::Push {
+                key,
+                elements,
+                left,
+            } => Self::push(keyspace, key, elements, left),
+            Command::Pop { key, count, left } => Self::pop(keyspace, key, count, left),
+            // Without a connection to block, like in a transaction, they never wait.
+            Command::BlockingPop { keys, left, .. } => {
+                Self::pop_first(keyspace, &keys, left).unwrap_or(Resp::NullArray)
+            }
+            Command::LMove {
+                source,
+                destination,
+                from_left,
+                to_left,
+            }
+            | Command::BlockingMove {
+                source,
+                destination,
+                from_left,
+                to_left,
+                ..
+            } => Self::lmove(keyspace, &source, &destination, from_left, to_left),
+            Command::LLen { key } => match Self::live_value(keyspace, &key) {
+                Some(RedisValue::List(list)) => Resp::Integer(list.len() as i64),
+                Some(_) => CommandError::WrongType.into(),
+                None => Resp::Integer(0),
+            },
+            Command::LRange { key, start, stop } => match Self::live_value(keyspace, &key) {
+                Some(RedisValue::List(list)) => match index_range(list.len(), start, stop) {
+                    Some(range) => Resp::Array(
+                        list.range(range)
+                            .map(|element| Resp::BulkString(element.clone()))
+                            .collect(),
+                    ),
+                    None => Resp::Array(vec![]),
+                },
+                Some(_) => CommandError::WrongType.into(),
+                None => Resp::Array(vec![]),
+            },
+            Command::LInsert {
+                key,
+                before,
+                pivot,
+                element,
+            } => Self::linsert(keyspace, key, before, pivot, element),
+            Command::LSet {
+                key,
+                index,
+                element,
+            } => Self::lset(keyspace, key, index, element),
+            Command::LRem {
+                key,
+                count,
+                element,
+            } => Self::lrem(keyspace, key, count, element),
+            Command::LTrim { key, start, stop } => Self::ltrim(keyspace, key, start, stop),
+            Command::HSet { key, pairs } => Self::hset(keyspace, key, pairs),
+            Command::HGet { key, field } => match Self::live_value(keyspace, &key) {
+                Some(RedisValue::Hash(hash)) => match hash.get(&field) {
+                    Some(value) => Resp::BulkString(value.clone()),
+                    None => Resp::Null,
+                },
+                Some(_) => CommandError::WrongType.into(),
+                None => Resp::Null,
+            },
+            Command::HExists { key, field } => match Self::live_value(keyspace, &key) {
+                Some(RedisValue::Hash(hash)) => Resp::Integer(hash.contains_key(&field) as i64),
+                Some(_) => CommandError::WrongType.into(),
+                None => Resp::Integer(0),
+            },
+            Command::HDel { key, fields } => Self::hdel(keyspace, key, fields),
+            Command::HGetAll { key } => match Self::live_value(keyspace, &key) {
+                Some(RedisValue::Hash(hash)) => Resp::Map(
+                    hash.iter()
+                        .map(|(field, value)| {
+                            (
+                                Resp::BulkString(field.clone()),
+                                Resp::BulkString(value.clone()),
+                            )
+                        })
+                        .collect(),
+                ),
+                Some(_) => CommandError::WrongType.into(),
+                None => Resp::Map(vec![]),
+            },
+            Command::HLen { key } => match Self::live_value(keyspace, &key) {
+                Some(RedisValue::Hash(hash)) => Resp::Integer(hash.len() as i64),
+                Some(_) => CommandError::WrongType.into(),
+                None => Resp::Integer(0),
+            },
+            Command::HKeys { key } => match Self::live_value(keyspace, &key) {
+                Some(RedisValue::Hash(hash)) => Resp::Array(
+                    hash.keys()
+                        .map(|field| Resp::BulkString(field.clone()))
+                        .collect(),
+                ),
+                Some(_) => CommandError::WrongType.into(),
+                None => Resp::Array(vec![]),
+            },
+            Command::HVals { key } => match Self::live_value(keyspace, &key) {
+                Some(RedisValue::Hash(hash)) => Resp::Array(
+                    hash.values()
+                        .map(|value| Resp::BulkString(value.clone()))
+                        .collect(),
+                ),
+                Some(_) => CommandError::WrongType.into(),
+                None => Resp::Array(vec![]),
+            },
+            Command::HMGet { key, fields } => {
+                let hash = match Self::live_value(keyspace, &key) {
+                    Some(RedisValue::Hash(hash)) => Some(hash),
+                    Some(_) => return CommandError::WrongType.into(),
+                    None => None,
+                };
+                Resp::Array(
+                    fields
+                        .iter()
+                        .map(|field| match hash.and_then(|hash| hash.get(field)) {
+                            Some(value) => Resp::BulkString(value.clone()),
+                            None => Resp::Null,
+                        })
+                        .collect(),
+                )
+            }
+            Command::HIncrBy {
+                key,
+                field,
+                increment,
+            } => Self::hincr_by(keyspace, key, field, increment),
+            Command::SAdd { key, members } => Self::sadd(keyspace, key, members),
+            Command::SRem { key, members } => Self::srem(keyspace, key, members),
+            Command::SMembers { key } => match Self::live_value(keyspace, &key) {
+                Some(RedisValue::Set(set)) => Resp::Set(
+                    set.iter()
+                        .map(|member| Resp::BulkString(member.clone()))
+                        .collect(),
+                ),
+                Some(_) => CommandError::WrongType.into(),
+                None => Resp::Set(vec![]),
+            },
+            Command::SIsMember { key, member } => match Self::live_value(keyspace, &key) {
+                Some(RedisValue::Set(set)) => Resp::Integer(set.contains(&member) as i64),
+                Some(_) => CommandError::WrongType.into(),
+                None => Resp::Integer(0),
+            },
+            Command::SCard { key } => match Self::live_value(keyspace, &key) {
+                Some(RedisValue::Set(set)) => Resp::Integer(set.len() as i64),
+                Some(_) => CommandError::WrongType.into(),
+                None => Resp::Integer(0),
+            },
+            Command::SetOperation {
+                operation,
+                keys,
+                destination,
+            } => Self::set_operation(keyspace, operation, keys, destination),
+            Command::SPop { key, count } => Self::spop(keyspace, key, count),
+            Command::SRandMember { key, count } => {
+                let members = match Self::live_value(keyspace, &key) {
+                    Some(RedisValue::Set(set)) => set.iter().collect::<Vec<_>>(),
+                    Some(_) => return CommandError::WrongType.into(),
+                    None if count.is_some() => return Resp::Array(vec![]),
+                    None => return Resp::Null,
+                };
+                let picked = match count {
+                    None => return Resp::BulkString(members[random_index(members.len())].clone()),
+                    Some(count) if count >= 0 => random_sample(members, count as usize),
+                    // NOTE: Not sized up front, the count comes from the client.
+                    Some(count) => {
+                        let mut picked = Vec::new();
+                        for _ in 0..count.unsigned_abs() {
+                            picked.push(members[random_index(members.len())]);
+                        }
+                        picked
+                    }
+                };
+                Resp::Array(
+                    picked
+                        .into_iter()
+                        .map(|member| Resp::BulkString(member.clone()))
+                        .collect(),
+                )
+            }
+            Command::SMove {
+                source,
+                destination,
+                member,
+            } => Self::smove(keyspace, source, destination, member),
+            Command::ZAdd {
+                key,
+                pairs,
+                condition,
+                comparison,
+                changed,
+                increment,
+            } => Self::zadd(
+                keyspace, key, pairs, condition, comparison, changed, increment,
+            ),
+            Command::ZScore { key, member } => match Self::live_value(keyspace, &key) {
+                Some(RedisValue::SortedSet(zset)) => match zset.score(&member) {
+                    Some(score) => Resp::Double(score),
+                    None => Resp::Null,
+                },
+                Some(_) => CommandError::WrongType.into(),
+                None => Resp::Null,
+            },
+            Command::ZRange {
+                key,
+                by,
+                rev,
+                limit,
+                with_scores,
+                ..
+            } => Self::zrange(keyspace, key, by, rev, limit, with_scores),
+            Command::ZRank {
+                key,
+                member,
+                rev,
+                with_score,
+            } => {
+                let zset = match Self::live_value(keyspace, &key) {
+                    Some(RedisValue::SortedSet(zset)) => zset,
+                    Some(_) => return CommandError::WrongType.into(),
+                    None => return Resp::Null,
+                };
+                let (Some(rank), Some(score)) = (zset.rank(&member), zset.score(&member)) else {
+                    return Resp::Null;
+                };
+
+                let rank = match rev {
+                    true => zset.len() - 1 - rank,
+                    false => rank,
+                };
+                match with_score {
+                    true => Resp::Array(vec![Resp::Integer(rank as i64), Resp::Double(score)]),
+                    false => Resp::Integer(rank as i64),
+                }
+            }
+            Command::ZRem { key, members } => Self::zrem(keyspace, key, members),
+            Command::ZIncrBy {
+                key,
+                increment,
+                member,
+            } => Self::zincr_by(keyspace, key, increment, member),
+            Command::ZCard { key } => match Self::live_value(keyspace, &key) {
+                Some(RedisValue::SortedSet(zset)) => Resp::Integer(zset.len() as i64),
+                Some(_) => CommandError::WrongType.into(),
+                None => Resp::Integer(0),
+            },
+            Command::ZCount { key, min, max } => match Self::live_value(keyspace, &key) {
+                Some(RedisValue::SortedSet(zset)) => {
+                    Resp::Integer(zset.range_by_score(min, max).len() as i64)
+                }
+                Some(_) => CommandError::WrongType.into(),
+                None => Resp::Integer(0),
+            },
+            Command::ZPop { key, count, max } => Self::zpop(keyspace, key, count, max),
+            Command::BlockingZPop { keys, max, .. } => {
+                Self::zpop_first(keyspace, &keys, max).unwrap_or(Resp::NullArray)
+            }
+            Command::SInterCard { keys, limit } => {
+                let sets = match Self::sets(keyspace, &keys) {
+                    Ok(sets) => sets,
+                    Err(error) => return error.into(),
+                };
+                let Some(mut sets) = sets.into_iter().collect::<Option<Vec<_>>>() else {
+                    return Resp::Integer(0);
+                };
+                sets.sort_by_key(|set| set.len());
+
+                let (smallest, rest) = sets.split_first().unwrap();
+                let common = smallest
+                    .iter()
+                    .filter(|member| rest.iter().all(|set| set.contains(*member)));
+                let count = match limit {
+                    0 => common.count(),
+                    limit => common.take(limit).count(),
+                };
+                Resp::Integer(count as i64)
+            }
+            Command::HIncrByFloat {
+                key,
+                field,
+                increment,
+            } => Self::hincr_by_float(keyspace, key, field, increment),
+            Command::HRandField {
+                key,
+                count,
+                with_values,
+            } => Self::hrandfield(keyspace, key, count, with_values),
+            Command::Type { key } => match Self::live_value(keyspace, &key) {
+                Some(value) => Resp::SimpleString(value.type_name().to_string()),
+                None => Resp::SimpleString("none".to_string()),
+            },
+            Command::ConfigGet { key } => {
+                let config = self.config.read().unwrap();
+                let parameters = config.matching(&key.to_lowercase());
+                Resp::Map(
+                    parameters
+                        .into_iter()
+                        .map(|(name, value)| {
+                            (
+                                Resp::BulkString(Bytes::from(name)),
+                                Resp::BulkString(Bytes::from(value)),
+                            )
+                        })
+                        .collect(),
+                )
+            }
+            Command::ConfigSet { parameters } => self.config_set(keyspace, parameters),
+            Command::ConfigRewrite => {
+                let config = self.config.read().unwrap();
+                match &config.file {
+                    Some(path) => match config.rewrite(path) {
+                        Ok(()) => Resp::SimpleString("OK".to_string()),
+                        Err(error) => CommandError::ConfigRewriteFailed(error).into(),
+                    },
+                    None => CommandError::NoConfigFile.into(),
+                }
+            }
+            Command::Keys { pattern } => {
+                let now = Self::ms_since_epoch();
+                let mut keys = keyspace
+                    .keys()
+                    .filter(|key| glob_match(pattern.as_bytes(), key))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                keys.retain(|key| !keyspace.expire_if_needed(key, now));
+                Resp::Array(keys.into_iter().map(Resp::BulkString).collect())
+            }
+            Command::DbSize => {
+                let expired = keyspace.expired(Self::ms_since_epoch());
+                Resp::Integer((keyspace.len() - expired) as i64)
+            }
+            Command::RandomKey => Self::random_key(keyspace),
+            Command::DebugPopulate {
+                count,
+                prefix,
+                size,
+            } => Self::debug_populate(keyspace, count, &prefix, size),
+            Command::Rename {
+                source,
+                destination,
+            } => Self::rename(keyspace, source, destination),
+            Command::Copy {
+                source,
+                destination,
+                replace,
+                db,
+            } => match db.map_or(Ok(()), |db| self.check_copy_target(db)) {
+                Ok(()) => Self::copy(keyspace, source, destination, replace, db),
+                Err(error) => error.into(),
+            },
+            Command::Expire {
+                key,
+                time,
+                milliseconds,
+                absolute,
+                conditions,
+            } => Self::expire(keyspace, key, time, milliseconds, absolute, &conditions),
+            Command::Del { keys, .. } => Self::del(keyspace, keys),
+            // A key given more than once is counted every time, like Redis does.
+            Command::Exists { keys } => {
+                let count = keys
+                    .iter()
+                    .filter(|key| Self::live_value(keyspace, key).is_some())
+                    .count();
+                Resp::Integer(count as i64)
+            }
+            Command::Ttl { key, milliseconds } => Self::ttl(keyspace, key, milliseconds),
+            Command::IncrByFloat { key, increment } => {
+                Self::incr_by_float(keyspace, key, increment)
+            }
+            Command::IncrBy {
+                key,
+                increment,
+                decrement,
+            } => {
+                let increment = increment.unwrap_or(1);
+                match decrement {
+                    true => match increment.checked_neg() {
+                        Some(increment) => Self::incr_by(keyspace, key, increment),
+                        None => CommandError::DecrementOverflow.into(),
+                    },
+                    false => Self::incr_by(keyspace, key, increment),
+                }
+            }
+            Command::Dump { key } => match Self::live_value(keyspace, &key) {
+                Some(value) => Resp::BulkString(Rdb::dump_value(value)),
+                None => Resp::Null,
+            },
+            Command::Restore {
+                key,
+                ttl,
+                payload,
+                replace,
+                absolute_ttl,
+            } => Self::restore(keyspace, key, ttl, payload, replace, absolute_ttl),
+            Command::CommandCount => Resp::Integer(commands::COMMANDS.len() as i64),
+            Command::CommandInfo { names } if names.is_empty() => Resp::Array(
+                commands::COMMANDS
+                    .iter()
+                    .map(commands::CommandInfo::to_resp)
+                    .collect(),
+            ),
+            Command::CommandInfo { names } => Resp::Array(
+                names
+                    .iter()
+                    .map(|name| commands::lookup(name).map_or(Resp::Null, |info| info.to_resp()))
+                    .collect(),
+            ),
+            Command::CommandDocs { names } => {
+                let infos: Vec<_> = match names.is_empty() {
+                    true => commands::COMMANDS.iter().collect(),
+                    false => names
+                        .iter()
+                        .filter_map(|name| commands::lookup(name))
+                        .collect(),
+                };
+                Resp::Map(
+                    infos
+                        .into_iter()
+                        .map(|info| (Resp::BulkString(Bytes::from(info.name)), info.docs()))
+                        .collect(),
+                )
+            }
+            Command::GetKeys {
+                command,
+                with_flags,
+            } => {
+                let specs = command.key_specs();
+                if specs.is_empty() {
+                    return CommandError::NoKeyArguments.into();
+                }
+
+                let keys = specs.into_iter().map(|spec| {
+                    let key = Resp::BulkString(Bytes::copy_from_slice(spec.key));
+                    if !with_flags {
+                        return key;
+                    }
+
+                    let flags = spec
+                        .flags
+                        .iter()
+                        .map(|flag| Resp::SimpleString(flag.to_string()));
+                    Resp::Array(vec![key, Resp::Set(flags.collect())])
+                });
+                Resp::Array(keys.collect())
+            }
+            Command::LatencyHistogram { commands } => self.latency.histogram(&commands),
+            Command::ClusterNodes => match &self.cluster {
+                Some(cluster) => Resp::BulkString(cluster.nodes().into()),
+                None => CommandError::ClusterSupportDisabled.into(),
+            },
+            Command::ClusterMyId => match &self.cluster {
+                Some(cluster) => Resp::BulkString(cluster.myid().into()),
+                None => CommandError::ClusterSupportDisabled.into(),
+            },
+            // Only change the connection's session, which `execute` takes care of.
+            Command::ReadOnly
+            | Command::ReadWrite
+            | Command::ReplConf { .. }
+            | Command::Hello { .. }
+            | Command::ClientId
+            | Command::ClientTracking { .. }
+            | Command::ClientCaching { .. }
+            | Command::ClientGetRedir => Resp::SimpleString("OK".to_string()),
+            // NOTE: Clients' transactions are run by `execute`. Replicas and persistence replay a
+            //       transaction command by command, the MULTI and EXEC around it only mark it.
+            Command::Multi | Command::Exec | Command::Discard => {
+                Resp::SimpleString("OK".to_string())
+            }
+            // NOTE: Unlike in Redis, messages aren't propagated, so only the subscribers of the
+            //       node they were published on receive them.
+            Command::Publish { channel, message } => {
+                Resp::Integer(self.pubsub.publish(&channel, &message) as i64)
+            }
+            Command::PubSubChannels { pattern } => Resp::Array(
+                self.pubsub
+                    .active_channels(pattern.as_deref())
+                    .into_iter()
+                    .map(Resp::BulkString)
+                    .collect(),
+            ),
+            Command::PubSubNumSub { channels } => Resp::Array(
+                channels
+                    .into_iter()
+                    .flat_map(|channel| {
+                        let count = self.pubsub.subscriber_count(&channel);
+                        [Resp::BulkString(channel), Resp::Integer(count as i64)]
+                    })
+                    .collect(),
+            ),
+            Command::PubSubNumPat => Resp::Integer(self.pubsub.pattern_count() as i64),
+            // Subscribing is up to `subscribe`, which nothing without a connection gets to.
+            command @ (Command::Subscribe { .. } | Command::Unsubscribe { .. }) => {
+                CommandError::DenyBlocking(command.name()).into()
+            }
+            // NOTE: PSYNC turns the connection into a replication link, which the server handles
+            //       before the command ever gets here.
+            Command::Psync { .. } => {
+                Resp::SimpleError("ERR PSYNC is only supported on client connections".to_string())
+            }
+            // NOTE: Talking to the other node means waiting on the network, which only `execute`
+            //       does. There are no locks to hold, so it never gets here.
+            Command::ClusterMeet { .. } => {
+                Resp::SimpleError("ERR CLUSTER MEET can't run with the keyspace locked".to_string())
+            }
+            Command::ClusterForget { id } => match &self.cluster {
+                Some(cluster) => match cluster.forget(&id) {
+                    Ok(()) => Resp::SimpleString("OK".to_string()),
+                    Err(error) => error.into(),
+                },
+                None => CommandError::ClusterSupportDisabled.into(),
+            },
+            Command::ClusterSlots { slots, assign, .. } => {
+                let Some(cluster) = &self.cluster else {
+                    return CommandError::ClusterSupportDisabled.into();
+                };
+                let result = match assign {
+                    true => cluster.add_slots(&slots),
+                    false => cluster.del_slots(&slots),
+                };
+                match result {
+                    Ok(()) => Resp::SimpleString("OK".to_string()),
+                    Err(error) => error.into(),
+                }
+            }
+            Command::BigKeys { count } => {
+                let mut big_keys = BigKeys::new(count);
+                big_keys.scan(keyspace, Self::ms_since_epoch());
+                big_keys.into()
+            }
+            Command::MemoryStats => {
+                let (keys, dataset) = Self::dataset_size(keyspace);
+                self.memory_stats_reply(keys, dataset)
+            }
+            Command::Object { subcommand, key } => Self::object(keyspace, subcommand, key),
+            Command::Scan { cursor, options } => {
+                let mut cursor = cursor;
+                let mut batch = ScanBatch::new(options.count);
+                loop {
+                    cursor = Self::scan_shard(keyspace, cursor, &options, &mut batch);
+                    if cursor == 0 || batch.is_full(options.count) {
+                        break;
+                    }
+                }
+                Self::scan_reply(cursor, batch.keys)
+            }
+            Command::ElementScan {
+                scan,
+                key,
+                cursor,
+                options,
+            } => Self::element_scan(keyspace, scan, key, cursor, options),
+        }
+    }
+
+    /// Picks a key at random, every key being as likely. Like in Redis, expired keys that get
+    /// picked are removed and another one is picked instead.
+    fn random_key(keyspace: &mut KeyspaceGuard) -> Resp {
+        let now = Self::ms_since_epoch();
+        loop {
+            let len = keyspace.len();
+            if len == 0 {
+                return Resp::Null;
+            }
+
+            let key = keyspace.keys().nth(random_index(len)).unwrap().clone();
+            if !keyspace.expire_if_needed(&key, now) {
+                return Resp::BulkString(key);
+            }
+        }
+    }
+
+    fn object(keyspace: &mut KeyspaceGuard, subcommand: ObjectSubcommand, key: Bytes) -> Resp {
+        if Self::live_value(keyspace, &key).is_none() {
+            return Resp::Null;
+        }
+        let object = keyspace.object(&key).unwrap();
+
+        match subcommand {
+            ObjectSubcommand::Encoding => {
+                Resp::BulkString(Bytes::from_static(object.encoding.name().as_bytes()))
+            }
+            // Values are shared between keys and snapshots until one of them is written to, which
+            // is what Redis counts references for.
+            ObjectSubcommand::RefCount => {
+                let shared = keyspace.get_shared(&key).unwrap();
+                Resp::Integer(Arc::strong_count(&shared) as i64 - 1)
+            }
+            ObjectSubcommand::IdleTime => Resp::Integer(object.accessed.elapsed().as_secs() as i64),
+            // NOTE: Without eviction there is no maxmemory-policy, so like Redis under its default
+            //       policy, access frequency is never tracked.
+            ObjectSubcommand::Freq => CommandError::LfuNotSelected.into(),
+        }
+    }
+
+    fn set(
+        keyspace: &mut KeyspaceGuard,
+        key: Bytes,
+        value: Bytes,
+        expiry: Option<SetExpiry>,
+        condition: Option<SetCondition>,
+        get: bool,
+    ) -> Resp {
+        let now = Self::ms_since_epoch() as i64;
+        let expires_at = match expiry {
+            None | Some(SetExpiry::KeepTtl) => None,
+            Some(SetExpiry::Ex(time)) if time > 0 => time
+                .checked_mul(1000)
+                .and_then(|time| time.checked_add(now)),
+            Some(SetExpiry::Px(time)) if time > 0 => time.checked_add(now),
+            Some(SetExpiry::ExAt(time)) if time > 0 => time.checked_mul(1000),
+            Some(SetExpiry::PxAt(time)) if time > 0 => Some(time),
+            Some(_) => return CommandError::InvalidExpireTime("set".to_string()).into(),
+        };
+        if expiry.is_some_and(|expiry| expiry != SetExpiry::KeepTtl) && expires_at.is_none() {
+            return CommandError::InvalidExpireTime("set".to_string()).into();
+        }
+
+        // Any type of value is overwritten, but only strings can be returned by GET.
+        let current = Self::live_value(keyspace, &key);
+        let exists = current.is_some();
+        let old = match current {
+            Some(RedisValue::String(old)) => Resp::BulkString(old.clone()),
+            Some(_) if get => return CommandError::WrongType.into(),
+            _ => Resp::Null,
+        };
+        let allowed = match condition {
+            Some(SetCondition::Nx) => !exists,
+            Some(SetCondition::Xx) => exists,
+            None => true,
+        };
+
+        let reply = match (get, allowed) {
+            (true, _) => old,
+            (false, true) => Resp::SimpleString("OK".to_string()),
+            (false, false) => Resp::Null,
+        };
+        if !allowed {
+            return reply;
+        }
+
+        let expiry = match expiry {
+            Some(SetExpiry::KeepTtl) => keyspace.expiry(&key),
+            _ => expires_at.map(|expires_at| expires_at as u64),
+        };
+
+        let mut propagated = vec![Bytes::from("SET"), key.clone(), value.clone()];
+
+        if let Some(expiry) = expiry {
+            keyspace.set_expiry(key.clone(), expiry);
+            propagated.push(Bytes::from("PXAT"));
+            propagated.push(Bytes::from(expiry.to_string()));
+        } else {
+            keyspace.remove_expiry(&key);
+        }
+
+        keyspace.insert(key, RedisValue::String(value));
+        keyspace.propagate(propagated);
+        reply
+    }
+
+    // NOTE: Like Redis, the generated keys are not propagated, so they are neither written to the
+    //       append only file nor sent to replicas.
+    fn debug_populate(
+        keyspace: &mut KeyspaceGuard,
+        count: u64,
+        prefix: &str,
+        size: Option<usize>,
+    ) -> Resp {
+        for i in 0..count {
+            let key = format!("{}:{}", prefix, i);
+            if keyspace.get(key.as_bytes()).is_some() {
+                continue;
+            }
+
+            let mut value = format!("value:{}", i).into_bytes();
+            if let Some(size) = size {
+                value.resize(size, 0);
+            }
+
+            keyspace.insert(Bytes::from(key), RedisValue::String(Bytes::from(value)));
+        }
+
+        Resp::SimpleString("OK".to_string())
+    }
+
+    // NOTE: Keys keep their time to live when they move: RENAME carries the source's expiry over,
+    //       COPY duplicates it and RESTORE applies its ttl argument. A destination that is
+    //       overwritten never keeps an expiry of its own.
+    fn rename(keyspace: &mut KeyspaceGuard, source: Bytes, destination: Bytes) -> Resp {
+        if Self::live_value(keyspace, &source).is_none() {
+            return CommandError::NoSuchKey.into();
+        }
+
+        let expiry = keyspace.expiry(&source);
+        let value = keyspace.remove(&source).unwrap();
+        Self::replace_key(keyspace, destination.clone(), value, expiry);
+
+        keyspace.propagate(vec![Bytes::from("RENAME"), source, destination]);
+        Resp::SimpleString("OK".to_string())
+    }
+
+    /// Checks the database COPY is given with DB, which in cluster mode can only be 0.
+    fn check_copy_target(&self, db: usize) -> Result<(), CommandError> {
+        if self.cluster.is_some() && db != 0 {
+            return Err(CommandError::CopyAcrossDatabasesInClusterMode);
+        }
+        match db < self.keyspace.databases() {
+            true => Ok(()),
+            false => Err(CommandError::DbIndexOutOfRange),
+        }
+    }
+
+    fn copy(
+        keyspace: &mut KeyspaceGuard,
+        source: Bytes,
+        destination: Bytes,
+        replace: bool,
+        db: Option<usize>,
+    ) -> Resp {
+        let origin = keyspace.db();
+        let target = db.unwrap_or(origin);
+        if target == origin && source == destination {
+            return CommandError::SameObject.into();
+        }
+        if Self::live_value(keyspace, &source).is_none() {
+            return Resp::Integer(0);
+        }
+
+        let value = keyspace.get_shared(&source).unwrap();
+        let expiry = keyspace.expiry(&source);
+        keyspace.select(target);
+        let copied = replace || Self::live_value(keyspace, &destination).is_none();
+        if copied {
+            Self::replace_key(keyspace, destination.clone(), value, expiry);
+        }
+        keyspace.select(origin);
+        if !copied {
+            return Resp::Integer(0);
+        }
+
+        let mut propagated = vec![Bytes::from("COPY"), source, destination];
+        if let Some(db) = db {
+            propagated.push(Bytes::from("DB"));
+            propagated.push(Bytes::from(db.to_string()));
+        }
+        if replace {
+            propagated.push(Bytes::from("REPLACE"));
+        }
+        keyspace.propagate(propagated);
+        Resp::Integer(1)
+    }
+
+    /// MOVE, which leaves a key where it is when the target database already has it.
+    fn move_key(&self, keyspace: &mut KeyspaceGuard, key: Bytes, db: usize) -> Resp {
+        if self.cluster.is_some() {
+            return CommandError::NotAllowedInClusterMode("MOVE").into();
+        }
+        if db >= self.keyspace.databases() {
+            return CommandError::DbIndexOutOfRange.into();
+        }
+        let origin = keyspace.db();
+        if db == origin {
+            return CommandError::SameObject.into();
+        }
+        if Self::live_value(keyspace, &key).is_none() {
+            return Resp::Integer(0);
+        }
+
+        keyspace.select(db);
+        let taken = Self::live_value(keyspace, &key).is_some();
+        keyspace.select(origin);
+        if taken {
+            return Resp::Integer(0);
+        }
+
+        let value = keyspace.get_shared(&key).unwrap();
+        let expiry = keyspace.expiry(&key);
+        keyspace.remove(&key);
+        keyspace.select(db);
+        Self::replace_key(keyspace, key.clone(), value, expiry);
+        keyspace.select(origin);
+
+        let db = Bytes::from(db.to_string());
+        keyspace.propagate(vec![Bytes::from("MOVE"), key, db]);
+        Resp::Integer(1)
+    }
+
+    fn swap_databases(&self, keyspace: &mut KeyspaceGuard, first: usize, second: usize) -> Resp {
+        if self.cluster.is_some() {
+            return CommandError::NotAllowedInClusterMode("SWAPDB").into();
+        }
+        let databases = self.keyspace.databases();
+        if first >= databases || second >= databases {
+            return CommandError::DbIndexOutOfRange.into();
+        }
+
+        keyspace.swap_databases(first, second);
+        keyspace.propagate(vec![
+            Bytes::from("SWAPDB"),
+            Bytes::from(first.to_string()),
+            Bytes::from(second.to_string()),
+        ]);
+        Resp::SimpleString("OK".to_string())
+    }
+
+    fn expire(
+        keyspace: &mut KeyspaceGuard,
+        key: Bytes,
+        time: i64,
+        milliseconds: bool,
+        absolute: bool,
+        conditions: &[ExpireCondition],
+    ) -> Resp {
+        let now = Self::ms_since_epoch() as i64;
+        let when = match (milliseconds, absolute) {
+            (true, true) => Some(time),
+            (true, false) => time.checked_add(now),
+            (false, true) => time.checked_mul(1000),
+            (false, false) => time
+                .checked_mul(1000)
+                .and_then(|time| time.checked_add(now)),
+        };
+        let Some(when) = when else {
+            let name = Command::expire_name(milliseconds, absolute);
+            return CommandError::InvalidExpireTime(name.to_string()).into();
+        };
+
+        if Self::live_value(keyspace, &key).is_none() {
+            return Resp::Integer(0);
+        }
+
+        let current = keyspace.expiry(&key).map(|expiry| expiry as i64);
+        if !conditions
+            .iter()
+            .all(|condition| condition.holds(current, when))
+        {
+            return Resp::Integer(0);
+        }
+
+        // Like Redis, a time in the past deletes the key rather than leaving it to expire.
+        if when <= now {
+            keyspace.remove(&key);
+            keyspace.propagate(vec![Bytes::from("DEL"), key]);
+            return Resp::Integer(1);
+        }
+
+        keyspace.set_expiry(key.clone(), when as u64);
+        keyspace.propagate(vec![
+            Bytes::from("PEXPIREAT"),
+            key,
+            Bytes::from(when.to_string()),
+        ]);
+        Resp::Integer(1)
+    }
+
+    // NOTE: UNLINK is the same as DEL. Values are reference counted and often shared with replies
+    //       still being written, so freeing them in the background wouldn't save much.
+    fn del(keyspace: &mut KeyspaceGuard, keys: Vec<Bytes>) -> Resp {
+        let mut deleted = vec![Bytes::from("DEL")];
+        for key in keys {
+            if Self::live_value(keyspace, &key).is_some() {
+                keyspace.remove(&key);
+                deleted.push(key);
+            }
+        }
+
+        let count = deleted.len() - 1;
+        if count > 0 {
+            keyspace.propagate(deleted);
+        }
+        Resp::Integer(count as i64)
+    }
+
+    // NOTE: The shard locks of every key are held for the whole command, so MSETNX checks and
+    //       sets them without anyone else seeing only some of the keys written.
+    fn mset(keyspace: &mut KeyspaceGuard, pairs: Vec<(Bytes, Bytes)>, only_new: bool) -> Resp {
+        if only_new
+            && pairs
+                .iter()
+                .any(|(key, _)| Self::live_value(keyspace, key).is_some())
+        {
+            return Resp::Integer(0);
+        }
+
+        let mut propagated = vec![Bytes::from("MSET")];
+        for (key, value) in pairs {
+            propagated.push(key.clone());
+            propagated.push(value.clone());
+            keyspace.remove_expiry(&key);
+            keyspace.insert(key, RedisValue::String(value));
+        }
+        keyspace.propagate(propagated);
+
+        match only_new {
+            true => Resp::Integer(1),
+            false => Resp::SimpleString("OK".to_string()),
+        }
+    }
+
+    fn push(keyspace: &mut KeyspaceGuard, key: Bytes, elements: Vec<Bytes>, left: bool) -> Resp {
+        let list = match Self::list_mut(keyspace, &key, true) {
+            Ok(list) => list.unwrap(),
+            Err(error) => return error.into(),
+        };
+        for element in &elements {
+            match left {
+                true => list.push_front(element.clone()),
+                false => list.push_back(element.clone()),
+            }
+        }
+        let length = list.len();
+
+        let mut propagated = vec![Bytes::from(if left { "LPUSH" } else { "RPUSH" }), key];
+        propagated.extend(elements);
+        keyspace.propagate(propagated);
+        Resp::Integer(length as i64)
+    }
+
+    /// Pops `count` elements off one end of a list, or a single one when no count is given. The
+    /// key is deleted once its list is empty.
+    fn pop(keyspace: &mut KeyspaceGuard, key: Bytes, count: Option<usize>, left: bool) -> Resp {
+        let list = match Self::list_mut(keyspace, &key, false) {
+            Ok(Some(list)) => list,
+            Ok(None) if count.is_some() => return Resp::NullArray,
+            Ok(None) => return Resp::Null,
+            Err(error) => return error.into(),
+        };
+
+        let popped = (0..count.unwrap_or(1))
+            .map_while(|_| match left {
+                true => list.pop_front(),
+                false => list.pop_back(),
+            })
+            .collect::<Vec<_>>();
+        if list.is_empty() {
+            keyspace.remove(&key);
+        }
+
+        if !popped.is_empty() {
+            keyspace.propagate(vec![
+                Bytes::from(if left { "LPOP" } else { "RPOP" }),
+                key,
+                Bytes::from(popped.len().to_string()),
+            ]);
+        }
+
+        match count {
+            Some(_) => Resp::Array(popped.into_iter().map(Resp::BulkString).collect()),
+            None => Resp::BulkString(popped.into_iter().next().unwrap()),
+        }
+    }
+
+    /// Inserts `element` next to the first occurrence of `pivot`, replying with the new length, or
+    /// -1 when the pivot isn't in the list.
+    fn linsert(
+        keyspace: &mut KeyspaceGuard,
+        key: Bytes,
+        before: bool,
+        pivot: Bytes,
+        element: Bytes,
+    ) -> Resp {
+        let list = match Self::list_mut(keyspace, &key, false) {
+            Ok(Some(list)) => list,
+            Ok(None) => return Resp::Integer(0),
+            Err(error) => return error.into(),
+        };
+        let Some(position) = list.iter().position(|candidate| *candidate == pivot) else {
+            return Resp::Integer(-1);
+        };
+
+        let index = if before { position } else { position + 1 };
+        list.insert(index, element.clone());
+        let length = list.len();
+
+        keyspace.propagate(vec![
+            Bytes::from("LINSERT"),
+            key,
+            Bytes::from(if before { "BEFORE" } else { "AFTER" }),
+            pivot,
+            element,
+        ]);
+        Resp::Integer(length as i64)
+    }
+
+    fn lset(keyspace: &mut KeyspaceGuard, key: Bytes, index: i64, element: Bytes) -> Resp {
+        let list = match Self::list_mut(keyspace, &key, false) {
+            Ok(Some(list)) => list,
+            Ok(None) => return CommandError::NoSuchKey.into(),
+            Err(error) => return error.into(),
+        };
+        let position = if index < 0 {
+            index + list.len() as i64
+        } else {
+            index
+        };
+        let Some(slot) = usize::try_from(position).ok().and_then(|i| list.get_mut(i)) else {
+            return CommandError::IndexOutOfRange.into();
+        };
+        *slot = element.clone();
+
+        keyspace.propagate(vec![
+            Bytes::from("LSET"),
+            key,
+            Bytes::from(index.to_string()),
+            element,
+        ]);
+        Resp::SimpleString("OK".to_string())
+    }
+
+    /// Removes up to `count` occurrences of `element`, from the head when `count` is positive and
+    /// from the tail when it's negative. Zero removes them all.
+    fn lrem(keyspace: &mut KeyspaceGuard, key: Bytes, count: i64, element: Bytes) -> Resp {
+        let list = match Self::list_mut(keyspace, &key, false) {
+            Ok(Some(list)) => list,
+            Ok(None) => return Resp::Integer(0),
+            Err(error) => return error.into(),
+        };
+
+        let limit = match count {
+            0 => usize::MAX,
+            count => count.unsigned_abs() as usize,
+        };
+        // Going from the tail means skipping the occurrences that are left in place at the head.
+        let occurrences = list
+            .iter()
+            .filter(|candidate| **candidate == element)
+            .count();
+        let mut skipped = match count < 0 {
+            true => occurrences.saturating_sub(limit),
+            false => 0,
+        };
+        let mut removed = 0;
+        list.retain(|candidate| {
+            if *candidate != element || removed == limit {
+                return true;
+            }
+            if skipped > 0 {
+                skipped -= 1;
+                return true;
+            }
+            removed += 1;
+            false
+        });
+
+        if list.is_empty() {
+            keyspace.remove(&key);
+        }
+        if removed > 0 {
+            keyspace.propagate(vec![
+                Bytes::from("LREM"),
+                key,
+                Bytes::from(count.to_string()),
+                element,
+            ]);
+        }
+        Resp::Integer(removed as i64)
+    }
+
+    /// Keeps only the elements between `start` and `stop`, deleting the key when none are left.
+    fn ltrim(keyspace: &mut KeyspaceGuard, key: Bytes, start: i64, stop: i64) -> Resp {
+        let list = match Self::list_mut(keyspace, &key, false) {
+            Ok(Some(list)) => list,
+            Ok(None) => return Resp::SimpleString("OK".to_string()),
+            Err(error) => return error.into(),
+        };
+
+        match index_range(list.len(), start, stop) {
+            Some(range) => {
+                list.truncate(range.end);
+                list.drain(..range.start);
+            }
+            None => list.clear(),
+        }
+        if list.is_empty() {
+            keyspace.remove(&key);
+        }
+
+        keyspace.propagate(vec![
+            Bytes::from("LTRIM"),
+            key,
+            Bytes::from(start.to_string()),
+            Bytes::from(stop.to_string()),
+        ]);
+        Resp::SimpleString("OK".to_string())
+    }
+
+    /// Sets every field of the hash at `key`, creating it when missing, and replies with how many
+    /// of the fields are new.
+    fn hset(keyspace: &mut KeyspaceGuard, key: Bytes, pairs: Vec<(Bytes, Bytes)>) -> Resp {
+        let hash = match Self::hash_mut(keyspace, &key, true) {
+            Ok(hash) => hash.unwrap(),
+            Err(error) => return error.into(),
+        };
+        let mut added = 0;
+        for (field, value) in &pairs {
+            if hash.insert(field.clone(), value.clone()).is_none() {
+                added += 1;
+            }
+        }
+
+        let mut propagated = vec![Bytes::from("HSET"), key];
+        propagated.extend(pairs.into_iter().flat_map(|(field, value)| [field, value]));
+        keyspace.propagate(propagated);
+        Resp::Integer(added)
+    }
+
+    /// Deletes fields from the hash at `key`, and the key itself once no fields are left.
+    fn hdel(keyspace: &mut KeyspaceGuard, key: Bytes, fields: Vec<Bytes>) -> Resp {
+        let hash = match Self::hash_mut(keyspace, &key, false) {
+            Ok(Some(hash)) => hash,
+            Ok(None) => return Resp::Integer(0),
+            Err(error) => return error.into(),
+        };
+        let mut deleted = vec![Bytes::from("HDEL"), key.clone()];
+        for field in fields {
+            if hash.remove(&field).is_some() {
+                deleted.push(field);
+            }
+        }
+        if hash.is_empty() {
+            keyspace.remove(&key);
+        }
+
+        let count = deleted.len() - 2;
+        if count > 0 {
+            keyspace.propagate(deleted);
+        }
+        Resp::Integer(count as i64)
+    }
+
+    fn hincr_by(keyspace: &mut KeyspaceGuard, key: Bytes, field: Bytes, increment: i64) -> Resp {
+        let hash = match Self::hash_mut(keyspace, &key, true) {
+            Ok(hash) => hash.unwrap(),
+            Err(error) => return error.into(),
+        };
+        let current = match hash.get(&field) {
+            Some(value) => match parse_strict_integer(value) {
+                Some(current) => current,
+                None => return CommandError::HashNotAnInteger.into(),
+            },
+            None => 0,
+        };
+
+        let Some(value) = current.checked_add(increment) else {
+            return CommandError::IncrementOverflow.into();
+        };
+        hash.insert(field.clone(), Bytes::from(value.to_string()));
+
+        keyspace.propagate(vec![
+            Bytes::from("HINCRBY"),
+            key,
+            field,
+            Bytes::from(increment.to_string()),
+        ]);
+        Resp::Integer(value)
+    }
+
+    fn hincr_by_float(
+        keyspace: &mut KeyspaceGuard,
+        key: Bytes,
+        field: Bytes,
+        increment: f64,
+    ) -> Resp {
+        let hash = match Self::hash_mut(keyspace, &key, true) {
+            Ok(hash) => hash.unwrap(),
+            Err(error) => return error.into(),
+        };
+        let current = match hash.get(&field) {
+            Some(value) => match parse_float(value) {
+                Some(current) => current,
+                None => return CommandError::HashNotAFloat.into(),
+            },
+            None => 0.0,
+        };
+
+        let value = current + increment;
+        if !value.is_finite() {
+            return CommandError::FloatOverflow.into();
+        }
+        let value = Bytes::from(value.to_string());
+        hash.insert(field.clone(), value.clone());
+
+        // Like INCRBYFLOAT, the result is propagated so replaying it can't round differently.
+        keyspace.propagate(vec![Bytes::from("HSET"), key, field, value.clone()]);
+        Resp::BulkString(value)
+    }
+
+    /// Picks random fields of a hash. A positive `count` picks that many distinct fields, while a
+    /// negative one picks as many as asked for, allowing the same field more than once.
+    fn hrandfield(
+        keyspace: &mut KeyspaceGuard,
+        key: Bytes,
+        count: Option<i64>,
+        with_values: bool,
+    ) -> Resp {
+        let fields = match Self::live_value(keyspace, &key) {
+            Some(RedisValue::Hash(hash)) => hash.iter().collect::<Vec<_>>(),
+            Some(_) => return CommandError::WrongType.into(),
+            None if count.is_some() => return Resp::Array(vec![]),
+            None => return Resp::Null,
+        };
+        let Some(count) = count else {
+            let (field, _) = fields[random_index(fields.len())];
+            return Resp::BulkString(field.clone());
+        };
+
+        let picked = match count >= 0 {
+            true => random_sample(fields, count as usize),
+            // NOTE: Not sized up front, the count comes from the client.
+            false => {
+                let mut picked = Vec::new();
+                for _ in 0..count.unsigned_abs() {
+                    picked.push(fields[random_index(fields.len())]);
+                }
+                picked
+            }
+        };
+        let picked = picked.into_iter();
+        match with_values {
+            true => Resp::Pairs(
+                picked
+                    .map(|(field, value)| {
+                        (
+                            Resp::BulkString(field.clone()),
+                            Resp::BulkString(value.clone()),
+                        )
+                    })
+                    .collect(),
+            ),
+            false => Resp::Array(
+                picked
+                    .map(|(field, _)| Resp::BulkString(field.clone()))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Adds members to the set at `key`, creating it when missing, and replies with how many of
+    /// them weren't in it yet.
+    fn sadd(keyspace: &mut KeyspaceGuard, key: Bytes, members: Vec<Bytes>) -> Resp {
+        let set = match Self::set_mut(keyspace, &key, true) {
+            Ok(set) => set.unwrap(),
+            Err(error) => return error.into(),
+        };
+        let mut added = vec![Bytes::from("SADD"), key];
+        for member in members {
+            if set.insert(member.clone()) {
+                added.push(member);
+            }
+        }
+
+        let count = added.len() - 2;
+        if count > 0 {
+            keyspace.propagate(added);
+        }
+        Resp::Integer(count as i64)
+    }
+
+    /// Removes members from the set at `key`, and the key itself once the set is empty.
+    fn srem(keyspace: &mut KeyspaceGuard, key: Bytes, members: Vec<Bytes>) -> Resp {
+        let set = match Self::set_mut(keyspace, &key, false) {
+            Ok(Some(set)) => set,
+            Ok(None) => return Resp::Integer(0),
+            Err(error) => return error.into(),
+        };
+        let mut removed = vec![Bytes::from("SREM"), key.clone()];
+        for member in members {
+            if set.remove(&member) {
+                removed.push(member);
+            }
+        }
+        if set.is_empty() {
+            keyspace.remove(&key);
+        }
+
+        let count = removed.len() - 2;
+        if count > 0 {
+            keyspace.propagate(removed);
+        }
+        Resp::Integer(count as i64)
+    }
+
+    /// Intersects, unites or subtracts the sets at `keys`, where missing keys count as empty sets.
+    /// The result is either the reply, or stored at `destination` replacing whatever was there.
+    fn set_operation(
+        keyspace: &mut KeyspaceGuard,
+        operation: SetOperation,
+        keys: Vec<Bytes>,
+        destination: Option<Bytes>,
+    ) -> Resp {
+        let sets = match Self::sets(keyspace, &keys) {
+            Ok(sets) => sets,
+            Err(error) => return error.into(),
+        };
+        let result = operation.apply(sets);
+
+        let Some(destination) = destination else {
+            return Resp::Set(result.into_iter().map(Resp::BulkString).collect());
+        };
+        let count = result.len();
+        match count {
+            0 => {
+                keyspace.remove(&destination);
+            }
+            _ => Self::replace_key(
+                keyspace,
+                destination.clone(),
+                Arc::new(RedisValue::Set(result)),
+                None,
+            ),
+        }
+
+        let mut propagated = vec![Bytes::from(operation.store_name()), destination];
+        propagated.extend(keys);
+        keyspace.propagate(propagated);
+        Resp::Integer(count as i64)
+    }
+
+    /// Removes random members from the set at `key`, a single one when no count is given.
+    // NOTE: The popped members are propagated as an SREM, so replicas remove the same ones.
+    fn spop(keyspace: &mut KeyspaceGuard, key: Bytes, count: Option<usize>) -> Resp {
+        let set = match Self::set_mut(keyspace, &key, false) {
+            Ok(Some(set)) => set,
+            Ok(None) if count.is_some() => return Resp::Set(vec![]),
+            Ok(None) => return Resp::Null,
+            Err(error) => return error.into(),
+        };
+
+        let members = set.iter().cloned().collect::<Vec<_>>();
+        let popped = random_sample(members, count.unwrap_or(1));
+        for member in &popped {
+            set.remove(member);
+        }
+        if set.is_empty() {
+            keyspace.remove(&key);
+        }
+
+        if !popped.is_empty() {
+            let mut propagated = vec![Bytes::from("SREM"), key];
+            propagated.extend(popped.iter().cloned());
+            keyspace.propagate(propagated);
+        }
+        match count {
+            Some(_) => Resp::Set(popped.into_iter().map(Resp::BulkString).collect()),
+            None => Resp::BulkString(popped.into_iter().next().unwrap()),
+        }
+    }
+
+    /// Moves `member` from one set to another, replying with whether it was in the source set.
+    fn smove(
+        keyspace: &mut KeyspaceGuard,
+        source: Bytes,
+        destination: Bytes,
+        member: Bytes,
+    ) -> Resp {
+        let sets = match Self::sets(keyspace, &[source.clone(), destination.clone()]) {
+            Ok(sets) => sets,
+            Err(error) => return error.into(),
+        };
+        if !sets[0].is_some_and(|set| set.contains(&member)) {
+            return Resp::Integer(0);
+        }
+        if source == destination {
+            return Resp::Integer(1);
+        }
+
+        let set = Self::set_mut(keyspace, &source, false).unwrap().unwrap();
+        set.remove(&member);
+        if set.is_empty() {
+            keyspace.remove(&source);
+        }
+        let set = Self::set_mut(keyspace, &destination, true)
+            .unwrap()
+            .unwrap();
+        set.insert(member.clone());
+
+        keyspace.propagate(vec![Bytes::from("SMOVE"), source, destination, member]);
+        Resp::Integer(1)
+    }
+
+    /// Adds members to the sorted set at `key` or updates their scores, as far as the NX, XX, GT
+    /// and LT conditions allow. Replies with how many members were added, or also changed with CH.
+    /// With INCR the score is added to instead, and the reply is the new score.
+    // NOTE: Only the resulting scores are propagated, so INCR can't round differently on replicas.
+    fn zadd(
+        keyspace: &mut KeyspaceGuard,
+        key: Bytes,
+        pairs: Vec<(f64, Bytes)>,
+        condition: Option<SetCondition>,
+        comparison: Option<Comparison>,
+        changed: bool,
+        increment: bool,
+    ) -> Resp {
+        // XX never adds members, so it mustn't create the set either.
+        let zset = match Self::zset_mut(keyspace, &key, condition != Some(SetCondition::Xx)) {
+            Ok(Some(zset)) => zset,
+            Ok(None) if increment => return Resp::Null,
+            Ok(None) => return Resp::Integer(0),
+            Err(error) => return error.into(),
+        };
+
+        let (mut added, mut updated) = (0, 0);
+        let mut new_score = None;
+        let mut propagated = vec![Bytes::from("ZADD"), key];
+        for (mut score, member) in pairs {
+            match zset.score(&member) {
+                Some(_) if condition == Some(SetCondition::Nx) => continue,
+                Some(current) => {
+                    if increment {
+                        score += current;
+                        if score.is_nan() {
+                            return CommandError::ScoreNan.into();
+                        }
+                    }
+                    if comparison
+                        .is_some_and(|comparison| score.partial_cmp(&current) != Some(comparison))
+                    {
+                        continue;
+                    }
+                    new_score = Some(score);
+                    if score == current {
+                        continue;
+                    }
+                    updated += 1;
+                }
+                None if condition == Some(SetCondition::Xx) => continue,
+                None => {
+                    new_score = Some(score);
+                    added += 1;
+                }
+            }
+
+            zset.insert(member.clone(), score);
+            propagated.push(Bytes::from(score.to_string()));
+            propagated.push(member);
+        }
+
+        if propagated.len() > 2 {
+            keyspace.propagate(propagated);
+        }
+        match increment {
+            true => new_score.map_or(Resp::Null, Resp::Double),
+            false if changed => Resp::Integer(added + updated),
+            false => Resp::Integer(added),
+        }
+    }
+
+    /// Replies with a range of the sorted set at `key`, by rank, score or member. A LIMIT then
+    /// skips `offset` members of the range and keeps up to `count` of them, or all with a negative
+    /// count.
+    fn zrange(
+        keyspace: &mut KeyspaceGuard,
+        key: Bytes,
+        by: ZRangeBy,
+        rev: bool,
+        limit: Option<(i64, i64)>,
+        with_scores: bool,
+    ) -> Resp {
+        let zset = match Self::live_value(keyspace, &key) {
+            Some(RedisValue::SortedSet(zset)) => zset,
+            Some(_) => return CommandError::WrongType.into(),
+            None => return Resp::Array(vec![]),
+        };
+
+        let mut members = match &by {
+            ZRangeBy::Rank(start, stop) => match index_range(zset.len(), *start, *stop) {
+                Some(range) if rev => zset
+                    .iter()
+                    .rev()
+                    .skip(range.start)
+                    .take(range.len())
+                    .collect(),
+                Some(range) => zset.iter().skip(range.start).take(range.len()).collect(),
+                None => vec![],
+            },
+            ZRangeBy::Score(min, max) => zset.range_by_score(*min, *max),
+            ZRangeBy::Lex(min, max) => zset.range_by_lex(min, max),
+        };
+        if rev && !matches!(by, ZRangeBy::Rank(..)) {
+            members.reverse();
+        }
+        if let Some((offset, count)) = limit {
+            let offset = usize::try_from(offset).unwrap_or(members.len());
+            let count = usize::try_from(count).unwrap_or(usize::MAX);
+            members = members.into_iter().skip(offset).take(count).collect();
+        }
+
+        let members = members.into_iter();
+        match with_scores {
+            true => Resp::Pairs(
+                members
+                    .map(|(member, score)| (Resp::BulkString(member.clone()), Resp::Double(score)))
+                    .collect(),
+            ),
+            false => Resp::Array(
+                members
+                    .map(|(member, _)| Resp::BulkString(member.clone()))
+                    .collect(),
+            ),
+        }
+    }
+
+    // NOTE: Like ZADD INCR, the resulting score is propagated so replicas can't round differently.
+    fn zincr_by(keyspace: &mut KeyspaceGuard, key: Bytes, increment: f64, member: Bytes) -> Resp {
+        let zset = match Self::zset_mut(keyspace, &key, true) {
+            Ok(zset) => zset.unwrap(),
+            Err(error) => return error.into(),
+        };
+        let score = zset.score(&member).unwrap_or(0.0) + increment;
+        if score.is_nan() {
+            return CommandError::ScoreNan.into();
+        }
+        zset.insert(member.clone(), score);
+
+        keyspace.propagate(vec![
+            Bytes::from("ZADD"),
+            key,
+            Bytes::from(score.to_string()),
+            member,
+        ]);
+        Resp::Double(score)
+    }
+
+    /// Pops the members with the lowest, or highest, scores off a sorted set, a single one when no
+    /// count is given. Replies with the members each followed by its score, paired up when a count
+    /// is given.
+    fn zpop(keyspace: &mut KeyspaceGuard, key: Bytes, count: Option<usize>, max: bool) -> Resp {
+        let zset = match Self::zset_mut(keyspace, &key, false) {
+            Ok(Some(zset)) => zset,
+            Ok(None) => return Resp::Array(vec![]),
+            Err(error) => return error.into(),
+        };
+
+        let mut popped = Vec::new();
+        for _ in 0..count.unwrap_or(1) {
+            let pair = match max {
+                true => zset.pop_last(),
+                false => zset.pop_first(),
+            };
+            let Some((member, score)) = pair else {
+                break;
+            };
+            popped.push((Resp::BulkString(member), Resp::Double(score)));
+        }
+        if zset.is_empty() {
+            keyspace.remove(&key);
+        }
+
+        if !popped.is_empty() {
+            keyspace.propagate(vec![
+                Bytes::from(if max { "ZPOPMAX" } else { "ZPOPMIN" }),
+                key,
+                Bytes::from(popped.len().to_string()),
+            ]);
+        }
+        match count {
+            Some(_) => Resp::Pairs(popped),
+            None => Resp::Array(
+                popped
+                    .into_iter()
+                    .flat_map(|(member, score)| [member, score])
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Pops a member off the first of `keys` holding a sorted set, replying with the key, the
+    /// member and its score, or `None` when none of them do.
+    fn zpop_first(keyspace: &mut KeyspaceGuard, keys: &[Bytes], max: bool) -> Option<Resp> {
+        for key in keys {
+            match Self::live_value(keyspace, key) {
+                Some(RedisValue::SortedSet(_)) => {
+                    let Resp::Array(popped) = Self::zpop(keyspace, key.clone(), None, max) else {
+                        unreachable!("popping a sorted set replies with an array");
+                    };
+                    let mut reply = vec![Resp::BulkString(key.clone())];
+                    reply.extend(popped);
+                    return Some(Resp::Array(reply));
+                }
+                Some(_) => return Some(CommandError::WrongType.into()),
+                None => {}
+            }
+        }
+
+        None
+    }
+
+    /// Removes members from the sorted set at `key`, and the key itself once it is empty.
+    fn zrem(keyspace: &mut KeyspaceGuard, key: Bytes, members: Vec<Bytes>) -> Resp {
+        let zset = match Self::zset_mut(keyspace, &key, false) {
+            Ok(Some(zset)) => zset,
+            Ok(None) => return Resp::Integer(0),
+            Err(error) => return error.into(),
+        };
+        let mut removed = vec![Bytes::from("ZREM"), key.clone()];
+        for member in members {
+            if zset.remove(&member).is_some() {
+                removed.push(member);
+            }
+        }
+        if zset.is_empty() {
+            keyspace.remove(&key);
+        }
+
+        let count = removed.len() - 2;
+        if count > 0 {
+            keyspace.propagate(removed);
+        }
+        Resp::Integer(count as i64)
+    }
+
+    /// The sets at `keys`, with `None` for the missing ones.
+    fn sets<'a>(
+        keyspace: &'a mut KeyspaceGuard,
+        keys: &[Bytes],
+    ) -> Result<Vec<Option<&'a HashSet<Bytes>>>, CommandError> {
+        for key in keys {
+            if let Some(value) = Self::live_value(keyspace, key) {
+                if !matches!(value, RedisValue::Set(_)) {
+                    return Err(CommandError::WrongType);
+                }
+            }
+        }
+
+        let keyspace = &*keyspace;
+        Ok(keys
+            .iter()
+            .map(|key| match keyspace.get(key) {
+                Some(RedisValue::Set(set)) => Some(set),
+                _ => None,
+            })
+            .collect())
+    }
+
+    /// Moves an element from one end of the `source` list to one end of the `destination` list,
+    /// which can be the same list. Replies with the element, or nil when the source is empty.
+    fn lmove(
+        keyspace: &mut KeyspaceGuard,
+        source: &Bytes,
+        destination: &Bytes,
+        from_left: bool,
+        to_left: bool,
+    ) -> Resp {
+        match Self::live_value(keyspace, source) {
+            Some(RedisValue::List(_)) => {}
+            Some(_) => return CommandError::WrongType.into(),
+            None => return Resp::Null,
+        }
+        if let Some(value) = Self::live_value(keyspace, destination) {
+            if !matches!(value, RedisValue::List(_)) {
+                return CommandError::WrongType.into();
+            }
+        }
+
+        let list = Self::list_mut(keyspace, source, false).unwrap().unwrap();
+        let element = match from_left {
+            true => list.pop_front(),
+            false => list.pop_back(),
+        };
+        let element = element.unwrap();
+        if list.is_empty() {
+            keyspace.remove(source);
+        }
+
+        let list = Self::list_mut(keyspace, destination, true)
+            .unwrap()
+            .unwrap();
+        match to_left {
+            true => list.push_front(element.clone()),
+            false => list.push_back(element.clone()),
+        }
+
+        let side = |left| Bytes::from(if left { "LEFT" } else { "RIGHT" });
+        keyspace.propagate(vec![
+            Bytes::from("LMOVE"),
+            source.clone(),
+            destination.clone(),
+            side(from_left),
+            side(to_left),
+        ]);
+        Resp::BulkString(element)
+    }
+
+    /// Pops an element off the first of `keys` holding a list, replying with the key and the
+    /// element, or `None` when none of them do.
+    fn pop_first(keyspace: &mut KeyspaceGuard, keys: &[Bytes], left: bool) -> Option<Resp> {
+        for key in keys {
+            match Self::live_value(keyspace, key) {
+                Some(RedisValue::List(_)) => {
+                    let element = Self::pop(keyspace, key.clone(), None, left);
+                    return Some(Resp::Array(vec![Resp::BulkString(key.clone()), element]));
+                }
+                Some(_) => return Some(CommandError::WrongType.into()),
+                None => {}
+            }
+        }
+
+        None
+    }
+
+    /// The list at `key` to modify, created empty when it's missing and `create` is set. Fails
+    /// when the key holds another type of value.
+    fn list_mut<'a>(
+        keyspace: &'a mut KeyspaceGuard,
+        key: &Bytes,
+        create: bool,
+    ) -> Result<Option<&'a mut VecDeque<Bytes>>, CommandError> {
+        match Self::live_value(keyspace, key) {
+            Some(RedisValue::List(_)) => {}
+            Some(_) => return Err(CommandError::WrongType),
+            None if create => keyspace.insert(key.clone(), RedisValue::List(VecDeque::new())),
+            None => return Ok(None),
+        }
+
+        match keyspace.get_mut(key) {
+            Some(RedisValue::List(list)) => Ok(Some(list)),
+            _ => unreachable!("the list was just checked for"),
+        }
+    }
+
+    /// The hash at `key` to change in place, created empty when missing if `create` is set.
+    fn hash_mut<'a>(
+        keyspace: &'a mut KeyspaceGuard,
+        key: &Bytes,
+        create: bool,
+    ) -> Result<Option<&'a mut HashMap<Bytes, Bytes>>, CommandError> {
+        match Self::live_value(keyspace, key) {
+            Some(RedisValue::Hash(_)) => {}
+            Some(_) => return Err(CommandError::WrongType),
+            None if create => keyspace.insert(key.clone(), RedisValue::Hash(HashMap::new())),
+            None => return Ok(None),
+        }
+
+        match keyspace.get_mut(key) {
+            Some(RedisValue::Hash(hash)) => Ok(Some(hash)),
+            _ => unreachable!("the hash was just checked for"),
+        }
+    }
+
+    /// The set at `key` to change in place, created empty when missing if `create` is set.
+    fn set_mut<'a>(
+        keyspace: &'a mut KeyspaceGuard,
+        key: &Bytes,
+        create: bool,
+    ) -> Result<Option<&'a mut HashSet<Bytes>>, CommandError> {
+        match Self::live_value(keyspace, key) {
+            Some(RedisValue::Set(_)) => {}
+            Some(_) => return Err(CommandError::WrongType),
+            None if create => keyspace.insert(key.clone(), RedisValue::Set(HashSet::new())),
+            None => return Ok(None),
+        }
+
+        match keyspace.get_mut(key) {
+            Some(RedisValue::Set(set)) => Ok(Some(set)),
+            _ => unreachable!("the set was just checked for"),
+        }
+    }
+
+    /// The sorted set at `key` to change in place, created empty when missing if `create` is set.
+    fn zset_mut<'a>(
+        keyspace: &'a mut KeyspaceGuard,
+        key: &Bytes,
+        create: bool,
+    ) -> Result<Option<&'a mut SortedSet>, CommandError> {
+        match Self::live_value(keyspace, key) {
+            Some(RedisValue::SortedSet(_)) => {}
+            Some(_) => return Err(CommandError::WrongType),
+            None if create => {
+                keyspace.insert(key.clone(), RedisValue::SortedSet(SortedSet::default()))
+            }
+            None => return Ok(None),
+        }
+
+        match keyspace.get_mut(key) {
+            Some(RedisValue::SortedSet(zset)) => Ok(Some(zset)),
+            _ => unreachable!("the sorted set was just checked for"),
+        }
+    }
+
+    /// Appends `value` to the string at `key`, creating it when missing, and replies with the new
+    /// length. The key keeps its time to live.
+    fn append(keyspace: &mut KeyspaceGuard, key: Bytes, value: Bytes) -> Resp {
+        let length = match Self::live_value(keyspace, &key) {
+            Some(RedisValue::String(current)) => {
+                let appended = Bytes::from([current.as_ref(), value.as_ref()].concat());
+                let length = appended.len();
+                keyspace.insert_modified_string(key.clone(), appended);
+                length
+            }
+            Some(_) => return CommandError::WrongType.into(),
+            // Like in Redis, a missing key is set rather than appended to.
+            None => {
+                keyspace.insert(key.clone(), RedisValue::String(value.clone()));
+                value.len()
+            }
+        };
+
+        keyspace.propagate(vec![Bytes::from("APPEND"), key, value]);
+        Resp::Integer(length as i64)
+    }
+
+    /// Sets or clears the bit at `offset` of the string at `key`, growing it with zero bytes as
+    /// needed. Replies with the bit it replaced.
+    fn setbit(&self, keyspace: &mut KeyspaceGuard, key: Bytes, offset: usize, value: bool) -> Resp {
+        let byte = offset / 8;
+        if byte >= self.proto_max_bulk_len() {
+            return CommandError::BitOffsetInvalid.into();
+        }
+        let mut bytes = match Self::live_value(keyspace, &key) {
+            Some(RedisValue::String(current)) => current.to_vec(),
+            Some(_) => return CommandError::WrongType.into(),
+            None => Vec::new(),
+        };
+        if bytes.len() <= byte {
+            bytes.resize(byte + 1, 0);
+        }
+
+        let old = bit_at(&bytes, offset);
+        let mask = 0x80 >> (offset % 8);
+        match value {
+            true => bytes[byte] |= mask,
+            false => bytes[byte] &= !mask,
+        }
+
+        keyspace.insert_modified_string(key.clone(), Bytes::from(bytes));
+        keyspace.propagate(vec![
+            Bytes::from("SETBIT"),
+            key,
+            Bytes::from(offset.to_string()),
+            Bytes::from(if value { "1" } else { "0" }),
+        ]);
+        Resp::Integer(old as i64)
+    }
+
+    /// Combines the strings at `keys` bit by bit into `destination`, replacing whatever was there.
+    /// Shorter strings, and missing keys, are padded with zero bytes to the longest one. Replies
+    /// with the length of the result, which deletes the destination when empty.
+    fn bitop(
+        keyspace: &mut KeyspaceGuard,
+        operation: BitOperation,
+        destination: Bytes,
+        keys: Vec<Bytes>,
+    ) -> Resp {
+        let mut values = Vec::with_capacity(keys.len());
+        for key in &keys {
+            match Self::live_value(keyspace, key) {
+                Some(RedisValue::String(value)) => values.push(value.clone()),
+                Some(_) => return CommandError::WrongType.into(),
+                None => values.push(Bytes::new()),
+            }
+        }
+
+        let len = values.iter().map(Bytes::len).max().unwrap_or(0);
+        let byte = |value: &Bytes, i: usize| value.get(i).copied().unwrap_or(0);
+        let result = (0..len)
+            .map(|i| {
+                let mut bytes = values.iter().map(|value| byte(value, i));
+                let first = bytes.next().unwrap();
+                match operation {
+                    BitOperation::And => bytes.fold(first, |result, byte| result & byte),
+                    BitOperation::Or => bytes.fold(first, |result, byte| result | byte),
+                    BitOperation::Xor => bytes.fold(first, |result, byte| result ^ byte),
+                    BitOperation::Not => !first,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        match result.is_empty() {
+            true => {
+                keyspace.remove(&destination);
+            }
+            false => Self::replace_key(
+                keyspace,
+                destination.clone(),
+                Arc::new(RedisValue::String(Bytes::from(result))),
+                None,
+            ),
+        }
+
+        let mut propagated = vec![
+            Bytes::from("BITOP"),
+            Bytes::from(operation.name()),
+            destination,
+        ];
+        propagated.extend(keys);
+        keyspace.propagate(propagated);
+        Resp::Integer(len as i64)
+    }
+
+    /// Overwrites part of the string at `key` starting at `offset`, padding it with zero bytes when
+    /// it is shorter, and replies with the new length.
+    fn setrange(
+        keyspace: &mut KeyspaceGuard,
+        key: Bytes,
+        offset: usize,
+        value: Bytes,
+        max_len: usize,
+    ) -> Resp {
+        let current = match Self::live_value(keyspace, &key) {
+            Some(RedisValue::String(current)) => current.clone(),
+            Some(_) => return CommandError::WrongType.into(),
+            None => Bytes::new(),
+        };
+
+        // An empty value changes nothing, and doesn't create a missing key either.
+        if value.is_empty() {
+            return Resp::Integer(current.len() as i64);
+        }
+        if offset + value.len() > max_len {
+            return CommandError::StringTooLong.into();
+        }
+
+        let mut updated = current.to_vec();
+        if updated.len() < offset + value.len() {
+            updated.resize(offset + value.len(), 0);
+        }
+        updated[offset..offset + value.len()].copy_from_slice(&value);
+        let length = updated.len();
+
+        keyspace.insert_modified_string(key.clone(), Bytes::from(updated));
+        keyspace.propagate(vec![
+            Bytes::from("SETRANGE"),
+            key,
+            Bytes::from(offset.to_string()),
+            value,
+        ]);
+        Resp::Integer(length as i64)
+    }
+
+    /// Adds `increment` to the integer stored at `key`, starting from 0 when it doesn't exist. The
+    /// key keeps its time to live.
+    fn incr_by(keyspace: &mut KeyspaceGuard, key: Bytes, increment: i64) -> Resp {
+        let current = match Self::live_value(keyspace, &key) {
+            Some(RedisValue::String(value)) => match parse_strict_integer(value) {
+                Some(current) => current,
+                None => return CommandError::NotAnInteger.into(),
+            },
+            Some(_) => return CommandError::WrongType.into(),
+            None => 0,
+        };
+
+        let Some(value) = current.checked_add(increment) else {
+            return CommandError::IncrementOverflow.into();
+        };
+
+        keyspace.insert(
+            key.clone(),
+            RedisValue::String(Bytes::from(value.to_string())),
+        );
+        keyspace.propagate(vec![
+            Bytes::from("INCRBY"),
+            key,
+            Bytes::from(increment.to_string()),
+        ]);
+        Resp::Integer(value)
+    }
+
+    // NOTE: Redis adds in long doubles, where this uses f64s. Results are printed in the shortest
+    //       form that reads back the same, so they can show f64 rounding, e.g. 0.1 + 0.2 is
+    //       0.30000000000000004 rather than 0.3.
+    fn incr_by_float(keyspace: &mut KeyspaceGuard, key: Bytes, increment: f64) -> Resp {
+        let current = match Self::live_value(keyspace, &key) {
+            Some(RedisValue::String(value)) => match parse_float(value) {
+                Some(current) => current,
+                None => return CommandError::NotAFloat.into(),
+            },
+            Some(_) => return CommandError::WrongType.into(),
+            None => 0.0,
+        };
+
+        let value = current + increment;
+        if !value.is_finite() {
+            return CommandError::FloatOverflow.into();
+        }
+
+        let value = Bytes::from(value.to_string());
+        keyspace.insert(key.clone(), RedisValue::String(value.clone()));
+
+        // The result is propagated rather than the increment, so replaying it can't round
+        // differently.
+        let mut propagated = vec![Bytes::from("SET"), key.clone(), value.clone()];
+        if let Some(expiry) = keyspace.expiry(&key) {
+            propagated.push(Bytes::from("PXAT"));
+            propagated.push(Bytes::from(expiry.to_string()));
+        }
+        keyspace.propagate(propagated);
+        Resp::BulkString(value)
+    }
+
+    fn ttl(keyspace: &mut KeyspaceGuard, key: Bytes, milliseconds: bool) -> Resp {
+        if Self::live_value(keyspace, &key).is_none() {
+            return Resp::Integer(-2);
+        }
+
+        let Some(expiry) = keyspace.expiry(&key) else {
+            return Resp::Integer(-1);
+        };
+
+        let ttl = expiry.saturating_sub(Self::ms_since_epoch()) as i64;
+        match milliseconds {
+            true => Resp::Integer(ttl),
+            // Rounded to the closest second, like Redis does.
+            false => Resp::Integer((ttl + 500) / 1000),
+        }
+    }
+
+    fn restore(
+        keyspace: &mut KeyspaceGuard,
+        key: Bytes,
+        ttl: u64,
+        payload: Bytes,
+        replace: bool,
+        absolute_ttl: bool,
+    ) -> Resp {
+        if !replace && Self::live_value(keyspace, &key).is_some() {
+            return CommandError::BusyKey.into();
+        }
+
+        let Some(value) = Rdb::restore_value(&payload) else {
+            return CommandError::BadDumpPayload.into();
+        };
+
+        let expiry = match (ttl, absolute_ttl) {
+            (0, _) => None,
+            (ttl, true) => Some(ttl),
+            (ttl, false) => Some(Self::ms_since_epoch() + ttl),
+        };
+
+        // A key restored already expired is just deleted, like Redis does.
+        if expiry.is_some_and(|expiry| expiry < Self::ms_since_epoch()) {
+            keyspace.remove(&key);
+            keyspace.propagate(vec![Bytes::from("DEL"), key]);
+            return Resp::SimpleString("OK".to_string());
+        }
+
+        Self::replace_key(keyspace, key.clone(), Arc::new(value), expiry);
+
+        keyspace.propagate(vec![
+            Bytes::from("RESTORE"),
+            key,
+            Bytes::from(expiry.unwrap_or(0).to_string()),
+            payload,
+            Bytes::from("ABSTTL"),
+            Bytes::from("REPLACE"),
+        ]);
+        Resp::SimpleString("OK".to_string())
+    }
+
+    fn replace_key(
+        keyspace: &mut KeyspaceGuard,
+        key: Bytes,
+        value: Arc<RedisValue>,
+        expiry: Option<u64>,
+    ) {
+        match expiry {
+            Some(expiry) => keyspace.set_expiry(key.clone(), expiry),
+            None => keyspace.remove_expiry(&key),
+        }
+        keyspace.insert_shared(key, value);
+    }
+
+    /// The value of `key`, unless it doesn't exist or has expired. An expired key is deleted on
+    /// the spot, so it stops taking up memory and showing up in KEYS.
+    fn live_value<'a>(keyspace: &'a mut KeyspaceGuard, key: &[u8]) -> Option<&'a RedisValue> {
+        if keyspace.expire_if_needed(key, Self::ms_since_epoch()) {
+            return None;
+        }
+
+        keyspace.get(key)
+    }
+
+    fn ms_since_epoch() -> u64 {
+        let since_the_epoch = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+
+        since_the_epoch.as_secs() * 1000 + since_the_epoch.subsec_nanos() as u64 / 1_000_000
+    }
+
+    fn get(keyspace: &mut KeyspaceGuard, key: Bytes) -> Resp {
+        match Self::live_value(keyspace, &key) {
+            Some(RedisValue::String(value)) => Resp::BulkString(value.clone()),
+            Some(_) => CommandError::WrongType.into(),
+            None => Resp::Null,
+        }
+    }
+}
+
+/// Parses a stored string as an integer the way Redis does, which refuses anything that wouldn't
+/// print back the same, like `+1`, `007` or surrounding spaces.
+pub(crate) fn parse_strict_integer(value: &[u8]) -> Option<i64> {
+    let value = std::str::from_utf8(value).ok()?;
+    let integer = value.parse::<i64>().ok()?;
+    (integer.to_string() == value).then_some(integer)
+}
+
+/// The range GETRANGE and LRANGE read from a string or list of `len` elements. Negative indexes
+/// count from the end and the range is clamped to the value, which leaves nothing to read when it
+/// is empty or starts past its end.
+fn index_range(len: usize, start: i64, end: i64) -> Option<std::ops::Range<usize>> {
+    let len = len as i64;
+    let start = if start < 0 {
+        (start + len).max(0)
+    } else {
+        start
+    };
+    let end = if end < 0 { end + len } else { end.min(len - 1) };
+
+    if len == 0 || end < 0 || start > end {
+        return None;
+    }
+    Some(start as usize..end as usize + 1)
+}
+
+/// A random index into something `len` long, which mustn't be empty.
+// NOTE: Like node IDs, this only needs to pick fairly, so std's randomly keyed hasher does without
+//       pulling in a random number generator.
+fn random_index(len: usize) -> usize {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos(),
+    );
+    (hasher.finish() % len as u64) as usize
+}
+
+/// Picks up to `count` distinct items at random, in no particular order.
+fn random_sample<T>(mut items: Vec<T>, count: usize) -> Vec<T> {
+    let count = count.min(items.len());
+    for i in 0..count {
+        let picked = i + random_index(items.len() - i);
+        items.swap(i, picked);
+    }
+    items.truncate(count);
+    items
+}
+
+/// Whether the bit at `offset` of a string is set, counting from the most significant bit of the
+/// first byte. Bits past the end of the string are clear.
+fn bit_at(value: &[u8], offset: usize) -> bool {
+    value
+        .get(offset / 8)
+        .is_some_and(|byte| byte & (0x80 >> (offset % 8)) != 0)
+}
+
+/// The bits of a string of `len` bytes that BITCOUNT and BITPOS look at. `start` and `end` count
+/// bytes, or bits if `bits` is set, and negative ones count from the end.
+fn bit_range(len: usize, start: i64, end: i64, bits: bool) -> Option<std::ops::Range<usize>> {
+    match bits {
+        true => index_range(len * 8, start, end),
+        false => index_range(len, start, end).map(|range| range.start * 8..range.end * 8),
+    }
+}
+
+/// Counts the set bits in a range of a string, a whole byte at a time where it can.
+fn count_bits(value: &[u8], bits: std::ops::Range<usize>) -> usize {
+    // The whole bytes in the range, from the first one starting at or after its start.
+    let (mut first_byte, end_byte) = (bits.start / 8, bits.end / 8);
+    if first_byte * 8 < bits.start {
+        first_byte += 1;
+    }
+    if first_byte >= end_byte {
+        return bits.filter(|offset| bit_at(value, *offset)).count();
+    }
+
+    let edges = (bits.start..first_byte * 8)
+        .chain(end_byte * 8..bits.end)
+        .filter(|offset| bit_at(value, *offset))
+        .count();
+    let bytes = value[first_byte..end_byte]
+        .iter()
+        .map(|byte| byte.count_ones() as usize)
+        .sum::<usize>();
+    edges + bytes
+}
+
+/// Parses a float argument or stored string. Like Redis, NaN and surrounding spaces are refused.
+fn parse_float(value: &[u8]) -> Option<f64> {
+    let value = std::str::from_utf8(value).ok()?;
+    value.parse::<f64>().ok().filter(|value| !value.is_nan())
+}
+
+#[derive(Debug, Error)]
+pub enum CommandError {
+    #[error("ERR {0}")]
+    Protocol(#[from] ParseError),
+    #[error("ERR Protocol error: expected a non-empty array of bulk strings")]
+    InvalidRequest,
+    #[error("ERR wrong number of arguments for '{0}' command")]
+    WrongArity(String),
+    #[error("ERR Unknown option or number of arguments for CONFIG SET - '{0}'")]
+    UnknownConfigParameter(String),
+    #[error("ERR CONFIG SET failed (possibly related to argument '{0}') - {1}")]
+    ConfigSet(String, ConfigError),
+    #[error("ERR The server is running without a config file")]
+    NoConfigFile,
+    #[error("ERR Rewriting config file: {0}")]
+    ConfigRewriteFailed(std::io::Error),
+    #[error("ERR unknown subcommand '{1}'. Try {0} HELP.")]
+    UnknownSubcommand(String, String),
+    #[error("WRONGTYPE Operation against a key holding the wrong kind of value")]
+    WrongType,
+    #[error("ERR syntax error")]
+    Syntax,
+    #[error("ERR value is not an integer or out of range")]
+    NotAnInteger,
+    #[error("ERR increment or decrement would overflow")]
+    IncrementOverflow,
+    #[error("ERR decrement would overflow")]
+    DecrementOverflow,
+    #[error("ERR offset is out of range")]
+    OffsetOutOfRange,
+    #[error("ERR string exceeds maximum allowed size (proto-max-bulk-len)")]
+    StringTooLong,
+    #[error("ERR timeout is not a float or out of range")]
+    TimeoutNotAFloat,
+    #[error("ERR timeout is negative")]
+    NegativeTimeout,
+    #[error("ERR index out of range")]
+    IndexOutOfRange,
+    #[error("ERR value is out of range, must be positive")]
+    NotPositive,
+    #[error("ERR value is out of range")]
+    ValueOutOfRange,
+    #[error("ERR value is not a valid float")]
+    NotAFloat,
+    #[error("ERR bit offset is not an integer or out of range")]
+    BitOffsetInvalid,
+    #[error("ERR bit is not an integer or out of range")]
+    BitNotAnInteger,
+    #[error("ERR The bit argument must be 1 or 0.")]
+    BitPosNotABit,
+    #[error("ERR BITOP NOT must be called with a single source key.")]
+    BitOpNotSingleKey,
+    #[error("ERR invalid cursor")]
+    InvalidCursor,
+    #[error("ERR An LFU maxmemory policy is not selected, access frequency not tracked. Please note that when switching between policies at runtime LRU and LFU data will take some time to adjust.")]
+    LfuNotSelected,
+    #[error("ERR numkeys should be greater than 0")]
+    NumKeysNotPositive,
+    #[error("ERR Number of keys can't be greater than number of args")]
+    TooManyKeys,
+    #[error("ERR LIMIT can't be negative")]
+    NegativeLimit,
+    #[error("ERR XX and NX options at the same time are not compatible")]
+    ZAddNxXxIncompatible,
+    #[error("ERR GT, LT, and/or NX options at the same time are not compatible")]
+    ZAddGtLtNxIncompatible,
+    #[error("ERR INCR option supports a single increment-element pair")]
+    ZAddIncrementPairs,
+    #[error("ERR resulting score is not a number (NaN)")]
+    ScoreNan,
+    #[error("ERR min or max is not a float")]
+    ScoreRangeNotAFloat,
+    #[error("ERR min or max not valid string range item")]
+    LexRangeInvalid,
+    #[error(
+        "ERR syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX"
+    )]
+    ZRangeLimitWithoutBy,
+    #[error("ERR syntax error, WITHSCORES not supported in combination with BYLEX")]
+    ZRangeWithScoresByLex,
+    #[error("ERR hash value is not an integer")]
+    HashNotAnInteger,
+    #[error("ERR hash value is not a float")]
+    HashNotAFloat,
+    #[error("ERR increment would produce NaN or Infinity")]
+    FloatOverflow,
+    #[error("ERR invalid expire time in '{0}' command")]
+    InvalidExpireTime(String),
+    #[error("ERR Unsupported option {0}")]
+    UnsupportedOption(String),
+    #[error("ERR NX and XX, GT or LT options at the same time are not compatible")]
+    ExpireNxIncompatible,
+    #[error("ERR GT and LT options at the same time are not compatible")]
+    ExpireGtLtIncompatible,
+    #[error("ERR Invalid TTL value, must be >= 0")]
+    InvalidTtl,
+    #[error("ERR no such key")]
+    NoSuchKey,
+    #[error("ERR DB index is out of range")]
+    DbIndexOutOfRange,
+    #[error("ERR invalid {0}DB index")]
+    InvalidDbIndex(&'static str),
+    #[error("ERR {0} is not allowed in cluster mode")]
+    NotAllowedInClusterMode(&'static str),
+    #[error("ERR Copying to another database is not allowed in cluster mode")]
+    CopyAcrossDatabasesInClusterMode,
+    #[error("ERR source and destination objects are the same")]
+    SameObject,
+    #[error("ERR MULTI calls can not be nested")]
+    NestedMulti,
+    #[error("ERR EXEC without MULTI")]
+    ExecWithoutMulti,
+    #[error("ERR DISCARD without MULTI")]
+    DiscardWithoutMulti,
+    #[error("EXECABORT Transaction discarded because of previous errors.")]
+    ExecAbort,
+    #[error(
+        "ERR Can't execute '{0}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET \
+        are allowed in this context"
+    )]
+    SubscribeContext(String),
+    #[error("ERR {0} isn't allowed for a DENY BLOCKING client")]
+    DenyBlocking(&'static str),
+    #[error("NOPROTO unsupported protocol version")]
+    NoProto,
+    #[error("ERR Protocol version is not an integer or out of range")]
+    InvalidProtocolVersion,
+    #[error("WRONGPASS invalid username-password pair or user is disabled.")]
+    WrongPass,
+    #[error("ERR A client can only redirect to a single other client")]
+    MultipleRedirects,
+    #[error("ERR The client ID you want redirect to does not exist")]
+    NoSuchRedirectClient,
+    #[error("ERR PREFIX option requires BCAST mode to be enabled")]
+    PrefixWithoutBcast,
+    #[error(
+        "ERR You can't switch {0} before disabling tracking for this client, and then \
+        re-enabling it with a different mode."
+    )]
+    TrackingModeSwitch(&'static str),
+    #[error("ERR OPTIN and OPTOUT are not compatible with BCAST")]
+    OptInOptOutWithBcast,
+    #[error("ERR You can't use both OPTIN and OPTOUT")]
+    OptInAndOptOut,
+    #[error(
+        "ERR Prefix '{0}' overlaps with {1} prefix '{2}'. Prefixes for a single client must not \
+        overlap."
+    )]
+    PrefixOverlap(String, &'static str, String),
+    #[error(
+        "ERR CLIENT CACHING can be called only when the client is in tracking mode with OPTIN or \
+        OPTOUT mode enabled"
+    )]
+    CachingWithoutTracking,
+    #[error("ERR CLIENT CACHING {0} is only valid when tracking is enabled in {1} mode.")]
+    CachingMode(&'static str, &'static str),
+    #[error("BUSYKEY Target key name already exists.")]
+    BusyKey,
+    #[error("ERR DUMP payload version or checksum are wrong")]
+    BadDumpPayload,
+    #[error("ERR Invalid command specified")]
+    InvalidCommandSpecified,
+    #[error("ERR Invalid number of arguments specified for command")]
+    InvalidCommandArguments,
+    #[error("ERR The command has no key arguments")]
+    NoKeyArguments,
+    #[error("ERR This instance has cluster support disabled")]
+    ClusterSupportDisabled,
+    #[error("MOVED {0} {1}")]
+    Moved(u16, String),
+    #[error("CROSSSLOT Keys in request don't hash to the same slot")]
+    CrossSlot,
+    #[error("CLUSTERDOWN Hash slot not served")]
+    ClusterDown,
+    #[error("ERR Invalid or out of range slot")]
+    InvalidSlot,
+    #[error("ERR start slot number {0} is greater than end slot number {1}")]
+    SlotRangeReversed(u16, u16),
+    #[error("ERR Slot {0} specified multiple times")]
+    SlotSpecifiedMultipleTimes(u16),
+    #[error("ERR Slot {0} is already busy")]
+    SlotBusy(u16),
+    #[error("ERR Slot {0} is already unassigned")]
+    SlotUnassigned(u16),
+    #[error("ERR persistence is disabled, set save or appendonly to take snapshots")]
+    PersistenceDisabled,
+    #[error("ERR {0}")]
+    SaveFailed(PersistenceError),
+    #[error("ERR Background save already in progress")]
+    BackgroundSaveInProgress,
+    #[error("ERR Invalid node address specified: {0}")]
+    InvalidNodeAddress(String),
+    #[error("ERR Invalid base port specified: {0}")]
+    InvalidBasePort(String),
+    #[error("ERR Invalid bus port specified: {0}")]
+    InvalidBusPort(String),
+    #[error("ERR Can't reach node {0}: {1}")]
+    NodeUnreachable(String, String),
+    #[error("ERR I tried hard but I can't forget myself...")]
+    CantForgetMyself,
+    #[error("ERR Unknown node {0}")]
+    UnknownNode(String),
+    #[error("ERR Unrecognized REPLCONF option: {0}")]
+    UnrecognizedReplConfOption(String),
+    #[error("READONLY You can't write against a read only replica.")]
+    ReadOnlyReplica,
+    #[error("ERR unknown command '{0}', with args beginning with: {1}")]
+    UnknownCommand(String, String),
+}
+
+impl CommandError {
+    // NOTE: A huge argument must not make for a huge error, so only its beginning is echoed.
+    fn unknown_command(name: &str, args: &[Resp]) -> CommandError {
+        let prefix = |text: &str, len: usize| {
+            let mut end = len.min(text.len());
+            while !text.is_char_boundary(end) {
+                end -= 1;
+            }
+            text[..end].to_string()
+        };
+
+        let mut echoed = String::new();
+        for arg in args {
+            if echoed.len() >= MAX_ECHOED_ARGS_LEN {
+                break;
+            }
+            let arg = prefix(&arg.to_string(), MAX_ECHOED_ARGS_LEN - echoed.len());
+            echoed.push_str(&format!("'{}' ", arg));
+        }
+        CommandError::UnknownCommand(prefix(name, MAX_ECHOED_ARGS_LEN), echoed)
+    }
+}
+
+impl From<CommandError> for Resp {
+    // NOTE: Errors can echo arguments back, which must not break the simple string framing.
+    fn from(error: CommandError) -> Self {
+        Resp::SimpleError(error.to_string().replace(['\r', '\n'], " "))
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum Command {
     Ping,
     Echo {
-        message: String,
+        message: Bytes,
     },
     Set {
-        key: String,
-        value: String,
-        options: Vec<(String, Option<String>)>,
+        key: Bytes,
+        value: Bytes,
+        expiry: Option<SetExpiry>,
+        condition: Option<SetCondition>,
+        get: bool,
     },
     Get {
+        key: Bytes,
+    },
+    // TODO: CONFIG GET actually supports multiple glob like parameters, but we only support one
+    ConfigGet {
         key: String,
     },
-    // TODO: CONFIG GET actually supports multiple glob like parameters, but we only support the simple case
-    ConfigGet {
-        key: String,
+    /// Parameters by their lowercase name, with their new values.
+    ConfigSet {
+        parameters: Vec<(String, String)>,
+    },
+    ConfigRewrite,
+    Keys {
+        pattern: String,
+    },
+    DbSize,
+    RandomKey,
+    Select {
+        db: usize,
+    },
+    SwapDb {
+        first: usize,
+        second: usize,
+    },
+    Move {
+        key: Bytes,
+        db: usize,
+    },
+    FlushDb,
+    FlushAll,
+    Multi,
+    Exec,
+    Discard,
+    /// SUBSCRIBE, or PSUBSCRIBE with `pattern`, where the channels are glob-style patterns.
+    Subscribe {
+        channels: Vec<Bytes>,
+        pattern: bool,
+    },
+    /// Every channel or pattern the connection is subscribed to when none are given.
+    Unsubscribe {
+        channels: Vec<Bytes>,
+        pattern: bool,
+    },
+    PubSubChannels {
+        pattern: Option<Bytes>,
+    },
+    PubSubNumSub {
+        channels: Vec<Bytes>,
+    },
+    PubSubNumPat,
+    Publish {
+        channel: Bytes,
+        message: Bytes,
+    },
+    Scan {
+        cursor: u64,
+        options: ScanOptions,
+    },
+    /// HSCAN, SSCAN or ZSCAN.
+    ElementScan {
+        scan: ElementScan,
+        key: Bytes,
+        cursor: u64,
+        options: ScanOptions,
+    },
+    Rename {
+        source: Bytes,
+        destination: Bytes,
+    },
+    Copy {
+        source: Bytes,
+        destination: Bytes,
+        replace: bool,
+        /// The database to copy to, the selected one when not given.
+        db: Option<usize>,
+    },
+    Del {
+        keys: Vec<Bytes>,
+        unlink: bool,
+    },
+    Exists {
+        keys: Vec<Bytes>,
+    },
+    MGet {
+        keys: Vec<Bytes>,
+    },
+    /// MSET, or MSETNX when only new keys may be set.
+    MSet {
+        pairs: Vec<(Bytes, Bytes)>,
+        only_new: bool,
+    },
+    Append {
+        key: Bytes,
+        value: Bytes,
+    },
+    Strlen {
+        key: Bytes,
+    },
+    Type {
+        key: Bytes,
+    },
+    /// LPUSH, or RPUSH when pushing to the right.
+    Push {
+        key: Bytes,
+        elements: Vec<Bytes>,
+        left: bool,
+    },
+    /// LPOP, or RPOP when popping from the right.
+    Pop {
+        key: Bytes,
+        count: Option<usize>,
+        left: bool,
+    },
+    /// BLPOP, or BRPOP when popping from the right. Waits forever without a timeout.
+    BlockingPop {
+        keys: Vec<Bytes>,
+        timeout: Option<Duration>,
+        left: bool,
+    },
+    LMove {
+        source: Bytes,
+        destination: Bytes,
+        from_left: bool,
+        to_left: bool,
+    },
+    BlockingMove {
+        source: Bytes,
+        destination: Bytes,
+        from_left: bool,
+        to_left: bool,
+        timeout: Option<Duration>,
+    },
+    LLen {
+        key: Bytes,
+    },
+    LInsert {
+        key: Bytes,
+        before: bool,
+        pivot: Bytes,
+        element: Bytes,
+    },
+    LSet {
+        key: Bytes,
+        index: i64,
+        element: Bytes,
+    },
+    LRem {
+        key: Bytes,
+        count: i64,
+        element: Bytes,
+    },
+    LTrim {
+        key: Bytes,
+        start: i64,
+        stop: i64,
+    },
+    LRange {
+        key: Bytes,
+        start: i64,
+        stop: i64,
+    },
+    HSet {
+        key: Bytes,
+        pairs: Vec<(Bytes, Bytes)>,
+    },
+    HGet {
+        key: Bytes,
+        field: Bytes,
+    },
+    HExists {
+        key: Bytes,
+        field: Bytes,
+    },
+    HDel {
+        key: Bytes,
+        fields: Vec<Bytes>,
+    },
+    HGetAll {
+        key: Bytes,
+    },
+    HLen {
+        key: Bytes,
+    },
+    HKeys {
+        key: Bytes,
+    },
+    HVals {
+        key: Bytes,
+    },
+    HMGet {
+        key: Bytes,
+        fields: Vec<Bytes>,
+    },
+    HIncrBy {
+        key: Bytes,
+        field: Bytes,
+        increment: i64,
+    },
+    HIncrByFloat {
+        key: Bytes,
+        field: Bytes,
+        increment: f64,
+    },
+    HRandField {
+        key: Bytes,
+        count: Option<i64>,
+        with_values: bool,
+    },
+    SAdd {
+        key: Bytes,
+        members: Vec<Bytes>,
+    },
+    SRem {
+        key: Bytes,
+        members: Vec<Bytes>,
+    },
+    SMembers {
+        key: Bytes,
+    },
+    SIsMember {
+        key: Bytes,
+        member: Bytes,
+    },
+    SCard {
+        key: Bytes,
+    },
+    SetOperation {
+        operation: SetOperation,
+        keys: Vec<Bytes>,
+        destination: Option<Bytes>,
+    },
+    SInterCard {
+        keys: Vec<Bytes>,
+        limit: usize,
+    },
+    SPop {
+        key: Bytes,
+        count: Option<usize>,
+    },
+    SRandMember {
+        key: Bytes,
+        count: Option<i64>,
+    },
+    SMove {
+        source: Bytes,
+        destination: Bytes,
+        member: Bytes,
+    },
+    ZAdd {
+        key: Bytes,
+        pairs: Vec<(f64, Bytes)>,
+        condition: Option<SetCondition>,
+        /// Only updates scores that compare this way to the current score, for GT and LT.
+        comparison: Option<Comparison>,
+        changed: bool,
+        increment: bool,
+    },
+    ZScore {
+        key: Bytes,
+        member: Bytes,
+    },
+    ZRange {
+        key: Bytes,
+        by: ZRangeBy,
+        rev: bool,
+        limit: Option<(i64, i64)>,
+        with_scores: bool,
+        /// Given as one of the older commands, like ZRANGEBYSCORE, rather than as ZRANGE.
+        legacy: bool,
+    },
+    ZRank {
+        key: Bytes,
+        member: Bytes,
+        rev: bool,
+        with_score: bool,
+    },
+    ZRem {
+        key: Bytes,
+        members: Vec<Bytes>,
+    },
+    ZIncrBy {
+        key: Bytes,
+        increment: f64,
+        member: Bytes,
+    },
+    ZCard {
+        key: Bytes,
+    },
+    ZCount {
+        key: Bytes,
+        min: ScoreBound,
+        max: ScoreBound,
+    },
+    ZPop {
+        key: Bytes,
+        count: Option<usize>,
+        max: bool,
+    },
+    BlockingZPop {
+        keys: Vec<Bytes>,
+        timeout: Option<Duration>,
+        max: bool,
+    },
+    GetRange {
+        key: Bytes,
+        start: i64,
+        end: i64,
+    },
+    SetRange {
+        key: Bytes,
+        offset: usize,
+        value: Bytes,
+    },
+    SetBit {
+        key: Bytes,
+        offset: usize,
+        value: bool,
+    },
+    GetBit {
+        key: Bytes,
+        offset: usize,
+    },
+    BitCount {
+        key: Bytes,
+        /// The start and end of the range, and whether they count bits rather than bytes.
+        range: Option<(i64, i64, bool)>,
+    },
+    BitPos {
+        key: Bytes,
+        bit: bool,
+        start: Option<i64>,
+        end: Option<i64>,
+        bits: bool,
+    },
+    BitOp {
+        operation: BitOperation,
+        destination: Bytes,
+        keys: Vec<Bytes>,
+    },
+    Object {
+        subcommand: ObjectSubcommand,
+        key: Bytes,
+    },
+    IncrByFloat {
+        key: Bytes,
+        increment: f64,
+    },
+    /// INCR, DECR, INCRBY and DECRBY. INCR and DECR have no increment and step by one.
+    IncrBy {
+        key: Bytes,
+        increment: Option<i64>,
+        decrement: bool,
+    },
+    Ttl {
+        key: Bytes,
+        milliseconds: bool,
+    },
+    /// EXPIRE and its PEXPIRE, EXPIREAT and PEXPIREAT variants.
+    Expire {
+        key: Bytes,
+        time: i64,
+        milliseconds: bool,
+        absolute: bool,
+        conditions: Vec<ExpireCondition>,
+    },
+    Dump {
+        key: Bytes,
+    },
+    Restore {
+        key: Bytes,
+        ttl: u64,
+        payload: Bytes,
+        replace: bool,
+        absolute_ttl: bool,
+    },
+    CommandCount,
+    /// COMMAND INFO, or COMMAND on its own, for every command when no names are given.
+    CommandInfo {
+        names: Vec<String>,
+    },
+    CommandDocs {
+        names: Vec<String>,
+    },
+    GetKeys {
+        command: Box<Command>,
+        with_flags: bool,
+    },
+    LatencyHistogram {
+        commands: Vec<String>,
+    },
+    DebugPopulate {
+        count: u64,
+        prefix: String,
+        size: Option<usize>,
+    },
+    BigKeys {
+        count: usize,
+    },
+    MemoryStats,
+    Save,
+    BgSave,
+    LastSave,
+    Info {
+        sections: Vec<String>,
+    },
+    ClusterNodes,
+    ClusterMyId,
+    ClusterMeet {
+        ip: IpAddr,
+        cport: u16,
+    },
+    ClusterForget {
+        id: String,
+    },
+    ReadOnly,
+    ReadWrite,
+    /// HELLO, with the protocol version to switch to, the AUTH username and password and the
+    /// SETNAME name.
+    Hello {
+        protocol: Option<Protocol>,
+        auth: Option<(Bytes, Bytes)>,
+        name: Option<Bytes>,
+    },
+    ClientId,
+    /// CLIENT TRACKING ON with its options, or OFF.
+    ClientTracking {
+        options: Option<TrackingOptions>,
+    },
+    ClientCaching {
+        yes: bool,
+    },
+    ClientGetRedir,
+    ReplConf {
+        options: Vec<(String, String)>,
+    },
+    Psync {
+        replid: String,
+        offset: i64,
     },
-    Keys {
-        pattern: String,
+    /// CLUSTER ADDSLOTS and DELSLOTS, or their RANGE variants.
+    ClusterSlots {
+        slots: Vec<u16>,
+        assign: bool,
+        ranges: bool,
     },
-    NotImplemented {
-        cmd: String,
+}
+
+/// The EX, PX, EXAT, PXAT and KEEPTTL options of SET, with the time as given.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SetExpiry {
+    Ex(i64),
+    Px(i64),
+    ExAt(i64),
+    PxAt(i64),
+    KeepTtl,
+}
+
+/// The options of the SCAN family.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScanOptions {
+    pattern: Option<Bytes>,
+    count: usize,
+    /// Only for SCAN, to only return keys of a type.
+    type_name: Option<String>,
+    /// Only for HSCAN, to return fields without their values.
+    no_values: bool,
+}
+
+impl ScanOptions {
+    fn matches(&self, element: &[u8]) -> bool {
+        match &self.pattern {
+            Some(pattern) => glob_match(pattern, element),
+            None => true,
+        }
+    }
+}
+
+/// The keys a SCAN found so far, and how much work it has left to find more.
+struct ScanBatch {
+    keys: Vec<Resp>,
+    visited: usize,
+    /// Like in Redis, a scan stops after ten times COUNT steps even if it found nothing, so a
+    /// sparse table can't keep a shard locked for long.
+    steps_left: usize,
+}
+
+impl ScanBatch {
+    fn new(count: usize) -> ScanBatch {
+        ScanBatch {
+            keys: Vec::new(),
+            visited: 0,
+            steps_left: count.saturating_mul(10),
+        }
+    }
+
+    fn is_full(&self, count: usize) -> bool {
+        self.visited >= count || self.steps_left == 0
+    }
+}
+
+/// The collection walked by HSCAN, SSCAN or ZSCAN.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ElementScan {
+    Hash,
+    Set,
+    SortedSet,
+}
+
+/// What OBJECT tells about a key.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ObjectSubcommand {
+    Encoding,
+    RefCount,
+    IdleTime,
+    Freq,
+}
+
+/// The bitwise operations of BITOP.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BitOperation {
+    And,
+    Or,
+    Xor,
+    Not,
+}
+
+impl BitOperation {
+    fn name(self) -> &'static str {
+        match self {
+            BitOperation::And => "AND",
+            BitOperation::Or => "OR",
+            BitOperation::Xor => "XOR",
+            BitOperation::Not => "NOT",
+        }
+    }
+}
+
+/// The set algebra of SINTER, SUNION and SDIFF.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SetOperation {
+    Inter,
+    Union,
+    Diff,
+}
+
+impl SetOperation {
+    /// Combines the given sets, where `None` stands for a missing key. SDIFF subtracts the rest of
+    /// the sets from the first one.
+    fn apply(self, sets: Vec<Option<&HashSet<Bytes>>>) -> HashSet<Bytes> {
+        let mut sets = match self {
+            SetOperation::Inter => match sets.into_iter().collect::<Option<Vec<_>>>() {
+                Some(sets) => sets,
+                None => return HashSet::new(),
+            },
+            SetOperation::Union => sets.into_iter().flatten().collect(),
+            SetOperation::Diff => match sets.split_first() {
+                Some((Some(first), rest)) => {
+                    let rest = rest.iter().flatten().collect::<Vec<_>>();
+                    return first
+                        .iter()
+                        .filter(|member| !rest.iter().any(|set| set.contains(*member)))
+                        .cloned()
+                        .collect();
+                }
+                _ => return HashSet::new(),
+            },
+        };
+
+        match self {
+            SetOperation::Union => sets.into_iter().flatten().cloned().collect(),
+            _ => {
+                sets.sort_by_key(|set| set.len());
+                let (smallest, rest) = sets.split_first().unwrap();
+                smallest
+                    .iter()
+                    .filter(|member| rest.iter().all(|set| set.contains(*member)))
+                    .cloned()
+                    .collect()
+            }
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            SetOperation::Inter => "sinter",
+            SetOperation::Union => "sunion",
+            SetOperation::Diff => "sdiff",
+        }
+    }
+
+    fn store_name(self) -> &'static str {
+        match self {
+            SetOperation::Inter => "SINTERSTORE",
+            SetOperation::Union => "SUNIONSTORE",
+            SetOperation::Diff => "SDIFFSTORE",
+        }
+    }
+}
+
+/// What ZRANGE selects members by: their rank, their score or the members themselves.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ZRangeBy {
+    Rank(i64, i64),
+    Score(ScoreBound, ScoreBound),
+    Lex(LexBound, LexBound),
+}
+
+/// The NX and XX options of SET.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SetCondition {
+    Nx,
+    Xx,
+}
+
+/// The NX, XX, GT and LT options of the EXPIRE family. All the given ones must hold for the
+/// expiry to be set.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExpireCondition {
+    Nx,
+    Xx,
+    Gt,
+    Lt,
+}
+
+impl ExpireCondition {
+    /// Whether the condition holds when moving the expiry from `current` to `new`. A key without
+    /// an expiry counts as living forever, so GT never holds for it and LT always does.
+    fn holds(self, current: Option<i64>, new: i64) -> bool {
+        match self {
+            ExpireCondition::Nx => current.is_none(),
+            ExpireCondition::Xx => current.is_some(),
+            ExpireCondition::Gt => current.is_some_and(|current| new > current),
+            ExpireCondition::Lt => match current {
+                Some(current) => new < current,
+                None => true,
+            },
+        }
+    }
+}
+
+/// The keys a command reads or writes, which decides the shard locks it needs.
+pub enum KeyScope<'a> {
+    Keys(Vec<&'a [u8]>),
+    /// The keys in both the selected database and database `db`.
+    Across {
+        db: usize,
+        keys: Vec<&'a [u8]>,
     },
+    /// Every key of the selected database.
+    All,
+    /// Every key of two databases.
+    Databases(usize, usize),
+    /// Every key of every database.
+    Everything,
+}
+
+/// A key argument of a command, with the flags COMMAND GETKEYSANDFLAGS reports for it.
+pub struct KeySpec<'a> {
+    pub key: &'a [u8],
+    pub flags: &'static [&'static str],
+}
+
+// Key spec flags, spelled the way Redis reports them.
+const READ: &[&str] = &["RO", "access"];
+const READ_WRITE: &[&str] = &["RW", "access", "update"];
+const OVERWRITE: &[&str] = &["OW", "update"];
+const UPDATE: &[&str] = &["RW", "update"];
+const DELETE: &[&str] = &["RM", "delete"];
+const INSERT: &[&str] = &["RW", "insert"];
+const REMOVE: &[&str] = &["RW", "delete"];
+const READ_DELETE: &[&str] = &["RW", "access", "delete"];
+
+impl Command {
+    /// The key arguments of the command, in the order they appear on the command line.
+    pub fn key_specs(&self) -> Vec<KeySpec<'_>> {
+        fn spec<'a>(key: &'a Bytes, flags: &'static [&'static str]) -> KeySpec<'a> {
+            KeySpec { key, flags }
+        }
+
+        match self {
+            Command::Get { key }
+            | Command::Dump { key }
+            | Command::Object { key, .. }
+            | Command::ElementScan { key, .. }
+            | Command::Ttl { key, .. }
+            | Command::Strlen { key }
+            | Command::Type { key }
+            | Command::LLen { key }
+            | Command::LRange { key, .. }
+            | Command::HGet { key, .. }
+            | Command::HExists { key, .. }
+            | Command::HGetAll { key }
+            | Command::HLen { key }
+            | Command::HKeys { key }
+            | Command::HVals { key }
+            | Command::HMGet { key, .. }
+            | Command::HRandField { key, .. }
+            | Command::SMembers { key }
+            | Command::SIsMember { key, .. }
+            | Command::SCard { key }
+            | Command::SRandMember { key, .. }
+            | Command::ZScore { key, .. }
+            | Command::ZRange { key, .. }
+            | Command::ZRank { key, .. }
+            | Command::ZCard { key }
+            | Command::ZCount { key, .. }
+            | Command::GetBit { key, .. }
+            | Command::BitCount { key, .. }
+            | Command::BitPos { key, .. }
+            | Command::GetRange { key, .. } => {
+                vec![spec(key, READ)]
+            }
+            Command::Set { key, .. } => vec![spec(key, READ_WRITE)],
+            Command::Expire { key, .. }
+            | Command::SetRange { key, .. }
+            | Command::SetBit { key, .. } => vec![spec(key, UPDATE)],
+            Command::Append { key, .. } | Command::Push { key, .. } => vec![spec(key, INSERT)],
+            Command::Pop { key, .. } => vec![spec(key, READ_DELETE)],
+            Command::BlockingPop { keys, .. } => {
+                keys.iter().map(|key| spec(key, READ_DELETE)).collect()
+            }
+            Command::LMove {
+                source,
+                destination,
+                ..
+            }
+            | Command::BlockingMove {
+                source,
+                destination,
+                ..
+            } => vec![spec(source, READ_DELETE), spec(destination, INSERT)],
+            Command::LInsert { key, .. } => vec![spec(key, INSERT)],
+            Command::LSet { key, .. } => vec![spec(key, UPDATE)],
+            Command::LRem { key, .. }
+            | Command::LTrim { key, .. }
+            | Command::HDel { key, .. }
+            | Command::SRem { key, .. }
+            | Command::ZRem { key, .. } => vec![spec(key, REMOVE)],
+            Command::ZAdd { key, .. } | Command::ZIncrBy { key, .. } => vec![spec(key, UPDATE)],
+            Command::ZPop { key, .. } => vec![spec(key, READ_DELETE)],
+            Command::BlockingZPop { keys, .. } => {
+                keys.iter().map(|key| spec(key, READ_DELETE)).collect()
+            }
+            Command::SAdd { key, .. } => vec![spec(key, INSERT)],
+            Command::SetOperation {
+                keys, destination, ..
+            } => destination
+                .iter()
+                .map(|destination| spec(destination, OVERWRITE))
+                .chain(keys.iter().map(|key| spec(key, READ)))
+                .collect(),
+            Command::SInterCard { keys, .. } => keys.iter().map(|key| spec(key, READ)).collect(),
+            Command::BitOp {
+                destination, keys, ..
+            } => std::iter::once(spec(destination, OVERWRITE))
+                .chain(keys.iter().map(|key| spec(key, READ)))
+                .collect(),
+            Command::SPop { key, .. } => vec![spec(key, READ_DELETE)],
+            Command::SMove {
+                source,
+                destination,
+                ..
+            } => vec![spec(source, READ_DELETE), spec(destination, INSERT)],
+            Command::HSet { key, .. }
+            | Command::HIncrBy { key, .. }
+            | Command::HIncrByFloat { key, .. } => vec![spec(key, UPDATE)],
+            Command::IncrBy { key, .. } | Command::IncrByFloat { key, .. } => {
+                vec![spec(key, READ_WRITE)]
+            }
+            Command::Del { keys, .. } => keys.iter().map(|key| spec(key, DELETE)).collect(),
+            Command::Exists { keys } | Command::MGet { keys } => {
+                keys.iter().map(|key| spec(key, READ)).collect()
+            }
+            Command::MSet { pairs, .. } => {
+                pairs.iter().map(|(key, _)| spec(key, OVERWRITE)).collect()
+            }
+            Command::Restore { key, .. } => vec![spec(key, OVERWRITE)],
+            Command::Rename {
+                source,
+                destination,
+            } => vec![spec(source, READ_DELETE), spec(destination, OVERWRITE)],
+            Command::Copy {
+                source,
+                destination,
+                ..
+            } => vec![spec(source, READ), spec(destination, OVERWRITE)],
+            Command::Move { key, .. } => vec![spec(key, READ_DELETE)],
+            Command::Ping
+            | Command::Echo { .. }
+            | Command::ConfigGet { .. }
+            | Command::ConfigSet { .. }
+            | Command::ConfigRewrite
+            | Command::Keys { .. }
+            | Command::Scan { .. }
+            | Command::DbSize
+            | Command::RandomKey
+            | Command::Select { .. }
+            | Command::SwapDb { .. }
+            | Command::FlushDb
+            | Command::FlushAll
+            | Command::Multi
+            | Command::Exec
+            | Command::Discard
+            | Command::Subscribe { .. }
+            | Command::Unsubscribe { .. }
+            | Command::Publish { .. }
+            | Command::PubSubChannels { .. }
+            | Command::PubSubNumSub { .. }
+            | Command::PubSubNumPat
+            | Command::CommandCount
+            | Command::CommandInfo { .. }
+            | Command::CommandDocs { .. }
+            | Command::GetKeys { .. }
+            | Command::LatencyHistogram { .. }
+            | Command::DebugPopulate { .. }
+            | Command::BigKeys { .. }
+            | Command::MemoryStats
+            | Command::Save
+            | Command::BgSave
+            | Command::LastSave
+            | Command::Info { .. }
+            | Command::ClusterNodes
+            | Command::ClusterMyId
+            | Command::ClusterMeet { .. }
+            | Command::ClusterForget { .. }
+            | Command::ClusterSlots { .. }
+            | Command::ReadOnly
+            | Command::ReadWrite
+            | Command::Hello { .. }
+            | Command::ClientId
+            | Command::ClientTracking { .. }
+            | Command::ClientCaching { .. }
+            | Command::ClientGetRedir
+            | Command::ReplConf { .. }
+            | Command::Psync { .. } => vec![],
+        }
+    }
+
+    /// The name latency is recorded under, with subcommands written as `command|subcommand` like
+    /// Redis does.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Ping => "ping",
+            Command::Echo { .. } => "echo",
+            Command::Set { .. } => "set",
+            Command::Get { .. } => "get",
+            Command::ConfigGet { .. } => "config|get",
+            Command::ConfigSet { .. } => "config|set",
+            Command::ConfigRewrite => "config|rewrite",
+            Command::Keys { .. } => "keys",
+            Command::Scan { .. } => "scan",
+            Command::DbSize => "dbsize",
+            Command::RandomKey => "randomkey",
+            Command::Select { .. } => "select",
+            Command::SwapDb { .. } => "swapdb",
+            Command::Move { .. } => "move",
+            Command::FlushDb => "flushdb",
+            Command::FlushAll => "flushall",
+            Command::Multi => "multi",
+            Command::Exec => "exec",
+            Command::Discard => "discard",
+            Command::Subscribe { pattern: false, .. } => "subscribe",
+            Command::Subscribe { pattern: true, .. } => "psubscribe",
+            Command::Unsubscribe { pattern: false, .. } => "unsubscribe",
+            Command::Unsubscribe { pattern: true, .. } => "punsubscribe",
+            Command::PubSubChannels { .. } => "pubsub|channels",
+            Command::PubSubNumSub { .. } => "pubsub|numsub",
+            Command::PubSubNumPat => "pubsub|numpat",
+            Command::Publish { .. } => "publish",
+            Command::ElementScan { scan, .. } => match scan {
+                ElementScan::Hash => "hscan",
+                ElementScan::Set => "sscan",
+                ElementScan::SortedSet => "zscan",
+            },
+            Command::Rename { .. } => "rename",
+            Command::Copy { .. } => "copy",
+            Command::Del { unlink: false, .. } => "del",
+            Command::Del { unlink: true, .. } => "unlink",
+            Command::Exists { .. } => "exists",
+            Command::MGet { .. } => "mget",
+            Command::MSet {
+                only_new: false, ..
+            } => "mset",
+            Command::MSet { only_new: true, .. } => "msetnx",
+            Command::Append { .. } => "append",
+            Command::Strlen { .. } => "strlen",
+            Command::Type { .. } => "type",
+            Command::Push { left: true, .. } => "lpush",
+            Command::Push { left: false, .. } => "rpush",
+            Command::Pop { left: true, .. } => "lpop",
+            Command::Pop { left: false, .. } => "rpop",
+            Command::BlockingPop { left: true, .. } => "blpop",
+            Command::BlockingPop { left: false, .. } => "brpop",
+            Command::LMove { .. } => "lmove",
+            Command::BlockingMove { .. } => "blmove",
+            Command::LLen { .. } => "llen",
+            Command::LRange { .. } => "lrange",
+            Command::LInsert { .. } => "linsert",
+            Command::LSet { .. } => "lset",
+            Command::LRem { .. } => "lrem",
+            Command::LTrim { .. } => "ltrim",
+            Command::HSet { .. } => "hset",
+            Command::HGet { .. } => "hget",
+            Command::HExists { .. } => "hexists",
+            Command::HDel { .. } => "hdel",
+            Command::HGetAll { .. } => "hgetall",
+            Command::HLen { .. } => "hlen",
+            Command::HKeys { .. } => "hkeys",
+            Command::HVals { .. } => "hvals",
+            Command::HMGet { .. } => "hmget",
+            Command::HIncrBy { .. } => "hincrby",
+            Command::HIncrByFloat { .. } => "hincrbyfloat",
+            Command::HRandField { .. } => "hrandfield",
+            Command::SAdd { .. } => "sadd",
+            Command::SRem { .. } => "srem",
+            Command::SMembers { .. } => "smembers",
+            Command::SIsMember { .. } => "sismember",
+            Command::SCard { .. } => "scard",
+            Command::SetOperation {
+                operation,
+                destination: None,
+                ..
+            } => operation.name(),
+            Command::SetOperation {
+                operation: SetOperation::Inter,
+                ..
+            } => "sinterstore",
+            Command::SetOperation {
+                operation: SetOperation::Union,
+                ..
+            } => "sunionstore",
+            Command::SetOperation {
+                operation: SetOperation::Diff,
+                ..
+            } => "sdiffstore",
+            Command::SInterCard { .. } => "sintercard",
+            Command::SPop { .. } => "spop",
+            Command::SRandMember { .. } => "srandmember",
+            Command::SMove { .. } => "smove",
+            Command::ZAdd { .. } => "zadd",
+            Command::ZScore { .. } => "zscore",
+            Command::ZRange { legacy: false, .. } => "zrange",
+            Command::ZRange { by, rev, .. } => match (by, rev) {
+                (ZRangeBy::Rank(..), false) => "zrange",
+                (ZRangeBy::Rank(..), true) => "zrevrange",
+                (ZRangeBy::Score(..), false) => "zrangebyscore",
+                (ZRangeBy::Score(..), true) => "zrevrangebyscore",
+                (ZRangeBy::Lex(..), false) => "zrangebylex",
+                (ZRangeBy::Lex(..), true) => "zrevrangebylex",
+            },
+            Command::ZRank { rev: false, .. } => "zrank",
+            Command::ZRank { rev: true, .. } => "zrevrank",
+            Command::ZRem { .. } => "zrem",
+            Command::ZIncrBy { .. } => "zincrby",
+            Command::ZCard { .. } => "zcard",
+            Command::ZCount { .. } => "zcount",
+            Command::ZPop { max: false, .. } => "zpopmin",
+            Command::ZPop { max: true, .. } => "zpopmax",
+            Command::BlockingZPop { max: false, .. } => "bzpopmin",
+            Command::BlockingZPop { max: true, .. } => "bzpopmax",
+            Command::GetRange { .. } => "getrange",
+            Command::SetRange { .. } => "setrange",
+            Command::SetBit { .. } => "setbit",
+            Command::GetBit { .. } => "getbit",
+            Command::BitCount { .. } => "bitcount",
+            Command::BitPos { .. } => "bitpos",
+            Command::BitOp { .. } => "bitop",
+            Command::Object { subcommand, .. } => match subcommand {
+                ObjectSubcommand::Encoding => "object|encoding",
+                ObjectSubcommand::RefCount => "object|refcount",
+                ObjectSubcommand::IdleTime => "object|idletime",
+                ObjectSubcommand::Freq => "object|freq",
+            },
+            Command::IncrByFloat { .. } => "incrbyfloat",
+            Command::IncrBy {
+                increment: None,
+                decrement: false,
+                ..
+            } => "incr",
+            Command::IncrBy {
+                increment: None,
+                decrement: true,
+                ..
+            } => "decr",
+            Command::IncrBy {
+                increment: Some(_),
+                decrement: false,
+                ..
+            } => "incrby",
+            Command::IncrBy {
+                increment: Some(_),
+                decrement: true,
+                ..
+            } => "decrby",
+            Command::Ttl {
+                milliseconds: false,
+                ..
+            } => "ttl",
+            Command::Ttl {
+                milliseconds: true, ..
+            } => "pttl",
+            Command::Expire {
+                milliseconds,
+                absolute,
+                ..
+            } => Command::expire_name(*milliseconds, *absolute),
+            Command::Dump { .. } => "dump",
+            Command::Restore { .. } => "restore",
+            Command::CommandCount => "command|count",
+            Command::CommandInfo { .. } => "command|info",
+            Command::CommandDocs { .. } => "command|docs",
+            Command::GetKeys {
+                with_flags: false, ..
+            } => "command|getkeys",
+            Command::GetKeys {
+                with_flags: true, ..
+            } => "command|getkeysandflags",
+            Command::LatencyHistogram { .. } => "latency|histogram",
+            Command::DebugPopulate { .. } => "debug",
+            Command::BigKeys { .. } => "memory|bigkeys",
+            Command::MemoryStats => "memory|stats",
+            Command::Save => "save",
+            Command::BgSave => "bgsave",
+            Command::LastSave => "lastsave",
+            Command::Info { .. } => "info",
+            Command::ClusterNodes => "cluster|nodes",
+            Command::ClusterMyId => "cluster|myid",
+            Command::ClusterMeet { .. } => "cluster|meet",
+            Command::ClusterForget { .. } => "cluster|forget",
+            Command::ReadOnly => "readonly",
+            Command::ReadWrite => "readwrite",
+            Command::Hello { .. } => "hello",
+            Command::ClientId => "client|id",
+            Command::ClientTracking { .. } => "client|tracking",
+            Command::ClientCaching { .. } => "client|caching",
+            Command::ClientGetRedir => "client|getredir",
+            Command::ReplConf { .. } => "replconf",
+            Command::Psync { .. } => "psync",
+            Command::ClusterSlots {
+                assign: true,
+                ranges: false,
+                ..
+            } => "cluster|addslots",
+            Command::ClusterSlots {
+                assign: false,
+                ranges: false,
+                ..
+            } => "cluster|delslots",
+            Command::ClusterSlots {
+                assign: true,
+                ranges: true,
+                ..
+            } => "cluster|addslotsrange",
+            Command::ClusterSlots {
+                assign: false,
+                ranges: true,
+                ..
+            } => "cluster|delslotsrange",
+        }
+    }
+
+    /// Whether the command changes the dataset, which a read only replica refuses.
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Command::Set { .. }
+                | Command::Rename { .. }
+                | Command::Copy { .. }
+                | Command::Move { .. }
+                | Command::SwapDb { .. }
+                | Command::FlushDb
+                | Command::FlushAll
+                | Command::Expire { .. }
+                | Command::Del { .. }
+                | Command::IncrBy { .. }
+                | Command::IncrByFloat { .. }
+                | Command::MSet { .. }
+                | Command::Append { .. }
+                | Command::SetRange { .. }
+                | Command::SetBit { .. }
+                | Command::BitOp { .. }
+                | Command::Push { .. }
+                | Command::Pop { .. }
+                | Command::BlockingPop { .. }
+                | Command::LMove { .. }
+                | Command::BlockingMove { .. }
+                | Command::LInsert { .. }
+                | Command::LSet { .. }
+                | Command::LRem { .. }
+                | Command::LTrim { .. }
+                | Command::HSet { .. }
+                | Command::HDel { .. }
+                | Command::HIncrBy { .. }
+                | Command::HIncrByFloat { .. }
+                | Command::SAdd { .. }
+                | Command::SRem { .. }
+                | Command::SPop { .. }
+                | Command::SMove { .. }
+                | Command::ZAdd { .. }
+                | Command::ZRem { .. }
+                | Command::ZIncrBy { .. }
+                | Command::ZPop { .. }
+                | Command::BlockingZPop { .. }
+                | Command::SetOperation {
+                    destination: Some(_),
+                    ..
+                }
+                | Command::Restore { .. }
+                | Command::DebugPopulate { .. }
+        )
+    }
+
+    /// Whether the command only changes the connection's session, without touching any key.
+    pub fn changes_session(&self) -> bool {
+        matches!(
+            self,
+            Command::ReadOnly
+                | Command::ReadWrite
+                | Command::ReplConf { .. }
+                | Command::Hello { .. }
+                | Command::ClientId
+                | Command::ClientTracking { .. }
+                | Command::ClientCaching { .. }
+                | Command::ClientGetRedir
+        )
+    }
+
+    /// SUBSCRIBE and UNSUBSCRIBE, which answer with a confirmation for every channel rather than a
+    /// single reply.
+    pub fn is_subscription(&self) -> bool {
+        matches!(
+            self,
+            Command::Subscribe { .. } | Command::Unsubscribe { .. }
+        )
+    }
+
+    /// Whether the command can wait for other clients, so its connection has to notice the client
+    /// going away in the meantime.
+    pub fn is_blocking(&self) -> bool {
+        matches!(
+            self,
+            Command::BlockingPop { .. }
+                | Command::BlockingMove { .. }
+                | Command::BlockingZPop { .. }
+        )
+    }
+
+    fn expire_name(milliseconds: bool, absolute: bool) -> &'static str {
+        match (milliseconds, absolute) {
+            (false, false) => "expire",
+            (true, false) => "pexpire",
+            (false, true) => "expireat",
+            (true, true) => "pexpireat",
+        }
+    }
+
+    pub fn key_scope(&self) -> KeyScope<'_> {
+        match self {
+            Command::Keys { .. }
+            | Command::Scan { .. }
+            | Command::DbSize
+            | Command::RandomKey
+            | Command::DebugPopulate { .. }
+            | Command::BigKeys { .. }
+            | Command::MemoryStats
+            | Command::FlushDb => KeyScope::All,
+            Command::Save | Command::BgSave | Command::FlushAll | Command::ConfigSet { .. } => {
+                KeyScope::Everything
+            }
+            Command::SwapDb { first, second } => KeyScope::Databases(*first, *second),
+            Command::Move { db, .. } | Command::Copy { db: Some(db), .. } => KeyScope::Across {
+                db: *db,
+                keys: self.key_specs().into_iter().map(|s| s.key).collect(),
+            },
+            command => KeyScope::Keys(command.key_specs().into_iter().map(|s| s.key).collect()),
+        }
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use crate::{
+        aof::Aof,
+        config::Config,
+        redis::{Command, CommandError, Redis, RedisValue, REDIS_VERSION},
+        resp::{Protocol, Resp},
+        session::Session,
+        storage::MemoryStorage,
+        Server,
+    };
+    #[allow(unused_imports)]
+    use bytes::Bytes;
+    #[allow(unused_imports)]
+    use std::{
+        collections::{HashMap, HashSet, VecDeque},
+        sync::atomic::Ordering,
+        time::Duration,
+    };
+
+    #[tokio::test]
+    async fn debug_populate_creates_keys() {
+        let redis = Server::builder().embedded();
+
+        assert_eq!(
+            redis.execute(["DEBUG", "POPULATE", "3"]).await,
+            Resp::SimpleString("OK".to_string())
+        );
+        assert_eq!(
+            redis.execute(["GET", "key:2"]).await,
+            Resp::BulkString("value:2".into())
+        );
+        assert_eq!(redis.execute(["GET", "key:3"]).await, Resp::Null);
+    }
+
+    #[tokio::test]
+    async fn debug_populate_uses_prefix_and_size() {
+        let redis = Server::builder().embedded();
+        redis
+            .execute(["DEBUG", "POPULATE", "2", "user", "10"])
+            .await;
+
+        assert_eq!(
+            redis.execute(["GET", "user:1"]).await,
+            Resp::BulkString("value:1\0\0\0".into())
+        );
+
+        redis
+            .execute(["DEBUG", "POPULATE", "1", "short", "3"])
+            .await;
+        assert_eq!(
+            redis.execute(["GET", "short:0"]).await,
+            Resp::BulkString("val".into())
+        );
+    }
+
+    #[tokio::test]
+    async fn debug_populate_keeps_existing_keys() {
+        let redis = Server::builder().embedded();
+        redis.execute(["SET", "key:0", "mine"]).await;
+        redis.execute(["DEBUG", "POPULATE", "2"]).await;
+
+        assert_eq!(
+            redis.execute(["GET", "key:0"]).await,
+            Resp::BulkString("mine".into())
+        );
+        assert_eq!(
+            redis.execute(["DEBUG", "POPULATE", "lots"]).await,
+            Resp::SimpleError("ERR value is not an integer or out of range".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn port_and_bind_come_from_the_command_line() {
+        let args = ["redis", "--port", "7000", "--bind", "0.0.0.0"];
+        let redis = Server::builder()
+            .args(args.map(String::from).to_vec())
+            .embedded();
+        let config_get = |name: &str, value: &str| {
+            Resp::Map(vec![(
+                Resp::BulkString(name.to_string().into()),
+                Resp::BulkString(value.to_string().into()),
+            )])
+        };
+
+        assert_eq!(
+            redis.execute(["CONFIG", "GET", "port"]).await,
+            config_get("port", "7000")
+        );
+        assert_eq!(
+            redis.execute(["CONFIG", "GET", "bind"]).await,
+            config_get("bind", "0.0.0.0")
+        );
+
+        let redis = Server::builder().embedded();
+        assert_eq!(
+            redis.execute(["CONFIG", "GET", "port"]).await,
+            config_get("port", "6379")
+        );
+    }
+
+    #[tokio::test]
+    async fn memory_stats_reports_the_dataset_and_allocator() {
+        let redis = Server::builder().embedded();
+        redis.execute(["SET", "foo", "bar"]).await;
+        redis.execute(["SET", "hello", "world"]).await;
+
+        let stats = match redis.execute(["MEMORY", "STATS"]).await {
+            Resp::Map(stats) => stats,
+            reply => panic!("expected a map reply, got {:?}", reply),
+        };
+        let stat = |name: &str| {
+            stats
+                .iter()
+                .find(|(field, _)| *field == Resp::BulkString(name.to_string().into()))
+                .map(|(_, value)| value.clone())
+        };
+
+        assert_eq!(stat("keys.count"), Some(Resp::Integer(2)));
+        assert_eq!(stat("dataset.bytes"), Some(Resp::Integer(64 + 6 + 64 + 10)));
+        match (stat("total.allocated"), stat("peak.allocated")) {
+            (Some(Resp::Integer(total)), Some(Resp::Integer(peak))) => {
+                assert!(total > 0 && peak >= total)
+            }
+            stats => panic!("expected allocator totals, got {:?}", stats),
+        }
+        assert_eq!(
+            redis.execute(["MEMORY", "STATS", "FULL"]).await,
+            Resp::SimpleError(
+                "ERR wrong number of arguments for 'memory|stats' command".to_string()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn save_points_start_a_background_save() {
+        let dir = std::env::temp_dir().join(format!("redis-save-points-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = HashMap::from([
+            ("dir".to_string(), dir.to_str().unwrap().to_string()),
+            ("dbfilename".to_string(), "dump.rdb".to_string()),
+            ("save".to_string(), "60 3 10 2".to_string()),
+        ]);
+        let config = Config::from_map(&config).unwrap();
+        let redis =
+            Redis::new(config, &|| Box::new(MemoryStorage::default()), None, vec![]).unwrap();
+        let started = match execute(&redis, &["LASTSAVE"]).await {
+            Resp::Integer(started) => started as u64,
+            reply => panic!("expected an integer, got {:?}", reply),
+        };
+
+        execute(&redis, &["SET", "foo", "bar"]).await;
+        execute(&redis, &["SET", "baz", "qux"]).await;
+        let info = execute(&redis, &["INFO", "persistence"]).await.to_string();
+        assert!(info.contains("rdb_changes_since_last_save:2\r\n"));
+        // Not long enough since the server started.
+        redis.save_point_cycle().await;
+        assert!(!dir.join("dump.rdb").exists());
+
+        let status = &redis.background_save;
+        status.last_save.store(started - 11, Ordering::SeqCst);
+        redis.save_point_cycle().await;
+        while status.in_progress.load(Ordering::SeqCst) {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        assert!(dir.join("dump.rdb").exists());
+        assert_eq!(status.dirty.load(Ordering::SeqCst), 0);
+        assert!(matches!(
+            execute(&redis, &["LASTSAVE"]).await,
+            Resp::Integer(last_save) if last_save as u64 >= started
+        ));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn save_disabled_runs_without_persistence() {
+        let dir = std::env::temp_dir().join(format!("redis-ephemeral-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // Loading this would fail, so it must not even be read.
+        std::fs::write(dir.join("dump.rdb"), "not an rdb file").unwrap();
+
+        let redis = Server::builder()
+            .dir(dir.to_str().unwrap())
+            .dbfilename("dump.rdb")
+            .config("save", "")
+            .embedded();
+        redis.execute(["SET", "foo", "bar"]).await;
+
+        let disabled = Resp::SimpleError(
+            "ERR persistence is disabled, set save or appendonly to take snapshots".to_string(),
+        );
+        assert_eq!(redis.execute(["SAVE"]).await, disabled);
+        assert_eq!(redis.execute(["BGSAVE"]).await, disabled);
+
+        let info = redis.execute(["INFO", "persistence"]).await.to_string();
+        assert!(info.starts_with("# Persistence\r\n"));
+        assert!(info.contains("persistence_enabled:0\r\n"));
+        assert!(info.contains("aof_enabled:0\r\n"));
+        assert!(!info.contains("# Memory"));
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn config_set_validates_every_parameter_before_changing_any() {
+        let redis = Server::builder().embedded();
+        let get = |parameter: &'static str| redis.execute(["CONFIG", "GET", parameter]);
+        let value = |parameter: &str, value: &str| {
+            Resp::Map(vec![(
+                Resp::BulkString(Bytes::copy_from_slice(parameter.as_bytes())),
+                Resp::BulkString(Bytes::copy_from_slice(value.as_bytes())),
+            )])
+        };
+
+        assert_eq!(
+            redis
+                .execute(["CONFIG", "SET", "maxmemory", "10mb", "SAVE", "60 100"])
+                .await,
+            Resp::SimpleString("OK".to_string())
+        );
+        assert_eq!(get("maxmemory").await, value("maxmemory", "10485760"));
+        assert_eq!(get("save").await, value("save", "60 100"));
+
+        assert_eq!(
+            redis
+                .execute(["CONFIG", "SET", "maxmemory", "1gb", "hz", "often"])
+                .await,
+            Resp::SimpleError(
+                "ERR CONFIG SET failed (possibly related to argument 'hz') - argument couldn't be \
+                 parsed into an integer"
+                    .to_string()
+            )
+        );
+        assert_eq!(get("maxmemory").await, value("maxmemory", "10485760"));
+
+        assert_eq!(
+            redis.execute(["CONFIG", "SET", "port", "7000"]).await,
+            Resp::SimpleError(
+                "ERR CONFIG SET failed (possibly related to argument 'port') - can't set \
+                 immutable config"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            redis.execute(["CONFIG", "SET", "nope", "1"]).await,
+            Resp::SimpleError(
+                "ERR Unknown option or number of arguments for CONFIG SET - 'nope'".to_string()
+            )
+        );
+        assert_eq!(
+            redis
+                .execute(["CONFIG", "SET", "hz", "20", "maxmemory"])
+                .await,
+            Resp::SimpleError("ERR wrong number of arguments for 'config|set' command".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn config_set_applies_to_the_running_server() {
+        let redis = redis();
+        let remote = "192.168.1.10".parse().unwrap();
+        assert!(!redis.accepts_connections_from(remote));
+        execute(&redis, &["CONFIG", "SET", "protected-mode", "no"]).await;
+        assert!(redis.accepts_connections_from(remote));
+
+        execute(&redis, &["CONFIG", "SET", "hz", "100"]).await;
+        assert_eq!(redis.active_expire_interval(), Duration::from_millis(10));
+
+        execute(&redis, &["CONFIG", "SET", "proto-max-bulk-len", "1kb"]).await;
+        assert_eq!(
+            execute(&redis, &["SETRANGE", "foo", "1024", "bar"]).await,
+            Resp::SimpleError(
+                "ERR string exceeds maximum allowed size (proto-max-bulk-len)".into()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn config_set_appendonly_starts_a_log_of_the_dataset() {
+        let dir = std::env::temp_dir().join(format!("redis-config-aof-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let redis = Server::builder()
+            .dir(dir.to_str().unwrap())
+            .config("save", "")
+            .embedded();
+        redis.execute(["SET", "foo", "bar"]).await;
+        assert_eq!(
+            redis.execute(["CONFIG", "SET", "appendonly", "yes"]).await,
+            Resp::SimpleString("OK".to_string())
+        );
+        redis.execute(["SELECT", "1"]).await;
+        redis.execute(["SET", "baz", "qux"]).await;
+        let info = redis.execute(["INFO", "persistence"]).await.to_string();
+        assert!(info.contains("aof_enabled:1\r\n"));
+
+        let restarted = Server::builder()
+            .dir(dir.to_str().unwrap())
+            .config("appendonly", "yes")
+            .embedded();
+        assert_eq!(
+            restarted.execute(["GET", "foo"]).await,
+            Resp::BulkString("bar".into())
+        );
+        restarted.execute(["SELECT", "1"]).await;
+        assert_eq!(
+            restarted.execute(["GET", "baz"]).await,
+            Resp::BulkString("qux".into())
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn config_rewrite_saves_changes_to_the_config_file() {
+        assert_eq!(
+            Server::builder()
+                .embedded()
+                .execute(["CONFIG", "REWRITE"])
+                .await,
+            Resp::SimpleError("ERR The server is running without a config file".to_string())
+        );
+
+        let path = std::env::temp_dir().join(format!("redis-config-{}.conf", std::process::id()));
+        std::fs::write(&path, "# Loaded on startup\nhz 20\nmaxmemory 1mb\n").unwrap();
+        let args = [path.to_str().unwrap(), "--maxmemory", "2mb"].map(String::from);
+        let redis = Server::builder()
+            .args([&["redis-server".to_string()], &args[..]].concat())
+            .embedded();
+        assert_eq!(
+            redis.execute(["CONFIG", "GET", "hz"]).await,
+            Resp::Map(vec![(
+                Resp::BulkString("hz".into()),
+                Resp::BulkString("20".into())
+            )])
+        );
+
+        redis.execute(["CONFIG", "SET", "hz", "50"]).await;
+        assert_eq!(
+            redis.execute(["CONFIG", "REWRITE"]).await,
+            Resp::SimpleString("OK".to_string())
+        );
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "# Loaded on startup\nhz 50\nmaxmemory 2097152\n"
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn get_replies_share_the_stored_value() {
+        let redis = Server::builder().embedded();
+        redis.execute(["SET", "foo", "bar"]).await;
+
+        let first = redis.execute(["GET", "foo"]).await;
+        let second = redis.execute(["GET", "foo"]).await;
+        match (first, second) {
+            (Resp::BulkString(first), Resp::BulkString(second)) => {
+                assert_eq!(first.as_ptr(), second.as_ptr())
+            }
+            replies => panic!("expected bulk string replies, got {:?}", replies),
+        }
+    }
+
+    #[tokio::test]
+    async fn active_expiry_removes_keys_nobody_reads() {
+        let redis = Server::builder().config("hz", "100").embedded();
+        redis.execute(["SET", "foo", "bar", "PX", "10"]).await;
+        redis.execute(["SET", "baz", "qux"]).await;
+
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+
+        assert_eq!(
+            redis.execute(["KEYS", "*"]).await,
+            Resp::Array(vec![Resp::BulkString("baz".into())])
+        );
+    }
+
+    #[allow(dead_code)]
+    fn redis() -> Redis {
+        Redis::new(
+            Config::default(),
+            &|| Box::new(MemoryStorage::default()),
+            None,
+            vec![],
+        )
+        .unwrap()
+    }
+
+    #[allow(dead_code)]
+    async fn execute<A: AsRef<[u8]>>(redis: &Redis, command_line: &[A]) -> Resp {
+        execute_in(redis, &mut redis.session(), command_line).await
+    }
+
+    #[allow(dead_code)]
+    async fn execute_in<A: AsRef<[u8]>>(
+        redis: &Redis,
+        session: &mut Session,
+        command_line: &[A],
+    ) -> Resp {
+        let request = Resp::Array(
+            command_line
+                .iter()
+                .map(|arg| Resp::BulkString(Bytes::copy_from_slice(arg.as_ref())))
+                .collect(),
+        );
+        match redis.parse_client_request(request) {
+            Ok(command) => redis.execute(command, session).await,
+            Err(error) => redis.reject(error, session),
+        }
+    }
+
+    #[allow(dead_code)]
+    async fn expiry(redis: &Redis, key: &str) -> Option<u64> {
+        redis.keyspace.lock(0, &[key]).await.expiry(key.as_bytes())
+    }
+
+    #[tokio::test]
+    async fn expire_sets_and_conditionally_updates_expiries() {
+        let redis = redis();
+        execute(&redis, &["SET", "foo", "bar"]).await;
+
+        assert_eq!(
+            execute(&redis, &["EXPIRE", "missing", "10"]).await,
+            Resp::Integer(0)
+        );
+        assert_eq!(
+            execute(&redis, &["EXPIRE", "foo", "10", "XX"]).await,
+            Resp::Integer(0)
+        );
+        assert_eq!(
+            execute(&redis, &["EXPIRE", "foo", "10", "GT"]).await,
+            Resp::Integer(0)
+        );
+        assert_eq!(
+            execute(&redis, &["EXPIRE", "foo", "100", "NX"]).await,
+            Resp::Integer(1)
+        );
+        let ttl = expiry(&redis, "foo").await.unwrap() - Redis::ms_since_epoch();
+        assert!(ttl > 90_000);
+
+        assert_eq!(
+            execute(&redis, &["PEXPIRE", "foo", "200000", "lt"]).await,
+            Resp::Integer(0)
+        );
+        assert_eq!(
+            execute(&redis, &["PEXPIRE", "foo", "200000", "GT"]).await,
+            Resp::Integer(1)
+        );
+        assert_eq!(
+            execute(&redis, &["EXPIREAT", "foo", "4000000000", "XX", "GT"]).await,
+            Resp::Integer(1)
+        );
+        assert_eq!(expiry(&redis, "foo").await, Some(4_000_000_000_000));
+        assert_eq!(
+            execute(&redis, &["PEXPIREAT", "foo", "5000000000000"]).await,
+            Resp::Integer(1)
+        );
+        assert_eq!(expiry(&redis, "foo").await, Some(5_000_000_000_000));
+
+        assert_eq!(
+            execute(&redis, &["EXPIRE", "foo", "10", "NX", "XX"]).await,
+            CommandError::ExpireNxIncompatible.into()
+        );
+        assert_eq!(
+            execute(&redis, &["EXPIRE", "foo", "10", "GT", "LT"]).await,
+            CommandError::ExpireGtLtIncompatible.into()
+        );
+        assert_eq!(
+            execute(&redis, &["EXPIRE", "foo", "10", "KEEPTTL"]).await,
+            CommandError::UnsupportedOption("KEEPTTL".to_string()).into()
+        );
+        assert_eq!(
+            execute(&redis, &["EXPIRE", "foo", "9223372036854775807"]).await,
+            CommandError::InvalidExpireTime("expire".to_string()).into()
+        );
+
+        assert_eq!(
+            execute(&redis, &["EXPIRE", "foo", "-1"]).await,
+            Resp::Integer(1)
+        );
+        assert_eq!(execute(&redis, &["GET", "foo"]).await, Resp::Null);
+        assert_eq!(expiry(&redis, "foo").await, None);
+    }
+
+    #[tokio::test]
+    async fn ttl_reports_the_remaining_lifetime() {
+        let redis = redis();
+        execute(&redis, &["SET", "foo", "bar"]).await;
+
+        assert_eq!(
+            execute(&redis, &["TTL", "missing"]).await,
+            Resp::Integer(-2)
+        );
+        assert_eq!(
+            execute(&redis, &["PTTL", "missing"]).await,
+            Resp::Integer(-2)
+        );
+        assert_eq!(execute(&redis, &["TTL", "foo"]).await, Resp::Integer(-1));
+        assert_eq!(execute(&redis, &["PTTL", "foo"]).await, Resp::Integer(-1));
+
+        execute(&redis, &["EXPIRE", "foo", "100"]).await;
+        assert_eq!(execute(&redis, &["TTL", "foo"]).await, Resp::Integer(100));
+        match execute(&redis, &["PTTL", "foo"]).await {
+            Resp::Integer(ttl) => assert!(ttl > 99_000 && ttl <= 100_000),
+            reply => panic!("expected an integer reply, got {:?}", reply),
+        }
+
+        execute(&redis, &["SET", "bar", "baz", "PX", "10"]).await;
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        assert_eq!(execute(&redis, &["PTTL", "bar"]).await, Resp::Integer(-2));
+    }
+
+    #[tokio::test]
+    async fn del_removes_every_given_key() {
+        let redis = redis();
+        execute(&redis, &["SET", "foo", "1"]).await;
+        execute(&redis, &["SET", "bar", "2", "PX", "100000"]).await;
+        execute(&redis, &["SET", "baz", "3"]).await;
+
+        assert_eq!(
+            execute(&redis, &["DEL", "foo", "bar", "missing", "foo"]).await,
+            Resp::Integer(2)
+        );
+        assert_eq!(execute(&redis, &["GET", "foo"]).await, Resp::Null);
+        assert_eq!(expiry(&redis, "bar").await, None);
+        assert_eq!(execute(&redis, &["UNLINK", "baz"]).await, Resp::Integer(1));
+        assert_eq!(execute(&redis, &["KEYS", "*"]).await, Resp::Array(vec![]));
+        assert_eq!(
+            execute(&redis, &["DEL"]).await,
+            CommandError::WrongArity("del".to_string()).into()
+        );
+    }
+
+    #[tokio::test]
+    async fn exists_counts_live_keys() {
+        let redis = redis();
+        execute(&redis, &["SET", "foo", "1"]).await;
+        execute(&redis, &["SET", "bar", "2", "PX", "10"]).await;
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+
+        assert_eq!(execute(&redis, &["EXISTS", "foo"]).await, Resp::Integer(1));
+        assert_eq!(
+            execute(&redis, &["EXISTS", "foo", "bar", "missing", "foo"]).await,
+            Resp::Integer(2)
+        );
+        assert_eq!(
+            execute(&redis, &["EXISTS"]).await,
+            CommandError::WrongArity("exists".to_string()).into()
+        );
+    }
+
+    #[tokio::test]
+    async fn counters_add_to_integer_strings() {
+        let redis = redis();
+
+        assert_eq!(
+            execute(&redis, &["INCR", "counter"]).await,
+            Resp::Integer(1)
+        );
+        assert_eq!(
+            execute(&redis, &["INCRBY", "counter", "41"]).await,
+            Resp::Integer(42)
+        );
+        assert_eq!(
+            execute(&redis, &["DECR", "counter"]).await,
+            Resp::Integer(41)
+        );
+        assert_eq!(
+            execute(&redis, &["DECRBY", "counter", "50"]).await,
+            Resp::Integer(-9)
+        );
+        assert_eq!(
+            execute(&redis, &["GET", "counter"]).await,
+            Resp::BulkString("-9".into())
+        );
+
+        execute(&redis, &["SET", "counter", "10", "PX", "100000"]).await;
+        assert_eq!(
+            execute(&redis, &["INCR", "counter"]).await,
+            Resp::Integer(11)
+        );
+        assert!(expiry(&redis, "counter").await.is_some());
+
+        for value in ["bar", "+1", "007", " 1", "1.5"] {
+            execute(&redis, &["SET", "foo", value]).await;
+            assert_eq!(
+                execute(&redis, &["INCR", "foo"]).await,
+                CommandError::NotAnInteger.into()
+            );
+        }
+        assert_eq!(
+            execute(&redis, &["INCRBY", "counter", "one"]).await,
+            CommandError::NotAnInteger.into()
+        );
+
+        execute(&redis, &["SET", "max", i64::MAX.to_string().as_str()]).await;
+        assert_eq!(
+            execute(&redis, &["INCR", "max"]).await,
+            CommandError::IncrementOverflow.into()
+        );
+        assert_eq!(
+            execute(
+                &redis,
+                &["DECRBY", "counter", i64::MIN.to_string().as_str()]
+            )
+            .await,
+            CommandError::DecrementOverflow.into()
+        );
+    }
+
+    #[tokio::test]
+    async fn incrbyfloat_adds_to_numeric_strings() {
+        let redis = redis();
+
+        assert_eq!(
+            execute(&redis, &["INCRBYFLOAT", "foo", "10.5"]).await,
+            Resp::BulkString("10.5".into())
+        );
+        assert_eq!(
+            execute(&redis, &["INCRBYFLOAT", "foo", "0.5"]).await,
+            Resp::BulkString("11".into())
+        );
+        assert_eq!(
+            execute(&redis, &["INCRBYFLOAT", "foo", "5.0e3"]).await,
+            Resp::BulkString("5011".into())
+        );
+        assert_eq!(
+            execute(&redis, &["INCRBYFLOAT", "foo", "-5011.25"]).await,
+            Resp::BulkString("-0.25".into())
+        );
+        assert_eq!(
+            execute(&redis, &["GET", "foo"]).await,
+            Resp::BulkString("-0.25".into())
+        );
+
+        assert_eq!(
+            execute(&redis, &["INCRBYFLOAT", "foo", "nan"]).await,
+            CommandError::NotAFloat.into()
+        );
+        assert_eq!(
+            execute(&redis, &["INCRBYFLOAT", "foo", "inf"]).await,
+            CommandError::FloatOverflow.into()
+        );
+        execute(&redis, &["SET", "bar", "baz"]).await;
+        assert_eq!(
+            execute(&redis, &["INCRBYFLOAT", "bar", "1"]).await,
+            CommandError::NotAFloat.into()
+        );
+    }
+
+    #[tokio::test]
+    async fn append_extends_strings() {
+        let redis = redis();
+
+        assert_eq!(execute(&redis, &["STRLEN", "foo"]).await, Resp::Integer(0));
+        assert_eq!(
+            execute(&redis, &["APPEND", "foo", "Hello"]).await,
+            Resp::Integer(5)
+        );
+        execute(&redis, &["PEXPIRE", "foo", "100000"]).await;
+        assert_eq!(
+            execute(&redis, &["APPEND", "foo", " World"]).await,
+            Resp::Integer(11)
+        );
+        assert_eq!(
+            execute(&redis, &["GET", "foo"]).await,
+            Resp::BulkString("Hello World".into())
+        );
+        assert_eq!(execute(&redis, &["STRLEN", "foo"]).await, Resp::Integer(11));
+        assert!(expiry(&redis, "foo").await.is_some());
+    }
+
+    #[tokio::test]
+    async fn getrange_reads_substrings() {
+        let redis = redis();
+        execute(&redis, &["SET", "foo", "This is a string"]).await;
+
+        for (start, end, expected) in [
+            ("0", "3", "This"),
+            ("-3", "-1", "ing"),
+            ("0", "-1", "This is a string"),
+            ("10", "100", "string"),
+            ("-100", "3", "This"),
+            ("5", "3", ""),
+            ("20", "30", ""),
+            ("0", "-100", ""),
+        ] {
+            assert_eq!(
+                execute(&redis, &["GETRANGE", "foo", start, end]).await,
+                Resp::BulkString(Bytes::from(expected))
+            );
+        }
+        assert_eq!(
+            execute(&redis, &["GETRANGE", "missing", "0", "-1"]).await,
+            Resp::BulkString(Bytes::new())
+        );
+    }
+
+    #[tokio::test]
+    async fn setrange_overwrites_and_pads_strings() {
+        let redis = redis();
+        execute(&redis, &["SET", "foo", "Hello World"]).await;
+
+        assert_eq!(
+            execute(&redis, &["SETRANGE", "foo", "6", "Redis"]).await,
+            Resp::Integer(11)
+        );
+        assert_eq!(
+            execute(&redis, &["GET", "foo"]).await,
+            Resp::BulkString("Hello Redis".into())
+        );
+
+        assert_eq!(
+            execute(&redis, &["SETRANGE", "bar", "3", "x"]).await,
+            Resp::Integer(4)
+        );
+        assert_eq!(
+            execute(&redis, &["GET", "bar"]).await,
+            Resp::BulkString(Bytes::from_static(b"\0\0\0x"))
+        );
+
+        assert_eq!(
+            execute(&redis, &["SETRANGE", "baz", "3", ""]).await,
+            Resp::Integer(0)
+        );
+        assert_eq!(execute(&redis, &["EXISTS", "baz"]).await, Resp::Integer(0));
+        assert_eq!(
+            execute(&redis, &["SETRANGE", "foo", "-1", "x"]).await,
+            CommandError::OffsetOutOfRange.into()
+        );
+        assert_eq!(
+            execute(&redis, &["SETRANGE", "foo", "536870911", "xx"]).await,
+            CommandError::StringTooLong.into()
+        );
+    }
+
+    #[tokio::test]
+    async fn mset_and_mget_work_on_many_keys() {
+        let redis = redis();
+        execute(&redis, &["SET", "foo", "old", "PX", "100000"]).await;
+
+        assert_eq!(
+            execute(&redis, &["MSET", "foo", "1", "bar", "2"]).await,
+            Resp::SimpleString("OK".to_string())
+        );
+        assert_eq!(expiry(&redis, "foo").await, None);
+        assert_eq!(
+            execute(&redis, &["MGET", "foo", "missing", "bar"]).await,
+            Resp::Array(vec![
+                Resp::BulkString("1".into()),
+                Resp::Null,
+                Resp::BulkString("2".into())
+            ])
+        );
+
+        assert_eq!(
+            execute(&redis, &["MSETNX", "baz", "3", "bar", "4"]).await,
+            Resp::Integer(0)
+        );
+        assert_eq!(execute(&redis, &["EXISTS", "baz"]).await, Resp::Integer(0));
+        assert_eq!(
+            execute(&redis, &["MSETNX", "baz", "3", "qux", "4"]).await,
+            Resp::Integer(1)
+        );
+        assert_eq!(
+            execute(&redis, &["MGET", "baz", "qux"]).await,
+            Resp::Array(vec![
+                Resp::BulkString("3".into()),
+                Resp::BulkString("4".into())
+            ])
+        );
+
+        assert_eq!(
+            execute(&redis, &["MSET", "foo", "1", "bar"]).await,
+            CommandError::WrongArity("mset".to_string()).into()
+        );
+    }
+
+    #[tokio::test]
+    async fn set_supports_every_option() {
+        let redis = redis();
+        let ok = Resp::SimpleString("OK".to_string());
+        let now = Redis::ms_since_epoch();
+
+        assert_eq!(execute(&redis, &["SET", "foo", "1", "EX", "100"]).await, ok);
+        assert!(expiry(&redis, "foo").await.unwrap() >= now + 100_000);
+        assert_eq!(execute(&redis, &["SET", "foo", "2", "KEEPTTL"]).await, ok);
+        assert!(expiry(&redis, "foo").await.is_some());
+        assert_eq!(
+            execute(&redis, &["SET", "foo", "3", "EXAT", "4000000000"]).await,
+            ok
+        );
+        assert_eq!(expiry(&redis, "foo").await, Some(4_000_000_000_000));
+        assert_eq!(execute(&redis, &["SET", "foo", "4"]).await, ok);
+        assert_eq!(expiry(&redis, "foo").await, None);
+
+        assert_eq!(
+            execute(&redis, &["SET", "foo", "5", "NX"]).await,
+            Resp::Null
+        );
+        assert_eq!(
+            execute(&redis, &["SET", "bar", "1", "XX"]).await,
+            Resp::Null
+        );
+        assert_eq!(execute(&redis, &["EXISTS", "bar"]).await, Resp::Integer(0));
+        assert_eq!(execute(&redis, &["SET", "bar", "1", "NX"]).await, ok);
+        assert_eq!(execute(&redis, &["SET", "bar", "2", "XX"]).await, ok);
+
+        assert_eq!(
+            execute(&redis, &["SET", "bar", "3", "GET"]).await,
+            Resp::BulkString("2".into())
+        );
+        assert_eq!(
+            execute(&redis, &["SET", "baz", "1", "GET"]).await,
+            Resp::Null
+        );
+        assert_eq!(
+            execute(&redis, &["SET", "bar", "4", "NX", "GET"]).await,
+            Resp::BulkString("3".into())
+        );
+        assert_eq!(
+            execute(&redis, &["GET", "bar"]).await,
+            Resp::BulkString("3".into())
+        );
+
+        for options in [
+            &["NX", "XX"][..],
+            &["EX", "10", "PX", "10"],
+            &["KEEPTTL", "EX", "10"],
+            &["EX"],
+            &["FOO"],
+        ] {
+            let mut command_line = vec!["SET", "foo", "bar"];
+            command_line.extend_from_slice(options);
+            assert_eq!(
+                execute(&redis, &command_line).await,
+                CommandError::Syntax.into()
+            );
+        }
+        assert_eq!(
+            execute(&redis, &["SET", "foo", "bar", "EX", "0"]).await,
+            CommandError::InvalidExpireTime("set".to_string()).into()
+        );
+        assert_eq!(
+            execute(&redis, &["SET", "foo", "bar", "EX", "9223372036854775807"]).await,
+            CommandError::InvalidExpireTime("set".to_string()).into()
+        );
+        assert_eq!(
+            execute(&redis, &["SET", "foo", "bar", "PX", "soon"]).await,
+            CommandError::NotAnInteger.into()
+        );
+    }
+
+    #[tokio::test]
+    async fn type_reports_the_kind_of_value() {
+        let redis = redis();
+        execute(&redis, &["SET", "foo", "bar"]).await;
+        {
+            let mut keyspace = redis.keyspace.lock(0, &["list"]).await;
+            let list = VecDeque::from([Bytes::from("a")]);
+            keyspace.insert(Bytes::from("list"), RedisValue::List(list));
+        }
+
+        assert_eq!(
+            execute(&redis, &["TYPE", "foo"]).await,
+            Resp::SimpleString("string".to_string())
+        );
+        assert_eq!(
+            execute(&redis, &["TYPE", "list"]).await,
+            Resp::SimpleString("list".to_string())
+        );
+        assert_eq!(
+            execute(&redis, &["TYPE", "missing"]).await,
+            Resp::SimpleString("none".to_string())
+        );
+
+        for command_line in [
+            &["GET", "list"][..],
+            &["APPEND", "list", "x"],
+            &["INCR", "list"],
+            &["STRLEN", "list"],
+            &["SET", "list", "x", "GET"],
+        ] {
+            assert_eq!(
+                execute(&redis, command_line).await,
+                CommandError::WrongType.into()
+            );
+        }
+        assert_eq!(
+            execute(&redis, &["MGET", "foo", "list"]).await,
+            Resp::Array(vec![Resp::BulkString("bar".into()), Resp::Null])
+        );
+        assert_eq!(
+            execute(&redis, &["SET", "list", "x"]).await,
+            Resp::SimpleString("OK".to_string())
+        );
+        assert_eq!(
+            execute(&redis, &["TYPE", "list"]).await,
+            Resp::SimpleString("string".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn lists_push_pop_and_range() {
+        let redis = redis();
+        let bulks = |elements: &[&str]| {
+            Resp::Array(
+                elements
+                    .iter()
+                    .map(|element| Resp::BulkString(Bytes::copy_from_slice(element.as_bytes())))
+                    .collect(),
+            )
+        };
+
+        assert_eq!(
+            execute(&redis, &["RPUSH", "list", "c", "d"]).await,
+            Resp::Integer(2)
+        );
+        assert_eq!(
+            execute(&redis, &["LPUSH", "list", "b", "a"]).await,
+            Resp::Integer(4)
+        );
+        assert_eq!(execute(&redis, &["LLEN", "list"]).await, Resp::Integer(4));
+        assert_eq!(
+            execute(&redis, &["LRANGE", "list", "0", "-1"]).await,
+            bulks(&["a", "b", "c", "d"])
+        );
+        assert_eq!(
+            execute(&redis, &["LRANGE", "list", "-3", "1"]).await,
+            bulks(&["b"])
+        );
+        assert_eq!(
+            execute(&redis, &["LRANGE", "list", "5", "10"]).await,
+            bulks(&[])
+        );
+
+        assert_eq!(
+            execute(&redis, &["LPOP", "list"]).await,
+            Resp::BulkString("a".into())
+        );
+        assert_eq!(
+            execute(&redis, &["RPOP", "list", "2"]).await,
+            bulks(&["d", "c"])
+        );
+        assert_eq!(execute(&redis, &["LPOP", "list", "5"]).await, bulks(&["b"]));
+        assert_eq!(execute(&redis, &["EXISTS", "list"]).await, Resp::Integer(0));
+        assert_eq!(execute(&redis, &["LPOP", "list"]).await, Resp::Null);
+        assert_eq!(
+            execute(&redis, &["RPOP", "list", "2"]).await,
+            Resp::NullArray
+        );
+        assert_eq!(execute(&redis, &["LLEN", "list"]).await, Resp::Integer(0));
+
+        execute(&redis, &["SET", "foo", "bar"]).await;
+        assert_eq!(
+            execute(&redis, &["LPUSH", "foo", "a"]).await,
+            CommandError::WrongType.into()
+        );
+        assert_eq!(
+            execute(&redis, &["LRANGE", "foo", "0", "-1"]).await,
+            CommandError::WrongType.into()
+        );
+        assert_eq!(
+            execute(&redis, &["LPOP", "foo", "-1"]).await,
+            CommandError::NotPositive.into()
+        );
+    }
+
+    #[tokio::test]
+    async fn lists_can_be_edited_in_place() {
+        let redis = redis();
+        let range = |redis| async move {
+            match execute(redis, &["LRANGE", "list", "0", "-1"]).await {
+                Resp::Array(elements) => elements
+                    .into_iter()
+                    .map(|element| element.to_string())
+                    .collect::<Vec<_>>(),
+                reply => panic!("expected an array reply, got {:?}", reply),
+            }
+        };
+        execute(&redis, &["RPUSH", "list", "a", "b", "a", "c", "a"]).await;
+
+        assert_eq!(
+            execute(&redis, &["LINSERT", "list", "BEFORE", "b", "x"]).await,
+            Resp::Integer(6)
+        );
+        assert_eq!(
+            execute(&redis, &["LINSERT", "list", "after", "c", "y"]).await,
+            Resp::Integer(7)
+        );
+        assert_eq!(
+            execute(&redis, &["LINSERT", "list", "BEFORE", "z", "x"]).await,
+            Resp::Integer(-1)
+        );
+        assert_eq!(
+            execute(&redis, &["LINSERT", "missing", "BEFORE", "z", "x"]).await,
+            Resp::Integer(0)
+        );
+        assert_eq!(range(&redis).await, ["a", "x", "b", "a", "c", "y", "a"]);
+
+        execute(&redis, &["LSET", "list", "-2", "Y"]).await;
+        assert_eq!(
+            execute(&redis, &["LSET", "list", "7", "z"]).await,
+            CommandError::IndexOutOfRange.into()
+        );
+        assert_eq!(
+            execute(&redis, &["LSET", "missing", "0", "z"]).await,
+            CommandError::NoSuchKey.into()
+        );
+
+        assert_eq!(
+            execute(&redis, &["LREM", "list", "-1", "a"]).await,
+            Resp::Integer(1)
+        );
+        assert_eq!(range(&redis).await, ["a", "x", "b", "a", "c", "Y"]);
+        assert_eq!(
+            execute(&redis, &["LREM", "list", "1", "a"]).await,
+            Resp::Integer(1)
+        );
+        assert_eq!(range(&redis).await, ["x", "b", "a", "c", "Y"]);
+
+        execute(&redis, &["LTRIM", "list", "1", "-2"]).await;
+        assert_eq!(range(&redis).await, ["b", "a", "c"]);
+        execute(&redis, &["LTRIM", "list", "5", "10"]).await;
+        assert_eq!(execute(&redis, &["EXISTS", "list"]).await, Resp::Integer(0));
+
+        execute(&redis, &["RPUSH", "list", "a", "a"]).await;
+        assert_eq!(
+            execute(&redis, &["LREM", "list", "0", "a"]).await,
+            Resp::Integer(2)
+        );
+        assert_eq!(execute(&redis, &["EXISTS", "list"]).await, Resp::Integer(0));
+    }
+
+    #[tokio::test]
+    async fn blocking_pops_wait_for_a_push() {
+        let redis = redis();
+        let pushed = |key: &str, element: &str| {
+            Resp::Array(vec![
+                Resp::BulkString(Bytes::copy_from_slice(key.as_bytes())),
+                Resp::BulkString(Bytes::copy_from_slice(element.as_bytes())),
+            ])
+        };
+        execute(&redis, &["RPUSH", "ready", "a"]).await;
+        assert_eq!(
+            execute(&redis, &["BLPOP", "empty", "ready", "0"]).await,
+            pushed("ready", "a")
+        );
+
+        let push_later = async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            execute(&redis, &["RPUSH", "list", "a", "b"]).await
+        };
+        let (first, second, pushed_length) = tokio::join!(
+            execute(&redis, &["BLPOP", "missing", "list", "0"]),
+            execute(&redis, &["BRPOP", "list", "0"]),
+            push_later,
+        );
+        assert_eq!(first, pushed("list", "a"));
+        assert_eq!(second, pushed("list", "b"));
+        assert_eq!(pushed_length, Resp::Integer(2));
+        assert_eq!(execute(&redis, &["EXISTS", "list"]).await, Resp::Integer(0));
+
+        let started = std::time::Instant::now();
+        assert_eq!(
+            execute(&redis, &["BLPOP", "list", "0.05"]).await,
+            Resp::NullArray
+        );
+        assert!(started.elapsed() >= Duration::from_millis(50));
+
+        assert_eq!(
+            execute(&redis, &["BLPOP", "list", "-1"]).await,
+            CommandError::NegativeTimeout.into()
+        );
+        assert_eq!(
+            execute(&redis, &["BLPOP", "list", "soon"]).await,
+            CommandError::TimeoutNotAFloat.into()
+        );
+        execute(&redis, &["SET", "foo", "bar"]).await;
+        assert_eq!(
+            execute(&redis, &["BLPOP", "foo", "0"]).await,
+            CommandError::WrongType.into()
+        );
+    }
+
+    #[tokio::test]
+    async fn lmove_moves_elements_between_lists() {
+        let redis = redis();
+        let range = |redis, key| async move { execute(redis, &["LRANGE", key, "0", "-1"]).await };
+        let bulks = |elements: &[&str]| {
+            Resp::Array(
+                elements
+                    .iter()
+                    .map(|element| Resp::BulkString(Bytes::copy_from_slice(element.as_bytes())))
+                    .collect(),
+            )
+        };
+        execute(&redis, &["RPUSH", "source", "a", "b", "c"]).await;
+
+        assert_eq!(
+            execute(&redis, &["LMOVE", "source", "destination", "RIGHT", "LEFT"]).await,
+            Resp::BulkString("c".into())
+        );
+        assert_eq!(
+            execute(&redis, &["LMOVE", "source", "destination", "left", "left"]).await,
+            Resp::BulkString("a".into())
+        );
+        assert_eq!(range(&redis, "source").await, bulks(&["b"]));
+        assert_eq!(range(&redis, "destination").await, bulks(&["a", "c"]));
+
+        // Rotating a list onto itself.
+        assert_eq!(
+            execute(
+                &redis,
+                &["LMOVE", "destination", "destination", "LEFT", "RIGHT"]
+            )
+            .await,
+            Resp::BulkString("a".into())
+        );
+        assert_eq!(range(&redis, "destination").await, bulks(&["c", "a"]));
+
+        execute(&redis, &["LMOVE", "source", "destination", "LEFT", "LEFT"]).await;
+        assert_eq!(
+            execute(&redis, &["EXISTS", "source"]).await,
+            Resp::Integer(0)
+        );
+        assert_eq!(
+            execute(&redis, &["LMOVE", "source", "destination", "LEFT", "LEFT"]).await,
+            Resp::Null
+        );
+
+        execute(&redis, &["SET", "foo", "bar"]).await;
+        assert_eq!(
+            execute(&redis, &["LMOVE", "destination", "foo", "LEFT", "LEFT"]).await,
+            CommandError::WrongType.into()
+        );
+        assert_eq!(
+            execute(&redis, &["LMOVE", "destination", "foo", "UP", "LEFT"]).await,
+            CommandError::Syntax.into()
+        );
+
+        let push_later = async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            execute(&redis, &["RPUSH", "source", "x"]).await
+        };
+        let (moved, _) = tokio::join!(
+            execute(&redis, &["BLMOVE", "source", "other", "LEFT", "RIGHT", "0"]),
+            push_later
+        );
+        assert_eq!(moved, Resp::BulkString("x".into()));
+        assert_eq!(range(&redis, "other").await, bulks(&["x"]));
+        assert_eq!(
+            execute(
+                &redis,
+                &["BLMOVE", "source", "other", "LEFT", "RIGHT", "0.02"]
+            )
+            .await,
+            Resp::Null
+        );
+    }
+
+    #[tokio::test]
+    async fn hashes_set_get_and_delete_fields() {
+        let redis = redis();
+
+        assert_eq!(
+            execute(&redis, &["HSET", "hash", "a", "1", "b", "2"]).await,
+            Resp::Integer(2)
+        );
+        assert_eq!(
+            execute(&redis, &["HSET", "hash", "b", "3", "c", "4"]).await,
+            Resp::Integer(1)
+        );
+        assert_eq!(
+            execute(&redis, &["HGET", "hash", "b"]).await,
+            Resp::BulkString("3".into())
+        );
+        assert_eq!(execute(&redis, &["HGET", "hash", "z"]).await, Resp::Null);
+        assert_eq!(
+            execute(&redis, &["HEXISTS", "hash", "a"]).await,
+            Resp::Integer(1)
+        );
+        assert_eq!(
+            execute(&redis, &["HEXISTS", "hash", "z"]).await,
+            Resp::Integer(0)
+        );
+        assert_eq!(execute(&redis, &["HLEN", "hash"]).await, Resp::Integer(3));
+
+        let mut pairs = match execute(&redis, &["HGETALL", "hash"]).await {
+            Resp::Map(pairs) => pairs,
+            reply => panic!("expected a map reply, got {:?}", reply),
+        };
+        pairs.sort_by_key(|(field, _)| field.to_string());
+        let bulk = |s: &'static str| Resp::BulkString(s.into());
+        assert_eq!(
+            pairs,
+            [
+                (bulk("a"), bulk("1")),
+                (bulk("b"), bulk("3")),
+                (bulk("c"), bulk("4"))
+            ]
+        );
+
+        assert_eq!(
+            execute(&redis, &["HDEL", "hash", "a", "z", "b"]).await,
+            Resp::Integer(2)
+        );
+        assert_eq!(
+            execute(&redis, &["HDEL", "hash", "c"]).await,
+            Resp::Integer(1)
+        );
+        assert_eq!(execute(&redis, &["EXISTS", "hash"]).await, Resp::Integer(0));
+        assert_eq!(
+            execute(&redis, &["HGETALL", "hash"]).await,
+            Resp::Map(vec![])
+        );
+
+        execute(&redis, &["SET", "foo", "bar"]).await;
+        assert_eq!(
+            execute(&redis, &["HSET", "foo", "a", "1"]).await,
+            CommandError::WrongType.into()
+        );
+        assert_eq!(
+            execute(&redis, &["HSET", "hash", "a"]).await,
+            CommandError::WrongArity("hset".to_string()).into()
+        );
+    }
+
+    #[tokio::test]
+    async fn hashes_count_and_read_fields() {
+        let redis = redis();
+        let sorted = |reply| match reply {
+            Resp::Array(items) => {
+                let mut items = items.iter().map(Resp::to_string).collect::<Vec<_>>();
+                items.sort();
+                items
+            }
+            reply => panic!("expected an array reply, got {:?}", reply),
+        };
+
+        assert_eq!(
+            execute(&redis, &["HINCRBY", "hash", "count", "5"]).await,
+            Resp::Integer(5)
+        );
+        assert_eq!(
+            execute(&redis, &["HINCRBY", "hash", "count", "-7"]).await,
+            Resp::Integer(-2)
+        );
+        assert_eq!(
+            execute(&redis, &["HINCRBYFLOAT", "hash", "float", "1.5"]).await,
+            Resp::BulkString("1.5".into())
+        );
+        assert_eq!(
+            execute(&redis, &["HINCRBYFLOAT", "hash", "count", "0.25"]).await,
+            Resp::BulkString("-1.75".into())
+        );
+        assert_eq!(
+            execute(&redis, &["HINCRBY", "hash", "count", "1"]).await,
+            CommandError::HashNotAnInteger.into()
+        );
+        execute(&redis, &["HSET", "hash", "name", "redis"]).await;
+        assert_eq!(
+            execute(&redis, &["HINCRBYFLOAT", "hash", "name", "1"]).await,
+            CommandError::HashNotAFloat.into()
+        );
+
+        assert_eq!(
+            sorted(execute(&redis, &["HKEYS", "hash"]).await),
+            ["count", "float", "name"]
+        );
+        assert_eq!(
+            sorted(execute(&redis, &["HVALS", "hash"]).await),
+            ["-1.75", "1.5", "redis"]
+        );
+        assert_eq!(
+            execute(&redis, &["HMGET", "hash", "name", "missing"]).await,
+            Resp::Array(vec![Resp::BulkString("redis".into()), Resp::Null])
+        );
+        assert_eq!(
+            execute(&redis, &["HMGET", "missing", "name"]).await,
+            Resp::Array(vec![Resp::Null])
+        );
+
+        assert_eq!(
+            sorted(execute(&redis, &["HRANDFIELD", "hash", "10"]).await),
+            ["count", "float", "name"]
+        );
+        match execute(&redis, &["HRANDFIELD", "hash", "2", "WITHVALUES"]).await {
+            Resp::Pairs(pairs) => assert_eq!(pairs.len(), 2),
+            reply => panic!("expected an array reply, got {:?}", reply),
+        }
+        match execute(&redis, &["HRANDFIELD", "hash", "-5"]).await {
+            Resp::Array(items) => assert_eq!(items.len(), 5),
+            reply => panic!("expected an array reply, got {:?}", reply),
+        }
+        assert_eq!(
+            execute(&redis, &["HRANDFIELD", "hash", &i64::MIN.to_string()]).await,
+            CommandError::ValueOutOfRange.into()
+        );
+        let count = (-i64::MAX / 2 - 1).to_string();
+        assert_eq!(
+            execute(&redis, &["HRANDFIELD", "hash", &count, "WITHVALUES"]).await,
+            CommandError::ValueOutOfRange.into()
+        );
+        match execute(&redis, &["HRANDFIELD", "hash"]).await {
+            Resp::BulkString(field) => assert!(["count", "float", "name"]
+                .iter()
+                .any(|name| field == name.as_bytes())),
+            reply => panic!("expected a bulk string reply, got {:?}", reply),
+        }
+        assert_eq!(
+            execute(&redis, &["HRANDFIELD", "missing"]).await,
+            Resp::Null
+        );
+        assert_eq!(
+            execute(&redis, &["HRANDFIELD", "missing", "3"]).await,
+            Resp::Array(vec![])
+        );
+    }
+
+    #[tokio::test]
+    async fn sets_add_and_remove_members() {
+        let redis = redis();
+
+        assert_eq!(
+            execute(&redis, &["SADD", "set", "a", "b", "a"]).await,
+            Resp::Integer(2)
+        );
+        assert_eq!(
+            execute(&redis, &["SADD", "set", "b", "c"]).await,
+            Resp::Integer(1)
+        );
+        assert_eq!(execute(&redis, &["SCARD", "set"]).await, Resp::Integer(3));
+        assert_eq!(
+            execute(&redis, &["SISMEMBER", "set", "a"]).await,
+            Resp::Integer(1)
+        );
+        assert_eq!(
+            execute(&redis, &["SISMEMBER", "set", "z"]).await,
+            Resp::Integer(0)
+        );
+
+        let mut members = match execute(&redis, &["SMEMBERS", "set"]).await {
+            Resp::Set(members) => members.iter().map(Resp::to_string).collect::<Vec<_>>(),
+            reply => panic!("expected a set reply, got {:?}", reply),
+        };
+        members.sort();
+        assert_eq!(members, ["a", "b", "c"]);
+
+        assert_eq!(
+            execute(&redis, &["SREM", "set", "a", "z", "b"]).await,
+            Resp::Integer(2)
+        );
+        assert_eq!(
+            execute(&redis, &["SREM", "set", "c"]).await,
+            Resp::Integer(1)
+        );
+        assert_eq!(execute(&redis, &["EXISTS", "set"]).await, Resp::Integer(0));
+        assert_eq!(
+            execute(&redis, &["SMEMBERS", "set"]).await,
+            Resp::Set(vec![])
+        );
+        assert_eq!(execute(&redis, &["SCARD", "set"]).await, Resp::Integer(0));
+
+        execute(&redis, &["SET", "foo", "bar"]).await;
+        assert_eq!(
+            execute(&redis, &["SADD", "foo", "a"]).await,
+            CommandError::WrongType.into()
+        );
+        assert_eq!(
+            execute(&redis, &["SISMEMBER", "foo", "a"]).await,
+            CommandError::WrongType.into()
+        );
+    }
+
+    #[tokio::test]
+    async fn sets_can_be_combined() {
+        let redis = redis();
+        let members = |reply| {
+            let mut members = match reply {
+                Resp::Set(members) => members.iter().map(Resp::to_string).collect::<Vec<_>>(),
+                reply => panic!("expected a set reply, got {:?}", reply),
+            };
+            members.sort();
+            members
+        };
+        execute(&redis, &["SADD", "a", "1", "2", "3", "4"]).await;
+        execute(&redis, &["SADD", "b", "2", "3", "5"]).await;
+        execute(&redis, &["SADD", "c", "3", "4", "6"]).await;
+
+        assert_eq!(
+            members(execute(&redis, &["SINTER", "a", "b", "c"]).await),
+            ["3"]
+        );
+        assert_eq!(
+            members(execute(&redis, &["SUNION", "a", "b", "missing"]).await),
+            ["1", "2", "3", "4", "5"]
+        );
+        assert_eq!(
+            members(execute(&redis, &["SDIFF", "a", "b", "c"]).await),
+            ["1"]
+        );
+        assert!(members(execute(&redis, &["SINTER", "a", "missing"]).await).is_empty());
+        assert!(members(execute(&redis, &["SDIFF", "missing", "a"]).await).is_empty());
+
+        execute(&redis, &["SET", "stored", "string"]).await;
+        execute(&redis, &["EXPIRE", "stored", "100"]).await;
+        assert_eq!(
+            execute(&redis, &["SUNIONSTORE", "stored", "b", "c"]).await,
+            Resp::Integer(5)
+        );
+        assert_eq!(execute(&redis, &["TTL", "stored"]).await, Resp::Integer(-1));
+        assert_eq!(
+            members(execute(&redis, &["SMEMBERS", "stored"]).await),
+            ["2", "3", "4", "5", "6"]
+        );
+        assert_eq!(
+            execute(&redis, &["SINTERSTORE", "stored", "a", "b"]).await,
+            Resp::Integer(2)
+        );
+        assert_eq!(
+            execute(&redis, &["SDIFFSTORE", "stored", "b", "a"]).await,
+            Resp::Integer(1)
+        );
+        assert_eq!(
+            execute(&redis, &["SINTERSTORE", "stored", "a", "missing"]).await,
+            Resp::Integer(0)
+        );
+        assert_eq!(
+            execute(&redis, &["EXISTS", "stored"]).await,
+            Resp::Integer(0)
+        );
+
+        assert_eq!(
+            execute(&redis, &["SINTERCARD", "2", "a", "b"]).await,
+            Resp::Integer(2)
+        );
+        assert_eq!(
+            execute(&redis, &["SINTERCARD", "2", "a", "b", "LIMIT", "1"]).await,
+            Resp::Integer(1)
+        );
+        assert_eq!(
+            execute(&redis, &["SINTERCARD", "2", "a", "missing"]).await,
+            Resp::Integer(0)
+        );
+        assert_eq!(
+            execute(&redis, &["SINTERCARD", "0", "a"]).await,
+            CommandError::NumKeysNotPositive.into()
+        );
+        assert_eq!(
+            execute(&redis, &["SINTERCARD", "3", "a", "b"]).await,
+            CommandError::TooManyKeys.into()
+        );
+        assert_eq!(
+            execute(&redis, &["SINTERCARD", "2", "a", "b", "LIMIT", "-1"]).await,
+            CommandError::NegativeLimit.into()
+        );
+
+        execute(&redis, &["SET", "foo", "bar"]).await;
+        assert_eq!(
+            execute(&redis, &["SUNION", "a", "foo"]).await,
+            CommandError::WrongType.into()
+        );
+    }
+
+    #[tokio::test]
+    async fn sets_give_out_random_members() {
+        let redis = redis();
+        let members = |reply| {
+            let mut members = match reply {
+                Resp::Set(members) | Resp::Array(members) => {
+                    members.iter().map(Resp::to_string).collect::<Vec<_>>()
+                }
+                reply => panic!("expected a set reply, got {:?}", reply),
+            };
+            members.sort();
+            members
+        };
+        execute(&redis, &["SADD", "set", "a", "b", "c", "d"]).await;
+
+        assert_eq!(
+            members(execute(&redis, &["SRANDMEMBER", "set", "10"]).await),
+            ["a", "b", "c", "d"]
+        );
+        assert_eq!(
+            members(execute(&redis, &["SRANDMEMBER", "set", "-6"]).await).len(),
+            6
+        );
+        assert_eq!(
+            execute(&redis, &["SRANDMEMBER", "set", &i64::MIN.to_string()]).await,
+            CommandError::ValueOutOfRange.into()
+        );
+        assert_eq!(
+            execute(&redis, &["SRANDMEMBER", "missing"]).await,
+            Resp::Null
+        );
+        assert_eq!(execute(&redis, &["SCARD", "set"]).await, Resp::Integer(4));
+
+        let popped = members(execute(&redis, &["SPOP", "set", "3"]).await);
+        assert_eq!(popped.len(), 3);
+        let last = match execute(&redis, &["SPOP", "set"]).await {
+            Resp::BulkString(member) => member,
+            reply => panic!("expected a bulk string reply, got {:?}", reply),
+        };
+        assert!(!popped.iter().any(|member| member.as_bytes() == last));
+        assert_eq!(execute(&redis, &["EXISTS", "set"]).await, Resp::Integer(0));
+        assert_eq!(execute(&redis, &["SPOP", "set"]).await, Resp::Null);
+        assert_eq!(
+            execute(&redis, &["SPOP", "set", "-1"]).await,
+            CommandError::NotPositive.into()
+        );
+
+        execute(&redis, &["SADD", "source", "a", "b"]).await;
+        assert_eq!(
+            execute(&redis, &["SMOVE", "source", "destination", "a"]).await,
+            Resp::Integer(1)
+        );
+        assert_eq!(
+            execute(&redis, &["SMOVE", "source", "destination", "z"]).await,
+            Resp::Integer(0)
+        );
+        assert_eq!(
+            execute(&redis, &["SMOVE", "source", "source", "b"]).await,
+            Resp::Integer(1)
+        );
+        assert_eq!(
+            execute(&redis, &["SMOVE", "source", "destination", "b"]).await,
+            Resp::Integer(1)
+        );
+        assert_eq!(
+            execute(&redis, &["EXISTS", "source"]).await,
+            Resp::Integer(0)
+        );
+        assert_eq!(
+            members(execute(&redis, &["SMEMBERS", "destination"]).await),
+            ["a", "b"]
+        );
+
+        execute(&redis, &["SET", "foo", "bar"]).await;
+        assert_eq!(
+            execute(&redis, &["SMOVE", "destination", "foo", "a"]).await,
+            CommandError::WrongType.into()
+        );
+    }
+
+    #[tokio::test]
+    async fn sorted_sets_order_members_by_score() {
+        let redis = redis();
+        let bulk = |s: &'static str| Resp::BulkString(s.into());
+
+        assert_eq!(
+            execute(&redis, &["ZADD", "zset", "2", "b", "1", "a", "3", "c"]).await,
+            Resp::Integer(3)
+        );
+        assert_eq!(
+            execute(&redis, &["ZRANGE", "zset", "0", "-1"]).await,
+            Resp::Array(vec![bulk("a"), bulk("b"), bulk("c")])
+        );
+        assert_eq!(
+            execute(&redis, &["ZRANGE", "zset", "0", "1", "REV", "WITHSCORES"]).await,
+            Resp::Pairs(vec![
+                (bulk("c"), Resp::Double(3.0)),
+                (bulk("b"), Resp::Double(2.0))
+            ])
+        );
+        assert_eq!(
+            execute(&redis, &["ZSCORE", "zset", "b"]).await,
+            Resp::Double(2.0)
+        );
+        assert_eq!(execute(&redis, &["ZSCORE", "zset", "z"]).await, Resp::Null);
+        assert_eq!(
+            execute(&redis, &["ZRANK", "zset", "c"]).await,
+            Resp::Integer(2)
+        );
+        assert_eq!(
+            execute(&redis, &["ZREVRANK", "zset", "c", "WITHSCORE"]).await,
+            Resp::Array(vec![Resp::Integer(0), Resp::Double(3.0)])
+        );
+        assert_eq!(execute(&redis, &["ZRANK", "zset", "z"]).await, Resp::Null);
+
+        // Updates only count with CH, and only go through when the conditions hold.
+        assert_eq!(
+            execute(&redis, &["ZADD", "zset", "5", "a", "4", "d"]).await,
+            Resp::Integer(1)
+        );
+        assert_eq!(
+            execute(&redis, &["ZADD", "zset", "CH", "6", "a", "4", "d"]).await,
+            Resp::Integer(1)
+        );
+        assert_eq!(
+            execute(&redis, &["ZADD", "zset", "NX", "CH", "0", "a", "0", "e"]).await,
+            Resp::Integer(1)
+        );
+        assert_eq!(
+            execute(&redis, &["ZADD", "zset", "XX", "CH", "1", "e", "1", "f"]).await,
+            Resp::Integer(1)
+        );
+        assert_eq!(
+            execute(&redis, &["ZADD", "zset", "GT", "CH", "1", "a", "7", "b"]).await,
+            Resp::Integer(1)
+        );
+        assert_eq!(
+            execute(&redis, &["ZADD", "zset", "LT", "CH", "2", "a", "8", "c"]).await,
+            Resp::Integer(1)
+        );
+        assert_eq!(
+            execute(&redis, &["ZADD", "zset", "INCR", "1.5", "a"]).await,
+            Resp::Double(3.5)
+        );
+        assert_eq!(
+            execute(&redis, &["ZADD", "zset", "GT", "INCR", "-1", "a"]).await,
+            Resp::Null
+        );
+        assert_eq!(
+            execute(&redis, &["ZRANGE", "zset", "0", "-1", "WITHSCORES"]).await,
+            Resp::Pairs(vec![
+                (bulk("e"), Resp::Double(1.0)),
+                (bulk("c"), Resp::Double(3.0)),
+                (bulk("a"), Resp::Double(3.5)),
+                (bulk("d"), Resp::Double(4.0)),
+                (bulk("b"), Resp::Double(7.0))
+            ])
+        );
+
+        assert_eq!(
+            execute(&redis, &["ZADD", "zset", "NX", "XX", "1", "a"]).await,
+            CommandError::ZAddNxXxIncompatible.into()
+        );
+        assert_eq!(
+            execute(&redis, &["ZADD", "zset", "NX", "GT", "1", "a"]).await,
+            CommandError::ZAddGtLtNxIncompatible.into()
+        );
+        assert_eq!(
+            execute(&redis, &["ZADD", "zset", "INCR", "1", "a", "2", "b"]).await,
+            CommandError::ZAddIncrementPairs.into()
+        );
+        assert_eq!(
+            execute(&redis, &["ZADD", "zset", "one", "a"]).await,
+            CommandError::NotAFloat.into()
+        );
+        assert_eq!(
+            execute(&redis, &["ZADD", "zset", "1", "a", "2"]).await,
+            CommandError::Syntax.into()
+        );
+        execute(&redis, &["ZADD", "inf", "inf", "a"]).await;
+        assert_eq!(
+            execute(&redis, &["ZADD", "inf", "INCR", "-inf", "a"]).await,
+            CommandError::ScoreNan.into()
+        );
+        assert_eq!(
+            execute(&redis, &["ZADD", "missing", "XX", "1", "a"]).await,
+            Resp::Integer(0)
+        );
+        assert_eq!(
+            execute(&redis, &["EXISTS", "missing"]).await,
+            Resp::Integer(0)
+        );
+
+        assert_eq!(
+            execute(&redis, &["ZREM", "zset", "a", "z", "b"]).await,
+            Resp::Integer(2)
+        );
+        execute(&redis, &["ZREM", "zset", "c", "d", "e"]).await;
+        assert_eq!(execute(&redis, &["EXISTS", "zset"]).await, Resp::Integer(0));
+        assert_eq!(
+            execute(&redis, &["TYPE", "inf"]).await,
+            Resp::SimpleString("zset".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn sorted_sets_range_by_score_and_member() {
+        let redis = redis();
+        let range = |redis, args: &'static [&'static str]| async move {
+            match execute(redis, args).await {
+                Resp::Array(items) => items.iter().map(Resp::to_string).collect::<Vec<_>>(),
+                Resp::Pairs(pairs) => pairs
+                    .iter()
+                    .flat_map(|(member, score)| [member.to_string(), score.to_string()])
+                    .collect(),
+                reply => panic!("expected an array reply, got {:?}", reply),
+            }
+        };
+        execute(
+            &redis,
+            &["ZADD", "zset", "1", "a", "2", "b", "2", "c", "3", "d"],
+        )
+        .await;
+        execute(
+            &redis,
+            &["ZADD", "lex", "0", "a", "0", "b", "0", "c", "0", "d"],
+        )
+        .await;
+
+        assert_eq!(
+            range(&redis, &["ZRANGEBYSCORE", "zset", "(1", "3"]).await,
+            ["b", "c", "d"]
+        );
+        assert_eq!(
+            range(
+                &redis,
+                &[
+                    "ZRANGEBYSCORE",
+                    "zset",
+                    "-inf",
+                    "+inf",
+                    "WITHSCORES",
+                    "LIMIT",
+                    "1",
+                    "2"
+                ]
+            )
+            .await,
+            ["b", "2", "c", "2"]
+        );
+        assert_eq!(
+            range(&redis, &["ZREVRANGEBYSCORE", "zset", "3", "(1"]).await,
+            ["d", "c", "b"]
+        );
+        assert_eq!(
+            range(
+                &redis,
+                &["ZRANGE", "zset", "(3", "2", "BYSCORE", "REV", "LIMIT", "0", "-1"]
+            )
+            .await,
+            ["c", "b"]
+        );
+        assert_eq!(
+            range(&redis, &["ZREVRANGE", "zset", "0", "1"]).await,
+            ["d", "c"]
+        );
+        assert_eq!(
+            range(&redis, &["ZRANGEBYLEX", "lex", "[b", "+"]).await,
+            ["b", "c", "d"]
+        );
+        assert_eq!(
+            range(
+                &redis,
+                &["ZREVRANGEBYLEX", "lex", "(c", "-", "LIMIT", "1", "5"]
+            )
+            .await,
+            ["a"]
+        );
+        assert_eq!(
+            range(&redis, &["ZRANGE", "lex", "(a", "[c", "BYLEX"]).await,
+            ["b", "c"]
+        );
+        assert!(range(
+            &redis,
+            &["ZRANGEBYSCORE", "zset", "1", "3", "LIMIT", "-1", "2"]
+        )
+        .await
+        .is_empty());
+
+        assert_eq!(
+            execute(&redis, &["ZRANGEBYSCORE", "zset", "one", "3"]).await,
+            CommandError::ScoreRangeNotAFloat.into()
+        );
+        assert_eq!(
+            execute(&redis, &["ZRANGEBYLEX", "lex", "a", "+"]).await,
+            CommandError::LexRangeInvalid.into()
+        );
+        assert_eq!(
+            execute(&redis, &["ZRANGE", "zset", "0", "1", "LIMIT", "0", "1"]).await,
+            CommandError::ZRangeLimitWithoutBy.into()
+        );
+        assert_eq!(
+            execute(&redis, &["ZRANGE", "lex", "-", "+", "BYLEX", "WITHSCORES"]).await,
+            CommandError::ZRangeWithScoresByLex.into()
+        );
+        assert_eq!(
+            execute(&redis, &["ZRANGEBYLEX", "lex", "-", "+", "WITHSCORES"]).await,
+            CommandError::Syntax.into()
+        );
+    }
+
+    #[tokio::test]
+    async fn sorted_sets_count_and_pop_members() {
+        let redis = redis();
+        let bulk = |s: &'static str| Resp::BulkString(s.into());
+
+        assert_eq!(
+            execute(&redis, &["ZINCRBY", "zset", "2.5", "a"]).await,
+            Resp::Double(2.5)
+        );
+        assert_eq!(
+            execute(&redis, &["ZINCRBY", "zset", "-1", "a"]).await,
+            Resp::Double(1.5)
+        );
+        execute(&redis, &["ZADD", "zset", "2", "b", "3", "c", "4", "d"]).await;
+        assert_eq!(execute(&redis, &["ZCARD", "zset"]).await, Resp::Integer(4));
+        assert_eq!(
+            execute(&redis, &["ZCARD", "missing"]).await,
+            Resp::Integer(0)
+        );
+        assert_eq!(
+            execute(&redis, &["ZCOUNT", "zset", "(1.5", "3"]).await,
+            Resp::Integer(2)
+        );
+        assert_eq!(
+            execute(&redis, &["ZCOUNT", "zset", "-inf", "+inf"]).await,
+            Resp::Integer(4)
+        );
+
+        assert_eq!(
+            execute(&redis, &["ZPOPMIN", "zset"]).await,
+            Resp::Array(vec![bulk("a"), Resp::Double(1.5)])
+        );
+        assert_eq!(
+            execute(&redis, &["ZPOPMAX", "zset", "2"]).await,
+            Resp::Pairs(vec![
+                (bulk("d"), Resp::Double(4.0)),
+                (bulk("c"), Resp::Double(3.0))
+            ])
+        );
+        assert_eq!(
+            execute(&redis, &["ZPOPMIN", "zset", "5"]).await,
+            Resp::Pairs(vec![(bulk("b"), Resp::Double(2.0))])
+        );
+        assert_eq!(execute(&redis, &["EXISTS", "zset"]).await, Resp::Integer(0));
+        assert_eq!(
+            execute(&redis, &["ZPOPMIN", "zset"]).await,
+            Resp::Array(vec![])
+        );
+        assert_eq!(
+            execute(&redis, &["ZPOPMIN", "zset", "-1"]).await,
+            CommandError::NotPositive.into()
+        );
+
+        let add_later = async {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            execute(&redis, &["ZADD", "zset", "1", "x", "2", "y"]).await
+        };
+        let (popped, _) = tokio::join!(
+            execute(&redis, &["BZPOPMAX", "missing", "zset", "0"]),
+            add_later
+        );
+        assert_eq!(
+            popped,
+            Resp::Array(vec![bulk("zset"), bulk("y"), Resp::Double(2.0)])
+        );
+        assert_eq!(
+            execute(&redis, &["BZPOPMIN", "zset", "0"]).await,
+            Resp::Array(vec![bulk("zset"), bulk("x"), Resp::Double(1.0)])
+        );
+        assert_eq!(
+            execute(&redis, &["BZPOPMIN", "zset", "0.02"]).await,
+            Resp::NullArray
+        );
+    }
+
+    #[tokio::test]
+    async fn strings_work_as_bitmaps() {
+        let redis = redis();
+
+        assert_eq!(
+            execute(&redis, &["SETBIT", "bits", "7", "1"]).await,
+            Resp::Integer(0)
+        );
+        assert_eq!(
+            execute(&redis, &["SETBIT", "bits", "7", "0"]).await,
+            Resp::Integer(1)
+        );
+        execute(&redis, &["SETBIT", "bits", "1", "1"]).await;
+        execute(&redis, &["SETBIT", "bits", "20", "1"]).await;
+        assert_eq!(
+            execute(&redis, &["GET", "bits"]).await,
+            Resp::BulkString(Bytes::from_static(b"\x40\x00\x08"))
+        );
+        assert_eq!(
+            execute(&redis, &["GETBIT", "bits", "1"]).await,
+            Resp::Integer(1)
+        );
+        assert_eq!(
+            execute(&redis, &["GETBIT", "bits", "2"]).await,
+            Resp::Integer(0)
+        );
+        assert_eq!(
+            execute(&redis, &["GETBIT", "bits", "999"]).await,
+            Resp::Integer(0)
+        );
+        assert_eq!(
+            execute(&redis, &["SETBIT", "bits", "-1", "1"]).await,
+            CommandError::BitOffsetInvalid.into()
+        );
+        assert_eq!(
+            execute(&redis, &["SETBIT", "bits", "1", "2"]).await,
+            CommandError::BitNotAnInteger.into()
+        );
+
+        execute(&redis, &["SET", "foo", "foobar"]).await;
+        assert_eq!(
+            execute(&redis, &["BITCOUNT", "foo"]).await,
+            Resp::Integer(26)
+        );
+        assert_eq!(
+            execute(&redis, &["BITCOUNT", "foo", "1", "1"]).await,
+            Resp::Integer(6)
+        );
+        assert_eq!(
+            execute(&redis, &["BITCOUNT", "foo", "5", "30", "BIT"]).await,
+            Resp::Integer(17)
+        );
+        assert_eq!(
+            execute(&redis, &["BITCOUNT", "foo", "-2", "-1"]).await,
+            Resp::Integer(7)
+        );
+        assert_eq!(
+            execute(&redis, &["BITCOUNT", "missing"]).await,
+            Resp::Integer(0)
+        );
+        assert_eq!(
+            execute(&redis, &["BITCOUNT", "foo", "1"]).await,
+            CommandError::Syntax.into()
+        );
+
+        {
+            let mut keyspace = redis.keyspace.lock(0, &["mask", "ones"]).await;
+            let bytes = |bytes: &'static [u8]| RedisValue::String(Bytes::from_static(bytes));
+            keyspace.insert(Bytes::from("mask"), bytes(b"\xff\xf0\x00"));
+            keyspace.insert(Bytes::from("ones"), bytes(b"\xff\xff"));
+        }
+        assert_eq!(
+            execute(&redis, &["BITPOS", "mask", "0"]).await,
+            Resp::Integer(12)
+        );
+        assert_eq!(
+            execute(&redis, &["BITPOS", "mask", "1", "1"]).await,
+            Resp::Integer(8)
+        );
+        assert_eq!(
+            execute(&redis, &["BITPOS", "mask", "1", "2", "-1"]).await,
+            Resp::Integer(-1)
+        );
+        assert_eq!(
+            execute(&redis, &["BITPOS", "mask", "1", "10", "-1", "BIT"]).await,
+            Resp::Integer(10)
+        );
+        assert_eq!(
+            execute(&redis, &["BITPOS", "ones", "0"]).await,
+            Resp::Integer(16)
+        );
+        assert_eq!(
+            execute(&redis, &["BITPOS", "ones", "0", "0", "-1"]).await,
+            Resp::Integer(-1)
+        );
+        assert_eq!(
+            execute(&redis, &["BITPOS", "missing", "0"]).await,
+            Resp::Integer(0)
+        );
+        assert_eq!(
+            execute(&redis, &["BITPOS", "missing", "1"]).await,
+            Resp::Integer(-1)
+        );
+        assert_eq!(
+            execute(&redis, &["BITPOS", "mask", "2"]).await,
+            CommandError::BitPosNotABit.into()
+        );
+    }
+
+    #[tokio::test]
+    async fn bitop_combines_strings() {
+        let redis = redis();
+        {
+            let mut keyspace = redis.keyspace.lock(0, &["a", "b"]).await;
+            let bytes = |bytes: &'static [u8]| RedisValue::String(Bytes::from_static(bytes));
+            keyspace.insert(Bytes::from("a"), bytes(b"\xf0\x0f\xff"));
+            keyspace.insert(Bytes::from("b"), bytes(b"\x3c"));
+        }
+        let get = |redis, key| async move { execute(redis, &["GET", key]).await };
+        let bulk = |bytes: &'static [u8]| Resp::BulkString(Bytes::from_static(bytes));
+
+        assert_eq!(
+            execute(&redis, &["BITOP", "AND", "dest", "a", "b"]).await,
+            Resp::Integer(3)
+        );
+        assert_eq!(get(&redis, "dest").await, bulk(b"\x30\x00\x00"));
+        execute(&redis, &["BITOP", "or", "dest", "a", "b", "missing"]).await;
+        assert_eq!(get(&redis, "dest").await, bulk(b"\xfc\x0f\xff"));
+        execute(&redis, &["BITOP", "XOR", "dest", "a", "b"]).await;
+        assert_eq!(get(&redis, "dest").await, bulk(b"\xcc\x0f\xff"));
+        execute(&redis, &["BITOP", "NOT", "dest", "a"]).await;
+        assert_eq!(get(&redis, "dest").await, bulk(b"\x0f\xf0\x00"));
+
+        assert_eq!(
+            execute(&redis, &["BITOP", "AND", "dest", "missing"]).await,
+            Resp::Integer(0)
+        );
+        assert_eq!(execute(&redis, &["EXISTS", "dest"]).await, Resp::Integer(0));
+        assert_eq!(
+            execute(&redis, &["BITOP", "NOT", "dest", "a", "b"]).await,
+            CommandError::BitOpNotSingleKey.into()
+        );
+        assert_eq!(
+            execute(&redis, &["BITOP", "NAND", "dest", "a"]).await,
+            CommandError::Syntax.into()
+        );
+        execute(&redis, &["RPUSH", "list", "x"]).await;
+        assert_eq!(
+            execute(&redis, &["BITOP", "OR", "dest", "a", "list"]).await,
+            CommandError::WrongType.into()
+        );
+    }
+
+    #[tokio::test]
+    async fn object_reports_encodings_and_references() {
+        let redis = redis();
+        let object = |redis, subcommand, key| async move {
+            execute(redis, &["OBJECT", subcommand, key]).await
+        };
+        let encoding = |name: &'static str| Resp::BulkString(Bytes::from(name));
+
+        execute(&redis, &["SET", "number", "12345"]).await;
+        execute(&redis, &["SET", "word", "hello"]).await;
+        execute(&redis, &["SADD", "set", "1", "2", "3"]).await;
+        execute(&redis, &["HSET", "hash", "field", "value"]).await;
+        assert_eq!(object(&redis, "ENCODING", "number").await, encoding("int"));
+        assert_eq!(object(&redis, "encoding", "word").await, encoding("embstr"));
+        assert_eq!(object(&redis, "ENCODING", "set").await, encoding("intset"));
+        assert_eq!(
+            object(&redis, "ENCODING", "hash").await,
+            encoding("listpack")
+        );
+        assert_eq!(object(&redis, "ENCODING", "missing").await, Resp::Null);
+
+        // Strings changed in place are raw, until they are set again.
+        execute(&redis, &["APPEND", "number", "6"]).await;
+        assert_eq!(object(&redis, "ENCODING", "number").await, encoding("raw"));
+        execute(&redis, &["SET", "number", "12345"]).await;
+        assert_eq!(object(&redis, "ENCODING", "number").await, encoding("int"));
+        execute(&redis, &["APPEND", "appended", "1"]).await;
+        assert_eq!(
+            object(&redis, "ENCODING", "appended").await,
+            encoding("int")
+        );
+        execute(&redis, &["SETRANGE", "word", "0", "j"]).await;
+        assert_eq!(object(&redis, "ENCODING", "word").await, encoding("raw"));
+        execute(&redis, &["SETBIT", "bits", "1", "1"]).await;
+        assert_eq!(object(&redis, "ENCODING", "bits").await, encoding("raw"));
+
+        // Outgrowing a compact encoding is for good, even once the value shrinks back.
+        execute(&redis, &["SADD", "set", "member"]).await;
+        assert_eq!(
+            object(&redis, "ENCODING", "set").await,
+            encoding("listpack")
+        );
+        let long = "x".repeat(100);
+        execute(&redis, &["HSET", "hash", "long", &long]).await;
+        execute(&redis, &["HDEL", "hash", "long"]).await;
+        assert_eq!(
+            object(&redis, "ENCODING", "hash").await,
+            encoding("hashtable")
+        );
+
+        assert_eq!(object(&redis, "REFCOUNT", "word").await, Resp::Integer(1));
+        execute(&redis, &["COPY", "word", "copy"]).await;
+        assert_eq!(object(&redis, "REFCOUNT", "word").await, Resp::Integer(2));
+        assert_eq!(object(&redis, "IDLETIME", "word").await, Resp::Integer(0));
+        assert_eq!(
+            object(&redis, "FREQ", "word").await,
+            CommandError::LfuNotSelected.into()
+        );
+        assert_eq!(
+            execute(&redis, &["OBJECT", "LRU", "word"]).await,
+            CommandError::UnknownSubcommand("object".to_string(), "lru".to_string()).into()
+        );
+        assert_eq!(
+            execute(&redis, &["OBJECT", "ENCODING"]).await,
+            CommandError::WrongArity("object|encoding".to_string()).into()
+        );
+    }
+
+    #[tokio::test]
+    async fn scan_walks_every_key() {
+        let redis = redis();
+        for i in 0..100 {
+            execute(&redis, &["SET", &format!("key:{}", i), "value"]).await;
+        }
+        execute(&redis, &["RPUSH", "list", "element"]).await;
+        execute(&redis, &["SET", "gone", "value", "PX", "1"]).await;
+        tokio::time::sleep(Duration::from_millis(5)).await;
+
+        let scan = |redis, args: Vec<String>| async move {
+            let mut args = args.iter().map(String::as_str).collect::<Vec<_>>();
+            args.insert(0, "SCAN");
+            match execute(redis, &args).await {
+                Resp::Array(reply) => match reply.as_slice() {
+                    [Resp::BulkString(cursor), Resp::Array(keys)] => (
+                        std::str::from_utf8(cursor).unwrap().to_string(),
+                        keys.iter().map(Resp::to_string).collect::<Vec<_>>(),
+                    ),
+                    reply => panic!("unexpected SCAN reply {:?}", reply),
+                },
+                reply => panic!("unexpected SCAN reply {:?}", reply),
+            }
+        };
+        let scan_all = |redis, options: &'static [&'static str]| async move {
+            let mut cursor = "0".to_string();
+            let mut keys = HashSet::new();
+            loop {
+                let mut args = vec![cursor];
+                args.extend(options.iter().map(|option| option.to_string()));
+                let (next, batch) = scan(redis, args).await;
+                assert!(batch.iter().all(|key| keys.insert(key.clone())));
+                cursor = next;
+                if cursor == "0" {
+                    return keys;
+                }
+            }
+        };
+
+        let keys = scan_all(&redis, &["COUNT", "7"]).await;
+        assert_eq!(keys.len(), 101);
+        assert!(keys.contains("key:42") && keys.contains("list"));
+        let matching = scan_all(&redis, &["MATCH", "key:1?"]).await;
+        assert_eq!(matching.len(), 10);
+        let lists = scan_all(&redis, &["TYPE", "LIST", "COUNT", "1000"]).await;
+        assert_eq!(lists, HashSet::from(["list".to_string()]));
+        match execute(&redis, &["KEYS", "key:9*"]).await {
+            Resp::Array(keys) => assert_eq!(keys.len(), 11),
+            reply => panic!("unexpected KEYS reply {:?}", reply),
+        }
+
+        assert_eq!(
+            execute(&redis, &["SCAN", "nope"]).await,
+            CommandError::InvalidCursor.into()
+        );
+        assert_eq!(
+            execute(&redis, &["SCAN", "0", "COUNT", "0"]).await,
+            CommandError::Syntax.into()
+        );
+        assert_eq!(
+            execute(&redis, &["SCAN", "0", "NOVALUES"]).await,
+            CommandError::Syntax.into()
+        );
+    }
+
+    #[tokio::test]
+    async fn collections_can_be_scanned() {
+        let redis = redis();
+        let scan = |redis, args: &'static [&'static str]| async move {
+            match execute(redis, args).await {
+                Resp::Array(reply) => match reply.as_slice() {
+                    [Resp::BulkString(cursor), Resp::Array(elements)] => {
+                        (cursor.clone(), elements.clone())
+                    }
+                    reply => panic!("unexpected scan reply {:?}", reply),
+                },
+                reply => panic!("unexpected scan reply {:?}", reply),
+            }
+        };
+
+        // Compact collections come back whole, whatever the COUNT.
+        execute(&redis, &["SADD", "small", "a", "b", "c"]).await;
+        let (cursor, members) = scan(&redis, &["SSCAN", "small", "0", "COUNT", "1"]).await;
+        assert_eq!((cursor, members.len()), (Bytes::from("0"), 3));
+        execute(&redis, &["ZADD", "zset", "1.5", "a"]).await;
+        assert_eq!(
+            scan(&redis, &["ZSCAN", "zset", "0"]).await.1,
+            [Resp::BulkString(Bytes::from("a")), Resp::Double(1.5)]
+        );
+
+        for i in 0..200 {
+            let field = format!("field:{}", i);
+            execute(&redis, &["HSET", "hash", &field, "value"]).await;
+        }
+        let mut fields = HashSet::new();
+        let mut cursor = "0".to_string();
+        loop {
+            let args = ["HSCAN", "hash", &cursor, "COUNT", "30", "NOVALUES"];
+            let (next, batch) = match execute(&redis, &args).await {
+                Resp::Array(reply) => match reply.as_slice() {
+                    [Resp::BulkString(next), Resp::Array(batch)] => (next.clone(), batch.clone()),
+                    reply => panic!("unexpected HSCAN reply {:?}", reply),
+                },
+                reply => panic!("unexpected HSCAN reply {:?}", reply),
+            };
+            assert!(batch.len() <= 31);
+            assert!(batch.iter().all(|field| fields.insert(field.to_string())));
+            cursor = std::str::from_utf8(&next).unwrap().to_string();
+            if cursor == "0" {
+                break;
+            }
+        }
+        assert_eq!(fields.len(), 200);
+
+        let (_, pairs) = scan(
+            &redis,
+            &["HSCAN", "hash", "0", "MATCH", "field:1?", "COUNT", "1000"],
+        )
+        .await;
+        assert_eq!(pairs.len(), 20);
+        assert_eq!(
+            scan(&redis, &["SSCAN", "missing", "0"]).await,
+            (Bytes::from("0"), vec![])
+        );
+        assert_eq!(
+            execute(&redis, &["HSCAN", "small", "0"]).await,
+            CommandError::WrongType.into()
+        );
+        assert_eq!(
+            execute(&redis, &["SSCAN", "small", "0", "TYPE", "set"]).await,
+            CommandError::Syntax.into()
+        );
+    }
+
+    #[tokio::test]
+    async fn dbsize_and_randomkey_skip_expired_keys() {
+        let redis = redis();
+        assert_eq!(execute(&redis, &["DBSIZE"]).await, Resp::Integer(0));
+        assert_eq!(execute(&redis, &["RANDOMKEY"]).await, Resp::Null);
+
+        execute(&redis, &["SET", "gone", "value", "PX", "1"]).await;
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        assert_eq!(execute(&redis, &["DBSIZE"]).await, Resp::Integer(0));
+        assert_eq!(execute(&redis, &["RANDOMKEY"]).await, Resp::Null);
+
+        for key in ["a", "b", "c"] {
+            execute(&redis, &["SET", key, "value"]).await;
+        }
+        execute(&redis, &["SET", "gone", "value", "PX", "1"]).await;
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        assert_eq!(execute(&redis, &["DBSIZE"]).await, Resp::Integer(3));
+
+        let mut picked = HashSet::new();
+        for _ in 0..100 {
+            picked.insert(execute(&redis, &["RANDOMKEY"]).await.to_string());
+        }
+        let expected = ["a", "b", "c"].map(String::from);
+        assert_eq!(picked, HashSet::from(expected));
+        assert_eq!(
+            execute(&redis, &["DBSIZE", "extra"]).await,
+            CommandError::WrongArity("dbsize".to_string()).into()
+        );
+    }
+
+    #[tokio::test]
+    async fn databases_are_selected_per_connection() {
+        let redis = redis();
+        let mut session = redis.session();
+        let ok = Resp::SimpleString("OK".to_string());
+        let string = |value: &'static str| Resp::BulkString(Bytes::from(value));
+
+        execute(&redis, &["SET", "foo", "zero"]).await;
+        assert_eq!(execute_in(&redis, &mut session, &["SELECT", "1"]).await, ok);
+        assert_eq!(
+            execute_in(&redis, &mut session, &["GET", "foo"]).await,
+            Resp::Null
+        );
+        execute_in(&redis, &mut session, &["SET", "foo", "one"]).await;
+        assert_eq!(execute(&redis, &["GET", "foo"]).await, string("zero"));
+        assert_eq!(
+            execute(&redis, &["SELECT", "16"]).await,
+            CommandError::DbIndexOutOfRange.into()
+        );
+        assert_eq!(
+            execute(&redis, &["SELECT", "one"]).await,
+            Resp::SimpleError("ERR invalid DB index".to_string())
+        );
+
+        assert_eq!(execute(&redis, &["SWAPDB", "0", "1"]).await, ok);
+        assert_eq!(execute(&redis, &["GET", "foo"]).await, string("one"));
+        assert_eq!(
+            execute_in(&redis, &mut session, &["GET", "foo"]).await,
+            string("zero")
+        );
+        assert_eq!(
+            execute(&redis, &["SWAPDB", "0", "x"]).await,
+            Resp::SimpleError("ERR invalid second DB index".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn keys_move_and_copy_across_databases() {
+        let redis = redis();
+        let mut session = redis.session();
+        let string = |value: &'static str| Resp::BulkString(Bytes::from(value));
+
+        execute(&redis, &["SET", "foo", "bar", "PXAT", "99999999999999"]).await;
+        execute(&redis, &["SET", "taken", "zero"]).await;
+        execute_in(&redis, &mut session, &["SELECT", "3"]).await;
+        execute_in(&redis, &mut session, &["SET", "taken", "three"]).await;
+
+        assert_eq!(
+            execute(&redis, &["MOVE", "foo", "3"]).await,
+            Resp::Integer(1)
+        );
+        assert_eq!(
+            execute(&redis, &["MOVE", "foo", "3"]).await,
+            Resp::Integer(0)
+        );
+        assert_eq!(
+            execute(&redis, &["MOVE", "taken", "3"]).await,
+            Resp::Integer(0)
+        );
+        assert_eq!(
+            execute(&redis, &["MOVE", "taken", "0"]).await,
+            CommandError::SameObject.into()
+        );
+        assert_eq!(
+            execute(&redis, &["MOVE", "taken", "99"]).await,
+            CommandError::DbIndexOutOfRange.into()
+        );
+        assert_eq!(
+            execute_in(&redis, &mut session, &["GET", "foo"]).await,
+            string("bar")
+        );
+        assert!(matches!(
+            execute_in(&redis, &mut session, &["PTTL", "foo"]).await,
+            Resp::Integer(ttl) if ttl > 0
+        ));
+
+        let copy = ["COPY", "foo", "copied", "DB", "0"];
+        assert_eq!(
+            execute_in(&redis, &mut session, &copy).await,
+            Resp::Integer(1)
+        );
+        assert_eq!(execute(&redis, &["GET", "copied"]).await, string("bar"));
+        let copy = ["COPY", "foo", "taken", "DB", "0"];
+        assert_eq!(
+            execute_in(&redis, &mut session, &copy).await,
+            Resp::Integer(0)
+        );
+        assert_eq!(
+            execute_in(&redis, &mut session, &["COPY", "foo", "foo"]).await,
+            CommandError::SameObject.into()
+        );
+
+        assert_eq!(
+            execute_in(&redis, &mut session, &["FLUSHDB", "ASYNC"]).await,
+            Resp::SimpleString("OK".to_string())
+        );
+        assert_eq!(
+            execute_in(&redis, &mut session, &["DBSIZE"]).await,
+            Resp::Integer(0)
+        );
+        assert_eq!(execute(&redis, &["DBSIZE"]).await, Resp::Integer(2));
+        execute_in(&redis, &mut session, &["SET", "foo", "bar"]).await;
+        execute(&redis, &["FLUSHALL"]).await;
+        assert_eq!(
+            execute_in(&redis, &mut session, &["DBSIZE"]).await,
+            Resp::Integer(0)
+        );
+        assert_eq!(execute(&redis, &["DBSIZE"]).await, Resp::Integer(0));
+    }
+
+    #[tokio::test]
+    async fn writes_are_propagated_with_their_database() {
+        let redis = redis();
+        let mut session = redis.session();
+        execute_in(&redis, &mut session, &["SELECT", "2"]).await;
+        execute_in(&redis, &mut session, &["SET", "foo", "bar"]).await;
+
+        let mut resync = redis.register_replica(None, None).await;
+        assert!(resync.databases[2].store.contains_key(b"foo".as_slice()));
+        assert!(resync.databases[0].store.is_empty());
+
+        execute_in(&redis, &mut session, &["SET", "baz", "qux"]).await;
+        execute(&redis, &["SET", "baz", "zero"]).await;
+        execute_in(&redis, &mut session, &["DEL", "baz"]).await;
+
+        let mut stream = Vec::new();
+        while let Ok(write) = resync.writes.try_recv() {
+            stream.extend_from_slice(&write);
+        }
+        let expected = [
+            &["SELECT", "2"][..],
+            &["SET", "baz", "qux"],
+            &["SELECT", "0"],
+            &["SET", "baz", "zero"],
+            &["SELECT", "2"],
+            &["DEL", "baz"],
+        ]
+        .iter()
+        .flat_map(|command| Aof::encode_command(command))
+        .collect::<Vec<_>>();
+        assert_eq!(stream, expected);
+    }
+
+    #[tokio::test]
+    async fn transactions_queue_commands_until_exec() {
+        let redis = redis();
+        let mut session = redis.session();
+        let ok = Resp::SimpleString("OK".to_string());
+        let queued = Resp::SimpleString("QUEUED".to_string());
+
+        assert_eq!(execute_in(&redis, &mut session, &["MULTI"]).await, ok);
+        assert_eq!(
+            execute_in(&redis, &mut session, &["MULTI"]).await,
+            CommandError::NestedMulti.into()
+        );
+        assert_eq!(
+            execute_in(&redis, &mut session, &["SET", "foo", "bar"]).await,
+            queued
+        );
+        assert_eq!(
+            execute_in(&redis, &mut session, &["INCR", "foo"]).await,
+            queued
+        );
+        assert_eq!(
+            execute_in(&redis, &mut session, &["SELECT", "1"]).await,
+            queued
+        );
+        assert_eq!(
+            execute_in(&redis, &mut session, &["SET", "foo", "one"]).await,
+            queued
+        );
+        assert_eq!(execute(&redis, &["GET", "foo"]).await, Resp::Null);
+        assert_eq!(
+            execute_in(&redis, &mut session, &["EXEC"]).await,
+            Resp::Array(vec![
+                ok.clone(),
+                CommandError::NotAnInteger.into(),
+                ok.clone(),
+                ok.clone(),
+            ])
+        );
+        assert_eq!(session.db, 1);
+        assert_eq!(
+            execute(&redis, &["GET", "foo"]).await,
+            Resp::BulkString("bar".into())
+        );
+
+        assert_eq!(
+            execute_in(&redis, &mut session, &["EXEC"]).await,
+            CommandError::ExecWithoutMulti.into()
+        );
+        assert_eq!(
+            execute_in(&redis, &mut session, &["DISCARD"]).await,
+            CommandError::DiscardWithoutMulti.into()
+        );
+        execute_in(&redis, &mut session, &["MULTI"]).await;
+        execute_in(&redis, &mut session, &["DEL", "foo"]).await;
+        assert_eq!(execute_in(&redis, &mut session, &["DISCARD"]).await, ok);
+        assert_eq!(
+            execute_in(&redis, &mut session, &["EXISTS", "foo"]).await,
+            Resp::Integer(1)
+        );
+
+        // A command refused while queuing discards the whole transaction.
+        execute_in(&redis, &mut session, &["MULTI"]).await;
+        execute_in(&redis, &mut session, &["DEL", "foo"]).await;
+        assert_eq!(
+            execute_in(&redis, &mut session, &["SET", "foo"]).await,
+            CommandError::WrongArity("set".to_string()).into()
+        );
+        assert_eq!(
+            execute_in(&redis, &mut session, &["EXEC"]).await,
+            CommandError::ExecAbort.into()
+        );
+        assert_eq!(
+            execute_in(&redis, &mut session, &["EXISTS", "foo"]).await,
+            Resp::Integer(1)
+        );
+        execute_in(&redis, &mut session, &["MULTI"]).await;
+        execute_in(&redis, &mut session, &["DEL", "foo"]).await;
+        assert_eq!(
+            execute_in(
+                &redis,
+                &mut session,
+                &["FooBar", "x", &"y".repeat(200), "z"]
+            )
+            .await,
+            Resp::SimpleError(format!(
+                "ERR unknown command 'FooBar', with args beginning with: 'x' '{}' ",
+                "y".repeat(124)
+            ))
+        );
+        assert_eq!(
+            execute_in(&redis, &mut session, &["EXEC"]).await,
+            CommandError::ExecAbort.into()
+        );
+        assert_eq!(
+            execute_in(&redis, &mut session, &["EXISTS", "foo"]).await,
+            Resp::Integer(1)
+        );
+    }
+
+    #[tokio::test]
+    async fn transactions_are_propagated_together() {
+        let redis = redis();
+        let mut session = redis.session();
+        let mut resync = redis.register_replica(None, None).await;
+
+        execute_in(&redis, &mut session, &["MULTI"]).await;
+        execute_in(&redis, &mut session, &["SET", "foo", "bar"]).await;
+        execute_in(&redis, &mut session, &["GET", "foo"]).await;
+        execute_in(&redis, &mut session, &["SET", "baz", "qux"]).await;
+        execute_in(&redis, &mut session, &["EXEC"]).await;
+        execute_in(&redis, &mut session, &["MULTI"]).await;
+        execute_in(&redis, &mut session, &["DEL", "foo"]).await;
+        execute_in(&redis, &mut session, &["EXEC"]).await;
+
+        let mut stream = Vec::new();
+        while let Ok(write) = resync.writes.try_recv() {
+            stream.extend_from_slice(&write);
+        }
+        let expected = [
+            &["SELECT", "0"][..],
+            &["MULTI"],
+            &["SET", "foo", "bar"],
+            &["SET", "baz", "qux"],
+            &["EXEC"],
+            &["DEL", "foo"],
+        ]
+        .iter()
+        .flat_map(|command| Aof::encode_command(command))
+        .collect::<Vec<_>>();
+        assert_eq!(stream, expected);
+    }
+
+    #[tokio::test]
+    async fn replconf_records_the_replica_port() {
+        let redis = redis();
+        let mut session = redis.session();
+        let replconf = |args: &[&str]| {
+            let mut request = vec![Resp::BulkString(Bytes::from("REPLCONF"))];
+            request.extend(
+                args.iter()
+                    .map(|arg| Resp::BulkString(arg.to_string().into())),
+            );
+            Redis::parse_request(Resp::Array(request))
+        };
+
+        let command = replconf(&["listening-port", "6380", "capa", "psync2"]).unwrap();
+        assert_eq!(
+            redis.execute(command, &mut session).await,
+            Resp::SimpleString("OK".to_string())
+        );
+        assert_eq!(session.replica_listening_port, Some(6380));
+
+        let command = replconf(&["speed", "fast"]).unwrap();
+        assert_eq!(
+            redis.execute(command, &mut session).await,
+            Resp::SimpleError("ERR Unrecognized REPLCONF option: speed".to_string())
+        );
+        assert!(replconf(&["capa"]).is_err());
+        assert_eq!(
+            execute(&redis, &["PSYNC", "?", "-1"]).await,
+            Resp::SimpleError("ERR PSYNC is only supported on client connections".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn rename_carries_the_ttl_over() {
+        let redis = redis();
+        execute(&redis, &["SET", "foo", "bar", "PXAT", "99999999999999"]).await;
+        execute(&redis, &["SET", "baz", "old", "PXAT", "88888888888888"]).await;
+
+        assert_eq!(
+            execute(&redis, &["RENAME", "foo", "baz"]).await,
+            Resp::SimpleString("OK".to_string())
+        );
+        assert_eq!(execute(&redis, &["GET", "foo"]).await, Resp::Null);
+        assert_eq!(
+            execute(&redis, &["GET", "baz"]).await,
+            Resp::BulkString("bar".into())
+        );
+        assert_eq!(expiry(&redis, "foo").await, None);
+        assert_eq!(expiry(&redis, "baz").await, Some(99999999999999));
+
+        execute(&redis, &["SET", "persistent", "value"]).await;
+        execute(&redis, &["RENAME", "persistent", "baz"]).await;
+        assert_eq!(expiry(&redis, "baz").await, None);
+
+        assert_eq!(
+            execute(&redis, &["RENAME", "missing", "baz"]).await,
+            Resp::SimpleError("ERR no such key".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn copy_duplicates_the_ttl() {
+        let redis = redis();
+        execute(&redis, &["SET", "foo", "bar", "PXAT", "99999999999999"]).await;
+        execute(&redis, &["SET", "baz", "old", "PXAT", "88888888888888"]).await;
+
+        assert_eq!(
+            execute(&redis, &["COPY", "foo", "baz"]).await,
+            Resp::Integer(0)
+        );
+        assert_eq!(
+            execute(&redis, &["COPY", "foo", "baz", "REPLACE"]).await,
+            Resp::Integer(1)
+        );
+        assert_eq!(
+            execute(&redis, &["GET", "baz"]).await,
+            Resp::BulkString("bar".into())
+        );
+        assert_eq!(expiry(&redis, "foo").await, Some(99999999999999));
+        assert_eq!(expiry(&redis, "baz").await, Some(99999999999999));
+
+        execute(&redis, &["SET", "persistent", "value"]).await;
+        execute(&redis, &["COPY", "persistent", "baz", "REPLACE"]).await;
+        assert_eq!(expiry(&redis, "baz").await, None);
+        assert_eq!(
+            execute(&redis, &["COPY", "missing", "qux"]).await,
+            Resp::Integer(0)
+        );
+    }
+
+    #[tokio::test]
+    async fn restore_applies_its_ttl_argument() {
+        let redis = redis();
+        execute(&redis, &["SET", "foo", "bar", "PXAT", "99999999999999"]).await;
+        let payload = match execute(&redis, &["DUMP", "foo"]).await {
+            Resp::BulkString(payload) => payload,
+            reply => panic!("expected a DUMP payload, got {:?}", reply),
+        };
+
+        assert_eq!(
+            execute(&redis, &[&b"RESTORE"[..], b"foo", b"0", &payload]).await,
+            Resp::SimpleError("BUSYKEY Target key name already exists.".to_string())
+        );
+        assert_eq!(
+            execute(
+                &redis,
+                &[&b"RESTORE"[..], b"foo", b"0", &payload, b"REPLACE"]
+            )
+            .await,
+            Resp::SimpleString("OK".to_string())
+        );
+        assert_eq!(expiry(&redis, "foo").await, None);
+
+        execute(
+            &redis,
+            &[
+                &b"RESTORE"[..],
+                b"baz",
+                b"77777777777777",
+                &payload,
+                b"ABSTTL",
+            ],
+        )
+        .await;
+        assert_eq!(expiry(&redis, "baz").await, Some(77777777777777));
+        assert_eq!(
+            execute(&redis, &["GET", "baz"]).await,
+            Resp::BulkString("bar".into())
+        );
+
+        execute(&redis, &[&b"RESTORE"[..], b"qux", b"60000", &payload]).await;
+        let remaining = expiry(&redis, "qux").await.unwrap() - Redis::ms_since_epoch();
+        assert!(remaining > 50000 && remaining <= 60000);
+
+        assert_eq!(
+            execute(&redis, &["RESTORE", "quux", "0", "garbage"]).await,
+            Resp::SimpleError("ERR DUMP payload version or checksum are wrong".to_string())
+        );
+        assert_eq!(
+            execute(&redis, &[&b"RESTORE"[..], b"quux", b"-1", &payload]).await,
+            Resp::SimpleError("ERR Invalid TTL value, must be >= 0".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn command_getkeys_extracts_key_arguments() {
+        let redis = Server::builder().embedded();
+
+        assert_eq!(
+            redis
+                .execute(["COMMAND", "GETKEYS", "SET", "foo", "bar", "PX", "10"])
+                .await,
+            Resp::Array(vec![Resp::BulkString("foo".into())])
+        );
+        assert_eq!(
+            redis
+                .execute(["COMMAND", "GETKEYSANDFLAGS", "RENAME", "foo", "bar"])
+                .await,
+            Resp::Array(vec![
+                Resp::Array(vec![
+                    Resp::BulkString("foo".into()),
+                    Resp::Set(vec![
+                        Resp::SimpleString("RW".to_string()),
+                        Resp::SimpleString("access".to_string()),
+                        Resp::SimpleString("delete".to_string()),
+                    ]),
+                ]),
+                Resp::Array(vec![
+                    Resp::BulkString("bar".into()),
+                    Resp::Set(vec![
+                        Resp::SimpleString("OW".to_string()),
+                        Resp::SimpleString("update".to_string()),
+                    ]),
+                ]),
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn command_getkeys_rejects_bad_command_lines() {
+        let redis = Server::builder().embedded();
+
+        assert_eq!(
+            redis.execute(["COMMAND", "GETKEYS", "PING"]).await,
+            Resp::SimpleError("ERR The command has no key arguments".to_string())
+        );
+        assert_eq!(
+            redis.execute(["COMMAND", "GETKEYS", "NOSUCHCOMMAND"]).await,
+            Resp::SimpleError("ERR Invalid command specified".to_string())
+        );
+        assert_eq!(
+            redis.execute(["COMMAND", "GETKEYS", "GET"]).await,
+            Resp::SimpleError("ERR Invalid number of arguments specified for command".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn latency_histogram_reports_called_commands() {
+        let redis = Server::builder().embedded();
+        for _ in 0..3 {
+            redis.execute(["SET", "foo", "bar"]).await;
+        }
+        redis.execute(["GET", "foo"]).await;
+        redis.execute(["NOSUCHCOMMAND"]).await;
+
+        let histograms = match redis.execute(["LATENCY", "HISTOGRAM", "set", "ping"]).await {
+            Resp::Map(histograms) => histograms,
+            reply => panic!("expected a map of histograms, got {:?}", reply),
+        };
+        assert_eq!(histograms.len(), 1);
+        assert_eq!(histograms[0].0, Resp::BulkString("set".into()));
+        match &histograms[0].1 {
+            Resp::Map(details) => assert_eq!(
+                details[0],
+                (Resp::BulkString("calls".into()), Resp::Integer(3))
+            ),
+            reply => panic!("expected the histogram details, got {:?}", reply),
+        }
+
+        let names = match redis.execute(["LATENCY", "HISTOGRAM"]).await {
+            Resp::Map(all) => all
+                .iter()
+                .map(|(name, _)| name.to_string())
+                .collect::<Vec<_>>(),
+            reply => panic!("expected a map of histograms, got {:?}", reply),
+        };
+        assert_eq!(names, vec!["get", "latency|histogram", "set"]);
+    }
+
+    #[tokio::test]
+    async fn rename_command_renames_and_disables_commands() {
+        let redis = Server::builder()
+            .rename_command("CONFIG", "cfg")
+            .rename_command("debug", "")
+            .config("hz", "20")
+            .embedded();
+
+        assert_eq!(
+            redis.execute(["CFG", "GET", "hz"]).await,
+            Resp::Map(vec![(
+                Resp::BulkString("hz".into()),
+                Resp::BulkString("20".into())
+            )])
+        );
+        assert_eq!(
+            redis.execute(["CONFIG", "GET", "hz"]).await,
+            Resp::SimpleError(
+                "ERR unknown command 'CONFIG', with args beginning with: 'GET' 'hz' ".to_string()
+            )
+        );
+        assert_eq!(
+            redis.execute(["DEBUG", "POPULATE", "1"]).await,
+            Resp::SimpleError(
+                "ERR unknown command 'DEBUG', with args beginning with: 'POPULATE' '1' "
+                    .to_string()
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn rename_command_can_swap_names() {
+        let args = [
+            "redis",
+            "--rename-command",
+            "get",
+            "echo",
+            "--rename-command",
+            "echo",
+            "get",
+        ];
+        let redis = Server::builder()
+            .args(args.iter().map(|arg| arg.to_string()).collect())
+            .embedded();
+        redis.execute(["SET", "foo", "bar"]).await;
+
+        assert_eq!(
+            redis.execute(["ECHO", "foo"]).await,
+            Resp::BulkString("bar".into())
+        );
+        assert_eq!(
+            redis.execute(["GET", "foo"]).await,
+            Resp::BulkString("foo".into())
+        );
+    }
+
+    #[test]
+    fn protected_mode_only_accepts_loopback_connections() {
+        let remote = "192.168.1.10".parse().unwrap();
+        let build = |config: &[(&str, &str)]| {
+            let config = config
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect();
+            let config = Config::from_map(&config).unwrap();
+            Redis::new(config, &|| Box::new(MemoryStorage::default()), None, vec![]).unwrap()
+        };
+
+        let redis = build(&[]);
+        assert!(redis.accepts_connections_from("127.0.0.1".parse().unwrap()));
+        assert!(redis.accepts_connections_from("::1".parse().unwrap()));
+        assert!(!redis.accepts_connections_from(remote));
+
+        assert!(build(&[("protected-mode", "no")]).accepts_connections_from(remote));
+        assert!(build(&[("requirepass", "secret")]).accepts_connections_from(remote));
+        assert!(build(&[("bind", "0.0.0.0")]).accepts_connections_from(remote));
+        assert!(!build(&[("requirepass", "")]).accepts_connections_from(remote));
+    }
+
+    #[tokio::test]
+    async fn reads_delete_expired_keys() {
+        let redis = redis();
+        execute(&redis, &["SET", "foo", "bar", "PX", "1"]).await;
+        execute(&redis, &["SET", "baz", "qux", "PX", "1"]).await;
+        execute(&redis, &["SET", "kept", "value"]).await;
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+
+        assert_eq!(execute(&redis, &["GET", "foo"]).await, Resp::Null);
+        assert!(redis.keyspace.lock(0, &["foo"]).await.get(b"foo").is_none());
+        assert_eq!(expiry(&redis, "foo").await, None);
+
+        assert_eq!(
+            execute(&redis, &["KEYS", "*"]).await,
+            Resp::Array(vec![Resp::BulkString("kept".into())])
+        );
+        assert!(redis.keyspace.lock(0, &["baz"]).await.get(b"baz").is_none());
+    }
+
+    #[tokio::test]
+    async fn lazy_expiry_propagates_a_del() {
+        let redis = redis();
+        execute(&redis, &["SET", "foo", "bar", "PX", "1"]).await;
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+
+        let mut keyspace = redis.keyspace.lock(0, &["foo"]).await;
+        keyspace.take_propagated();
+        assert!(Redis::live_value(&mut keyspace, b"foo").is_none());
+        assert_eq!(
+            keyspace.take_propagated(),
+            vec![(0, vec![Bytes::from("DEL"), Bytes::from("foo")])]
+        );
+    }
+
+    #[tokio::test]
+    async fn subscriptions_are_confirmed_channel_by_channel() {
+        let redis = redis();
+        let mut session = redis.session();
+        let subscribe = |channels: &[&str]| Command::Subscribe {
+            channels: channels
+                .iter()
+                .map(|c| Bytes::copy_from_slice(c.as_bytes()))
+                .collect(),
+            pattern: false,
+        };
+        let unsubscribe = |channels: &[&str]| Command::Unsubscribe {
+            channels: channels
+                .iter()
+                .map(|c| Bytes::copy_from_slice(c.as_bytes()))
+                .collect(),
+            pattern: false,
+        };
+        let confirmation = |kind: &'static str, channel: Resp, count: i64| {
+            Resp::Push(vec![
+                Resp::BulkString(kind.into()),
+                channel,
+                Resp::Integer(count),
+            ])
+        };
+
+        assert_eq!(
+            redis.subscribe(unsubscribe(&[]), &mut session),
+            [confirmation("unsubscribe", Resp::Null, 0)]
+        );
+        assert_eq!(
+            redis.subscribe(subscribe(&["a", "b", "a"]), &mut session),
+            [
+                confirmation("subscribe", Resp::BulkString("a".into()), 1),
+                confirmation("subscribe", Resp::BulkString("b".into()), 2),
+                confirmation("subscribe", Resp::BulkString("a".into()), 2),
+            ]
+        );
+        assert!(session.subscribe_mode());
+        assert_eq!(
+            execute_in(&redis, &mut session, &["PUBLISH", "a", "hi"]).await,
+            CommandError::SubscribeContext("publish".to_string()).into()
+        );
+        assert_eq!(
+            execute(&redis, &["PUBLISH", "a", "hi"]).await,
+            Resp::Integer(1)
+        );
+        assert_eq!(
+            session.pushes.try_next(),
+            Some(Resp::Push(vec![
+                Resp::BulkString("message".into()),
+                Resp::BulkString("a".into()),
+                Resp::BulkString("hi".into()),
+            ]))
+        );
+
+        assert_eq!(
+            redis.subscribe(unsubscribe(&["c", "a", "b"]), &mut session),
+            [
+                confirmation("unsubscribe", Resp::BulkString("c".into()), 2),
+                confirmation("unsubscribe", Resp::BulkString("a".into()), 1),
+                confirmation("unsubscribe", Resp::BulkString("b".into()), 0),
+            ]
+        );
+        assert!(!session.subscribe_mode());
+
+        // Neither transactions nor handles have a connection to push messages to.
+        assert_eq!(
+            execute_in(&redis, &mut session, &["MULTI"]).await,
+            Resp::SimpleString("OK".to_string())
+        );
+        assert_eq!(
+            execute_in(&redis, &mut session, &["SUBSCRIBE", "a"]).await,
+            CommandError::DenyBlocking("subscribe").into()
+        );
+        assert_eq!(
+            execute(&redis, &["SUBSCRIBE", "a"]).await,
+            CommandError::DenyBlocking("subscribe").into()
+        );
+    }
+
+    #[tokio::test]
+    async fn pattern_subscriptions_show_up_in_pubsub_introspection() {
+        let redis = redis();
+        let mut session = redis.session();
+        let mut other = redis.session();
+        let bytes = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| Bytes::copy_from_slice(name.as_bytes()))
+                .collect::<Vec<_>>()
+        };
+        let confirmation = |kind: &'static str, channel: Resp, count: i64| {
+            Resp::Push(vec![
+                Resp::BulkString(kind.into()),
+                channel,
+                Resp::Integer(count),
+            ])
+        };
+
+        let psubscribe = Command::Subscribe {
+            channels: bytes(&["news.*", "*"]),
+            pattern: true,
+        };
+        assert_eq!(
+            redis.subscribe(psubscribe, &mut session),
+            [
+                confirmation("psubscribe", Resp::BulkString("news.*".into()), 1),
+                confirmation("psubscribe", Resp::BulkString("*".into()), 2),
+            ]
+        );
+        let subscribe = Command::Subscribe {
+            channels: bytes(&["news.tech", "sports"]),
+            pattern: false,
+        };
+        redis.subscribe(subscribe, &mut other);
+
+        assert_eq!(
+            execute(&redis, &["PUBLISH", "news.tech", "hi"]).await,
+            Resp::Integer(3)
+        );
+        assert_eq!(
+            execute(&redis, &["PUBSUB", "CHANNELS", "news.*"]).await,
+            Resp::Array(vec![Resp::BulkString("news.tech".into())])
+        );
+        assert_eq!(
+            execute(&redis, &["PUBSUB", "NUMSUB", "sports", "news.*"]).await,
+            Resp::Array(vec![
+                Resp::BulkString("sports".into()),
+                Resp::Integer(1),
+                Resp::BulkString("news.*".into()),
+                Resp::Integer(0),
+            ])
+        );
+        assert_eq!(
+            execute(&redis, &["PUBSUB", "NUMPAT"]).await,
+            Resp::Integer(2)
+        );
+
+        // Unsubscribing from every channel leaves the patterns, and the other way around.
+        let unsubscribe = Command::Unsubscribe {
+            channels: Vec::new(),
+            pattern: false,
+        };
+        assert_eq!(
+            redis.subscribe(unsubscribe, &mut session),
+            [confirmation("unsubscribe", Resp::Null, 2)]
+        );
+        let punsubscribe = Command::Unsubscribe {
+            channels: bytes(&["*"]),
+            pattern: true,
+        };
+        assert_eq!(
+            redis.subscribe(punsubscribe, &mut session),
+            [confirmation(
+                "punsubscribe",
+                Resp::BulkString("*".into()),
+                1
+            )]
+        );
+        drop(session);
+        assert_eq!(
+            execute(&redis, &["PUBSUB", "NUMPAT"]).await,
+            Resp::Integer(0)
+        );
+    }
+
+    #[tokio::test]
+    async fn tracking_clients_are_told_when_the_keys_they_read_change() {
+        let redis = redis();
+        let mut reader = redis.session();
+        reader.protocol = Protocol::Resp3;
+        let mut writer = redis.session();
+        let ok = Resp::SimpleString("OK".to_string());
+        let invalidate = |keys: Resp| Resp::Push(vec![Resp::BulkString("invalidate".into()), keys]);
+        let keys = |key: &'static str| Resp::Array(vec![Resp::BulkString(key.into())]);
+
+        assert_eq!(
+            execute_in(&redis, &mut reader, &["CLIENT", "GETREDIR"]).await,
+            Resp::Integer(-1)
+        );
+        assert_eq!(
+            execute_in(&redis, &mut reader, &["CLIENT", "TRACKING", "on", "NOLOOP"]).await,
+            ok
+        );
+        execute_in(&redis, &mut reader, &["GET", "foo"]).await;
+        execute_in(&redis, &mut writer, &["SET", "foo", "1"]).await;
+        assert_eq!(reader.pushes.try_next(), Some(invalidate(keys("foo"))));
+
+        // The key is only invalidated once until it is read again, never for the reader's writes.
+        execute_in(&redis, &mut writer, &["SET", "foo", "2"]).await;
+        execute_in(&redis, &mut reader, &["GET", "foo"]).await;
+        execute_in(&redis, &mut reader, &["SET", "foo", "3"]).await;
+        assert_eq!(reader.pushes.try_next(), None);
+
+        execute_in(&redis, &mut reader, &["GET", "foo"]).await;
+        execute_in(&redis, &mut writer, &["FLUSHALL"]).await;
+        assert_eq!(reader.pushes.try_next(), Some(invalidate(Resp::Null)));
+
+        // With OPTIN only the reads right after CLIENT CACHING YES are tracked.
+        execute_in(&redis, &mut reader, &["CLIENT", "TRACKING", "off"]).await;
+        execute_in(&redis, &mut reader, &["CLIENT", "TRACKING", "on", "OPTIN"]).await;
+        assert_eq!(
+            execute_in(&redis, &mut reader, &["CLIENT", "CACHING", "no"]).await,
+            CommandError::CachingMode("NO", "OPTOUT").into()
+        );
+        execute_in(&redis, &mut reader, &["GET", "foo"]).await;
+        execute_in(&redis, &mut reader, &["CLIENT", "CACHING", "yes"]).await;
+        execute_in(&redis, &mut reader, &["GET", "bar"]).await;
+        execute_in(&redis, &mut writer, &["MSET", "foo", "1", "bar", "1"]).await;
+        assert_eq!(reader.pushes.try_next(), Some(invalidate(keys("bar"))));
+        assert_eq!(reader.pushes.try_next(), None);
+        assert_eq!(
+            execute_in(&redis, &mut reader, &["CLIENT", "TRACKING", "on", "OPTOUT"]).await,
+            CommandError::TrackingModeSwitch("OPTIN/OPTOUT mode").into()
+        );
+    }
+
+    #[tokio::test]
+    async fn invalidations_can_be_broadcast_and_redirected() {
+        let redis = redis();
+        let mut client = redis.session();
+        let mut listener = redis.session();
+        let listener_id = listener.id.to_string();
+        let message = |key: &'static str| {
+            Resp::Push(vec![
+                Resp::BulkString("message".into()),
+                Resp::BulkString("__redis__:invalidate".into()),
+                Resp::Array(vec![Resp::BulkString(key.into())]),
+            ])
+        };
+
+        let tracking = ["CLIENT", "TRACKING", "on", "BCAST", "PREFIX", "user:"];
+        let redirect = ["REDIRECT", listener_id.as_str()];
+        assert_eq!(
+            execute_in(
+                &redis,
+                &mut client,
+                &[&tracking[..], &redirect[..]].concat()
+            )
+            .await,
+            CommandError::NoSuchRedirectClient.into()
+        );
+        let subscribe = Command::Subscribe {
+            channels: vec![Bytes::from("__redis__:invalidate")],
+            pattern: false,
+        };
+        redis.subscribe(subscribe, &mut listener);
+        assert_eq!(
+            execute_in(
+                &redis,
+                &mut client,
+                &[&tracking[..], &redirect[..]].concat()
+            )
+            .await,
+            Resp::SimpleString("OK".to_string())
+        );
+        assert_eq!(
+            execute_in(&redis, &mut client, &["CLIENT", "GETREDIR"]).await,
+            Resp::Integer(listener.id as i64)
+        );
+
+        // Broadcasting tracks the keys by prefix, whether or not they were read.
+        execute(&redis, &["SET", "user:1", "a"]).await;
+        execute(&redis, &["SET", "session:1", "a"]).await;
+        assert_eq!(listener.pushes.try_next(), Some(message("user:1")));
+        assert_eq!(listener.pushes.try_next(), None);
+
+        assert_eq!(
+            execute_in(
+                &redis,
+                &mut client,
+                &["CLIENT", "TRACKING", "on", "BCAST", "PREFIX", "u"]
+            )
+            .await,
+            CommandError::PrefixOverlap("u".to_string(), "an existing", "user:".to_string()).into()
+        );
+        assert_eq!(
+            execute_in(
+                &redis,
+                &mut client,
+                &["CLIENT", "TRACKING", "on", "PREFIX", "a"]
+            )
+            .await,
+            CommandError::PrefixWithoutBcast.into()
+        );
+        assert_eq!(
+            execute_in(
+                &redis,
+                &mut client,
+                &["CLIENT", "TRACKING", "on", "BCAST", "OPTIN"]
+            )
+            .await,
+            CommandError::OptInOptOutWithBcast.into()
+        );
+        assert_eq!(
+            execute_in(
+                &redis,
+                &mut client,
+                &["CLIENT", "TRACKING", "on", "REDIRECT", "1", "REDIRECT", "2"]
+            )
+            .await,
+            CommandError::MultipleRedirects.into()
+        );
+    }
+
+    #[tokio::test]
+    async fn hello_checks_the_protocol_version_and_credentials() {
+        let redis = redis();
+        let mut session = redis.session();
+
+        assert_eq!(
+            execute_in(&redis, &mut session, &["HELLO", "three"]).await,
+            CommandError::InvalidProtocolVersion.into()
+        );
+        assert_eq!(
+            execute_in(
+                &redis,
+                &mut session,
+                &["HELLO", "3", "AUTH", "ann", "secret"]
+            )
+            .await,
+            CommandError::WrongPass.into()
+        );
+        assert_eq!(session.protocol, Protocol::Resp2);
+
+        execute_in(
+            &redis,
+            &mut session,
+            &["HELLO", "3", "AUTH", "default", "x"],
+        )
+        .await;
+        assert_eq!(session.protocol, Protocol::Resp3);
+        match execute_in(&redis, &mut session, &["HELLO"]).await {
+            Resp::Map(hello) => {
+                assert!(hello.contains(&(
+                    Resp::BulkString("id".into()),
+                    Resp::Integer(session.id as i64)
+                )));
+                assert!(hello.contains(&(
+                    Resp::BulkString("version".into()),
+                    Resp::BulkString(REDIS_VERSION.into())
+                )));
+            }
+            reply => panic!("expected a map, got {:?}", reply),
+        }
+        let info = execute(&redis, &["INFO", "server"]).await.to_string();
+        assert!(info.contains(&format!("redis_version:{}\r\n", REDIS_VERSION)));
+    }
+
+    #[tokio::test]
+    async fn command_reports_the_command_table() {
+        let redis = redis();
+
+        // Every command in the table is one the server understands.
+        for info in crate::commands::COMMANDS {
+            let mut names = info
+                .subcommands
+                .iter()
+                .map(|sub| sub.name)
+                .collect::<Vec<_>>();
+            names.push(info.name);
+            for name in names {
+                let mut words = name.split('|').map(|word| Resp::BulkString(word.into()));
+                let command = words.next().unwrap();
+                let parsed = Redis::parse_command(command, words.collect());
+                assert!(
+                    !matches!(parsed, Err(CommandError::UnknownCommand(..))),
+                    "{} isn't implemented",
+                    name
+                );
+            }
+        }
+
+        let count = execute(&redis, &["COMMAND", "COUNT"]).await;
+        match execute(&redis, &["COMMAND"]).await {
+            Resp::Array(infos) => assert_eq!(Resp::Integer(infos.len() as i64), count),
+            reply => panic!("expected an array, got {:?}", reply),
+        }
+        match execute(&redis, &["COMMAND", "INFO", "GET", "nope", "config|get"]).await {
+            Resp::Array(infos) => {
+                let get = match &infos[0] {
+                    Resp::Array(get) => get,
+                    info => panic!("expected an array, got {:?}", info),
+                };
+                assert_eq!(get[..2], [Resp::BulkString("get".into()), Resp::Integer(2)]);
+                assert_eq!(
+                    get[3..6],
+                    [Resp::Integer(1), Resp::Integer(1), Resp::Integer(1)]
+                );
+                assert_eq!(infos[1], Resp::Null);
+                assert!(matches!(&infos[2], Resp::Array(info) if info[1] == Resp::Integer(-3)));
+            }
+            reply => panic!("expected an array, got {:?}", reply),
+        }
+        match execute(&redis, &["COMMAND", "DOCS", "lpush", "nope"]).await {
+            Resp::Map(docs) => {
+                assert_eq!(docs.len(), 1);
+                assert_eq!(docs[0].0, Resp::BulkString("lpush".into()));
+            }
+            reply => panic!("expected a map, got {:?}", reply),
+        }
+    }
+
+    #[test]
+    fn arguments_are_counted_before_commands_are_parsed() {
+        let names = crate::commands::COMMANDS
+            .iter()
+            .flat_map(|info| std::iter::once(info).chain(info.subcommands))
+            .map(|info| info.name);
+
+        // Whatever the number of arguments, parsing never panics, and the wrong number of them is
+        // always reported as such.
+        for name in names {
+            let info = crate::commands::lookup(name).unwrap();
+            let words = name.split('|').collect::<Vec<_>>();
+            for extra in 0..8 {
+                let mut args = words[1..]
+                    .iter()
+                    .map(|word| Resp::BulkString(Bytes::copy_from_slice(word.as_bytes())))
+                    .collect::<Vec<_>>();
+                args.extend((0..extra).map(|_| Resp::BulkString("1".into())));
+                let argc = args.len() + 1;
+
+                let parsed = Redis::parse_command(Resp::BulkString(words[0].into()), args);
+                if !info.accepts(argc) {
+                    assert!(
+                        matches!(&parsed, Err(CommandError::WrongArity(n)) if n == name),
+                        "{} with {} arguments",
+                        name,
+                        argc
+                    );
+                }
+            }
+        }
+
+        let config_get = Redis::parse_command(
+            Resp::BulkString("CONFIG".into()),
+            vec![Resp::BulkString("GET".into())],
+        );
+        assert!(matches!(config_get, Err(CommandError::WrongArity(n)) if n == "config|get"));
+    }
 }
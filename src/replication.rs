@@ -0,0 +1,463 @@
+use std::{
+    io,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use bytes::{Bytes, BytesMut};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+};
+
+use crate::{aof::Aof, cluster, keyspace::Snapshot, rdb::Rdb, redis::Redis, resp::Resp};
+
+// How long a replica waits before connecting to its master again after the link went down.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// The master's side of replication: the stream of writes replicas follow, and the replicas
+/// following it. Every server is a master, whether or not it is a replica itself.
+pub struct Replicas {
+    replid: String,
+    state: Mutex<ReplicasState>,
+}
+
+struct ReplicasState {
+    /// How many bytes of writes have been streamed under the replication ID.
+    offset: u64,
+    next_id: u64,
+    replicas: Vec<Replica>,
+}
+
+struct Replica {
+    id: u64,
+    ip: Option<IpAddr>,
+    /// The port the replica accepts clients on, as told by REPLCONF listening-port.
+    port: Option<u16>,
+    /// Set once the snapshot was sent and writes are streamed.
+    online: bool,
+    // TODO: Redis disconnects replicas whose output buffer grows past
+    //       `client-output-buffer-limit`, a replica that stops reading piles up writes here.
+    writes: UnboundedSender<Bytes>,
+    ack_offset: u64,
+    last_ack: Instant,
+}
+
+/// What a replica starting a full resync needs: the dataset, where in the stream it was taken,
+/// and the writes that follow it.
+pub struct FullResync {
+    pub id: u64,
+    pub offset: u64,
+    pub databases: Vec<Snapshot>,
+    pub writes: UnboundedReceiver<Bytes>,
+}
+
+impl Default for Replicas {
+    fn default() -> Self {
+        Replicas {
+            // NOTE: Replication IDs are made up like cluster node IDs, both are 40 hex characters.
+            replid: cluster::random_node_id(),
+            state: Mutex::new(ReplicasState {
+                offset: 0,
+                next_id: 0,
+                replicas: Vec::new(),
+            }),
+        }
+    }
+}
+
+impl Replicas {
+    /// Streams writes to every replica. Has to be called with the shards the writes touched still
+    /// locked, so replicas apply them in the order they were made.
+    pub fn propagate(&self, commands: &[Vec<Bytes>]) {
+        let mut state = self.state.lock().unwrap();
+        for command in commands {
+            let encoded = Bytes::from(Aof::encode_command(command));
+            state.offset += encoded.len() as u64;
+            // A replica whose link is gone stops receiving, it is removed once its task notices.
+            for replica in &state.replicas {
+                let _ = replica.writes.send(encoded.clone());
+            }
+        }
+    }
+
+    /// Adds a replica starting a full resync from `databases`, which must have been snapshot with
+    /// every shard locked so no write is either missed or applied twice.
+    pub fn register(
+        &self,
+        databases: Vec<Snapshot>,
+        ip: Option<IpAddr>,
+        port: Option<u16>,
+    ) -> FullResync {
+        let (sender, writes) = mpsc::unbounded_channel();
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.replicas.push(Replica {
+            id,
+            ip,
+            port,
+            online: false,
+            writes: sender,
+            ack_offset: 0,
+            last_ack: Instant::now(),
+        });
+
+        FullResync {
+            id,
+            offset: state.offset,
+            databases,
+            writes,
+        }
+    }
+
+    fn update(&self, id: u64, update: impl FnOnce(&mut Replica)) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(replica) = state.replicas.iter_mut().find(|replica| replica.id == id) {
+            update(replica);
+        }
+    }
+
+    fn unregister(&self, id: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.replicas.retain(|replica| replica.id != id);
+    }
+
+    /// The replication section of INFO on a master.
+    pub fn info(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        let mut info = vec![
+            "role:master".to_string(),
+            format!("connected_slaves:{}", state.replicas.len()),
+        ];
+        for (index, replica) in state.replicas.iter().enumerate() {
+            info.push(format!(
+                "slave{}:ip={},port={},state={},offset={},lag={}",
+                index,
+                replica.ip.map(|ip| ip.to_string()).unwrap_or_default(),
+                replica.port.unwrap_or(0),
+                if replica.online {
+                    "online"
+                } else {
+                    "wait_bgsave"
+                },
+                replica.ack_offset,
+                replica.last_ack.elapsed().as_secs()
+            ));
+        }
+        info.push(format!("master_replid:{}", self.replid));
+        info.push(format!("master_repl_offset:{}", state.offset));
+        info
+    }
+}
+
+/// Turns a client connection that sent PSYNC into a replication link: sends the replica a
+/// snapshot of the dataset for a full resync, then every write from then on, while reading the
+/// offsets it acknowledges. `buffer` holds whatever the replica sent after PSYNC.
+pub(crate) async fn serve_replica(
+    stream: &mut TcpStream,
+    redis: &Redis,
+    mut buffer: BytesMut,
+    listening_port: Option<u16>,
+) {
+    let ip = stream.peer_addr().ok().map(|address| address.ip());
+    let FullResync {
+        id,
+        offset,
+        databases,
+        mut writes,
+    } = redis.register_replica(ip, listening_port).await;
+    let replicas = redis.replicas();
+
+    let link = async {
+        let reply = format!("+FULLRESYNC {} {}\r\n", replicas.replid, offset);
+        stream.write_all(reply.as_bytes()).await?;
+        // NOTE: Serializing a large dataset takes a while, which mustn't stall other connections.
+        let rdb = tokio::task::spawn_blocking(move || Rdb::serialize(&databases))
+            .await
+            .map_err(|error| protocol_error(error.to_string()))?;
+        stream
+            .write_all(format!("${}\r\n", rdb.len()).as_bytes())
+            .await?;
+        stream.write_all(&rdb).await?;
+        replicas.update(id, |replica| replica.online = true);
+
+        loop {
+            tokio::select! {
+                write = writes.recv() => match write {
+                    Some(write) => stream.write_all(&write).await?,
+                    None => return Ok(()),
+                },
+                read = stream.read_buf(&mut buffer) => {
+                    if read? == 0 {
+                        return Ok(());
+                    }
+                    while let Some((request, _)) =
+                        Resp::parse(&mut buffer, redis.proto_max_bulk_len())
+                            .map_err(|error| protocol_error(error.to_string()))?
+                    {
+                        if let Some(offset) = acknowledged_offset(&request) {
+                            replicas.update(id, |replica| {
+                                replica.ack_offset = offset;
+                                replica.last_ack = Instant::now();
+                            });
+                        }
+                    }
+                }
+            }
+        }
+    };
+
+    let result: io::Result<()> = link.await;
+    if let Err(error) = result {
+        eprintln!("lost the link to a replica: {}", error);
+    }
+    replicas.unregister(id);
+}
+
+// The offset of a `REPLCONF ACK <offset>` sent by a replica.
+fn acknowledged_offset(request: &Resp) -> Option<u64> {
+    let Resp::Array(args) = request else {
+        return None;
+    };
+    match args.as_slice() {
+        [command, option, offset]
+            if command.to_string().eq_ignore_ascii_case("replconf")
+                && option.to_string().eq_ignore_ascii_case("ack") =>
+        {
+            offset.to_string().parse().ok()
+        }
+        _ => None,
+    }
+}
+
+/// The link of a replica to its master, set up with `replicaof <host> <port>`.
+pub struct MasterLink {
+    host: String,
+    port: u16,
+    state: Mutex<LinkState>,
+}
+
+#[derive(Default)]
+struct LinkState {
+    up: bool,
+    sync_in_progress: bool,
+    /// The replication ID of the master's stream, and how far into it this replica is.
+    replid: Option<String>,
+    offset: u64,
+}
+
+impl MasterLink {
+    /// Parses the `replicaof` configuration, `<host> <port>`.
+    pub fn parse(value: &str) -> Option<MasterLink> {
+        let (host, port) = value.trim().split_once(' ')?;
+        Some(MasterLink {
+            host: host.to_string(),
+            port: port.trim().parse().ok()?,
+            state: Mutex::default(),
+        })
+    }
+
+    /// The replication section of INFO on a replica.
+    pub fn info(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        vec![
+            "role:slave".to_string(),
+            format!("master_host:{}", self.host),
+            format!("master_port:{}", self.port),
+            format!(
+                "master_link_status:{}",
+                if state.up { "up" } else { "down" }
+            ),
+            format!("master_sync_in_progress:{}", state.sync_in_progress as u8),
+            format!("slave_repl_offset:{}", state.offset),
+            format!("master_replid:{}", state.replid.as_deref().unwrap_or("")),
+        ]
+    }
+
+    fn offset(&self) -> u64 {
+        self.state.lock().unwrap().offset
+    }
+
+    fn advance(&self, len: usize) {
+        self.state.lock().unwrap().offset += len as u64;
+    }
+}
+
+/// Keeps a replica in sync with its master: connects, asks for a full resync, loads the snapshot
+/// it is sent and then applies the master's stream of writes. Whenever the link drops, it
+/// reconnects and starts over.
+pub(crate) async fn replica_loop(redis: Arc<Redis>, listening_port: u16) {
+    let Some(link) = redis.master_link() else {
+        return;
+    };
+
+    loop {
+        if let Err(error) = sync_with_master(&redis, link, listening_port).await {
+            eprintln!(
+                "lost the link to master {}:{}: {}",
+                link.host, link.port, error
+            );
+        }
+
+        {
+            let mut state = link.state.lock().unwrap();
+            state.up = false;
+            state.sync_in_progress = false;
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+async fn sync_with_master(redis: &Redis, link: &MasterLink, listening_port: u16) -> io::Result<()> {
+    let stream = TcpStream::connect((link.host.as_str(), link.port)).await?;
+    let mut master = Connection {
+        stream,
+        buffer: BytesMut::with_capacity(4096),
+        max_bulk_len: redis.proto_max_bulk_len(),
+    };
+
+    master.command(&["PING"]).await?;
+    master
+        .command(&["REPLCONF", "listening-port", &listening_port.to_string()])
+        .await?;
+    master.command(&["REPLCONF", "capa", "psync2"]).await?;
+
+    // NOTE: Partial resyncs need a backlog on the master's side, so this always asks for a full one.
+    let reply = master.command(&["PSYNC", "?", "-1"]).await?.to_string();
+    let mut fields = reply.split(' ');
+    let (Some("FULLRESYNC"), Some(replid), Some(offset)) =
+        (fields.next(), fields.next(), fields.next())
+    else {
+        return Err(protocol_error(format!(
+            "unexpected reply to PSYNC: {}",
+            reply
+        )));
+    };
+    let offset = offset
+        .parse::<u64>()
+        .map_err(|_| protocol_error(format!("invalid replication offset {}", offset)))?;
+    link.state.lock().unwrap().sync_in_progress = true;
+
+    let rdb = master.read_snapshot().await?;
+    redis
+        .replace_dataset(&rdb)
+        .await
+        .map_err(|error| protocol_error(format!("invalid snapshot {}", error)))?;
+
+    {
+        let mut state = link.state.lock().unwrap();
+        state.up = true;
+        state.sync_in_progress = false;
+        state.replid = Some(replid.to_string());
+        state.offset = offset;
+    }
+
+    // Writes arrive for database 0 until the master selects another one.
+    let mut db = 0;
+    loop {
+        let (request, len) = master.read_request().await?;
+        // The master asks how far along the stream the replica is, not counting the question.
+        if is_getack(&request) {
+            let offset = link.offset().to_string();
+            master.send(&["REPLCONF", "ACK", &offset]).await?;
+        } else {
+            match Redis::parse_request(request) {
+                Ok(command) => redis.apply_replicated(command, &mut db).await,
+                Err(error) => eprintln!("skipping unparsable command from master: {}", error),
+            }
+        }
+        link.advance(len);
+    }
+}
+
+fn is_getack(request: &Resp) -> bool {
+    match request {
+        Resp::Array(args) if args.len() >= 2 => {
+            args[0].to_string().eq_ignore_ascii_case("replconf")
+                && args[1].to_string().eq_ignore_ascii_case("getack")
+        }
+        _ => false,
+    }
+}
+
+fn protocol_error(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// The replica's end of the connection to its master.
+struct Connection {
+    stream: TcpStream,
+    buffer: BytesMut,
+    max_bulk_len: usize,
+}
+
+impl Connection {
+    async fn fill(&mut self) -> io::Result<()> {
+        match self.stream.read_buf(&mut self.buffer).await? {
+            0 => Err(io::ErrorKind::UnexpectedEof.into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn send(&mut self, args: &[&str]) -> io::Result<()> {
+        self.stream.write_all(&Aof::encode_command(args)).await
+    }
+
+    /// Sends a handshake command, failing if the master answers with an error.
+    async fn command(&mut self, args: &[&str]) -> io::Result<Resp> {
+        self.send(args).await?;
+        match self.read_request().await?.0 {
+            Resp::SimpleError(error) => Err(protocol_error(format!(
+                "master refused {}: {}",
+                args.join(" "),
+                error
+            ))),
+            reply => Ok(reply),
+        }
+    }
+
+    /// Reads the next frame, along with its length in the replication stream.
+    async fn read_request(&mut self) -> io::Result<(Resp, usize)> {
+        loop {
+            match Resp::parse(&mut self.buffer, self.max_bulk_len) {
+                Ok(Some(frame)) => return Ok(frame),
+                Ok(None) => self.fill().await?,
+                Err(error) => return Err(protocol_error(error.to_string())),
+            }
+        }
+    }
+
+    /// Reads the RDB payload of a full resync. It is sent like a bulk string without the trailing
+    /// CRLF, and possibly preceded by newlines the master sends to keep the link alive while the
+    /// snapshot is being prepared.
+    async fn read_snapshot(&mut self) -> io::Result<Bytes> {
+        let header_len = loop {
+            let keepalives = self
+                .buffer
+                .iter()
+                .take_while(|&&byte| byte == b'\n')
+                .count();
+            let _ = self.buffer.split_to(keepalives);
+
+            if let Some(end) = self.buffer.windows(2).position(|window| window == b"\r\n") {
+                break end;
+            }
+            self.fill().await?;
+        };
+
+        let header = self.buffer.split_to(header_len + 2);
+        let len = std::str::from_utf8(&header[..header_len])
+            .ok()
+            .and_then(|header| header.strip_prefix('$'))
+            .and_then(|len| len.parse::<usize>().ok())
+            .ok_or_else(|| protocol_error("invalid snapshot header".to_string()))?;
+
+        while self.buffer.len() < len {
+            self.fill().await?;
+        }
+        Ok(self.buffer.split_to(len).freeze())
+    }
+}
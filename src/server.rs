@@ -0,0 +1,538 @@
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Arc, Weak},
+};
+
+use bytes::BytesMut;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpSocket, TcpStream},
+    task::{JoinError, JoinHandle, JoinSet},
+};
+
+use crate::{
+    cluster,
+    config::{self, Config, ConfigError},
+    handle::RedisHandle,
+    hook::CommandHook,
+    persistence::{Persistence, PersistenceError},
+    redis::{Command, Redis},
+    replication,
+    resp::{Protocol, ReplyBuffer, Resp},
+    storage::{MemoryStorage, Storage, StorageFactory},
+};
+
+pub(crate) const DEFAULT_BIND: &str = "127.0.0.1";
+pub(crate) const DEFAULT_PORT: u16 = 6379;
+
+// How many pipelined requests of one connection are answered before other connections get a turn.
+const MAX_COMMANDS_PER_TURN: usize = 32;
+
+const PROTECTED_MODE_ERROR: &str = "DENIED Redis is running in protected mode because protected \
+mode is enabled and no password is set for the default user. In this mode connections are only \
+accepted from the loopback interface. If you want to connect from external computers to Redis you \
+may adopt one of the following solutions: 1) Just disable protected mode sending the command \
+'CONFIG SET protected-mode no' from the loopback interface by connecting to Redis from the same \
+host the server is running, however MAKE SURE Redis is not publicly accessible from internet if \
+you do so. Use CONFIG REWRITE to make this change permanent. 2) Alternatively you can just disable \
+the protected mode by editing the Redis configuration file, and setting the protected mode option \
+to 'no', and then restarting the server. 3) If you started the server manually just for testing, \
+restart it with the '--protected-mode no' option. 4) Set up an authentication password for the \
+default user. NOTE: You only need to do one of the above things in order for the server to start \
+accepting connections from the outside.";
+
+/// A running server, accepting connections until it is shut down.
+///
+/// ```no_run
+/// # async fn run() -> std::io::Result<()> {
+/// let server = redis_starter_rust::Server::builder()
+///     .port(0)
+///     .dir("/tmp/redis-files")
+///     .spawn()
+///     .await?;
+///
+/// println!("listening on {}", server.local_addr());
+/// # Ok(())
+/// # }
+/// ```
+pub struct Server {
+    local_addr: SocketAddr,
+    redis: Arc<Redis>,
+    task: JoinHandle<()>,
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+
+    /// The address the server is listening on, useful when it was spawned on port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Returns an in-process handle to the same store the server's clients are using.
+    pub fn handle(&self) -> RedisHandle {
+        RedisHandle::new(self.redis.clone())
+    }
+
+    /// Waits for the accept loop to finish, which only happens once the server is shut down.
+    pub async fn wait(mut self) -> Result<(), JoinError> {
+        match (&mut self.task).await {
+            Err(error) if error.is_cancelled() => Ok(()),
+            result => result,
+        }
+    }
+
+    pub fn shutdown(&self) {
+        self.task.abort();
+    }
+}
+
+// NOTE: Dropping the server stops accepting connections, which keeps embedded servers from
+//       outliving the tests or tools that started them.
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+#[derive(Default)]
+pub struct ServerBuilder {
+    config: HashMap<String, String>,
+    config_file: Option<PathBuf>,
+    storage: Option<Box<StorageFactory>>,
+    persistence: Option<Box<dyn Persistence>>,
+    hooks: Vec<Box<dyn CommandHook>>,
+    /// The argument `args` couldn't parse, reported along with the rest of the configuration.
+    invalid_argument: Option<(String, ConfigError)>,
+}
+
+impl ServerBuilder {
+    /// Applies command line arguments (including the program name), e.g. `--dir /tmp`, after an
+    /// optional config file like `redis-server redis.conf --port 7000`.
+    pub fn args(mut self, args: Vec<String>) -> Self {
+        match config::parse_arguments(args.get(1..).unwrap_or_default()) {
+            Ok((file, parameters)) => {
+                if let Some(file) = file {
+                    self.config_file = Some(file);
+                }
+                self.config.extend(parameters);
+            }
+            Err(error) => self.invalid_argument = Some(error),
+        }
+        self
+    }
+
+    /// Reads parameters from a redis.conf file, which the ones set on the builder override.
+    /// CONFIG REWRITE writes changes back to it.
+    pub fn config_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config_file = Some(path.into());
+        self
+    }
+
+    pub fn port(self, port: u16) -> Self {
+        self.config("port", port.to_string())
+    }
+
+    pub fn bind(self, address: impl Into<String>) -> Self {
+        self.config("bind", address)
+    }
+
+    pub fn dir(self, dir: impl Into<String>) -> Self {
+        self.config("dir", dir)
+    }
+
+    pub fn dbfilename(self, dbfilename: impl Into<String>) -> Self {
+        self.config("dbfilename", dbfilename)
+    }
+
+    /// Makes `command` available to clients as `name` instead, or disables it if `name` is empty.
+    pub fn rename_command(self, command: &str, name: impl Into<String>) -> Self {
+        self.config(format!("rename-command {}", command.to_lowercase()), name)
+    }
+
+    /// Sets any configuration parameter by its redis.conf name.
+    pub fn config(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.config.insert(key.into(), value.into());
+        self
+    }
+
+    /// Uses `factory` to create the backend of each keyspace shard instead of [`MemoryStorage`].
+    pub fn storage<F>(mut self, factory: F) -> Self
+    where
+        F: Fn() -> Box<dyn Storage> + Send + Sync + 'static,
+    {
+        self.storage = Some(Box::new(factory));
+        self
+    }
+
+    /// Uses `persistence` instead of the engine selected by the `appendonly`, `dir` and
+    /// `dbfilename` configuration, which CONFIG SET then doesn't switch either.
+    pub fn persistence(mut self, persistence: Box<dyn Persistence>) -> Self {
+        self.persistence = Some(persistence);
+        self
+    }
+
+    /// Runs `hook` around every command clients execute, after any hooks registered before it.
+    pub fn hook(mut self, hook: impl CommandHook + 'static) -> Self {
+        self.hooks.push(Box::new(hook));
+        self
+    }
+
+    /// Parses the configuration parameters set so far, on top of the config file's.
+    fn parse_config(&self) -> io::Result<Config> {
+        if let Some((argument, error)) = &self.invalid_argument {
+            let message = format!("invalid argument '{}': {}", argument, error);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, message));
+        }
+        let mut parameters = match &self.config_file {
+            Some(path) => Config::read_file(path)?,
+            None => HashMap::new(),
+        };
+        parameters.extend(self.config.clone());
+
+        let mut config = Config::from_map(&parameters).map_err(|(name, error)| {
+            let message = format!("invalid value for '{}': {}", name, error);
+            io::Error::new(io::ErrorKind::InvalidInput, message)
+        })?;
+        config.file = self.config_file.clone();
+        Ok(config)
+    }
+
+    fn build(self, config: Config) -> Result<Redis, PersistenceError> {
+        match self.storage {
+            Some(storage) => Redis::new(config, storage.as_ref(), self.persistence, self.hooks),
+            None => Redis::new(
+                config,
+                &|| Box::new(MemoryStorage::default()),
+                self.persistence,
+                self.hooks,
+            ),
+        }
+    }
+
+    /// Loads the dataset without listening for connections, returning a handle that executes
+    /// commands in-process.
+    pub fn embedded(self) -> RedisHandle {
+        let config = self.parse_config().expect("invalid configuration");
+        let redis = Arc::new(self.build(config).expect("failed to load persisted data"));
+        // NOTE: Without a runtime there is nothing to run the cycle on, expired keys are then only
+        //       hidden from readers rather than removed.
+        if tokio::runtime::Handle::try_current().is_ok() {
+            tokio::spawn(cron_loop(Arc::downgrade(&redis)));
+        }
+
+        RedisHandle::new(redis)
+    }
+
+    /// Loads the dataset, binds the listener and starts accepting connections in the background.
+    pub async fn spawn(self) -> io::Result<Server> {
+        let config = self.parse_config()?;
+        let bind = config
+            .bind
+            .clone()
+            .unwrap_or_else(|| DEFAULT_BIND.to_string());
+        let port = config.port;
+
+        let listeners = match config.io_threads {
+            1 => vec![TcpListener::bind((bind.as_str(), port)).await?],
+            threads => reuseport_listeners(&bind, port, threads).await?,
+        };
+        let local_addr = listeners[0].local_addr()?;
+        let redis = self
+            .build(config)
+            .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+        let redis = Arc::new(redis);
+
+        let bus_listener = match redis.cluster_bus_port(port) {
+            Some(cport) => Some(TcpListener::bind((bind.as_str(), cport)).await?),
+            None => None,
+        };
+        let cport = match &bus_listener {
+            Some(listener) => listener.local_addr()?.port(),
+            None => 0,
+        };
+        redis.listening_on(local_addr, cport);
+
+        let acceptors = redis.clone();
+        let task = tokio::spawn(async move {
+            // Dropping the set when the server is shut down aborts every accept loop, and the
+            // replica's link to its master.
+            let mut accept_loops = JoinSet::new();
+            for listener in listeners {
+                accept_loops.spawn(accept_loop(listener, acceptors.clone()));
+            }
+            if let Some(listener) = bus_listener {
+                accept_loops.spawn(cluster_bus_loop(listener, acceptors.clone()));
+            }
+            if acceptors.master_link().is_some() {
+                let port = local_addr.port();
+                accept_loops.spawn(replication::replica_loop(acceptors.clone(), port));
+            }
+            while accept_loops.join_next().await.is_some() {}
+        });
+        tokio::spawn(cron_loop(Arc::downgrade(&redis)));
+        if redis.cluster().is_some() {
+            tokio::spawn(cluster_heartbeat_loop(Arc::downgrade(&redis)));
+        }
+
+        Ok(Server {
+            local_addr,
+            redis,
+            task,
+        })
+    }
+}
+
+/// Binds `count` listeners to the same address with SO_REUSEPORT (`io-threads`), so the kernel
+/// spreads incoming connections over that many accept loops.
+#[cfg(unix)]
+async fn reuseport_listeners(bind: &str, port: u16, count: usize) -> io::Result<Vec<TcpListener>> {
+    let mut address = tokio::net::lookup_host((bind, port))
+        .await?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "bind address not found"))?;
+
+    let mut listeners = Vec::with_capacity(count);
+    for _ in 0..count {
+        let socket = match address {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        socket.set_reuseaddr(true)?;
+        socket.set_reuseport(true)?;
+        socket.bind(address)?;
+        let listener = socket.listen(1024)?;
+
+        // The others have to share the port the first one was given when binding to port 0.
+        address = listener.local_addr()?;
+        listeners.push(listener);
+    }
+
+    Ok(listeners)
+}
+
+#[cfg(not(unix))]
+async fn reuseport_listeners(_: &str, _: u16, _: usize) -> io::Result<Vec<TcpListener>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "io-threads needs SO_REUSEPORT, which this platform doesn't have",
+    ))
+}
+
+// Runs active expiry, incremental rehashing and save points `hz` times a second. Only holds a weak
+// reference, so the loop ends once the server and every handle are gone.
+async fn cron_loop(redis: Weak<Redis>) {
+    let (mut interval, mut cursors) = match redis.upgrade() {
+        Some(redis) => (
+            tokio::time::interval(redis.active_expire_interval()),
+            vec![0; redis.shard_count()],
+        ),
+        None => return,
+    };
+
+    loop {
+        interval.tick().await;
+        match redis.upgrade() {
+            Some(redis) => {
+                redis.active_expire_cycle(&mut cursors).await;
+                redis.rehash_cycle().await;
+                redis.save_point_cycle().await;
+                // CONFIG SET hz takes effect from the next cycle on.
+                let period = redis.active_expire_interval();
+                if interval.period() != period {
+                    interval = tokio::time::interval(period);
+                }
+            }
+            None => return,
+        }
+    }
+}
+
+// Pings the other cluster nodes, only holding a weak reference like the cron loop.
+async fn cluster_heartbeat_loop(redis: Weak<Redis>) {
+    let mut interval = match redis.upgrade().as_ref().and_then(|redis| redis.cluster()) {
+        Some(cluster) => tokio::time::interval(cluster.heartbeat_interval()),
+        None => return,
+    };
+
+    loop {
+        interval.tick().await;
+        let Some(redis) = redis.upgrade() else {
+            return;
+        };
+        let Some(cluster) = redis.cluster() else {
+            return;
+        };
+
+        for target in cluster.heartbeat() {
+            let redis = redis.clone();
+            tokio::spawn(async move {
+                let cluster = redis.cluster().unwrap();
+                let message = cluster.message(target.kind);
+                match cluster::exchange(target.ip, target.cport, &message, cluster.bus_timeout())
+                    .await
+                {
+                    Ok(reply) => {
+                        cluster.receive(&reply, target.ip);
+                    }
+                    Err(_) => cluster.ping_failed(&target.id),
+                }
+            });
+        }
+    }
+}
+
+// Answers the messages other cluster nodes send, one per connection.
+async fn cluster_bus_loop(listener: TcpListener, redis: Arc<Redis>) {
+    loop {
+        let (mut stream, peer) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(error) => {
+                eprintln!("failed to accept cluster bus connection: {}", error);
+                continue;
+            }
+        };
+
+        let redis = redis.clone();
+        tokio::spawn(async move {
+            let cluster = redis.cluster().unwrap();
+            let message =
+                tokio::time::timeout(cluster.bus_timeout(), cluster::read_message(&mut stream));
+            let Ok(Ok(message)) = message.await else {
+                return;
+            };
+            if let Some(reply) = cluster.receive(&message, peer.ip()) {
+                let _ = stream.write_all(reply.as_bytes()).await;
+            }
+        });
+    }
+}
+
+async fn accept_loop(listener: TcpListener, redis: Arc<Redis>) {
+    loop {
+        let (mut stream, peer) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(error) => {
+                eprintln!("failed to accept connection: {}", error);
+                continue;
+            }
+        };
+
+        let redis = redis.clone();
+        tokio::spawn(async move {
+            if redis.accepts_connections_from(peer.ip()) {
+                handle_connection(&mut stream, redis).await;
+            } else {
+                let mut replies = ReplyBuffer::new();
+                let error = Resp::SimpleError(PROTECTED_MODE_ERROR.to_string());
+                error
+                    .encode_into(Protocol::default(), &mut replies)
+                    .unwrap();
+                let _ = replies.write_to(&mut stream).await;
+            }
+        });
+    }
+}
+
+async fn handle_connection(stream: &mut TcpStream, redis: Arc<Redis>) {
+    let mut buffer = BytesMut::with_capacity(4096);
+    let mut replies = ReplyBuffer::new();
+    let mut session = redis.session();
+
+    loop {
+        // Answers the requests already buffered, up to a turn's worth, with a single write.
+        let mut answered = 0;
+        while answered < MAX_COMMANDS_PER_TURN {
+            match Resp::parse_request(&mut buffer, redis.proto_max_bulk_len()) {
+                Ok(Some((request, _))) => {
+                    let response = match redis.parse_client_request(request) {
+                        // From here on the connection is a replication link.
+                        Ok(Command::Psync { .. }) => {
+                            if replies.write_to(stream).await.is_ok() {
+                                let buffer = std::mem::take(&mut buffer);
+                                let port = session.replica_listening_port;
+                                replication::serve_replica(stream, &redis, buffer, port).await;
+                            }
+                            return;
+                        }
+                        // NOTE: Everything answered so far goes out before blocking, and the socket
+                        //       is still read while waiting, so a client that goes away stops
+                        //       waiting and can't take anything off a list.
+                        Ok(command) if command.is_blocking() => {
+                            if replies.write_to(stream).await.is_err() {
+                                return;
+                            }
+                            let execute = redis.execute(command, &mut session);
+                            tokio::pin!(execute);
+                            loop {
+                                tokio::select! {
+                                    response = &mut execute => break response,
+                                    read = stream.read_buf(&mut buffer) => {
+                                        if matches!(read, Ok(0) | Err(_)) {
+                                            return;
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                        Ok(command)
+                            if command.is_subscription() && session.transaction.is_none() =>
+                        {
+                            for reply in redis.subscribe(command, &mut session) {
+                                reply.encode_into(session.protocol, &mut replies).unwrap();
+                            }
+                            answered += 1;
+                            continue;
+                        }
+                        Ok(command) => redis.execute(command, &mut session).await,
+                        Err(error) => redis.reject(error, &mut session),
+                    };
+                    response
+                        .encode_into(session.protocol, &mut replies)
+                        .unwrap();
+                    answered += 1;
+                }
+                Ok(None) => break,
+                Err(error) => {
+                    let error = Resp::SimpleError(format!("ERR {}", error));
+                    error.encode_into(session.protocol, &mut replies).unwrap();
+                    let _ = replies.write_to(stream).await;
+                    return;
+                }
+            }
+        }
+
+        // Pushes, like messages published to the connection's channels, go out after the replies
+        // before them.
+        while let Some(push) = session.pushes.try_next() {
+            push.encode_into(session.protocol, &mut replies).unwrap();
+        }
+        if replies.write_to(stream).await.is_err() {
+            break;
+        }
+
+        // NOTE: A client pipelining more than a turn's worth of requests goes to the back of the
+        //       scheduler's queue before the rest are answered, so it can't starve the others.
+        if answered == MAX_COMMANDS_PER_TURN {
+            tokio::task::yield_now().await;
+            continue;
+        }
+
+        let read = tokio::select! {
+            read = stream.read_buf(&mut buffer) => read,
+            push = session.pushes.next() => {
+                push.encode_into(session.protocol, &mut replies).unwrap();
+                continue;
+            }
+        };
+        match read {
+            Ok(0) | Err(_) => break,
+            Ok(_) => {}
+        }
+    }
+}
@@ -0,0 +1,86 @@
+use bytes::Bytes;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use crate::{
+    pubsub::Subscriptions,
+    redis::Command,
+    resp::{Protocol, Resp},
+    tracking::TrackingOptions,
+};
+
+/// State of a single client connection that commands can read or change, e.g. the protocol
+/// replies are shaped for.
+#[derive(Default)]
+pub struct Session {
+    /// Tells the server's connections apart, as reported by CLIENT ID.
+    pub id: u64,
+    /// Set by HELLO's SETNAME option.
+    pub name: Option<Bytes>,
+    pub protocol: Protocol,
+    /// Set by READONLY, lets a cluster replica serve reads for its master's slots.
+    pub readonly: bool,
+    /// The port a replica connecting to this server accepts clients on, from REPLCONF.
+    pub replica_listening_port: Option<u16>,
+    /// The database commands read and write, changed by SELECT.
+    pub db: usize,
+    /// Set between MULTI and EXEC or DISCARD.
+    pub transaction: Option<Transaction>,
+    /// Set once the connection first subscribed to a channel.
+    pub subscriptions: Option<Subscriptions>,
+    /// Set by CLIENT TRACKING, while the keys the connection reads are tracked for invalidation.
+    pub tracking: Option<TrackingOptions>,
+    /// Set by CLIENT CACHING, for the command right after it only.
+    pub caching: Option<bool>,
+    pub pushes: Pushes,
+}
+
+impl Session {
+    /// Whether the connection is subscribed to any channel while speaking RESP2, where replies
+    /// could be mistaken for published messages, so only a few commands are allowed.
+    pub fn subscribe_mode(&self) -> bool {
+        self.protocol == Protocol::Resp2
+            && self
+                .subscriptions
+                .as_ref()
+                .is_some_and(|subscriptions| subscriptions.count() > 0)
+    }
+}
+
+/// The commands a client queued since MULTI, to run when it sends EXEC.
+#[derive(Default)]
+pub struct Transaction {
+    pub queued: Vec<Command>,
+    /// Set once a command was refused while queuing, which makes EXEC discard the transaction.
+    pub aborted: bool,
+}
+
+/// Replies a connection gets without asking for them, like published messages, waiting to be
+/// written.
+pub struct Pushes {
+    sender: UnboundedSender<Resp>,
+    receiver: UnboundedReceiver<Resp>,
+}
+
+impl Default for Pushes {
+    fn default() -> Self {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        Pushes { sender, receiver }
+    }
+}
+
+impl Pushes {
+    pub fn sender(&self) -> UnboundedSender<Resp> {
+        self.sender.clone()
+    }
+
+    /// Takes a reply pushed since the last one was taken, if there is any.
+    pub fn try_next(&mut self) -> Option<Resp> {
+        self.receiver.try_recv().ok()
+    }
+
+    /// Waits for the next reply to be pushed.
+    pub async fn next(&mut self) -> Resp {
+        // Never closed, this holds on to a sender itself.
+        self.receiver.recv().await.unwrap()
+    }
+}
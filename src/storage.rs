@@ -0,0 +1,225 @@
+use std::sync::Arc;
+
+use bytes::Bytes;
+
+use crate::{dict::Dict, redis::RedisValue};
+
+/// Backend holding the keys of one keyspace shard. Command handlers only ever go through this
+/// trait, so alternative backends (persistent, tiered, or mocks for tests) can be plugged in with
+/// [`ServerBuilder::storage`](crate::ServerBuilder::storage).
+///
+/// Values are handed around as `Arc`s so snapshots can share them, writers get copy-on-write
+/// semantics through `Arc::make_mut` on the value returned by `get_mut`.
+pub trait Storage: Send {
+    fn get(&self, key: &[u8]) -> Option<&Arc<RedisValue>>;
+    fn get_mut(&mut self, key: &[u8]) -> Option<&mut Arc<RedisValue>>;
+    fn set(&mut self, key: Bytes, value: Arc<RedisValue>) -> Option<Arc<RedisValue>>;
+    fn delete(&mut self, key: &[u8]) -> Option<Arc<RedisValue>>;
+
+    /// Iterates over every key and value in no particular order.
+    fn scan(&self) -> Box<dyn Iterator<Item = (&Bytes, &Arc<RedisValue>)> + '_>;
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The absolute expiry of `key` in milliseconds since the epoch, if it has one.
+    fn expiry(&self, key: &[u8]) -> Option<u64>;
+    fn set_expiry(&mut self, key: Bytes, expiry: u64);
+    fn remove_expiry(&mut self, key: &[u8]) -> Option<u64>;
+
+    /// Iterates over every key that has an expiry, in no particular order.
+    fn expiring(&self) -> Box<dyn Iterator<Item = (&Bytes, u64)> + '_>;
+
+    /// Like `scan_step`, over the keys that have an expiry. The active expiry cycle samples them
+    /// this way, resuming where it left off.
+    // NOTE: Like `scan_step`, by default the cursor is a position in `expiring`.
+    fn expiring_step(&self, cursor: u64, visit: &mut dyn FnMut(&Bytes, u64)) -> u64 {
+        match self.expiring().nth(cursor as usize) {
+            Some((key, expiry)) => {
+                visit(key, expiry);
+                cursor + 1
+            }
+            None => 0,
+        }
+    }
+
+    /// Visits a few keys of a SCAN starting at `cursor`, returning the cursor to continue from, or
+    /// 0 once every key was visited. Keys present for the whole scan must be visited at least once.
+    // NOTE: By default the cursor is a position in `scan`, which only holds up as long as no keys
+    //       are added or removed, and walks over every key before it on every step.
+    fn scan_step(&self, cursor: u64, visit: &mut dyn FnMut(&Bytes, &Arc<RedisValue>)) -> u64 {
+        match self.scan().nth(cursor as usize) {
+            Some((key, value)) => {
+                visit(key, value);
+                cursor + 1
+            }
+            None => 0,
+        }
+    }
+
+    /// Does up to `steps` steps of resizing the backend's tables, if they are being resized
+    /// incrementally, returning whether there is more to do. Called from the server cron so idle
+    /// shards finish resizing too.
+    fn rehash(&mut self, _steps: usize) -> bool {
+        false
+    }
+}
+
+pub type StorageFactory = dyn Fn() -> Box<dyn Storage> + Send + Sync;
+
+/// The default, purely in-memory backend. Its tables resize incrementally, so a growing dataset
+/// doesn't cause latency spikes.
+#[derive(Default)]
+pub struct MemoryStorage {
+    store: Dict<Arc<RedisValue>>,
+    expiry_table: Dict<u64>,
+}
+
+impl Storage for MemoryStorage {
+    fn get(&self, key: &[u8]) -> Option<&Arc<RedisValue>> {
+        self.store.get(key)
+    }
+
+    fn get_mut(&mut self, key: &[u8]) -> Option<&mut Arc<RedisValue>> {
+        self.store.get_mut(key)
+    }
+
+    fn set(&mut self, key: Bytes, value: Arc<RedisValue>) -> Option<Arc<RedisValue>> {
+        self.store.insert(key, value)
+    }
+
+    fn delete(&mut self, key: &[u8]) -> Option<Arc<RedisValue>> {
+        self.expiry_table.remove(key);
+        self.store.remove(key)
+    }
+
+    fn scan(&self) -> Box<dyn Iterator<Item = (&Bytes, &Arc<RedisValue>)> + '_> {
+        Box::new(self.store.iter())
+    }
+
+    fn len(&self) -> usize {
+        self.store.len()
+    }
+
+    fn expiry(&self, key: &[u8]) -> Option<u64> {
+        self.expiry_table.get(key).copied()
+    }
+
+    fn set_expiry(&mut self, key: Bytes, expiry: u64) {
+        self.expiry_table.insert(key, expiry);
+    }
+
+    fn remove_expiry(&mut self, key: &[u8]) -> Option<u64> {
+        self.expiry_table.remove(key)
+    }
+
+    fn expiring(&self) -> Box<dyn Iterator<Item = (&Bytes, u64)> + '_> {
+        Box::new(self.expiry_table.iter().map(|(key, expiry)| (key, *expiry)))
+    }
+
+    fn scan_step(&self, cursor: u64, visit: &mut dyn FnMut(&Bytes, &Arc<RedisValue>)) -> u64 {
+        self.store.scan(cursor, visit)
+    }
+
+    fn expiring_step(&self, cursor: u64, visit: &mut dyn FnMut(&Bytes, u64)) -> u64 {
+        self.expiry_table
+            .scan(cursor, |key, expiry| visit(key, *expiry))
+    }
+
+    fn rehash(&mut self, steps: usize) -> bool {
+        let store = self.store.rehash(steps);
+        let expiry_table = self.expiry_table.rehash(steps);
+        store || expiry_table
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use crate::{
+        redis::RedisValue,
+        resp::Resp,
+        storage::{MemoryStorage, Storage},
+        Server,
+    };
+    #[allow(unused_imports)]
+    use bytes::Bytes;
+    #[allow(unused_imports)]
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    #[allow(dead_code)]
+    struct CountingStorage {
+        inner: MemoryStorage,
+        writes: Arc<AtomicUsize>,
+    }
+
+    impl Storage for CountingStorage {
+        fn get(&self, key: &[u8]) -> Option<&Arc<RedisValue>> {
+            self.inner.get(key)
+        }
+
+        fn get_mut(&mut self, key: &[u8]) -> Option<&mut Arc<RedisValue>> {
+            self.inner.get_mut(key)
+        }
+
+        fn set(&mut self, key: Bytes, value: Arc<RedisValue>) -> Option<Arc<RedisValue>> {
+            self.writes.fetch_add(1, Ordering::SeqCst);
+            self.inner.set(key, value)
+        }
+
+        fn delete(&mut self, key: &[u8]) -> Option<Arc<RedisValue>> {
+            self.inner.delete(key)
+        }
+
+        fn scan(&self) -> Box<dyn Iterator<Item = (&Bytes, &Arc<RedisValue>)> + '_> {
+            self.inner.scan()
+        }
+
+        fn len(&self) -> usize {
+            self.inner.len()
+        }
+
+        fn expiry(&self, key: &[u8]) -> Option<u64> {
+            self.inner.expiry(key)
+        }
+
+        fn set_expiry(&mut self, key: Bytes, expiry: u64) {
+            self.inner.set_expiry(key, expiry)
+        }
+
+        fn remove_expiry(&mut self, key: &[u8]) -> Option<u64> {
+            self.inner.remove_expiry(key)
+        }
+
+        fn expiring(&self) -> Box<dyn Iterator<Item = (&Bytes, u64)> + '_> {
+            self.inner.expiring()
+        }
+    }
+
+    #[tokio::test]
+    async fn commands_go_through_the_configured_storage() {
+        let writes = Arc::new(AtomicUsize::new(0));
+        let factory_writes = writes.clone();
+        let redis = Server::builder()
+            .storage(move || {
+                Box::new(CountingStorage {
+                    inner: MemoryStorage::default(),
+                    writes: factory_writes.clone(),
+                })
+            })
+            .embedded();
+
+        redis.execute(["SET", "foo", "bar"]).await;
+        redis.execute(["SET", "baz", "qux"]).await;
+
+        assert_eq!(writes.load(Ordering::SeqCst), 2);
+        assert_eq!(
+            redis.execute(["GET", "foo"]).await,
+            Resp::BulkString("bar".into())
+        );
+    }
+}
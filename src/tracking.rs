@@ -0,0 +1,184 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::{pubsub::PubSub, resp::Resp};
+
+/// The channel RESP2 clients subscribe to for the invalidations redirected to them.
+pub const INVALIDATE_CHANNEL: &str = "__redis__:invalidate";
+
+/// The options of CLIENT TRACKING ON.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TrackingOptions {
+    /// The client that receives the invalidations instead.
+    pub redirect: Option<u64>,
+    /// Invalidates every key starting with one of the prefixes, whether it was read or not.
+    pub bcast: bool,
+    pub prefixes: Vec<Bytes>,
+    /// Only tracks the keys read right after CLIENT CACHING YES.
+    pub optin: bool,
+    /// Tracks every key read, except right after CLIENT CACHING NO.
+    pub optout: bool,
+    /// Leaves out the keys the client changed itself.
+    pub noloop: bool,
+}
+
+impl TrackingOptions {
+    /// Whether a client in this mode tracks the keys of its next read, given what CLIENT CACHING
+    /// asked for before it.
+    pub fn tracks_reads(&self, caching: Option<bool>) -> bool {
+        match (self.bcast, self.optin, self.optout) {
+            (true, _, _) => false,
+            (_, true, _) => caching == Some(true),
+            (_, _, true) => caching != Some(false),
+            _ => true,
+        }
+    }
+}
+
+/// What a command did to the keys it names, as far as client side caching is concerned.
+pub enum Access {
+    Read(Vec<Bytes>),
+    Write(Vec<Bytes>),
+    /// Changed every key, like FLUSHALL.
+    Flush,
+}
+
+/// Client side caching: which clients read which keys, so they can be told to drop what they
+/// cached once the keys change.
+// NOTE: Like in Redis, keys are tracked by name regardless of their database, and a client that
+//       turned tracking off or went away is only forgotten once one of its keys changes.
+pub struct Tracking {
+    pubsub: Arc<PubSub>,
+    clients: Mutex<HashMap<u64, Tracker>>,
+    /// The clients that read each key since it last changed.
+    keys: Mutex<HashMap<Bytes, HashSet<u64>>>,
+}
+
+/// A client with tracking on.
+struct Tracker {
+    options: TrackingOptions,
+    /// Where the client's own replies go, only used without a redirect.
+    sender: UnboundedSender<Resp>,
+    resp3: bool,
+}
+
+impl Tracking {
+    pub fn new(pubsub: Arc<PubSub>) -> Tracking {
+        Tracking {
+            pubsub,
+            clients: Mutex::default(),
+            keys: Mutex::default(),
+        }
+    }
+
+    pub fn enable(
+        &self,
+        id: u64,
+        options: TrackingOptions,
+        sender: UnboundedSender<Resp>,
+        resp3: bool,
+    ) {
+        let tracker = Tracker {
+            options,
+            sender,
+            resp3,
+        };
+        self.clients.lock().unwrap().insert(id, tracker);
+    }
+
+    pub fn disable(&self, id: u64) {
+        self.clients.lock().unwrap().remove(&id);
+    }
+
+    /// Whether any client has tracking on, before bothering to work out what a command accessed.
+    pub fn is_enabled(&self) -> bool {
+        !self.clients.lock().unwrap().is_empty()
+    }
+
+    /// Whether the client `id` could receive redirected invalidations.
+    pub fn can_redirect_to(&self, id: u64) -> bool {
+        self.pubsub.is_subscribed(INVALIDATE_CHANNEL.as_bytes(), id)
+    }
+
+    /// Remembers that the client `id` read `keys`, to invalidate them once they change.
+    pub fn remember(&self, id: u64, keys: Vec<Bytes>) {
+        let mut tracked = self.keys.lock().unwrap();
+        for key in keys {
+            tracked.entry(key).or_default().insert(id);
+        }
+    }
+
+    /// Tells the clients that read `keys`, or broadcast a prefix of them, that they changed.
+    /// `writer` is the client that changed them, if any, which NOLOOP leaves out.
+    pub fn invalidate(&self, keys: &[Bytes], writer: Option<u64>) {
+        let mut clients = self.clients.lock().unwrap();
+        let mut tracked = self.keys.lock().unwrap();
+
+        for key in keys {
+            let readers = tracked.remove(key).unwrap_or_default();
+            let broadcasters = clients.iter().filter_map(|(id, tracker)| {
+                let prefixes = &tracker.options.prefixes;
+                let matches = prefixes.is_empty() || prefixes.iter().any(|p| key.starts_with(p));
+                (tracker.options.bcast && matches).then_some(*id)
+            });
+            let targets = readers
+                .into_iter()
+                .filter(|id| clients.get(id).is_some_and(|t| !t.options.bcast))
+                .chain(broadcasters)
+                .collect::<Vec<_>>();
+
+            let keys = Resp::Array(vec![Resp::BulkString(key.clone())]);
+            for id in targets {
+                let noloop = clients.get(&id).is_some_and(|t| t.options.noloop);
+                if !(noloop && writer == Some(id)) {
+                    self.notify(&mut clients, id, keys.clone());
+                }
+            }
+        }
+    }
+
+    /// Tells every tracking client that all keys changed at once, like after FLUSHALL.
+    pub fn invalidate_all(&self) {
+        let mut clients = self.clients.lock().unwrap();
+        self.keys.lock().unwrap().clear();
+
+        let ids = clients.keys().copied().collect::<Vec<_>>();
+        for id in ids {
+            self.notify(&mut clients, id, Resp::Null);
+        }
+    }
+
+    fn notify(&self, clients: &mut HashMap<u64, Tracker>, id: u64, keys: Resp) {
+        let Some(tracker) = clients.get(&id) else {
+            return;
+        };
+        if tracker.sender.is_closed() {
+            clients.remove(&id);
+            return;
+        }
+
+        match tracker.options.redirect {
+            Some(redirect) => {
+                let message = Resp::Push(vec![
+                    Resp::BulkString(Bytes::from("message")),
+                    Resp::BulkString(Bytes::from(INVALIDATE_CHANNEL)),
+                    keys,
+                ]);
+                self.pubsub
+                    .send_to(INVALIDATE_CHANNEL.as_bytes(), redirect, message);
+            }
+            None if tracker.resp3 => {
+                let push = Resp::Push(vec![Resp::BulkString(Bytes::from("invalidate")), keys]);
+                let _ = tracker.sender.send(push);
+            }
+            // NOTE: Without a redirect a RESP2 connection has nowhere to receive invalidations,
+            //       Redis drops them as well.
+            None => {}
+        }
+    }
+}
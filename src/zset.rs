@@ -0,0 +1,286 @@
+use std::{
+    cmp::Ordering,
+    collections::{BTreeSet, HashMap},
+};
+
+use bytes::Bytes;
+
+/// A set of members ordered by their score, and members with the same score by their bytes, like
+/// the zset of Redis. Members are kept both in order and in a map to their score, so looking up a
+/// score doesn't have to search for it.
+// NOTE: Redis orders members in a skiplist that knows how many members every link skips over, which
+//       makes ranks a lookup. A BTreeSet can't count what comes before an entry, so ranks walk the
+//       set up to it instead.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SortedSet {
+    ordered: BTreeSet<(Score, Bytes)>,
+    scores: HashMap<Bytes, f64>,
+}
+
+/// A score that can be ordered, which sorted sets can promise by never holding NaN.
+#[derive(Clone, Copy, Debug)]
+struct Score(f64);
+
+impl PartialEq for Score {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// One end of a range of scores, like `1`, `(1` or `-inf` in ZRANGEBYSCORE.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ScoreBound {
+    pub score: f64,
+    pub exclusive: bool,
+}
+
+impl ScoreBound {
+    pub fn parse(bound: &[u8]) -> Option<ScoreBound> {
+        let (bound, exclusive) = match bound.strip_prefix(b"(") {
+            Some(bound) => (bound, true),
+            None => (bound, false),
+        };
+        let score = std::str::from_utf8(bound).ok()?.parse::<f64>().ok()?;
+        (!score.is_nan()).then_some(ScoreBound { score, exclusive })
+    }
+
+    fn below(&self, score: f64) -> bool {
+        score > self.score || !self.exclusive && score == self.score
+    }
+
+    fn above(&self, score: f64) -> bool {
+        score < self.score || !self.exclusive && score == self.score
+    }
+}
+
+/// One end of a range of members, like `[a`, `(a`, `-` or `+` in ZRANGEBYLEX.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LexBound {
+    /// `-`, before every member.
+    Min,
+    /// `+`, after every member.
+    Max,
+    Inclusive(Bytes),
+    Exclusive(Bytes),
+}
+
+impl LexBound {
+    pub fn parse(bound: &[u8]) -> Option<LexBound> {
+        match bound {
+            b"-" => Some(LexBound::Min),
+            b"+" => Some(LexBound::Max),
+            [b'[', member @ ..] => Some(LexBound::Inclusive(Bytes::copy_from_slice(member))),
+            [b'(', member @ ..] => Some(LexBound::Exclusive(Bytes::copy_from_slice(member))),
+            _ => None,
+        }
+    }
+
+    fn below(&self, member: &[u8]) -> bool {
+        match self {
+            LexBound::Min => true,
+            LexBound::Max => false,
+            LexBound::Inclusive(bound) => member >= bound,
+            LexBound::Exclusive(bound) => member > bound,
+        }
+    }
+
+    fn above(&self, member: &[u8]) -> bool {
+        match self {
+            LexBound::Min => false,
+            LexBound::Max => true,
+            LexBound::Inclusive(bound) => member <= bound,
+            LexBound::Exclusive(bound) => member < bound,
+        }
+    }
+}
+
+impl SortedSet {
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    pub fn score(&self, member: &[u8]) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// Adds `member` or changes its score, returning the score it had before.
+    pub fn insert(&mut self, member: Bytes, score: f64) -> Option<f64> {
+        debug_assert!(!score.is_nan(), "sorted sets can't hold NaN scores");
+        // -0 and 0 are the same score, which total_cmp would tell apart.
+        let score = if score == 0.0 { 0.0 } else { score };
+
+        let old = self.scores.insert(member.clone(), score);
+        if let Some(old) = old {
+            self.ordered.remove(&(Score(old), member.clone()));
+        }
+        self.ordered.insert((Score(score), member));
+        old
+    }
+
+    /// Removes `member`, returning the score it had.
+    pub fn remove(&mut self, member: &[u8]) -> Option<f64> {
+        let (member, score) = self.scores.remove_entry(member)?;
+        self.ordered.remove(&(Score(score), member));
+        Some(score)
+    }
+
+    /// How many members come before `member`, lowest score first.
+    pub fn rank(&self, member: &[u8]) -> Option<usize> {
+        let (member, score) = self.scores.get_key_value(member)?;
+        Some(
+            self.ordered
+                .range(..(Score(*score), member.clone()))
+                .count(),
+        )
+    }
+
+    /// Removes the member with the lowest score.
+    pub fn pop_first(&mut self) -> Option<(Bytes, f64)> {
+        let (score, member) = self.ordered.pop_first()?;
+        self.scores.remove(&member);
+        Some((member, score.0))
+    }
+
+    /// Removes the member with the highest score.
+    pub fn pop_last(&mut self) -> Option<(Bytes, f64)> {
+        let (score, member) = self.ordered.pop_last()?;
+        self.scores.remove(&member);
+        Some((member, score.0))
+    }
+
+    /// The members and their scores, lowest score first.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = (&Bytes, f64)> {
+        self.ordered.iter().map(|(score, member)| (member, score.0))
+    }
+
+    /// The members with a score between `min` and `max`, lowest score first.
+    pub fn range_by_score(&self, min: ScoreBound, max: ScoreBound) -> Vec<(&Bytes, f64)> {
+        // No member sorts before the empty one, so this starts at the first with the min score.
+        self.ordered
+            .range((Score(min.score), Bytes::new())..)
+            .map(|(score, member)| (member, score.0))
+            .skip_while(|(_, score)| !min.below(*score))
+            .take_while(|(_, score)| max.above(*score))
+            .collect()
+    }
+
+    /// The members between `min` and `max`, which is only meaningful when every member has the
+    /// same score, like ZRANGEBYLEX expects.
+    pub fn range_by_lex(&self, min: &LexBound, max: &LexBound) -> Vec<(&Bytes, f64)> {
+        self.iter()
+            .skip_while(|(member, _)| !min.below(member))
+            .take_while(|(member, _)| max.above(member))
+            .collect()
+    }
+}
+
+mod test {
+    #[allow(unused_imports)]
+    use super::{LexBound, ScoreBound, SortedSet};
+    #[allow(unused_imports)]
+    use bytes::Bytes;
+
+    #[allow(dead_code)]
+    fn members(zset: &SortedSet) -> Vec<(&str, f64)> {
+        zset.iter()
+            .map(|(member, score)| (std::str::from_utf8(member).unwrap(), score))
+            .collect()
+    }
+
+    #[allow(dead_code)]
+    fn names(range: Vec<(&Bytes, f64)>) -> Vec<&str> {
+        range
+            .into_iter()
+            .map(|(member, _)| std::str::from_utf8(member).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn members_are_ordered_by_score_then_by_member() {
+        let mut zset = SortedSet::default();
+        zset.insert(Bytes::from("c"), 1.0);
+        zset.insert(Bytes::from("b"), 2.0);
+        zset.insert(Bytes::from("a"), 1.0);
+        zset.insert(Bytes::from("d"), f64::NEG_INFINITY);
+
+        assert_eq!(
+            members(&zset),
+            [("d", f64::NEG_INFINITY), ("a", 1.0), ("c", 1.0), ("b", 2.0)]
+        );
+        assert_eq!(zset.rank(b"c"), Some(2));
+        assert_eq!(zset.rank(b"z"), None);
+        assert_eq!(zset.len(), 4);
+    }
+
+    #[test]
+    fn changing_a_score_moves_the_member() {
+        let mut zset = SortedSet::default();
+        assert_eq!(zset.insert(Bytes::from("a"), 1.0), None);
+        zset.insert(Bytes::from("b"), 2.0);
+        assert_eq!(zset.insert(Bytes::from("a"), 3.0), Some(1.0));
+
+        assert_eq!(members(&zset), [("b", 2.0), ("a", 3.0)]);
+        assert_eq!(zset.score(b"a"), Some(3.0));
+
+        // Negative and positive zero are the same score, so members still sort by their bytes.
+        zset.insert(Bytes::from("y"), -0.0);
+        zset.insert(Bytes::from("x"), 0.0);
+        assert_eq!(zset.rank(b"x"), Some(0));
+
+        assert_eq!(zset.remove(b"a"), Some(3.0));
+        assert_eq!(zset.remove(b"a"), None);
+        assert_eq!(members(&zset), [("x", 0.0), ("y", 0.0), ("b", 2.0)]);
+    }
+
+    #[test]
+    fn ranges_respect_exclusive_bounds() {
+        let mut zset = SortedSet::default();
+        for (member, score) in [("a", 1.0), ("b", 2.0), ("c", 2.0), ("d", 3.0)] {
+            zset.insert(Bytes::from(member), score);
+        }
+        let by_score = |min: &[u8], max: &[u8]| {
+            let (min, max) = (ScoreBound::parse(min), ScoreBound::parse(max));
+            names(zset.range_by_score(min.unwrap(), max.unwrap()))
+        };
+
+        assert_eq!(by_score(b"2", b"3"), ["b", "c", "d"]);
+        assert_eq!(by_score(b"(1", b"(3"), ["b", "c"]);
+        assert_eq!(by_score(b"-inf", b"+inf"), ["a", "b", "c", "d"]);
+        assert!(by_score(b"3", b"1").is_empty());
+        assert_eq!(ScoreBound::parse(b"nan"), None);
+        assert_eq!(ScoreBound::parse(b"[1"), None);
+
+        let mut zset = SortedSet::default();
+        for member in ["a", "b", "c", "d"] {
+            zset.insert(Bytes::from(member), 0.0);
+        }
+        let by_lex = |min: &[u8], max: &[u8]| {
+            let (min, max) = (LexBound::parse(min), LexBound::parse(max));
+            names(zset.range_by_lex(&min.unwrap(), &max.unwrap()))
+        };
+
+        assert_eq!(by_lex(b"-", b"+"), ["a", "b", "c", "d"]);
+        assert_eq!(by_lex(b"[b", b"(d"), ["b", "c"]);
+        assert_eq!(by_lex(b"(a", b"[b"), ["b"]);
+        assert!(by_lex(b"+", b"-").is_empty());
+        assert_eq!(LexBound::parse(b"a"), None);
+    }
+}
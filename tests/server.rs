@@ -0,0 +1,841 @@
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use redis_starter_rust::{resp::Resp, Server};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+struct Client {
+    stream: TcpStream,
+    buffer: BytesMut,
+}
+
+impl Client {
+    async fn connect(server: &Server) -> Client {
+        Client {
+            stream: TcpStream::connect(server.local_addr()).await.unwrap(),
+            buffer: BytesMut::new(),
+        }
+    }
+
+    fn encode(command: &[&str]) -> Vec<u8> {
+        let request = Resp::Array(
+            command
+                .iter()
+                .map(|arg| Resp::BulkString(Bytes::copy_from_slice(arg.as_bytes())))
+                .collect(),
+        );
+        request.encoded().unwrap().into_bytes()
+    }
+
+    async fn send(&mut self, command: &[&str]) {
+        self.stream.write_all(&Self::encode(command)).await.unwrap();
+    }
+
+    async fn read_reply(&mut self) -> Resp {
+        loop {
+            if let Some((reply, _)) = Resp::parse(&mut self.buffer, usize::MAX).unwrap() {
+                return reply;
+            }
+
+            let read = self.stream.read_buf(&mut self.buffer).await.unwrap();
+            assert!(read > 0, "server closed the connection");
+        }
+    }
+
+    async fn command(&mut self, command: &[&str]) -> Resp {
+        self.send(command).await;
+        self.read_reply().await
+    }
+
+    /// Like `command`, but returns the reply exactly as it was sent.
+    async fn raw_command(&mut self, command: &[&str]) -> Vec<u8> {
+        self.send(command).await;
+        loop {
+            let frame = self.buffer.clone();
+            if let Some((_, len)) = Resp::parse(&mut self.buffer, usize::MAX).unwrap() {
+                return frame[..len].to_vec();
+            }
+
+            let read = self.stream.read_buf(&mut self.buffer).await.unwrap();
+            assert!(read > 0, "server closed the connection");
+        }
+    }
+}
+
+// NOTE: Without `save ""` servers would share ./dump.rdb, so tests keep their data in memory.
+async fn spawn_server() -> Server {
+    Server::builder()
+        .port(0)
+        .config("save", "")
+        .spawn()
+        .await
+        .unwrap()
+}
+
+fn ok() -> Resp {
+    Resp::SimpleString("OK".to_string())
+}
+
+fn bulk(value: &str) -> Resp {
+    Resp::BulkString(Bytes::copy_from_slice(value.as_bytes()))
+}
+
+#[tokio::test]
+async fn ping_and_echo() {
+    let server = spawn_server().await;
+    let mut client = Client::connect(&server).await;
+
+    assert_eq!(
+        client.command(&["PING"]).await,
+        Resp::SimpleString("PONG".to_string())
+    );
+    assert_eq!(client.command(&["ECHO", "hello"]).await, bulk("hello"));
+}
+
+#[tokio::test]
+async fn set_then_get() {
+    let server = spawn_server().await;
+    let mut client = Client::connect(&server).await;
+
+    assert_eq!(client.command(&["SET", "foo", "bar"]).await, ok());
+    assert_eq!(client.command(&["GET", "foo"]).await, bulk("bar"));
+    assert_eq!(client.command(&["GET", "missing"]).await, Resp::Null);
+}
+
+#[tokio::test]
+async fn binary_keys_and_values_round_trip() {
+    let server = spawn_server().await;
+    let mut client = Client::connect(&server).await;
+
+    let value = b"\x00\xff\xfe\r\nMixed Case";
+    let mut request = b"*3\r\n$3\r\nSET\r\n$4\r\nK\xe9y\x00\r\n$15\r\n".to_vec();
+    request.extend_from_slice(value);
+    request.extend_from_slice(b"\r\n");
+    client.stream.write_all(&request).await.unwrap();
+    assert_eq!(client.read_reply().await, ok());
+
+    client
+        .stream
+        .write_all(b"*2\r\n$3\r\nget\r\n$4\r\nK\xe9y\x00\r\n")
+        .await
+        .unwrap();
+    assert_eq!(
+        client.read_reply().await,
+        Resp::BulkString(Bytes::from_static(value))
+    );
+    assert_eq!(client.command(&["GET", "k\u{e9}y"]).await, Resp::Null);
+}
+
+#[tokio::test]
+async fn writes_are_visible_to_other_clients() {
+    let server = spawn_server().await;
+    let mut writer = Client::connect(&server).await;
+    let mut reader = Client::connect(&server).await;
+
+    assert_eq!(writer.command(&["SET", "foo", "bar"]).await, ok());
+    assert_eq!(reader.command(&["GET", "foo"]).await, bulk("bar"));
+}
+
+#[tokio::test]
+async fn blocked_clients_wait_for_other_clients() {
+    let server = spawn_server().await;
+    let mut blocked = Client::connect(&server).await;
+    let mut gone = Client::connect(&server).await;
+    let mut pusher = Client::connect(&server).await;
+
+    gone.send(&["BLPOP", "list", "0"]).await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    drop(gone);
+    blocked.send(&["BLPOP", "list", "0"]).await;
+    tokio::time::sleep(Duration::from_millis(50)).await;
+
+    assert_eq!(
+        pusher.command(&["RPUSH", "list", "a", "b"]).await,
+        Resp::Integer(2)
+    );
+    assert_eq!(
+        blocked.read_reply().await,
+        Resp::Array(vec![bulk("list"), bulk("a")])
+    );
+    // The client that went away didn't take anything.
+    assert_eq!(
+        pusher.command(&["LRANGE", "list", "0", "-1"]).await,
+        Resp::Array(vec![bulk("b")])
+    );
+}
+
+#[tokio::test]
+async fn null_replies_keep_their_shape() {
+    let server = spawn_server().await;
+    let mut client = Client::connect(&server).await;
+
+    assert_eq!(client.raw_command(&["GET", "missing"]).await, b"$-1\r\n");
+    assert_eq!(
+        client.raw_command(&["BLPOP", "missing", "0.01"]).await,
+        b"*-1\r\n"
+    );
+    assert_eq!(
+        client.raw_command(&["BZPOPMIN", "missing", "0.01"]).await,
+        b"*-1\r\n"
+    );
+    assert_eq!(client.raw_command(&["LPOP", "missing"]).await, b"$-1\r\n");
+    assert_eq!(
+        client.raw_command(&["LPOP", "missing", "2"]).await,
+        b"*-1\r\n"
+    );
+
+    client.command(&["HELLO", "3"]).await;
+    assert_eq!(
+        client.raw_command(&["BLPOP", "missing", "0.01"]).await,
+        b"_\r\n"
+    );
+}
+
+#[tokio::test]
+async fn scores_are_paired_with_their_members_in_resp3() {
+    let server = spawn_server().await;
+    let mut client = Client::connect(&server).await;
+    client.command(&["ZADD", "zset", "1", "a", "2", "b"]).await;
+
+    let range = ["ZRANGE", "zset", "0", "-1", "WITHSCORES"];
+    assert_eq!(
+        client.raw_command(&range).await,
+        b"*4\r\n$1\r\na\r\n$1\r\n1\r\n$1\r\nb\r\n$1\r\n2\r\n"
+    );
+
+    client.command(&["HELLO", "3"]).await;
+    assert_eq!(
+        client.raw_command(&range).await,
+        b"*2\r\n*2\r\n$1\r\na\r\n,1\r\n*2\r\n$1\r\nb\r\n,2\r\n"
+    );
+}
+
+#[tokio::test]
+async fn keys_expire() {
+    let server = spawn_server().await;
+    let mut client = Client::connect(&server).await;
+
+    assert_eq!(
+        client.command(&["SET", "foo", "bar", "PX", "100"]).await,
+        ok()
+    );
+    assert_eq!(client.command(&["GET", "foo"]).await, bulk("bar"));
+
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    assert_eq!(client.command(&["GET", "foo"]).await, Resp::Null);
+}
+
+#[tokio::test]
+async fn overwriting_a_key_clears_its_expiry() {
+    let server = spawn_server().await;
+    let mut client = Client::connect(&server).await;
+
+    assert_eq!(
+        client.command(&["SET", "foo", "bar", "PX", "100"]).await,
+        ok()
+    );
+    assert_eq!(client.command(&["SET", "foo", "baz"]).await, ok());
+
+    tokio::time::sleep(Duration::from_millis(150)).await;
+    assert_eq!(client.command(&["GET", "foo"]).await, bulk("baz"));
+}
+
+#[tokio::test]
+async fn pipelined_commands_are_answered_in_order() {
+    let server = spawn_server().await;
+    let mut client = Client::connect(&server).await;
+
+    let mut pipeline = Vec::new();
+    for i in 0..50 {
+        pipeline.extend(Client::encode(&[
+            "SET",
+            &format!("key:{}", i),
+            &i.to_string(),
+        ]));
+        pipeline.extend(Client::encode(&["GET", &format!("key:{}", i)]));
+    }
+    client.stream.write_all(&pipeline).await.unwrap();
+
+    for i in 0..50 {
+        assert_eq!(client.read_reply().await, ok());
+        assert_eq!(client.read_reply().await, bulk(&i.to_string()));
+    }
+}
+
+#[tokio::test]
+async fn pipelining_clients_dont_starve_others() {
+    let server = spawn_server().await;
+    let mut flooder = Client::connect(&server).await;
+    let mut interactive = Client::connect(&server).await;
+
+    let mut pipeline = Vec::new();
+    for _ in 0..10_000 {
+        pipeline.extend(Client::encode(&["PING"]));
+    }
+    let flood = tokio::spawn(async move {
+        flooder.stream.write_all(&pipeline).await.unwrap();
+        for _ in 0..10_000 {
+            assert_eq!(
+                flooder.read_reply().await,
+                Resp::SimpleString("PONG".to_string())
+            );
+        }
+    });
+
+    for i in 0..10 {
+        let key = format!("key:{}", i);
+        assert_eq!(interactive.command(&["SET", &key, "value"]).await, ok());
+        assert_eq!(interactive.command(&["GET", &key]).await, bulk("value"));
+    }
+
+    flood.await.unwrap();
+}
+
+#[tokio::test]
+async fn requests_split_across_writes() {
+    let server = spawn_server().await;
+    let mut client = Client::connect(&server).await;
+
+    let value = "x".repeat(10_000);
+    let request = Client::encode(&["SET", "big", &value]);
+    for chunk in request.chunks(1000) {
+        client.stream.write_all(chunk).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+
+    assert_eq!(client.read_reply().await, ok());
+    assert_eq!(client.command(&["GET", "big"]).await, bulk(&value));
+}
+
+#[tokio::test]
+async fn many_clients_in_parallel() {
+    let server = spawn_server().await;
+
+    let mut tasks = Vec::new();
+    for i in 0..20 {
+        let mut client = Client::connect(&server).await;
+        tasks.push(tokio::spawn(async move {
+            for j in 0..50 {
+                let key = format!("client:{}:{}", i, j);
+                assert_eq!(client.command(&["SET", &key, "value"]).await, ok());
+                assert_eq!(client.command(&["GET", &key]).await, bulk("value"));
+            }
+        }));
+    }
+
+    for task in tasks {
+        task.await.unwrap();
+    }
+}
+
+#[tokio::test]
+async fn io_threads_share_the_listening_port() {
+    let server = Server::builder()
+        .port(0)
+        .config("save", "")
+        .config("io-threads", "4")
+        .spawn()
+        .await
+        .unwrap();
+
+    let mut clients = Vec::new();
+    for i in 0..16 {
+        let mut client = Client::connect(&server).await;
+        let key = format!("key:{}", i);
+        assert_eq!(client.command(&["SET", &key, "value"]).await, ok());
+        clients.push((client, key));
+    }
+    for (client, key) in &mut clients {
+        assert_eq!(client.command(&["GET", key]).await, bulk("value"));
+    }
+}
+
+fn cluster_node_dir(name: &str) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!(
+        "redis-cluster-node-{}-{}",
+        std::process::id(),
+        name
+    ))
+}
+
+async fn spawn_cluster_node(name: &str) -> Server {
+    let dir = cluster_node_dir(name);
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+
+    Server::builder()
+        .port(0)
+        .dir(dir.to_str().unwrap())
+        .config("cluster-enabled", "yes")
+        .config("cluster-node-timeout", "200")
+        .spawn()
+        .await
+        .unwrap()
+}
+
+async fn cluster_nodes(server: &Server) -> String {
+    server
+        .handle()
+        .execute(["CLUSTER", "NODES"])
+        .await
+        .to_string()
+}
+
+async fn meet(server: &Server, other: &Server) -> Resp {
+    let nodes = cluster_nodes(other).await;
+    // The line of `other` itself reads `<id> 127.0.0.1:<port>@<cport> myself,master ...`.
+    let address = nodes
+        .lines()
+        .find(|line| line.contains("myself"))
+        .unwrap()
+        .split(' ')
+        .nth(1)
+        .unwrap()
+        .to_string();
+    let (address, cport) = address.split_once('@').unwrap();
+    let (ip, port) = address.rsplit_once(':').unwrap();
+
+    server
+        .handle()
+        .execute(["CLUSTER", "MEET", ip, port, cport])
+        .await
+}
+
+#[tokio::test]
+async fn cluster_nodes_meet_and_learn_about_each_other() {
+    let a = spawn_cluster_node("a").await;
+    let b = spawn_cluster_node("b").await;
+    let c = spawn_cluster_node("c").await;
+    let id = |server: &Server| {
+        let handle = server.handle();
+        async move { handle.execute(["CLUSTER", "MYID"]).await.to_string() }
+    };
+    let (a_id, b_id, c_id) = (id(&a).await, id(&b).await, id(&c).await);
+
+    b.handle()
+        .execute(["CLUSTER", "ADDSLOTSRANGE", "0", "16383"])
+        .await;
+    assert_eq!(meet(&a, &b).await, ok());
+
+    // Both sides know each other right away, with the slots their owner announced.
+    let nodes = cluster_nodes(&a).await;
+    let b_line = nodes.lines().find(|line| line.starts_with(&b_id)).unwrap();
+    assert!(b_line.contains(" master - 0 "));
+    assert!(b_line.ends_with(" connected 0-16383"));
+    assert!(cluster_nodes(&b).await.contains(&a_id));
+    match a.handle().execute(["GET", "foo"]).await {
+        Resp::SimpleError(error) => assert!(error.starts_with("MOVED 12182 127.0.0.1:")),
+        reply => panic!("expected a redirect, got {:?}", reply),
+    }
+
+    // B only hears about C through A's gossip.
+    assert_eq!(meet(&c, &a).await, ok());
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    while !cluster_nodes(&b).await.contains(&c_id) {
+        assert!(tokio::time::Instant::now() < deadline, "C never reached B");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(cluster_nodes(&c).await.contains(&b_id));
+
+    assert_eq!(a.handle().execute(["CLUSTER", "FORGET", &b_id]).await, ok());
+    assert!(!cluster_nodes(&a).await.contains(&b_id));
+    assert_eq!(
+        a.handle().execute(["CLUSTER", "FORGET", &a_id]).await,
+        Resp::SimpleError("ERR I tried hard but I can't forget myself...".to_string())
+    );
+    assert_eq!(
+        a.handle().execute(["CLUSTER", "FORGET", &b_id]).await,
+        Resp::SimpleError(format!("ERR Unknown node {}", b_id))
+    );
+    assert_eq!(
+        a.handle()
+            .execute(["CLUSTER", "MEET", "not-an-ip", "7000"])
+            .await,
+        Resp::SimpleError("ERR Invalid node address specified: not-an-ip:7000".to_string())
+    );
+
+    for name in ["a", "b", "c"] {
+        std::fs::remove_dir_all(cluster_node_dir(name)).unwrap();
+    }
+}
+
+fn command(args: &[&str]) -> Resp {
+    Resp::Array(args.iter().map(|arg| bulk(arg)).collect())
+}
+
+#[tokio::test]
+async fn replica_syncs_from_its_master() {
+    let master = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let master_addr = master.local_addr().unwrap();
+    let replica = Server::builder()
+        .port(0)
+        .config("save", "")
+        .config("replicaof", format!("127.0.0.1 {}", master_addr.port()))
+        .spawn()
+        .await
+        .unwrap();
+
+    let (stream, _) = master.accept().await.unwrap();
+    let mut link = Client {
+        stream,
+        buffer: BytesMut::new(),
+    };
+    let listening_port = replica.local_addr().port().to_string();
+    assert_eq!(link.read_reply().await, command(&["PING"]));
+    link.stream.write_all(b"+PONG\r\n").await.unwrap();
+    assert_eq!(
+        link.read_reply().await,
+        command(&["REPLCONF", "listening-port", &listening_port])
+    );
+    link.stream.write_all(b"+OK\r\n").await.unwrap();
+    assert_eq!(
+        link.read_reply().await,
+        command(&["REPLCONF", "capa", "psync2"])
+    );
+    link.stream.write_all(b"+OK\r\n").await.unwrap();
+    assert_eq!(link.read_reply().await, command(&["PSYNC", "?", "-1"]));
+
+    // A snapshot holding `foo` and `old`, preceded by a keepalive newline.
+    let mut rdb =
+        b"REDIS0011\xFE\x00\xFB\x02\x00\x00\x03foo\x03bar\x00\x03old\x05value\xFF".to_vec();
+    rdb.extend_from_slice(&[0; 8]);
+    let mut sync =
+        format!("+FULLRESYNC {} 100\r\n\n${}\r\n", "8".repeat(40), rdb.len()).into_bytes();
+    sync.extend_from_slice(&rdb);
+    link.stream.write_all(&sync).await.unwrap();
+
+    let set = Client::encode(&["SET", "baz", "qux"]);
+    let mut stream = set.clone();
+    stream.extend(Client::encode(&["SET", "foo", "updated"]));
+    stream.extend(Client::encode(&["REPLCONF", "GETACK", "*"]));
+    link.stream.write_all(&stream).await.unwrap();
+
+    // The acknowledged offset covers the writes, but not the GETACK itself.
+    let offset = 100 + set.len() + Client::encode(&["SET", "foo", "updated"]).len();
+    assert_eq!(
+        link.read_reply().await,
+        command(&["REPLCONF", "ACK", &offset.to_string()])
+    );
+
+    let mut client = Client::connect(&replica).await;
+    assert_eq!(client.command(&["GET", "baz"]).await, bulk("qux"));
+    assert_eq!(client.command(&["GET", "foo"]).await, bulk("updated"));
+    assert_eq!(client.command(&["GET", "old"]).await, bulk("value"));
+    let info = client.command(&["INFO", "replication"]).await.to_string();
+    assert!(info.contains("role:slave"));
+    assert!(info.contains("master_link_status:up"));
+}
+
+async fn wait_for_reply(client: &mut Client, command: &[&str], expected: Resp) {
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    while client.command(command).await != expected {
+        assert!(
+            tokio::time::Instant::now() < deadline,
+            "{:?} never arrived",
+            expected
+        );
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+#[tokio::test]
+async fn masters_stream_writes_to_their_replicas() {
+    let master = spawn_server().await;
+    let mut writer = Client::connect(&master).await;
+    assert_eq!(writer.command(&["SET", "before", "sync"]).await, ok());
+
+    let replica = Server::builder()
+        .port(0)
+        .config("save", "")
+        .config(
+            "replicaof",
+            format!("127.0.0.1 {}", master.local_addr().port()),
+        )
+        .spawn()
+        .await
+        .unwrap();
+    let mut reader = Client::connect(&replica).await;
+    wait_for_reply(&mut reader, &["GET", "before"], bulk("sync")).await;
+
+    assert_eq!(writer.command(&["SET", "after", "sync"]).await, ok());
+    assert_eq!(writer.command(&["RENAME", "before", "renamed"]).await, ok());
+    wait_for_reply(&mut reader, &["GET", "renamed"], bulk("sync")).await;
+    assert_eq!(reader.command(&["GET", "after"]).await, bulk("sync"));
+    assert_eq!(reader.command(&["GET", "before"]).await, Resp::Null);
+
+    let info = writer.command(&["INFO", "replication"]).await.to_string();
+    assert!(info.contains("connected_slaves:1"), "{}", info);
+    assert!(info.contains(&format!(
+        "port={},state=online",
+        replica.local_addr().port()
+    )));
+    assert_eq!(
+        reader.command(&["SET", "foo", "bar"]).await,
+        Resp::SimpleError("READONLY You can't write against a read only replica.".to_string())
+    );
+}
+
+#[tokio::test]
+async fn transactions_reach_replicas_in_their_database() {
+    let master = spawn_server().await;
+    let replica = Server::builder()
+        .port(0)
+        .config("save", "")
+        .config(
+            "replicaof",
+            format!("127.0.0.1 {}", master.local_addr().port()),
+        )
+        .spawn()
+        .await
+        .unwrap();
+    let mut writer = Client::connect(&master).await;
+    let mut reader = Client::connect(&replica).await;
+    assert_eq!(reader.command(&["SELECT", "3"]).await, ok());
+
+    let queued = Resp::SimpleString("QUEUED".to_string());
+    assert_eq!(writer.command(&["SELECT", "3"]).await, ok());
+    assert_eq!(writer.command(&["MULTI"]).await, ok());
+    assert_eq!(writer.command(&["SET", "foo", "bar"]).await, queued);
+    assert_eq!(writer.command(&["RPUSH", "list", "a", "b"]).await, queued);
+    assert_eq!(
+        writer.command(&["EXEC"]).await,
+        Resp::Array(vec![ok(), Resp::Integer(2)])
+    );
+
+    wait_for_reply(&mut reader, &["LLEN", "list"], Resp::Integer(2)).await;
+    assert_eq!(reader.command(&["GET", "foo"]).await, bulk("bar"));
+    assert_eq!(reader.command(&["SELECT", "0"]).await, ok());
+    assert_eq!(reader.command(&["DBSIZE"]).await, Resp::Integer(0));
+}
+
+#[tokio::test]
+async fn resp2_connections_get_flattened_replies() {
+    let server = Server::builder()
+        .port(0)
+        .dir("/tmp")
+        .config("save", "")
+        .spawn()
+        .await
+        .unwrap();
+    let mut client = Client::connect(&server).await;
+
+    assert_eq!(
+        client.command(&["CONFIG", "GET", "dir"]).await,
+        Resp::Array(vec![bulk("dir"), bulk("/tmp")])
+    );
+    assert_eq!(client.command(&["GET", "missing"]).await, Resp::Null);
+}
+
+#[tokio::test]
+async fn bad_command_line_arguments_fail_to_start() {
+    let args = ["redis-server", "--save", "", "--port"].map(String::from);
+    let error = Server::builder()
+        .args(args.to_vec())
+        .spawn()
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(error.kind(), std::io::ErrorKind::InvalidInput);
+    assert_eq!(
+        error.to_string(),
+        "invalid argument '--port': wrong number of arguments"
+    );
+
+    let args = ["redis-server", "--save", "", "--port", "lots"].map(String::from);
+    let error = Server::builder()
+        .args(args.to_vec())
+        .spawn()
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(
+        error.to_string(),
+        "invalid value for 'port': argument couldn't be parsed into an integer"
+    );
+}
+
+#[tokio::test]
+async fn subscribers_receive_published_messages() {
+    let server = spawn_server().await;
+    let mut subscriber = Client::connect(&server).await;
+    let mut publisher = Client::connect(&server).await;
+    let confirmation = |kind: &str, channel: &str, count: i64| {
+        Resp::Array(vec![bulk(kind), bulk(channel), Resp::Integer(count)])
+    };
+
+    subscriber.send(&["SUBSCRIBE", "news", "sports"]).await;
+    assert_eq!(
+        subscriber.read_reply().await,
+        confirmation("subscribe", "news", 1)
+    );
+    assert_eq!(
+        subscriber.read_reply().await,
+        confirmation("subscribe", "sports", 2)
+    );
+
+    assert_eq!(
+        publisher.command(&["PUBLISH", "news", "hello"]).await,
+        Resp::Integer(1)
+    );
+    assert_eq!(
+        publisher.command(&["PUBLISH", "weather", "rain"]).await,
+        Resp::Integer(0)
+    );
+    assert_eq!(
+        subscriber.read_reply().await,
+        Resp::Array(vec![bulk("message"), bulk("news"), bulk("hello")])
+    );
+
+    assert_eq!(
+        subscriber.command(&["GET", "foo"]).await,
+        Resp::SimpleError(
+            "ERR Can't execute 'get': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / \
+            RESET are allowed in this context"
+                .to_string()
+        )
+    );
+    assert_eq!(
+        subscriber.command(&["PING"]).await,
+        Resp::Array(vec![bulk("pong"), bulk("")])
+    );
+
+    assert_eq!(
+        subscriber.command(&["UNSUBSCRIBE", "news"]).await,
+        confirmation("unsubscribe", "news", 1)
+    );
+    assert_eq!(
+        subscriber.command(&["UNSUBSCRIBE"]).await,
+        confirmation("unsubscribe", "sports", 0)
+    );
+    assert_eq!(subscriber.command(&["GET", "foo"]).await, Resp::Null);
+
+    // Going away unsubscribes a client from everything.
+    subscriber.send(&["SUBSCRIBE", "news"]).await;
+    assert_eq!(
+        subscriber.read_reply().await,
+        confirmation("subscribe", "news", 1)
+    );
+    drop(subscriber);
+    wait_for_reply(
+        &mut publisher,
+        &["PUBLISH", "news", "anyone?"],
+        Resp::Integer(0),
+    )
+    .await;
+}
+
+#[tokio::test]
+async fn hello_switches_the_connection_to_resp3() {
+    let server = spawn_server().await;
+    let mut client = Client::connect(&server).await;
+    let mut writer = Client::connect(&server).await;
+    client.command(&["HSET", "user", "name", "ann"]).await;
+
+    let hello = match client.command(&["HELLO", "3", "SETNAME", "cache"]).await {
+        Resp::Map(hello) => hello,
+        reply => panic!("expected a map, got {:?}", reply),
+    };
+    assert!(hello.contains(&(bulk("proto"), Resp::Integer(3))));
+    assert!(hello.contains(&(bulk("role"), bulk("master"))));
+    assert_eq!(
+        client.command(&["HGETALL", "user"]).await,
+        Resp::Map(vec![(bulk("name"), bulk("ann"))])
+    );
+
+    // Invalidations are pushed on the connection itself.
+    assert_eq!(client.command(&["CLIENT", "TRACKING", "on"]).await, ok());
+    client.command(&["HGETALL", "user"]).await;
+    writer.command(&["DEL", "user"]).await;
+    assert_eq!(
+        client.read_reply().await,
+        Resp::Push(vec![bulk("invalidate"), Resp::Array(vec![bulk("user")])])
+    );
+
+    assert_eq!(
+        client.command(&["HELLO", "4"]).await,
+        Resp::SimpleError("NOPROTO unsupported protocol version".to_string())
+    );
+    client.command(&["HSET", "user", "name", "bob"]).await;
+    client.command(&["HELLO", "2"]).await;
+    assert_eq!(
+        client.command(&["HGETALL", "user"]).await,
+        Resp::Array(vec![bulk("name"), bulk("bob")])
+    );
+}
+
+#[tokio::test]
+async fn inline_commands_are_understood() {
+    let server = spawn_server().await;
+    let mut client = Client::connect(&server).await;
+
+    client
+        .stream
+        .write_all(b"PING\r\nSET greeting \"hello world\"\r\n\r\nGET greeting\n")
+        .await
+        .unwrap();
+    assert_eq!(
+        client.read_reply().await,
+        Resp::SimpleString("PONG".to_string())
+    );
+    assert_eq!(client.read_reply().await, ok());
+    assert_eq!(client.read_reply().await, bulk("hello world"));
+
+    client
+        .stream
+        .write_all(b"GET \"greeting\r\n")
+        .await
+        .unwrap();
+    assert_eq!(
+        client.read_reply().await,
+        Resp::SimpleError("ERR Protocol error: unbalanced quotes in request".to_string())
+    );
+}
+
+#[tokio::test]
+async fn malformed_requests_only_close_their_own_connection() {
+    let server = spawn_server().await;
+    let mut bystander = Client::connect(&server).await;
+
+    let mut nested = Client::connect(&server).await;
+    nested
+        .stream
+        .write_all(&b"*1\r\n".repeat(4096))
+        .await
+        .unwrap();
+    assert_eq!(
+        nested.read_reply().await,
+        Resp::SimpleError("ERR Protocol error: expected '$', got '*'".to_string())
+    );
+
+    let mut huge = Client::connect(&server).await;
+    huge.stream.write_all(b"*99999999999\r\n").await.unwrap();
+    assert_eq!(
+        huge.read_reply().await,
+        Resp::SimpleError("ERR Protocol error: invalid multibulk length".to_string())
+    );
+
+    assert_eq!(
+        bystander.command(&["PING"]).await,
+        Resp::SimpleString("PONG".to_string())
+    );
+}
+
+#[tokio::test]
+async fn unknown_commands_only_echo_the_beginning_of_their_arguments() {
+    let server = spawn_server().await;
+    let mut client = Client::connect(&server).await;
+
+    let huge = "x".repeat(5_000_000);
+    let reply = client.raw_command(&["NoSuchCommand", &huge]).await;
+    assert_eq!(
+        reply,
+        format!(
+            "-ERR unknown command 'NoSuchCommand', with args beginning with: '{}' \r\n",
+            "x".repeat(128)
+        )
+        .into_bytes()
+    );
+}